@@ -3,7 +3,8 @@ use std::hint::black_box;
 use criterion::{criterion_group, criterion_main, Criterion};
 
 use cimdea::request::{DataRequest, SimpleRequest};
-use cimdea::tabulate::tabulate;
+use cimdea::tabulate::{tabulate, tabulate_with_connection, TabulateOptions};
+use duckdb::Connection;
 
 fn tabulate_simple_request_benchmark(c: &mut Criterion) {
     let data_root = String::from("tests/data_root");
@@ -24,5 +25,42 @@ fn tabulate_simple_request_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, tabulate_simple_request_benchmark);
+/// A request with ten per-dataset queries, tabulated on a new connection each time and on
+/// a single shared connection.
+fn tabulate_ten_datasets_benchmark(c: &mut Criterion) {
+    let data_root = String::from("tests/data_root");
+    let datasets = [
+        "us2015b", "us2016b", "us1940a", "us1900m", "us1850a", "us2015b", "us2016b", "us1940a",
+        "us1900m", "us1850a",
+    ];
+    let (ctx, rq) = SimpleRequest::from_names(
+        "usa",
+        &datasets,
+        &["MARST"],
+        Some("P".to_string()),
+        None,
+        Some(data_root),
+    )
+    .expect("Should be able to set up request and context");
+
+    c.bench_function("tabulate ten datasets", |b| {
+        b.iter(|| {
+            tabulate(black_box(&ctx), black_box(rq.clone())).ok();
+        })
+    });
+
+    let options = TabulateOptions::default();
+    let conn = Connection::open_in_memory().expect("Should be able to open a connection");
+    c.bench_function("tabulate ten datasets on a shared connection", |b| {
+        b.iter(|| {
+            tabulate_with_connection(black_box(&ctx), black_box(rq.clone()), &options, &conn).ok();
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    tabulate_simple_request_benchmark,
+    tabulate_ten_datasets_benchmark
+);
 criterion_main!(benches);
@@ -1,6 +1,7 @@
 use std::fs::File;
 use std::io::{self, BufRead, Write};
 
+use cimdea::input_schema_tabulation::GeneralDetailedSelection;
 use cimdea::request::{AbacusRequest, DataRequest, SimpleRequest};
 use cimdea::tabulate::{self, TableFormat};
 
@@ -52,6 +53,12 @@ struct TabArgs {
     /// The path to the data root, which contains layouts and parquet data [default: inferred from the product]
     #[arg(short, long)]
     data_root: Option<String>,
+    /// The record type of the unit of analysis (e.g. P or H) [default: the product's default unit of analysis]
+    #[arg(short, long)]
+    uoa: Option<String>,
+    /// Tabulate the general codes of the variables instead of their detailed codes
+    #[arg(short, long)]
+    general: bool,
 }
 
 #[derive(Args, Debug)]
@@ -87,11 +94,11 @@ fn main() {
         }
         CliCommand::Tab(tab_args) => {
             let variables: Vec<_> = tab_args.variables.iter().map(|v| v.as_str()).collect();
-            let (context, request) = match SimpleRequest::from_names(
+            let (context, mut request) = match SimpleRequest::from_names(
                 &tab_args.product,
                 &[&tab_args.sample],
                 variables.as_slice(),
-                None,
+                tab_args.uoa,
                 None,
                 tab_args.data_root,
             ) {
@@ -101,6 +108,9 @@ fn main() {
                     std::process::exit(1);
                 }
             };
+            if tab_args.general {
+                request.use_general_variables = GeneralDetailedSelection::General;
+            }
             tabulate::tabulate(&context, request)
         }
     };
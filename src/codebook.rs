@@ -0,0 +1,198 @@
+//! Structured codebooks describing the datasets and variables in a request.
+//!
+//! [DataRequest::print_codebook] renders a plain text codebook from the request alone. A
+//! [Codebook] additionally carries the frequencies of each variable's categories, which
+//! requires running a univariate tabulation for each request variable.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::conventions::Context;
+use crate::input_schema_tabulation::CountColumns;
+use crate::mderror::MdError;
+use crate::request::{DataRequest, OutputFormat, RequestType, RequestVariable, SimpleRequest};
+use crate::tabulate::{self, Table};
+
+/// One category of a codebook variable along with its frequency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodebookCategory {
+    /// The code as it appears in tabulation output.
+    pub code: String,
+    pub label: Option<String>,
+    /// The unweighted count of records with this code across all requested datasets.
+    pub count: u64,
+}
+
+/// The codebook section for one request variable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodebookVariable {
+    pub name: String,
+    pub label: Option<String>,
+    pub general: bool,
+    pub categories: Vec<CodebookCategory>,
+}
+
+impl CodebookVariable {
+    /// Look up a category by its code.
+    pub fn category(&self, code: &str) -> Option<&CodebookCategory> {
+        self.categories.iter().find(|c| c.code == code)
+    }
+}
+
+/// A codebook for a request, with the frequency of each category of each request variable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Codebook {
+    pub datasets: Vec<String>,
+    pub variables: Vec<CodebookVariable>,
+}
+
+impl Codebook {
+    /// Build a detailed codebook for the request by tabulating each request variable on its
+    /// own over all of the requested datasets, within the request's subpopulation.
+    pub fn detailed<R>(ctx: &Context, rq: &R) -> Result<Self, MdError>
+    where
+        R: DataRequest + ?Sized,
+    {
+        let samples = rq.get_request_samples();
+        let mut variables = Vec::new();
+        for rv in rq.get_request_variables() {
+            let frequencies = Self::frequencies(ctx, rq, &rv)?;
+            let categories = frequencies
+                .into_iter()
+                .map(|(code, count)| CodebookCategory {
+                    label: rv.category_label(&code),
+                    code,
+                    count,
+                })
+                .collect();
+
+            variables.push(CodebookVariable {
+                name: rv.name.clone(),
+                label: rv.variable.label.clone(),
+                general: rv.is_general(),
+                categories,
+            });
+        }
+
+        Ok(Self {
+            datasets: samples.into_iter().map(|s| s.name).collect(),
+            variables,
+        })
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&CodebookVariable> {
+        self.variables.iter().find(|v| v.name == name)
+    }
+
+    // Tabulate a single request variable and return the unweighted count for each code.
+    fn frequencies<R>(
+        ctx: &Context,
+        rq: &R,
+        rv: &RequestVariable,
+    ) -> Result<Vec<(String, u64)>, MdError>
+    where
+        R: DataRequest + ?Sized,
+    {
+        let mut variable = rv.variable.clone();
+        variable.category_bins = rv.category_bins.clone();
+
+        let univariate = SimpleRequest {
+            product: ctx.settings.name.clone(),
+            datasets: rq
+                .get_request_samples()
+                .into_iter()
+                .map(|s| s.sample)
+                .collect(),
+            variables: vec![variable],
+            unit_rectype: ctx.settings.default_unit_of_analysis.clone(),
+            request_type: RequestType::Tabulation,
+            output_format: OutputFormat::Json,
+            conditions: Some(rq.all_conditions()).filter(|c| !c.is_empty()),
+            use_general_variables: rv.general_detailed_selection.clone(),
+            weight_rectype: rq.weight_rectype(),
+            weight_variable: None,
+            table_units: Vec::new(),
+            count_columns: CountColumns::Unweighted,
+            case_select_logic: rq.case_select_logic(),
+            case_selections: BTreeMap::new(),
+            general_detailed_selections: BTreeMap::new(),
+        };
+
+        let tables = tabulate::tabulate(ctx, univariate)?.into_inner();
+        let pooled = Table::merge_sum(&tables)?;
+
+        // The columns are ct, then the variable's code.
+        let mut frequencies = Vec::new();
+        for row in pooled.rows {
+            let count = row[0].parse().map_err(|err| {
+                MdError::Msg(format!(
+                    "cannot read the count for {} code {}: {err}",
+                    rv.name, row[1]
+                ))
+            })?;
+            frequencies.push((row[1].clone(), count));
+        }
+        Ok(frequencies)
+    }
+}
+
+impl fmt::Display for Codebook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Datasets: {}", self.datasets.join(", "))?;
+        writeln!(f)?;
+        writeln!(f, "Variables:")?;
+        for v in &self.variables {
+            let general_detailed = if v.general { "General" } else { "detailed" };
+            writeln!(
+                f,
+                "{}\t\t{} -- {}",
+                v.name,
+                v.label.as_deref().unwrap_or("NO LABEL"),
+                general_detailed
+            )?;
+            for c in &v.categories {
+                writeln!(
+                    f,
+                    "\t{}\t{}\t{}",
+                    c.code,
+                    c.label.as_deref().unwrap_or(""),
+                    c.count
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detailed_codebook_marst_counts() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let codebook = rq
+            .detailed_codebook(&ctx)
+            .expect("should build a detailed codebook");
+        assert_eq!(codebook.datasets, vec!["us2015b"]);
+
+        let marst = codebook
+            .variable("MARST")
+            .expect("MARST should have a codebook section");
+        assert_eq!(marst.categories.len(), 6);
+        assert_eq!(marst.category("1").map(|c| c.count), Some(10050));
+        assert_eq!(marst.category("6").map(|c| c.count), Some(13574));
+
+        let text = codebook.to_string();
+        assert!(text.contains("\t1\t\t10050\n"));
+    }
+}
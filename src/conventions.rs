@@ -32,12 +32,20 @@ use crate::defaults;
 use crate::ipums_data_model::*;
 use crate::ipums_metadata_model::*;
 use crate::layout;
-use crate::mderror::{metadata_error, MdError};
-use crate::request::InputType;
-
+use crate::mderror::{metadata_error, parsing_error, MdError};
+use crate::request::{DataRequest, InputType};
+use crate::tabulate;
+
+use parquet::basic::{ConvertedType, LogicalType, Type as PhysicalType};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::schema::types::ColumnDescriptor;
+use serde::Deserialize;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::mem::size_of;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Key characteristics of data collections
 #[derive(Clone, Debug)]
@@ -49,7 +57,182 @@ pub struct MicroDataCollection {
     pub metadata: Option<MetadataEntities>,
 }
 
+// The contents of a collection configuration file. See
+// [MicroDataCollection::from_config_file].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CollectionConfig {
+    name: String,
+    default_unit_of_analysis: String,
+    record_types: Vec<RecordTypeConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RecordTypeConfig {
+    name: String,
+    value: String,
+    unique_id: String,
+    #[serde(default)]
+    foreign_keys: Vec<ForeignKeyConfig>,
+    weight: Option<WeightConfig>,
+    sample_weight: Option<WeightConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForeignKeyConfig {
+    record_type: String,
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WeightConfig {
+    name: String,
+    divisor: usize,
+}
+
+impl From<WeightConfig> for RecordWeight {
+    fn from(config: WeightConfig) -> Self {
+        RecordWeight::new(&config.name, config.divisor)
+    }
+}
+
 impl MicroDataCollection {
+    /// Load a collection's record types, hierarchy, weights and default unit of analysis
+    /// from a TOML file, for collections without [defaults](crate::defaults::defaults_for).
+    /// A collection of households and persons looks like
+    ///
+    /// ```toml
+    /// name = "USA"
+    /// default_unit_of_analysis = "P"
+    ///
+    /// [[record_types]]
+    /// name = "Household"
+    /// value = "H"
+    /// unique_id = "SERIAL"
+    /// weight = { name = "HHWT", divisor = 100 }
+    ///
+    /// [[record_types]]
+    /// name = "Person"
+    /// value = "P"
+    /// unique_id = "PSERIAL"
+    /// foreign_keys = [{ record_type = "H", key = "SERIALP" }]
+    /// weight = { name = "PERWT", divisor = 100 }
+    /// ```
+    ///
+    /// Record types may also have a `sample_weight`. The record type without foreign keys
+    /// is the root of the record hierarchy, and each other record type's parent is the
+    /// record type of its first foreign key. Every foreign key must refer to a declared
+    /// record type.
+    pub fn from_config_file(path: &Path) -> Result<Self, MdError> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::from_config(&contents).map_err(|err| {
+            MdError::Msg(format!(
+                "Can't load the collection configuration {}: {err}",
+                path.display()
+            ))
+        })
+    }
+
+    // Build a collection from the contents of a configuration file.
+    fn from_config(contents: &str) -> Result<Self, MdError> {
+        let config: CollectionConfig =
+            toml::from_str(contents).map_err(|err| parsing_error!("{err}"))?;
+
+        let mut record_types: HashMap<String, RecordType> = HashMap::new();
+        for rt in config.record_types {
+            if record_types.contains_key(&rt.value) {
+                return Err(metadata_error!(
+                    "record type '{}' is declared more than once",
+                    rt.value
+                ));
+            }
+            let record_type = RecordType {
+                name: rt.name,
+                value: rt.value.clone(),
+                unique_id: rt.unique_id,
+                foreign_keys: rt
+                    .foreign_keys
+                    .into_iter()
+                    .map(|fk| (fk.record_type, fk.key))
+                    .collect(),
+                weight: rt.weight.map(RecordWeight::from),
+                sample_weight: rt.sample_weight.map(RecordWeight::from),
+            };
+            record_types.insert(rt.value, record_type);
+        }
+
+        for rt in record_types.values() {
+            if let Some((parent, _)) = rt
+                .foreign_keys
+                .iter()
+                .find(|(parent, _)| !record_types.contains_key(parent))
+            {
+                return Err(metadata_error!(
+                    "record type '{}' has a foreign key to undeclared record type '{parent}'",
+                    rt.value
+                ));
+            }
+        }
+
+        let mut roots: Vec<&String> = record_types
+            .values()
+            .filter(|rt| rt.foreign_keys.is_empty())
+            .map(|rt| &rt.value)
+            .collect();
+        roots.sort();
+        let [root] = roots[..] else {
+            return Err(metadata_error!(
+                "expected one record type without foreign keys for the root of the record hierarchy, found {}",
+                roots.len()
+            ));
+        };
+
+        // Add each record type once its parent is in the hierarchy. A record type whose
+        // parent never gets added is part of a cycle.
+        let mut record_hierarchy = RecordHierarchy::new(root);
+        let mut remaining: Vec<&RecordType> = record_types
+            .values()
+            .filter(|rt| &rt.value != root)
+            .collect();
+        remaining.sort_by(|a, b| a.value.cmp(&b.value));
+        while !remaining.is_empty() {
+            let (ready, waiting): (Vec<&RecordType>, Vec<&RecordType>) = remaining
+                .into_iter()
+                .partition(|rt| record_hierarchy.levels.contains_key(&rt.foreign_keys[0].0));
+            if ready.is_empty() {
+                let values: Vec<&str> = waiting.iter().map(|rt| rt.value.as_str()).collect();
+                return Err(metadata_error!(
+                    "the foreign keys of record types {} form a cycle",
+                    values.join(", ")
+                ));
+            }
+            for rt in ready {
+                record_hierarchy.add_member(&rt.value, &rt.foreign_keys[0].0)?;
+            }
+            remaining = waiting;
+        }
+
+        let Some(default_unit_of_analysis) =
+            record_types.get(&config.default_unit_of_analysis).cloned()
+        else {
+            return Err(metadata_error!(
+                "the default unit of analysis '{}' isn't a declared record type",
+                config.default_unit_of_analysis
+            ));
+        };
+
+        Ok(Self {
+            name: config.name,
+            record_hierarchy,
+            record_types,
+            default_unit_of_analysis,
+            metadata: None,
+        })
+    }
+
     pub fn weight_for_rectype(&self, rt: &str) -> Option<String> {
         let rectype = self.record_types.get(rt)?;
         let weight = &rectype.weight.clone()?;
@@ -74,6 +257,23 @@ impl MicroDataCollection {
         Some(weight.divisor)
     }
 
+    /// Every weight configured on the collection's record types, both the main weights and
+    /// any sample line weights, sorted by name.
+    pub fn weights(&self) -> Vec<&RecordWeight> {
+        let mut weights: Vec<&RecordWeight> = self
+            .record_types
+            .values()
+            .flat_map(|rt| rt.weight.iter().chain(rt.sample_weight.iter()))
+            .collect();
+        weights.sort_by(|a, b| a.name.cmp(&b.name));
+        weights
+    }
+
+    /// Look up one of the collection's [weights](Self::weights) by its name, like "PERWT".
+    pub fn weight(&self, name: &str) -> Option<&RecordWeight> {
+        self.weights().into_iter().find(|w| w.name == name)
+    }
+
     pub fn base_filename_for_dataset(&self, dataset_name: &str) -> String {
         format!("{}_{}", dataset_name, &self.name.to_ascii_lowercase())
     }
@@ -115,22 +315,149 @@ impl MicroDataCollection {
 
     /// Read one fixed-width layout file. These files contain some variable level metadata for
     /// every record type in the data product.
-    pub fn load_metadata_from_layout(&mut self, _layout_file: &Path) {
-        todo!("implement");
+    ///
+    /// The dataset is named after the file, so "us2015b.layout.txt" adds dataset us2015b and
+    /// its variables to the loaded metadata, creating the metadata if there isn't any yet.
+    /// Loading the same layout again doesn't add anything new.
+    pub fn load_metadata_from_layout(&mut self, layout_file: &Path) -> Result<(), MdError> {
+        let Some(dataset_name) = layout_file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".layout.txt"))
+        else {
+            return Err(MdError::Msg(format!(
+                "Can't get a dataset name from layout file {}; expected a name like 'us2015b.layout.txt'.",
+                layout_file.display()
+            )));
+        };
+
+        let layout = layout::DatasetLayout::try_from_layout_file(layout_file)?;
+        let md = self.metadata.get_or_insert_with(MetadataEntities::new);
+        let ipums_dataset = IpumsDataset::from((dataset_name.to_string(), 0));
+        for (index_v, var) in layout.all_variables().iter().enumerate() {
+            md.add_dataset_variable(ipums_dataset.clone(), IpumsVariable::from((var, index_v)));
+        }
+        Ok(())
     }
 
     /// Read all layout files for the data root like `../output_data/current/layouts`
     /// The existence of a layout file implies existence of a dataset. The presence of
     /// a variable in a dataset's layout indicates availability in that dataset.
-    pub fn load_metadata_from_all_layouts(&mut self, _layouts_dir: &Path) {
-        todo!("implement");
+    ///
+    /// Each "*.layout.txt" file is loaded with [MicroDataCollection::load_metadata_from_layout],
+    /// in order by file name, and other files are skipped. A layout which can't be read
+    /// doesn't stop the others from loading; the errors for all of them are returned together.
+    pub fn load_metadata_from_all_layouts(
+        &mut self,
+        layouts_dir: &Path,
+    ) -> Result<(), Vec<MdError>> {
+        let layout_files = layout_files(layouts_dir).map_err(|err| vec![err])?;
+
+        let mut errors = Vec::new();
+        for layout_file in layout_files {
+            if let Err(err) = self.load_metadata_from_layout(&layout_file) {
+                errors.push(MdError::Msg(format!(
+                    "Can't load layout {}: {err}",
+                    layout_file.display()
+                )));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
     }
 
     /// The path like `../output_data/current/parquet/us2019a/`
     /// Reading the schema will give approximately the same metadata information
     /// as reading the fixed-width layout file for the same dataset.
-    pub fn load_metadata_from_parquet(&mut self, _parquet_dataset_path: &Path) {
-        todo!("implement");
+    ///
+    /// The dataset is named after the directory, and each record type's variables come from the
+    /// schema of its file, like "us2019a_usa.P.parquet", or of the first of its part files. Integer
+    /// columns become [IpumsDataType::Integer], floating point and decimal columns
+    /// [IpumsDataType::Float], and UTF8 columns [IpumsDataType::String]. Columns of other types
+    /// have no data type. Parquet has no record positions, so the `formatting` of a variable is
+    /// only set for fixed length and decimal columns, with a start of 0 and the column's width.
+    ///
+    /// Like [MicroDataCollection::load_metadata_from_layout], this creates the metadata if there
+    /// isn't any yet, and loading the same dataset again doesn't add anything new.
+    pub fn load_metadata_from_parquet(
+        &mut self,
+        parquet_dataset_path: &Path,
+    ) -> Result<(), MdError> {
+        let Some(dataset_name) = parquet_dataset_path
+            .file_name()
+            .and_then(|name| name.to_str())
+        else {
+            return Err(MdError::Msg(format!(
+                "Can't get a dataset name from Parquet directory {}; expected a name like 'us2019a'.",
+                parquet_dataset_path.display()
+            )));
+        };
+
+        let mut file_names = Vec::new();
+        for entry in std::fs::read_dir(parquet_dataset_path)? {
+            if let Some(name) = entry?.file_name().to_str() {
+                file_names.push(name.to_string());
+            }
+        }
+        file_names.sort();
+
+        let base_filename = self.base_filename_for_dataset(dataset_name);
+        let mut record_types: Vec<&String> = self.record_types.keys().collect();
+        record_types.sort();
+        let mut schema_files = Vec::new();
+        for record_type in record_types {
+            let prefix = format!("{base_filename}.{record_type}.");
+            // Part files share a schema, so the first one is enough.
+            if let Some(name) = file_names
+                .iter()
+                .find(|name| name.starts_with(&prefix) && name.ends_with(".parquet"))
+            {
+                schema_files.push((record_type.clone(), parquet_dataset_path.join(name)));
+            }
+        }
+        if schema_files.is_empty() {
+            return Err(MdError::Msg(format!(
+                "No Parquet files like '{base_filename}.P.parquet' in {}",
+                parquet_dataset_path.display()
+            )));
+        }
+
+        // The sample size is the number of records of the default unit of analysis, over all
+        // of its part files.
+        let uoa_prefix = format!("{base_filename}.{}.", self.default_unit_of_analysis.value);
+        let mut sample_size = None;
+        for name in file_names
+            .iter()
+            .filter(|name| name.starts_with(&uoa_prefix) && name.ends_with(".parquet"))
+        {
+            let path = parquet_dataset_path.join(name);
+            let num_rows = parquet_reader(&path)?.metadata().file_metadata().num_rows();
+            *sample_size.get_or_insert(0) += usize::try_from(num_rows).unwrap_or_default();
+        }
+
+        let mut variables = Vec::new();
+        for (record_type, path) in schema_files {
+            let reader = parquet_reader(&path)?;
+            let schema = reader.metadata().file_metadata().schema_descr();
+            for column in schema.columns() {
+                let id = variables.len();
+                variables.push(variable_from_parquet_column(column, &record_type, id));
+            }
+        }
+
+        let md = self.metadata.get_or_insert_with(MetadataEntities::new);
+        let ipums_dataset = IpumsDataset {
+            sample_size,
+            ..IpumsDataset::from((dataset_name.to_string(), 0))
+        };
+        for var in variables {
+            md.add_dataset_variable(ipums_dataset.clone(), var);
+        }
+        Ok(())
     }
 
     /// Using the data_root, scan the layouts and load metadata from them.
@@ -155,6 +482,52 @@ impl MicroDataCollection {
         Ok(())
     }
 
+    /// Re-read the layouts of the datasets in the loaded metadata, replacing the metadata
+    /// with what the layouts contain now. Does nothing when no metadata is loaded.
+    ///
+    /// Datasets keep their ids. Variables which are still in the layouts are created first
+    /// in their old order, so they keep their ids unless an earlier variable was removed.
+    /// All layouts are read before the metadata is replaced, so on error the metadata is
+    /// unchanged.
+    pub fn refresh_metadata_from_layouts(&mut self, data_root: &Path) -> Result<(), MdError> {
+        let Some(ref old) = self.metadata else {
+            return Ok(());
+        };
+
+        let layouts_path = data_root.join("layouts");
+        let mut layouts = Vec::new();
+        for ds in &old.datasets_index {
+            let layout = layout::DatasetLayout::try_from_layout_file(
+                &layouts_path.join(format!("{}.layout.txt", ds.name)),
+            )?;
+            layouts.push((ds.name.clone(), layout.all_variables()));
+        }
+
+        let mut fresh: HashMap<String, IpumsVariable> = HashMap::new();
+        for (_, vars) in &layouts {
+            for (index_v, var) in vars.iter().enumerate() {
+                fresh
+                    .entry(var.name.clone())
+                    .or_insert_with(|| IpumsVariable::from((var, index_v)));
+            }
+        }
+
+        let mut md = MetadataEntities::new();
+        for old_var in &old.variables_index {
+            if let Some(var) = fresh.remove(&*old_var.name) {
+                md.create_variable(var);
+            }
+        }
+        for (index_ds, (ds, vars)) in layouts.into_iter().enumerate() {
+            let ipums_dataset = IpumsDataset::from((ds.to_string(), index_ds));
+            for (index_v, var) in vars.iter().enumerate() {
+                md.add_dataset_variable(ipums_dataset.clone(), IpumsVariable::from((var, index_v)));
+            }
+        }
+        self.metadata = Some(md);
+        Ok(())
+    }
+
     /// Uses default product_root to find metadata database and load all metadata for given datasets.
     pub fn load_full_metadata_for_datasets(&mut self, _datasets: &[String]) {
         todo!("implement");
@@ -190,11 +563,95 @@ impl MicroDataCollection {
     pub fn clear_metadata(&mut self) {}
 }
 
+// A reader for the metadata of a Parquet file, like its schema and number of rows.
+fn parquet_reader(path: &Path) -> Result<SerializedFileReader<std::fs::File>, MdError> {
+    std::fs::File::open(path)
+        .map_err(MdError::from)
+        .and_then(|file| {
+            SerializedFileReader::new(file).map_err(|err| MdError::Msg(err.to_string()))
+        })
+        .map_err(|err| {
+            MdError::Msg(format!(
+                "Can't read the Parquet metadata of {}: {err}",
+                path.display()
+            ))
+        })
+}
+
+// A variable for one column of a record type's Parquet schema. Only fixed length and decimal
+// columns have a width; Parquet has no record positions, so their start is 0.
+fn variable_from_parquet_column(
+    column: &ColumnDescriptor,
+    record_type: &str,
+    id: usize,
+) -> IpumsVariable {
+    let (data_type, width) = match (column.physical_type(), column.logical_type()) {
+        (_, Some(LogicalType::Decimal { precision, .. })) => {
+            (Some(IpumsDataType::Float), usize::try_from(precision).ok())
+        }
+        (PhysicalType::INT32 | PhysicalType::INT64, None | Some(LogicalType::Integer { .. })) => {
+            (Some(IpumsDataType::Integer), None)
+        }
+        (PhysicalType::FLOAT | PhysicalType::DOUBLE, _) => (Some(IpumsDataType::Float), None),
+        (PhysicalType::BYTE_ARRAY, _) if column.converted_type() == ConvertedType::UTF8 => {
+            (Some(IpumsDataType::String), None)
+        }
+        (PhysicalType::FIXED_LEN_BYTE_ARRAY, _)
+            if column.converted_type() == ConvertedType::UTF8 =>
+        {
+            (
+                Some(IpumsDataType::String),
+                usize::try_from(column.type_length()).ok(),
+            )
+        }
+        _ => (None, None),
+    };
+
+    IpumsVariable {
+        id,
+        name: Arc::from(column.name()),
+        record_type: record_type.to_string(),
+        data_type,
+        label: None,
+        categories: None,
+        category_bins: None,
+        formatting: width.map(|width| (0, width)),
+        general_width: None,
+        description: None,
+    }
+}
+
+// The "*.layout.txt" files in the layouts directory, sorted by name. Other files and
+// directories are skipped.
+fn layout_files(layouts_dir: &Path) -> Result<Vec<PathBuf>, MdError> {
+    let entries = std::fs::read_dir(layouts_dir).map_err(|err| {
+        MdError::Msg(format!(
+            "Can't read layouts directory {}: {err}",
+            layouts_dir.display()
+        ))
+    })?;
+
+    let mut layout_files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let is_layout = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(".layout.txt"));
+        if is_layout && path.is_file() {
+            layout_files.push(path);
+        }
+    }
+    layout_files.sort();
+    Ok(layout_files)
+}
+
 #[derive(Clone, Debug)]
 pub struct MetadataEntities {
-    //// Name -> Id
-    pub datasets_by_name: HashMap<String, usize>,
-    pub variables_by_name: HashMap<String, usize>,
+    //// Name -> Id. The names are interned, so each key shares its storage with the name
+    //// of its dataset or variable.
+    pub datasets_by_name: HashMap<Arc<str>, usize>,
+    pub variables_by_name: HashMap<Arc<str>, usize>,
     /// The valid cross-products
     pub available_variables: VariablesForDataset,
     pub available_datasets: DatasetsForVariable,
@@ -203,9 +660,24 @@ pub struct MetadataEntities {
     pub variables_index: Vec<IpumsVariable>,
     /// The owning structs
     pub datasets_index: Vec<IpumsDataset>,
+
+    /// Every name interned by [MetadataEntities::intern].
+    names: HashSet<Arc<str>>,
 }
 
 impl MetadataEntities {
+    /// Get the shared copy of a name like "MARST", adding it if this is the first time it's
+    /// been interned. Variables and datasets get their names interned when they're created,
+    /// so each name is stored once however many places refer to it.
+    pub fn intern(&mut self, name: &str) -> Arc<str> {
+        if let Some(interned) = self.names.get(name) {
+            return Arc::clone(interned);
+        }
+        let interned: Arc<str> = Arc::from(name);
+        self.names.insert(Arc::clone(&interned));
+        interned
+    }
+
     fn next_dataset_id(&self) -> IpumsDatasetId {
         self.datasets_index.len()
     }
@@ -214,6 +686,39 @@ impl MetadataEntities {
         self.variables_index.len()
     }
 
+    /// Estimate the memory used by the loaded metadata in bytes, to help decide when to
+    /// clear it. This counts the variables with their category labels and bins, the
+    /// datasets, the name indexes and the sets of variables available in each dataset.
+    /// It's a rough estimate which ignores allocator overhead.
+    pub fn approx_memory_bytes(&self) -> usize {
+        // Each interned name is stored once, behind the two reference counts of its Arc.
+        let names: usize = self
+            .names
+            .iter()
+            .map(|name| size_of::<Arc<str>>() + 2 * size_of::<usize>() + name.len())
+            .sum();
+        let index_entries = (self.datasets_by_name.len() + self.variables_by_name.len())
+            * size_of::<(Arc<str>, usize)>();
+        let variables: usize = self
+            .variables_index
+            .iter()
+            .map(IpumsVariable::approx_memory_bytes)
+            .sum();
+        let datasets: usize = self
+            .datasets_index
+            .iter()
+            .map(IpumsDataset::approx_memory_bytes)
+            .sum();
+
+        size_of::<Self>()
+            + names
+            + index_entries
+            + variables
+            + datasets
+            + self.available_variables.approx_memory_bytes()
+            + self.available_datasets.approx_memory_bytes()
+    }
+
     pub fn cloned_variable_from_id(&self, var_id: IpumsVariableId) -> IpumsVariable {
         self.variables_index[var_id].clone()
     }
@@ -242,7 +747,8 @@ impl MetadataEntities {
         let id = self.next_variable_id();
         let mut new_var = var;
         new_var.id = id;
-        self.variables_by_name.insert(new_var.name.clone(), id);
+        new_var.name = self.intern(&new_var.name);
+        self.variables_by_name.insert(Arc::clone(&new_var.name), id);
         self.variables_index.push(new_var);
         id
     }
@@ -251,7 +757,8 @@ impl MetadataEntities {
         let id = self.next_dataset_id();
         let mut new_ds = ds;
         new_ds.id = id;
-        self.datasets_by_name.insert(new_ds.name.clone(), id);
+        new_ds.name = self.intern(&new_ds.name);
+        self.datasets_by_name.insert(Arc::clone(&new_ds.name), id);
         self.datasets_index.push(new_ds);
         id
     }
@@ -264,6 +771,7 @@ impl MetadataEntities {
             available_datasets: DatasetsForVariable::new(),
             variables_index: Vec::new(),
             datasets_index: Vec::new(),
+            names: HashSet::new(),
         }
     }
 }
@@ -281,9 +789,16 @@ impl VariablesForDataset {
         }
     }
 
+    fn approx_memory_bytes(&self) -> usize {
+        approx_id_sets_memory_bytes(&self.ipums_variables_by_dataset_id)
+    }
+
     pub fn add_or_update(&mut self, dataset_id: IpumsDatasetId, variable_id: IpumsVariableId) {
-        if self.ipums_variables_by_dataset_id.get(dataset_id).is_none() {
-            self.ipums_variables_by_dataset_id.push(HashSet::new());
+        // Dataset ids may arrive out of order or with gaps, so grow the Vec with empty
+        // sets up to this id.
+        if self.ipums_variables_by_dataset_id.len() <= dataset_id {
+            self.ipums_variables_by_dataset_id
+                .resize_with(dataset_id + 1, HashSet::new);
         }
         self.ipums_variables_by_dataset_id[dataset_id].insert(variable_id);
     }
@@ -293,6 +808,13 @@ impl VariablesForDataset {
     }
 }
 
+// Roughly the memory used by a Vec of sets of ids, counting each set's capacity.
+fn approx_id_sets_memory_bytes(sets: &[HashSet<usize>]) -> usize {
+    sets.iter()
+        .map(|ids| size_of::<HashSet<usize>>() + ids.capacity() * size_of::<usize>())
+        .sum()
+}
+
 //// There's a master Vec of datasets this structure points into:
 #[derive(Clone, Debug)]
 pub struct DatasetsForVariable {
@@ -306,13 +828,16 @@ impl DatasetsForVariable {
         }
     }
 
+    fn approx_memory_bytes(&self) -> usize {
+        approx_id_sets_memory_bytes(&self.ipums_datasets_by_variable_id)
+    }
+
     pub fn add_or_update(&mut self, dataset_id: IpumsDatasetId, variable_id: IpumsVariableId) {
-        if self
-            .ipums_datasets_by_variable_id
-            .get(variable_id)
-            .is_none()
-        {
-            self.ipums_datasets_by_variable_id.push(HashSet::new());
+        // Variables aren't always connected in the order of their ids, for example when
+        // refreshing metadata.
+        if self.ipums_datasets_by_variable_id.len() <= variable_id {
+            self.ipums_datasets_by_variable_id
+                .resize_with(variable_id + 1, HashSet::new);
         }
 
         self.ipums_datasets_by_variable_id[variable_id].insert(dataset_id);
@@ -369,6 +894,109 @@ impl MetadataEntities {
 
         self.connect(dataset_id, variable_id);
     }
+
+    /// Compute how much of a series of datasets a variable is available in.
+    ///
+    /// Datasets which aren't in the loaded metadata count as gaps, as do all datasets when the
+    /// variable itself isn't in the metadata. An empty list of datasets has a coverage of 0.
+    pub fn coverage(&self, variable: &str, datasets: &[&str]) -> Coverage {
+        let available_in = self
+            .variables_by_name
+            .get(variable)
+            .and_then(|var_id| self.available_datasets.for_variable(*var_id));
+
+        let missing_in: Vec<String> = datasets
+            .iter()
+            .filter(|ds| {
+                let dataset_id = self.datasets_by_name.get(**ds);
+                match (dataset_id, available_in) {
+                    (Some(ds_id), Some(ds_ids)) => !ds_ids.contains(ds_id),
+                    _ => true,
+                }
+            })
+            .map(|ds| ds.to_string())
+            .collect();
+
+        let fraction = if datasets.is_empty() {
+            0.0
+        } else {
+            (datasets.len() - missing_in.len()) as f64 / datasets.len() as f64
+        };
+
+        Coverage {
+            fraction,
+            missing_in,
+        }
+    }
+}
+
+/// The availability of a variable across a series of datasets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coverage {
+    /// The fraction of the datasets which have the variable, between 0 and 1
+    pub fraction: f64,
+    /// The datasets without the variable, in the order they were requested
+    pub missing_in: Vec<String>,
+}
+
+/// Determine the IPUMS collection that a dataset belongs to from the dataset's name, for
+/// example to choose the collection to create a [Context] for. Dataset names start with a
+/// collection prefix and a 4 digit year: "us" for USA like "us2015b", "cps" for CPS like
+/// "cps2020_03s", and a two letter country code for IPUMSI like "mx2000a".
+///
+/// Returns the collection name, which can be passed to [Context::from_ipums_collection_name],
+/// or `None` if the name doesn't follow the conventions.
+///
+/// ```
+/// use cimdea::conventions::collection_for_dataset;
+///
+/// assert_eq!(collection_for_dataset("us2015b").as_deref(), Some("usa"));
+/// assert_eq!(collection_for_dataset("br2010a").as_deref(), Some("ipumsi"));
+/// assert_eq!(collection_for_dataset("notadataset"), None);
+/// ```
+pub fn collection_for_dataset(name: &str) -> Option<String> {
+    // The collection prefix must be followed by a year.
+    year_from_dataset_name(name)?;
+    let prefix: String = name
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let collection = match prefix.as_str() {
+        "us" => "usa",
+        "cps" => "cps",
+        // Every other two letter prefix is a country code.
+        _ if prefix.len() == 2 => "ipumsi",
+        _ => return None,
+    };
+    Some(collection.to_string())
+}
+
+/// Check whether a data path from [Context::paths_from_dataset_name] exists. A path
+/// whose file name is a glob like "us2015b_usa.P*.parquet" exists when at least one file
+/// in its directory matches it.
+pub fn data_path_exists(path: &Path) -> bool {
+    let file_name = path
+        .file_name()
+        .map(|f| f.to_string_lossy())
+        .unwrap_or_default();
+    let Some((prefix, suffix)) = file_name.split_once('*') else {
+        return path.exists();
+    };
+
+    let Some(dir) = path.parent() else {
+        return false;
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        name.len() >= prefix.len() + suffix.len()
+            && name.starts_with(prefix)
+            && name.ends_with(suffix)
+    })
 }
 
 /// Holds loaded metadata and information for finding data and additional metadata.
@@ -413,6 +1041,9 @@ pub struct Context {
     pub settings: MicroDataCollection,
     pub allow_full_metadata: bool,
     pub enable_full_metadata: bool,
+    /// The data file extension for each input type, without a leading '.'. This starts
+    /// out with the [InputType::default_extension] for each type.
+    pub file_extensions: HashMap<InputType, String>,
 }
 
 impl Context {
@@ -431,23 +1062,157 @@ impl Context {
         }
     }
 
+    /// Select loaded datasets by their attributes rather than by name.
+    ///
+    /// Only datasets already in the loaded metadata are considered, in the order they were
+    /// loaded. Returns an empty Vec if no metadata is loaded.
+    ///
+    /// ```
+    /// use cimdea::conventions::Context;
+    ///
+    /// let mut ctx = Context::from_ipums_collection_name(
+    ///     "usa",
+    ///     None,
+    ///     Some("tests/data_root".to_string()),
+    /// ).unwrap();
+    /// ctx.load_metadata_for_datasets(&["us1940a", "us2015b"]).unwrap();
+    ///
+    /// let recent = ctx.datasets_matching(|ds| ds.year.is_some_and(|year| year >= 2010));
+    /// assert_eq!(recent.len(), 1);
+    /// assert_eq!(&*recent[0].name, "us2015b");
+    /// ```
+    pub fn datasets_matching<P>(&self, predicate: P) -> Vec<IpumsDataset>
+    where
+        P: Fn(&IpumsDataset) -> bool,
+    {
+        match self.settings.metadata {
+            Some(ref md) => md
+                .datasets_index
+                .iter()
+                .filter(|ds| predicate(ds))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Check whether the given datasets exist and have the given variables, without setting up
+    /// a request. This is meant as a quick check of user selections.
+    ///
+    /// Datasets in the loaded metadata are checked against it. Other datasets are checked
+    /// against their layout files in the data root, which are read but not added to the
+    /// metadata. Returns an error for each dataset which can't be found and for each variable
+    /// missing from a dataset which was found.
+    ///
+    /// ```
+    /// use cimdea::conventions::Context;
+    ///
+    /// let ctx = Context::from_ipums_collection_name(
+    ///     "usa",
+    ///     None,
+    ///     Some("tests/data_root".to_string()),
+    /// ).unwrap();
+    /// assert!(ctx.can_satisfy(&["us2015b"], &["MARST", "INCWAGE"]).is_ok());
+    ///
+    /// let errors = ctx.can_satisfy(&["us1850a"], &["INCWAGE"]).unwrap_err();
+    /// assert_eq!(errors.len(), 1);
+    /// ```
+    pub fn can_satisfy(&self, datasets: &[&str], variables: &[&str]) -> Result<(), Vec<MdError>> {
+        let mut errors = Vec::new();
+        for dataset in datasets {
+            let available = match self.variable_names_for_dataset(dataset) {
+                Ok(available) => available,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+
+            for variable in variables {
+                let name = variable.to_ascii_uppercase();
+                if !available.contains(&name) {
+                    errors.push(metadata_error!(
+                        "Variable {name} is not available in dataset {dataset}."
+                    ));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // The names of the variables in a dataset, from the loaded metadata if it has the
+    // dataset and otherwise from the dataset's layout file.
+    fn variable_names_for_dataset(&self, dataset: &str) -> Result<HashSet<String>, MdError> {
+        if let Some(ref md) = self.settings.metadata {
+            if let Some(&dataset_id) = md.datasets_by_name.get(dataset) {
+                let names = md
+                    .available_variables
+                    .for_dataset(dataset_id)
+                    .into_iter()
+                    .flatten()
+                    .map(|&id| md.variables_index[id].name.to_string())
+                    .collect();
+                return Ok(names);
+            }
+        }
+
+        let Some(ref data_root) = self.data_root else {
+            return Err(metadata_error!(
+                "No dataset named {dataset} in the loaded metadata, and no data_root to find its layout."
+            ));
+        };
+        let layout_file = data_root
+            .join("layouts")
+            .join(format!("{dataset}.layout.txt"));
+        if !layout_file.exists() {
+            return Err(metadata_error!(
+                "No dataset named {dataset} found in metadata or layouts."
+            ));
+        }
+        let layout = layout::DatasetLayout::try_from_layout_file(&layout_file)?;
+        Ok(layout.all_variables().into_iter().map(|v| v.name).collect())
+    }
+
+    /// Count the records of the request's unit of analysis which meet its conditions. Returns
+    /// the unweighted and weighted totals over all of the request's datasets. This is
+    /// [tabulate::count](crate::tabulate::count), which is much faster than tabulating the
+    /// request and summing its counts.
+    pub fn count<R: DataRequest>(&self, rq: R) -> Result<(usize, f64), MdError> {
+        tabulate::count(self, rq)
+    }
+
     /// Formats the exact paths needed to get data for this dataset, by record type.
+    ///
+    /// Parquet data for a record type may be split into several part files, like
+    /// "us2015b_usa.P.0.parquet" and "us2015b_usa.P.1.parquet". When there's no single
+    /// file for the record type but there are part files, its path is a glob like
+    /// "us2015b_usa.P*.parquet" which matches all of the parts. See [data_path_exists].
+    ///
+    /// CSV data may be gzip-compressed, like "us2015b_usa.P.csv.gz". When there's no
+    /// uncompressed file for a record type but there is a compressed one, its path is the
+    /// compressed file. Set the CSV extension to "csv.gz" with [Context::set_file_extension]
+    /// to always read the compressed files.
+    ///
+    /// Fixed-width data has a single file for all record types, so its path is the only
+    /// entry, under the empty string. This is an error when the context has no data root.
     pub fn paths_from_dataset_name(
         &self,
         dataset_name: &str,
         data_format: &InputType,
     ) -> Result<HashMap<String, PathBuf>, MdError> {
-        let extension = match data_format {
-            InputType::Csv => "csv",
-            InputType::Parquet => "parquet",
-            InputType::Fw => "dat.gz",
-            InputType::NativeDb => "",
-        };
+        let extension = self.file_extension(data_format);
 
         let data_path = if let Some(ref data_root) = self.data_root {
             PathBuf::from(data_root)
         } else {
-            return Err(MdError::Msg("No data root set.".to_string()));
+            return Err(MdError::Msg(format!(
+                "Can't find the data files for {dataset_name}: no data root is set."
+            )));
         };
 
         let mut all_paths = HashMap::new();
@@ -461,12 +1226,25 @@ impl Context {
                             .settings
                             .base_filename_for_dataset_and_rectype(dataset_name, rt);
                         let full_filename = format!("{}.{}", &base_filename, extension);
-                        let full_path = parent_dir.join(full_filename);
+                        let mut full_path = parent_dir.join(&full_filename);
+                        if matches!(data_format, InputType::Parquet) && !full_path.exists() {
+                            let glob = parent_dir.join(format!("{base_filename}*.{extension}"));
+                            if data_path_exists(&glob) {
+                                full_path = glob;
+                            }
+                        }
+                        if matches!(data_format, InputType::Csv) && !full_path.exists() {
+                            // DuckDB decompresses gzipped CSV files as it reads them.
+                            let compressed = parent_dir.join(format!("{full_filename}.gz"));
+                            if compressed.exists() {
+                                full_path = compressed;
+                            }
+                        }
                         all_paths.insert(rt.to_string(), full_path);
                     } else {
-                        return Err(MdError::Msg(
-                            "InputType of data should have a sub directory name.".to_string(),
-                        ));
+                        return Err(MdError::Msg(format!(
+                            "Can't find the data files for {dataset_name}: input type {data_format:?} has no data sub-directory."
+                        )));
                     }
                 }
             }
@@ -486,6 +1264,150 @@ impl Context {
         Ok(all_paths)
     }
 
+    /// Make sure the metadata has the structural variables for the dataset: each record
+    /// type's unique id, foreign keys, weight and sample weight. Joining record types and
+    /// weighting counts rely on these even when they aren't requested.
+    ///
+    /// This loads any of them which are missing from the dataset's layout, leaving the rest of
+    /// the metadata alone, and returns the names of the structural variables that the layout
+    /// doesn't have. The dataset is added to the metadata if it isn't there already.
+    pub fn ensure_structural_variables(&mut self, dataset: &str) -> Result<Vec<String>, MdError> {
+        let Some(ref data_root) = self.data_root else {
+            return Err(metadata_error!(
+                "Cannot load structural variables for {dataset} without a data_root."
+            ));
+        };
+
+        let mut names = BTreeSet::new();
+        for rt in self.settings.record_types.values() {
+            names.insert(rt.unique_id.clone());
+            names.extend(rt.foreign_keys.iter().map(|(_, key)| key.clone()));
+            names.extend(rt.weight.iter().map(|w| w.name.clone()));
+            names.extend(rt.sample_weight.iter().map(|w| w.name.clone()));
+        }
+        let names: Vec<String> = names.into_iter().collect();
+
+        let layout = layout::DatasetLayout::try_from_layout_file(
+            &data_root
+                .join("layouts")
+                .join(format!("{}.layout.txt", dataset)),
+        )?;
+        let found = layout.find_variables(&names);
+
+        let md = self
+            .settings
+            .metadata
+            .get_or_insert_with(MetadataEntities::new);
+        let ipums_dataset = IpumsDataset::from((dataset.to_string(), 0));
+        for (index, var) in found.iter().enumerate() {
+            md.add_dataset_variable(ipums_dataset.clone(), IpumsVariable::from((var, index)));
+        }
+
+        let missing = names
+            .into_iter()
+            .filter(|name| !found.iter().any(|var| &var.name == name))
+            .collect();
+        Ok(missing)
+    }
+
+    /// Label the codes of variables in the loaded metadata from a CSV file with a header row of
+    /// "variable,code,label", for when neither the layouts nor a metadata database have labels.
+    /// These labels are used for labeled output like [TabulateOptions::codes_and_labels].
+    ///
+    /// Each row gives the label of one code of one variable, replacing the variable's label for
+    /// that code if it already has one. Codes without a row keep any label they have. Rows for
+    /// variables which aren't in the loaded metadata are skipped, so one file can cover a whole
+    /// product, but the metadata for the datasets must be loaded first.
+    ///
+    /// [TabulateOptions::codes_and_labels]: crate::tabulate::TabulateOptions::codes_and_labels
+    pub fn load_value_labels_csv(&mut self, path: &Path) -> Result<(), MdError> {
+        let Some(ref mut md) = self.settings.metadata else {
+            return Err(metadata_error!(
+                "Load metadata before loading value labels from {}.",
+                path.display()
+            ));
+        };
+
+        let mut reader = csv::Reader::from_path(path).map_err(|err| {
+            parsing_error!("Can't read value labels from {}: {err}", path.display())
+        })?;
+        let headers = reader
+            .headers()
+            .map_err(|err| {
+                parsing_error!("Can't read value labels from {}: {err}", path.display())
+            })?
+            .clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|h| h.trim() == name)
+                .ok_or_else(|| {
+                    parsing_error!(
+                        "The value labels file {} has no '{name}' column.",
+                        path.display()
+                    )
+                })
+        };
+        let (variable_column, code_column, label_column) =
+            (column("variable")?, column("code")?, column("label")?);
+
+        for record in reader.records() {
+            let record = record.map_err(|err| {
+                parsing_error!("Can't read value labels from {}: {err}", path.display())
+            })?;
+            let field = |index: usize| record.get(index).unwrap_or_default().trim();
+            let name = field(variable_column).to_ascii_uppercase();
+            let Some(&id) = md.variables_by_name.get(name.as_str()) else {
+                continue;
+            };
+            let var = &mut md.variables_index[id];
+
+            let code = field(code_column);
+            let value = if var.data_type == Some(IpumsDataType::String) {
+                IpumsValue::String {
+                    utf8: true,
+                    value: code.as_bytes().to_vec(),
+                }
+            } else {
+                let code: i64 = code.parse().map_err(|_| {
+                    parsing_error!(
+                        "Value label code '{code}' for variable {name} in {} is not an integer.",
+                        path.display()
+                    )
+                })?;
+                IpumsValue::Integer(code)
+            };
+
+            let category = IpumsCategory::new(
+                record.get(label_column).unwrap_or_default(),
+                UniversalCategoryType::Value,
+                value,
+            );
+            let categories = var.categories.get_or_insert_with(Vec::new);
+            match categories.iter_mut().find(|c| c.value == category.value) {
+                Some(existing) => *existing = category,
+                None => categories.push(category),
+            }
+        }
+        Ok(())
+    }
+
+    /// The extension of data files of the given input type, without a leading '.'.
+    pub fn file_extension(&self, input_type: &InputType) -> &str {
+        self.file_extensions
+            .get(input_type)
+            .map(|extension| extension.as_str())
+            .unwrap_or_else(|| input_type.default_extension())
+    }
+
+    /// Use a different extension for data files of the given input type, for example "pq"
+    /// instead of "parquet". A leading '.' on the extension is ignored.
+    pub fn set_file_extension(&mut self, input_type: InputType, extension: &str) {
+        let extension = extension.strip_prefix('.').unwrap_or(extension);
+        self.file_extensions
+            .insert(input_type, extension.to_string());
+    }
+
     /// When called, the context should be already set to read from layouts or full metadata
     pub fn load_metadata_for_datasets(&mut self, datasets: &[&str]) -> Result<(), MdError> {
         if !self.enable_full_metadata {
@@ -500,6 +1422,68 @@ impl Context {
         }
     }
 
+    /// Load the metadata for every dataset with a layout in the data root's "layouts"
+    /// directory, like [Context::load_metadata_for_datasets] with all of their names, and
+    /// return the names in order. Layout files are named like "us2015b.layout.txt"; other
+    /// files are ignored.
+    ///
+    /// Loading every dataset of a large product can take a lot of time and memory, so when
+    /// `limit` is given and there are more datasets than that, this returns an error without
+    /// loading any of them.
+    pub fn load_all_available_metadata(
+        &mut self,
+        limit: Option<usize>,
+    ) -> Result<Vec<String>, MdError> {
+        let Some(ref data_root) = self.data_root else {
+            return Err(metadata_error!(
+                "Cannot find the available datasets without a data_root."
+            ));
+        };
+        let layouts_dir = data_root.join("layouts");
+
+        let datasets: Vec<String> = layout_files(&layouts_dir)?
+            .iter()
+            .filter_map(|path| path.file_name()?.to_str()?.strip_suffix(".layout.txt"))
+            .map(str::to_string)
+            .collect();
+
+        if let Some(limit) = limit {
+            if datasets.len() > limit {
+                return Err(metadata_error!(
+                    "Found {} datasets in {}, more than the limit of {limit}.",
+                    datasets.len(),
+                    layouts_dir.display()
+                ));
+            }
+        }
+
+        let names: Vec<&str> = datasets.iter().map(String::as_str).collect();
+        self.load_metadata_for_datasets(&names)?;
+        Ok(datasets)
+    }
+
+    /// Reload the metadata for the datasets already loaded, for example after their data was
+    /// rebuilt on disk. This replaces stale variables, adds new ones and drops removed ones,
+    /// keeping ids where possible. See
+    /// [MicroDataCollection::refresh_metadata_from_layouts]. Refreshing twice in a row
+    /// leaves the metadata the same. Metadata from the full metadata database can't be
+    /// refreshed yet, so that's an error.
+    pub fn refresh_metadata(&mut self) -> Result<(), MdError> {
+        if !self.enable_full_metadata {
+            if let Some(ref data_root) = self.data_root {
+                self.settings.refresh_metadata_from_layouts(data_root)
+            } else {
+                Err(metadata_error!(
+                    "Cannot refresh metadata without a data_root to read layouts from."
+                ))
+            }
+        } else {
+            Err(metadata_error!(
+                "Refreshing metadata from the metadata database isn't supported yet."
+            ))
+        }
+    }
+
     /// The context should be set to read from layouts or full metadata
     pub fn load_metadata_for_datasets_and_variables(
         &mut self,
@@ -545,21 +1529,55 @@ impl Context {
             settings,
             allow_full_metadata,
             enable_full_metadata: false,
+            file_extensions: [
+                InputType::Csv,
+                InputType::Parquet,
+                InputType::Fw,
+                InputType::NativeDb,
+            ]
+            .into_iter()
+            .map(|input_type| {
+                let extension = input_type.default_extension().to_string();
+                (input_type, extension)
+            })
+            .collect(),
         })
     }
 
-    /*
-     // Give the path like '/pkg/ipums/usa'. Extract product name from path
-     // if possible and use defaults.
-     pub fn default_from_product_root(product_path: &str) -> Self {
-
-     }
+    /// Give the path like '/pkg/ipums/usa'. The product name is the last component of the
+    /// path, and the context has the defaults for that product. The data root is the
+    /// "output_data/current" directory in the product root.
+    ///
+    /// Full metadata is only allowed when the product root has a "metadata" directory.
+    /// Returns an error if the last component of the path isn't the name of a recognized
+    /// product.
+    pub fn default_from_product_root(product_path: &str) -> Result<Self, MdError> {
+        let product_root = PathBuf::from(product_path);
+        let Some(name) = product_root.file_name().and_then(|n| n.to_str()) else {
+            return Err(MdError::Msg(format!(
+                "Can't find a product name at the end of the product root '{product_path}'"
+            )));
+        };
+        let data_root = product_root.join("output_data").join("current");
+
+        let mut ctx = Self::from_ipums_collection_name(
+            name,
+            Some(product_path.to_string()),
+            Some(data_root.to_string_lossy().into_owned()),
+        )?;
+        ctx.allow_full_metadata = product_root.join("metadata").is_dir();
+        Ok(ctx)
+    }
 
-     // Use name for product and apply defaults, but  substitute the data_root for
-     // the default data_root.
-     pub fn from_name_and_data_root(name: &str, data_root: &str) -> Self {
-     }
+    /// Use name for product and apply defaults, but substitute the data_root for the
+    /// default data_root. The product root is the default one.
+    ///
+    /// Returns an error if the given name isn't the name of a recognized product.
+    pub fn from_name_and_data_root(name: &str, data_root: &str) -> Result<Self, MdError> {
+        Self::from_ipums_collection_name(name, None, Some(data_root.to_string()))
+    }
 
+    /*
      // If the context has the project root in addition to the data root it can
      // attempt to access the metadata DB. Using full metadata requires the
      // Some(product_root).
@@ -573,6 +1591,21 @@ impl Context {
 #[cfg(test)]
 mod test {
     use super::*;
+
+    #[test]
+    fn test_weights_for_default_collection() {
+        let settings = defaults::defaults_for("cps").expect("should have defaults for CPS");
+        let names: Vec<&str> = settings.weights().iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(names, vec!["HHWT", "PERWT"]);
+
+        let usa = defaults::defaults_for("usa").expect("should have defaults for USA");
+        let names: Vec<&str> = usa.weights().iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(names, vec!["HHWT", "PERWT", "SLWT"]);
+
+        assert_eq!(usa.weight("PERWT").map(|w| w.divisor), Some(100));
+        assert!(usa.weight("NOTAWEIGHT").is_none());
+    }
+
     #[test]
     pub fn test_context() {
         // Look in test directory
@@ -605,6 +1638,160 @@ mod test {
         assert!(usa_ctx.settings.record_types.contains_key("P"));
     }
 
+    #[test]
+    fn test_add_or_update_sparse_ids() {
+        let mut variables = VariablesForDataset::new();
+        variables.add_or_update(3, 7);
+        variables.add_or_update(1, 2);
+        assert_eq!(variables.for_dataset(3), Some(&HashSet::from([7])));
+        assert_eq!(variables.for_dataset(1), Some(&HashSet::from([2])));
+        assert_eq!(variables.for_dataset(0), Some(&HashSet::new()));
+        assert_eq!(variables.for_dataset(4), None);
+
+        let mut datasets = DatasetsForVariable::new();
+        datasets.add_or_update(3, 7);
+        datasets.add_or_update(1, 2);
+        assert_eq!(datasets.for_variable(7), Some(&HashSet::from([3])));
+        assert_eq!(datasets.for_variable(2), Some(&HashSet::from([1])));
+        assert_eq!(datasets.for_variable(5), Some(&HashSet::new()));
+        assert_eq!(datasets.for_variable(8), None);
+    }
+
+    #[test]
+    fn test_default_from_product_root() {
+        let product_root =
+            std::env::temp_dir().join(format!("cimdea_product_{}", std::process::id()));
+        let usa_root = product_root.join("usa");
+        std::fs::create_dir_all(&usa_root).expect("should create the product root");
+
+        let ctx = Context::default_from_product_root(&usa_root.to_string_lossy())
+            .expect("should make a context for the usa product root");
+        assert_eq!(ctx.name, "usa");
+        assert_eq!(ctx.settings.name, "USA");
+        assert_eq!(ctx.product_root, Some(usa_root.clone()));
+        assert_eq!(
+            ctx.data_root,
+            Some(usa_root.join("output_data").join("current"))
+        );
+        assert!(!ctx.allow_full_metadata);
+
+        std::fs::create_dir_all(usa_root.join("metadata"))
+            .expect("should create the metadata directory");
+        let ctx = Context::default_from_product_root(&usa_root.to_string_lossy())
+            .expect("should make a context for the usa product root");
+        assert!(ctx.allow_full_metadata);
+
+        std::fs::remove_dir_all(&product_root).expect("should clean up the product root");
+    }
+
+    #[test]
+    fn test_default_from_product_root_unknown_product_error() {
+        assert!(Context::default_from_product_root("/pkg/ipums/notaproduct").is_err());
+        assert!(Context::default_from_product_root("/").is_err());
+    }
+
+    #[test]
+    fn test_from_ipums_collection_name_registered_collection() {
+        let collection =
+            MicroDataCollection::from_config_file(Path::new("tests/collections/time_use.toml"))
+                .expect("should load the collection configuration");
+        defaults::register_collection("test_time_use", collection);
+
+        let context = Context::from_ipums_collection_name(
+            "test_time_use",
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should make a context for the registered collection");
+        assert_eq!(context.settings.name, "Time Use");
+        assert!(context.settings.record_types.contains_key("A"));
+    }
+
+    #[test]
+    fn test_from_name_and_data_root() {
+        let ctx = Context::from_name_and_data_root("usa", "tests/data_root")
+            .expect("should make a context for usa");
+        assert_eq!(ctx.data_root, Some(PathBuf::from("tests/data_root")));
+        assert_eq!(ctx.product_root, Some(PathBuf::from("/pkg/ipums/usa")));
+        assert_eq!(ctx.settings.name, "USA");
+
+        assert!(Context::from_name_and_data_root("notaproduct", "tests/data_root").is_err());
+    }
+
+    #[test]
+    fn test_refresh_metadata_full_metadata_error() {
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .expect("should be able to create USA context");
+        ctx.enable_full_metadata = true;
+        let result = ctx.refresh_metadata();
+        assert!(result.is_err(), "expected an error but got {result:?}");
+    }
+
+    #[test]
+    fn test_refresh_metadata_picks_up_layout_changes() {
+        let data_root = std::env::temp_dir().join(format!("cimdea_refresh_{}", std::process::id()));
+        let layouts = data_root.join("layouts");
+        std::fs::create_dir_all(&layouts).expect("should create the layouts directory");
+        let layout_file = layouts.join("us2015b.layout.txt");
+        let original = std::fs::read_to_string("tests/data_root/layouts/us2015b.layout.txt")
+            .expect("should read the fixture layout");
+        std::fs::write(&layout_file, &original).expect("should write the layout");
+
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some(data_root.display().to_string()))
+                .expect("should be able to create USA context");
+        ctx.load_metadata_for_datasets(&["us2015b"])
+            .expect("should load metadata");
+        let marst_before = ctx.get_md_variable_by_name("MARST").unwrap();
+        assert!(ctx.get_md_variable_by_name("NEWVAR").is_err());
+
+        // The data is rebuilt with a new variable.
+        std::fs::write(&layout_file, format!("{original}NEWVAR P 4405 2 integer\n"))
+            .expect("should update the layout");
+        ctx.refresh_metadata().expect("should refresh the metadata");
+        ctx.refresh_metadata()
+            .expect("refreshing again should do nothing new");
+        let _ = std::fs::remove_dir_all(&data_root);
+
+        let newvar = ctx
+            .get_md_variable_by_name("NEWVAR")
+            .expect("the new variable should be loaded");
+        assert_eq!(newvar.formatting, Some((4405, 2)));
+        let marst_after = ctx.get_md_variable_by_name("MARST").unwrap();
+        assert_eq!(marst_after.id, marst_before.id);
+        let md = ctx.settings.metadata.as_ref().unwrap();
+        assert_eq!(md.datasets_index.len(), 1);
+        assert_eq!(md.variables_index.len(), newvar.id + 1);
+    }
+
+    #[test]
+    fn test_can_satisfy_reports_each_failure() {
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .expect("should be able to create USA context");
+        // us2015b comes from the loaded metadata, us1850a from its layout file.
+        ctx.load_metadata_for_datasets(&["us2015b"])
+            .expect("should load metadata for us2015b");
+
+        assert!(ctx
+            .can_satisfy(&["us2015b", "us1850a"], &["marst", "SEX"])
+            .is_ok());
+
+        let errors = ctx
+            .can_satisfy(
+                &["us2015b", "us1850a", "us1776z"],
+                &["MARST", "INCWAGE", "NOTAVAR"],
+            )
+            .expect_err("should report the missing dataset and variables");
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages.len(), 4, "{messages:?}");
+        assert!(messages[0].contains("NOTAVAR") && messages[0].contains("us2015b"));
+        assert!(messages[1].contains("INCWAGE") && messages[1].contains("us1850a"));
+        assert!(messages[2].contains("NOTAVAR") && messages[2].contains("us1850a"));
+        assert!(messages[3].contains("us1776z"));
+    }
+
     #[test]
     pub fn test_paths_for_dataset_names() {
         let data_root = Some(String::from("test/data_root"));
@@ -625,6 +1812,454 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_paths_for_dataset_names_custom_parquet_extension() {
+        let data_root = Some(String::from("test/data_root"));
+        let mut usa_ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+        assert_eq!(usa_ctx.file_extension(&InputType::Parquet), "parquet");
+
+        usa_ctx.set_file_extension(InputType::Parquet, ".pq");
+        assert_eq!(usa_ctx.file_extension(&InputType::Parquet), "pq");
+
+        let paths_by_rectype = usa_ctx
+            .paths_from_dataset_name("us2015b", &InputType::Parquet)
+            .expect("should be able to get paths from dataset name");
+        assert_eq!(
+            paths_by_rectype.get("P"),
+            Some(&PathBuf::from(
+                "test/data_root/parquet/us2015b/us2015b_usa.P.pq"
+            ))
+        );
+    }
+
+    /// There's no single Parquet file for us1850a person records, only part files.
+    #[test]
+    fn test_paths_for_dataset_names_multi_part_parquet() {
+        let data_root = Some(String::from("tests/data_root"));
+        let usa_ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+
+        let paths_by_rectype = usa_ctx
+            .paths_from_dataset_name("us1850a", &InputType::Parquet)
+            .expect("should be able to get paths from dataset name");
+        let person_path = paths_by_rectype
+            .get("P")
+            .expect("should have a path for person records");
+        assert_eq!(
+            person_path,
+            &PathBuf::from("tests/data_root/parquet/us1850a/us1850a_usa.P*.parquet")
+        );
+        assert!(data_path_exists(person_path));
+
+        // Without any part files, the path is the usual single file.
+        let household_path = paths_by_rectype
+            .get("H")
+            .expect("should have a path for household records");
+        assert_eq!(
+            household_path,
+            &PathBuf::from("tests/data_root/parquet/us1850a/us1850a_usa.H.parquet")
+        );
+        assert!(!data_path_exists(household_path));
+    }
+
+    #[test]
+    fn test_paths_for_dataset_names_fixed_width() {
+        let data_root = Some(String::from("tests/data_root"));
+        let usa_ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+        let paths = usa_ctx
+            .paths_from_dataset_name("us2015b", &InputType::Fw)
+            .expect("should be able to get paths from dataset name");
+        assert_eq!(paths.len(), 1);
+        assert_eq!(
+            paths.get(""),
+            Some(&PathBuf::from("tests/data_root/us2015b_usa.dat.gz"))
+        );
+    }
+
+    #[test]
+    fn test_paths_for_dataset_names_no_data_root_error() {
+        let mut usa_ctx = Context::from_ipums_collection_name("usa", None, None)
+            .expect("should be able to create USA context");
+        usa_ctx.data_root = None;
+        let err = usa_ctx
+            .paths_from_dataset_name("us2015b", &InputType::Parquet)
+            .expect_err("there's no data root to find the files in");
+        assert!(err.to_string().contains("us2015b: no data root is set"));
+    }
+
+    #[test]
+    fn test_paths_for_dataset_names_compressed_csv() {
+        let data_root = std::env::temp_dir().join(format!("cimdea_csv_gz_{}", std::process::id()));
+        let dataset_dir = data_root.join("csv").join("us2015b");
+        std::fs::create_dir_all(&dataset_dir).expect("should be able to create the data root");
+        std::fs::write(dataset_dir.join("us2015b_usa.P.csv.gz"), "")
+            .expect("should be able to write the person file");
+        std::fs::write(dataset_dir.join("us2015b_usa.H.csv"), "")
+            .expect("should be able to write the household file");
+
+        let mut ctx = Context::from_ipums_collection_name(
+            "usa",
+            None,
+            Some(data_root.to_string_lossy().to_string()),
+        )
+        .expect("should be able to create USA context");
+        let paths_by_rectype = ctx
+            .paths_from_dataset_name("us2015b", &InputType::Csv)
+            .expect("should be able to get paths from dataset name");
+        assert_eq!(
+            paths_by_rectype.get("P"),
+            Some(&dataset_dir.join("us2015b_usa.P.csv.gz"))
+        );
+        // The uncompressed file comes first when there is one.
+        assert_eq!(
+            paths_by_rectype.get("H"),
+            Some(&dataset_dir.join("us2015b_usa.H.csv"))
+        );
+
+        ctx.set_file_extension(InputType::Csv, "csv.gz");
+        let paths_by_rectype = ctx
+            .paths_from_dataset_name("us2015b", &InputType::Csv)
+            .expect("should be able to get paths from dataset name");
+        assert_eq!(
+            paths_by_rectype.get("H"),
+            Some(&dataset_dir.join("us2015b_usa.H.csv.gz"))
+        );
+
+        std::fs::remove_dir_all(&data_root).expect("should be able to clean up the data root");
+    }
+
+    #[test]
+    fn test_ensure_structural_variables() {
+        let data_root = Some(String::from("tests/data_root"));
+        let mut ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+        assert!(ctx.settings.metadata.is_none());
+
+        let missing = ctx
+            .ensure_structural_variables("us2015b")
+            .expect("should be able to load structural variables");
+        // The person unique id isn't in the layout file.
+        assert_eq!(missing, vec!["PSERIAL"]);
+
+        let md = ctx
+            .settings
+            .metadata
+            .as_ref()
+            .expect("metadata should be loaded");
+        for name in ["SERIAL", "SERIALP", "PERWT", "HHWT", "SLWT"] {
+            assert!(
+                md.cloned_variable_from_name(name).is_some(),
+                "{name} should be loaded"
+            );
+            assert_eq!(md.coverage(name, &["us2015b"]).fraction, 1.0);
+        }
+        assert!(
+            md.cloned_variable_from_name("MARST").is_none(),
+            "only structural variables should be loaded"
+        );
+    }
+
+    #[test]
+    fn test_datasets_matching_year_threshold() {
+        let data_root = Some(String::from("tests/data_root"));
+        let mut ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+        ctx.load_metadata_for_datasets(&["us1900m", "us2015b", "us1940a", "us2016b"])
+            .expect("should be able to load metadata for datasets");
+
+        let matching = ctx.datasets_matching(|ds| ds.year.is_some_and(|year| year >= 2010));
+        let names: Vec<_> = matching.iter().map(|ds| ds.name.as_ref()).collect();
+        assert_eq!(names, ["us2015b", "us2016b"]);
+    }
+
+    #[test]
+    fn test_datasets_matching_sample_size_threshold() {
+        let data_root = Some(String::from("tests/data_root"));
+        let mut ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+        for dataset in ["us1940a", "us2015b", "us2016b"] {
+            ctx.settings
+                .load_metadata_from_parquet(&Path::new("tests/data_root/parquet").join(dataset))
+                .expect("should load the Parquet schemas");
+        }
+
+        // us2015b has 30767 persons and us2016b has 30224.
+        let matching = ctx.datasets_matching(|ds| ds.sample_size.is_some_and(|size| size > 30500));
+        let names: Vec<_> = matching.iter().map(|ds| ds.name.as_ref()).collect();
+        assert_eq!(names, ["us2015b"]);
+    }
+
+    #[test]
+    fn test_datasets_matching_no_metadata() {
+        let data_root = Some(String::from("tests/data_root"));
+        let ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+        assert!(ctx.datasets_matching(|_| true).is_empty());
+    }
+
+    fn test_variable(name: &str) -> IpumsVariable {
+        let layout_var = layout::LayoutVar {
+            name: name.to_string(),
+            rectype: "P".to_string(),
+            start: 1,
+            width: 2,
+            col: 0,
+            data_type: IpumsDataType::Integer,
+        };
+        IpumsVariable::from((&layout_var, 0))
+    }
+
+    #[test]
+    fn test_collection_for_dataset() {
+        assert_eq!(collection_for_dataset("us2015b").as_deref(), Some("usa"));
+        assert_eq!(collection_for_dataset("cps2020").as_deref(), Some("cps"));
+        assert_eq!(
+            collection_for_dataset("cps2020_03s").as_deref(),
+            Some("cps")
+        );
+        assert_eq!(collection_for_dataset("mx2000a").as_deref(), Some("ipumsi"));
+        assert_eq!(collection_for_dataset("US2015B").as_deref(), Some("usa"));
+        assert_eq!(collection_for_dataset("us"), None);
+        assert_eq!(collection_for_dataset("abc2015a"), None);
+    }
+
+    #[test]
+    fn test_load_metadata_from_layout_twice() {
+        let mut settings = defaults::defaults_for("usa").expect("should have defaults for USA");
+        let layout_file = Path::new("tests/data_root/layouts/us2015b.layout.txt");
+        settings
+            .load_metadata_from_layout(layout_file)
+            .expect("should load the layout");
+        let variable_count = settings
+            .metadata
+            .as_ref()
+            .expect("metadata should be loaded")
+            .variables_index
+            .len();
+        assert!(variable_count > 0);
+
+        settings
+            .load_metadata_from_layout(layout_file)
+            .expect("should load the layout again");
+        let md = settings
+            .metadata
+            .as_ref()
+            .expect("metadata should be loaded");
+        assert_eq!(md.datasets_index.len(), 1);
+        assert_eq!(&*md.datasets_index[0].name, "us2015b");
+        assert_eq!(md.variables_index.len(), variable_count);
+        assert!(md.variables_by_name.contains_key("AGE"));
+    }
+
+    #[test]
+    fn test_load_metadata_from_all_layouts_collects_errors() {
+        let dir = std::env::temp_dir().join(format!("cimdea_layouts_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("should create the layouts directory");
+        for ds in ["us1850a", "us2015b"] {
+            let name = format!("{ds}.layout.txt");
+            std::fs::copy(
+                Path::new("tests/data_root/layouts").join(&name),
+                dir.join(&name),
+            )
+            .expect("should copy the layout");
+        }
+        std::fs::write(dir.join("README.txt"), "not a layout").expect("should write a file");
+        std::fs::write(
+            dir.join("us1999z.layout.txt"),
+            "AGE P fifty-eight 3 integer\n",
+        )
+        .expect("should write a broken layout");
+
+        let mut settings = defaults::defaults_for("usa").expect("should have defaults for USA");
+        let result = settings.load_metadata_from_all_layouts(&dir);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let errors = result.expect_err("the broken layout should be an error");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("us1999z.layout.txt"));
+
+        let md = settings
+            .metadata
+            .as_ref()
+            .expect("metadata should be loaded");
+        let names: Vec<&str> = md
+            .datasets_index
+            .iter()
+            .map(|ds| ds.name.as_ref())
+            .collect();
+        assert_eq!(names, vec!["us1850a", "us2015b"]);
+    }
+
+    #[test]
+    fn test_load_metadata_from_parquet() {
+        let mut settings = defaults::defaults_for("usa").expect("should have defaults for USA");
+        settings
+            .load_metadata_from_parquet(Path::new("tests/data_root/parquet/us2015b"))
+            .expect("should load the Parquet schemas");
+
+        let md = settings
+            .metadata
+            .as_ref()
+            .expect("metadata should be loaded");
+        assert_eq!(md.datasets_index.len(), 1);
+        assert_eq!(&*md.datasets_index[0].name, "us2015b");
+
+        let age = &md.variables_index[md.variables_by_name["AGE"]];
+        assert_eq!(age.record_type, "P");
+        assert_eq!(age.data_type, Some(IpumsDataType::Integer));
+        let rectype = &md.variables_index[md.variables_by_name["RECTYPE"]];
+        assert_eq!(rectype.record_type, "H");
+        assert_eq!(rectype.data_type, Some(IpumsDataType::String));
+        assert!(md.variables_by_name.contains_key("GQ"));
+        assert_eq!(md.datasets_index[0].sample_size, Some(30767));
+    }
+
+    /// The sample size of a dataset with part files counts the records in all of them.
+    #[test]
+    fn test_load_metadata_from_parquet_part_files_sample_size() {
+        let mut settings = defaults::defaults_for("usa").expect("should have defaults for USA");
+        settings
+            .load_metadata_from_parquet(Path::new("tests/data_root/parquet/us1850a"))
+            .expect("should load the Parquet schemas");
+        let md = settings.metadata.as_ref().unwrap();
+        assert_eq!(md.datasets_index[0].sample_size, Some(5));
+    }
+
+    #[test]
+    fn test_load_metadata_from_parquet_missing_files_error() {
+        let mut settings = defaults::defaults_for("usa").expect("should have defaults for USA");
+        let result = settings.load_metadata_from_parquet(Path::new("tests/data_root/layouts"));
+        assert!(result.is_err());
+        assert!(settings.metadata.is_none());
+    }
+
+    #[test]
+    fn test_load_all_available_metadata() {
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .expect("should be able to create USA context");
+
+        let result = ctx.load_all_available_metadata(Some(10));
+        assert!(result.is_err());
+        assert!(ctx.settings.metadata.is_none());
+
+        let datasets = ctx
+            .load_all_available_metadata(None)
+            .expect("should load every layout");
+        assert!(datasets.len() > 100);
+        assert_eq!(datasets[0], "us1850a");
+        assert!(datasets.contains(&"us2015b".to_string()));
+
+        let md = ctx
+            .settings
+            .metadata
+            .as_ref()
+            .expect("metadata should be loaded");
+        assert_eq!(md.datasets_index.len(), datasets.len());
+        assert!(md.datasets_by_name.contains_key("us2022d"));
+    }
+
+    #[test]
+    fn test_load_metadata_from_layout_missing_file_error() {
+        let mut settings = defaults::defaults_for("usa").expect("should have defaults for USA");
+        let result = settings
+            .load_metadata_from_layout(Path::new("tests/data_root/layouts/us1777a.layout.txt"));
+        assert!(result.is_err());
+        assert!(settings.metadata.is_none());
+    }
+
+    #[test]
+    fn test_metadata_entities_interns_names() {
+        let mut md = MetadataEntities::new();
+        let first = md.intern("MARST");
+        let second = md.intern(&String::from("MARST"));
+        assert!(Arc::ptr_eq(&first, &second));
+
+        for ds in ["us2015a", "us2016a"] {
+            md.add_dataset_variable(
+                IpumsDataset::from((ds.to_string(), 0)),
+                test_variable("MARST"),
+            );
+        }
+        let marst = md
+            .cloned_variable_from_name("MARST")
+            .expect("MARST should be in the metadata");
+        let (key, _) = md
+            .variables_by_name
+            .get_key_value("MARST")
+            .expect("MARST should be indexed");
+        assert!(Arc::ptr_eq(&marst.name, &first));
+        assert!(Arc::ptr_eq(key, &first));
+    }
+
+    #[test]
+    fn test_metadata_entities_approx_memory_bytes_grows() {
+        let data_root = Some(String::from("tests/data_root"));
+        let mut ctx = Context::from_ipums_collection_name("usa", None, data_root)
+            .expect("should be able to create USA context");
+
+        ctx.load_metadata_for_datasets(&["us2015b"])
+            .expect("should load metadata for us2015b");
+        let one_dataset = ctx
+            .settings
+            .metadata
+            .as_ref()
+            .expect("metadata should be loaded")
+            .approx_memory_bytes();
+        assert!(one_dataset > 0);
+
+        ctx.load_metadata_for_datasets(&["us2016b", "us1940a"])
+            .expect("should load metadata for more datasets");
+        let three_datasets = ctx
+            .settings
+            .metadata
+            .as_ref()
+            .expect("metadata should be loaded")
+            .approx_memory_bytes();
+        assert!(three_datasets > one_dataset);
+    }
+
+    #[test]
+    fn test_metadata_entities_coverage_missing_from_one_dataset() {
+        let mut md = MetadataEntities::new();
+        for ds in ["us2014a", "us2015a", "us2016a"] {
+            md.add_dataset_variable(
+                IpumsDataset::from((ds.to_string(), 0)),
+                test_variable("AGE"),
+            );
+        }
+        for ds in ["us2014a", "us2016a"] {
+            md.add_dataset_variable(
+                IpumsDataset::from((ds.to_string(), 0)),
+                test_variable("CINETHH"),
+            );
+        }
+
+        let coverage = md.coverage("CINETHH", &["us2014a", "us2015a", "us2016a"]);
+        assert_eq!(coverage.missing_in, ["us2015a"]);
+        assert!((coverage.fraction - 2.0 / 3.0).abs() < f64::EPSILON);
+
+        let full_coverage = md.coverage("AGE", &["us2014a", "us2015a", "us2016a"]);
+        assert!(full_coverage.missing_in.is_empty());
+        assert_eq!(full_coverage.fraction, 1.0);
+    }
+
+    #[test]
+    fn test_metadata_entities_coverage_unknown_variable() {
+        let mut md = MetadataEntities::new();
+        md.add_dataset_variable(
+            IpumsDataset::from(("us2015a".to_string(), 0)),
+            test_variable("AGE"),
+        );
+
+        let coverage = md.coverage("NOTAVAR", &["us2015a", "us0000z"]);
+        assert_eq!(coverage.missing_in, ["us2015a", "us0000z"]);
+        assert_eq!(coverage.fraction, 0.0);
+    }
+
     #[test]
     fn test_micro_data_collection_default_table_name() {
         let collection =
@@ -642,4 +2277,114 @@ mod test {
         let result = collection.default_table_name("us2021a", "Z");
         assert!(result.is_err(), "expected an error but got {result:?}");
     }
+
+    #[test]
+    fn test_micro_data_collection_from_config_file() {
+        let collection =
+            MicroDataCollection::from_config_file(Path::new("tests/collections/time_use.toml"))
+                .expect("should load the collection configuration");
+        assert_eq!(collection.name, "Time Use");
+        assert_eq!(collection.default_unit_of_analysis.value, "P");
+        assert_eq!(collection.record_hierarchy.root, "H");
+        assert_eq!(collection.record_types.len(), 3);
+
+        let activity = collection
+            .record_types
+            .get("A")
+            .expect("should have an activity record type");
+        assert_eq!(
+            activity.foreign_keys,
+            vec![("P".to_string(), "PSERIALA".to_string())]
+        );
+        assert!(activity.weight.is_none());
+
+        let person_level = collection
+            .record_hierarchy
+            .levels
+            .get("P")
+            .expect("person should be in the record hierarchy");
+        assert_eq!(person_level.parent.as_deref(), Some("H"));
+        assert_eq!(collection.weight_for_rectype("P"), Some("WT06".to_string()));
+    }
+
+    #[test]
+    fn test_micro_data_collection_from_config_file_missing_file_error() {
+        let result =
+            MicroDataCollection::from_config_file(Path::new("tests/collections/nonexistent.toml"));
+        assert!(
+            matches!(result, Err(MdError::IoError(_))),
+            "expected an I/O error but got {result:?}"
+        );
+    }
+
+    #[test]
+    fn test_micro_data_collection_from_config_undeclared_foreign_key_error() {
+        let config = r#"
+            name = "Bad"
+            default_unit_of_analysis = "P"
+
+            [[record_types]]
+            name = "Household"
+            value = "H"
+            unique_id = "SERIAL"
+
+            [[record_types]]
+            name = "Person"
+            value = "P"
+            unique_id = "PSERIAL"
+            foreign_keys = [{ record_type = "F", key = "SERIALP" }]
+        "#;
+        let err = MicroDataCollection::from_config(config)
+            .expect_err("should be an error because record type F isn't declared");
+        assert_eq!(
+            err.to_string(),
+            "metadata error: record type 'P' has a foreign key to undeclared record type 'F'"
+        );
+    }
+
+    #[test]
+    fn test_micro_data_collection_from_config_cycle_error() {
+        let config = r#"
+            name = "Bad"
+            default_unit_of_analysis = "H"
+
+            [[record_types]]
+            name = "Household"
+            value = "H"
+            unique_id = "SERIAL"
+
+            [[record_types]]
+            name = "Person"
+            value = "P"
+            unique_id = "PSERIAL"
+            foreign_keys = [{ record_type = "A", key = "SERIALA" }]
+
+            [[record_types]]
+            name = "Activity"
+            value = "A"
+            unique_id = "ASERIAL"
+            foreign_keys = [{ record_type = "P", key = "SERIALP" }]
+        "#;
+        let err = MicroDataCollection::from_config(config)
+            .expect_err("should be an error because P and A refer to each other");
+        assert_eq!(
+            err.to_string(),
+            "metadata error: the foreign keys of record types A, P form a cycle"
+        );
+    }
+
+    #[test]
+    fn test_micro_data_collection_from_config_unknown_unit_of_analysis_error() {
+        let config = r#"
+            name = "Bad"
+            default_unit_of_analysis = "P"
+
+            [[record_types]]
+            name = "Household"
+            value = "H"
+            unique_id = "SERIAL"
+        "#;
+        let result = MicroDataCollection::from_config(config);
+        assert!(result.is_err(), "expected an error but got {result:?}");
+    }
 }
@@ -1,9 +1,10 @@
 //! Definitions of default IPUMS values.
 //!
-//! The Household - Person record structure is the default for much IPUMS data. Here we have some
-//! functions to support setting up such a default structure without needing any external
-//! configuration. Everything modeled here could originate from a run-time configuration process
-//! instead.
+//! The Household - Person record structure is the default for much IPUMS data. Time use data
+//! like ATUS adds an Activity record below each person, for a Household - Person - Activity
+//! structure. Here we have some functions to support setting up such a default structure without
+//! needing any external configuration. Everything modeled here could originate from a run-time
+//! configuration process instead.
 //!
 //!  A generic record type generator could use Cow instead of String, as in
 //!  <https://stackoverflow.com/questions/63201351/writing-a-rust-struct-type-that-contains-a-string-and-can-be-used-in-a-constant>
@@ -12,14 +13,51 @@ use crate::conventions::*;
 use crate::ipums_data_model::*;
 use crate::mderror::MdError;
 use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+// Collections registered with [register_collection], by lowercase name.
+static REGISTERED_COLLECTIONS: OnceLock<RwLock<HashMap<String, MicroDataCollection>>> =
+    OnceLock::new();
+
+fn registered_collections() -> &'static RwLock<HashMap<String, MicroDataCollection>> {
+    REGISTERED_COLLECTIONS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// Register a data collection for the rest of the process, so that [defaults_for] and
+/// [Context::from_ipums_collection_name] return it for `name`. Registered collections take
+/// precedence over the built-in defaults. Names are case-insensitive, and registering a name
+/// again replaces the collection registered before.
+///
+/// ```
+/// use cimdea::defaults::{defaults_for, register_collection};
+///
+/// let mut nhgis = defaults_for("usa").unwrap();
+/// nhgis.name = "NHGIS".to_string();
+/// register_collection("nhgis", nhgis);
+///
+/// let settings = defaults_for("NHGIS").unwrap();
+/// assert_eq!(settings.name, "NHGIS");
+/// ```
+pub fn register_collection(name: &str, collection: MicroDataCollection) {
+    registered_collections()
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(name.to_lowercase(), collection);
+}
+
+fn household(product: &str) -> RecordType {
+    // ATUS weights respondents rather than households.
+    let weight = match product.to_lowercase().as_ref() {
+        "atus" => None,
+        _ => Some(default_household_weight()),
+    };
 
-fn household(_product: &str) -> RecordType {
     RecordType {
         name: "Household".to_string(),
         value: "H".to_string(),
         unique_id: "SERIAL".to_string(),
         foreign_keys: Vec::new(),
-        weight: Some(default_household_weight()),
+        weight,
         sample_weight: None,
     }
 }
@@ -30,22 +68,45 @@ fn person(product: &str) -> RecordType {
         _ => None,
     };
 
+    let weight = match product.to_lowercase().as_ref() {
+        "atus" => atus_person_weight(),
+        _ => default_person_weight(),
+    };
+
     RecordType {
         name: "Person".to_string(),
         value: "P".to_string(),
         unique_id: "PSERIAL".to_string(),
         foreign_keys: vec![("H".to_string(), "SERIALP".to_string())],
-        weight: Some(default_person_weight()),
+        weight: Some(weight),
         sample_weight: slwt,
     }
 }
 
+// Activities have no weight of their own. Requests can weight them by the person weight with a
+// weight record type of "P".
+fn activity(_product: &str) -> RecordType {
+    RecordType {
+        name: "Activity".to_string(),
+        value: "A".to_string(),
+        unique_id: "ASERIAL".to_string(),
+        foreign_keys: vec![("P".to_string(), "PSERIALA".to_string())],
+        weight: None,
+        sample_weight: None,
+    }
+}
+
 fn default_record_types(product: &str) -> HashMap<String, RecordType> {
     match product.to_lowercase().as_ref() {
         "usa" | "ipumsi" | "cps" => HashMap::from([
             ("H".to_string(), household(product)),
             ("P".to_string(), person(product)),
         ]),
+        "atus" => HashMap::from([
+            ("H".to_string(), household(product)),
+            ("P".to_string(), person(product)),
+            ("A".to_string(), activity(product)),
+        ]),
         // TODO add some other default hierarchies or load from a config file
         _ => HashMap::from([
             ("H".to_string(), household(product)),
@@ -66,26 +127,38 @@ fn usa_sample_line_weight() -> RecordWeight {
     RecordWeight::new("SLWT", 100)
 }
 
-fn default_hierarchy() -> RecordHierarchy {
+fn atus_person_weight() -> RecordWeight {
+    RecordWeight::new("WT06", 1)
+}
+
+fn default_hierarchy(product: &str) -> RecordHierarchy {
     let mut hierarchy = RecordHierarchy::new("H");
     let result = hierarchy.add_member("P", "H");
     assert!(result.is_ok());
+    if product.to_lowercase() == "atus" {
+        let result = hierarchy.add_member("A", "P");
+        assert!(result.is_ok());
+    }
     hierarchy
 }
 
 fn default_settings_named(name: &str) -> MicroDataCollection {
     MicroDataCollection {
         name: name.to_string(),
-        record_hierarchy: default_hierarchy(),
+        record_hierarchy: default_hierarchy(name),
         record_types: default_record_types(name),
         default_unit_of_analysis: person(name),
         metadata: None,
     }
 }
 
+/// The names of the data collections with default configurations, in the lowercase form
+/// [defaults_for] accepts.
+pub const SUPPORTED_PRODUCTS: [&str; 4] = ["usa", "cps", "ipumsi", "atus"];
+
 /// Get the default configuration for a data collection.
 ///
-/// There are default configurations for USA, IPUMSI and CPS currently. You can get them like
+/// There are default configurations for USA, IPUMSI, CPS and ATUS currently. You can get them like
 ///
 /// ```
 /// use cimdea::defaults::defaults_for;
@@ -93,7 +166,9 @@ fn default_settings_named(name: &str) -> MicroDataCollection {
 /// let settings = defaults_for("usa").unwrap();
 /// ```
 ///
-/// Passing an unknown data collection name causes an error.
+/// Collections added with [register_collection] come before the built-in defaults. Passing an
+/// unknown data collection name causes an error which lists the
+/// [supported products](SUPPORTED_PRODUCTS).
 ///
 /// ```
 /// use cimdea::defaults::defaults_for;
@@ -105,11 +180,24 @@ pub fn defaults_for(product: &str) -> Result<MicroDataCollection, MdError> {
     // Right now we only set defaults programmatically but in the future this should set some
     // additional properties particular to products or stuff loaded in from an external
     // configuration.
+    let registered = registered_collections()
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(&product.to_lowercase())
+        .cloned();
+    if let Some(collection) = registered {
+        return Ok(collection);
+    }
+
     match product.to_lowercase().as_ref() {
         "usa" => Ok(default_settings_named("USA")),
         "cps" => Ok(default_settings_named("cps")),
         "ipumsi" => Ok(default_settings_named("ipumsi")),
-        _ => Err(MdError::Msg(format!("Product '{product}' not supported"))),
+        "atus" => Ok(default_settings_named("atus")),
+        _ => Err(MdError::Msg(format!(
+            "Product '{product}' not supported. The supported products are {}.",
+            SUPPORTED_PRODUCTS.join(", ")
+        ))),
     }
 }
 
@@ -126,6 +214,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_defaults_for_atus() {
+        let settings = defaults_for("atus").expect("should have defaults for ATUS");
+        assert_eq!(settings.default_unit_of_analysis.value, "P");
+
+        let activity = settings
+            .record_types
+            .get("A")
+            .expect("ATUS should have an activity record type");
+        assert_eq!(
+            activity.foreign_keys,
+            vec![("P".to_string(), "PSERIALA".to_string())]
+        );
+
+        let activity_level = settings
+            .record_hierarchy
+            .levels
+            .get("A")
+            .expect("activities should be in the record hierarchy");
+        assert_eq!(activity_level.parent.as_deref(), Some("P"));
+        let person_level = settings
+            .record_hierarchy
+            .levels
+            .get("P")
+            .expect("persons should be in the record hierarchy");
+        assert_eq!(person_level.parent.as_deref(), Some("H"));
+    }
+
+    #[test]
+    fn test_defaults_for_usa_has_no_activities() {
+        let settings = defaults_for("usa").expect("should have defaults for USA");
+        assert!(!settings.record_types.contains_key("A"));
+        assert!(!settings.record_hierarchy.levels.contains_key("A"));
+    }
+
     #[test]
     fn test_defaults_for_unknown_product() {
         let result = defaults_for("????");
@@ -134,4 +257,47 @@ mod tests {
             "there should not be any defaults for product '????'"
         );
     }
+
+    #[test]
+    fn test_defaults_for_unknown_product_lists_supported_products() {
+        let err = defaults_for("nhis").expect_err("there are no defaults for NHIS");
+        assert_eq!(
+            err.to_string(),
+            "Product 'nhis' not supported. The supported products are usa, cps, ipumsi, atus."
+        );
+    }
+
+    #[test]
+    fn test_register_collection() {
+        let mut collection = defaults_for("usa").expect("should have defaults for USA");
+        collection.name = "Test Registered".to_string();
+        register_collection("Test_Registered", collection);
+
+        let settings =
+            defaults_for("test_REGISTERED").expect("should find the registered collection");
+        assert_eq!(settings.name, "Test Registered");
+    }
+
+    #[test]
+    fn test_register_collection_replaces_prior_definition() {
+        let mut first = defaults_for("usa").expect("should have defaults for USA");
+        first.name = "First".to_string();
+        register_collection("test_replaced", first);
+
+        let mut second = defaults_for("atus").expect("should have defaults for ATUS");
+        second.name = "Second".to_string();
+        register_collection("test_replaced", second);
+
+        let settings = defaults_for("test_replaced").expect("should find the collection");
+        assert_eq!(settings.name, "Second");
+        assert!(settings.record_types.contains_key("A"));
+    }
+
+    #[test]
+    fn test_defaults_for_supported_products() {
+        for product in SUPPORTED_PRODUCTS {
+            let result = defaults_for(product);
+            assert!(result.is_ok(), "should have defaults for {product}");
+        }
+    }
 }
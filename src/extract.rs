@@ -0,0 +1,617 @@
+//! Run extracts: the values of the request variables for every record of the unit of
+//! analysis which meets the request's conditions, one row per record.
+//!
+//! [extract] returns the records as an [Extract], which formats them in any of the
+//! [OutputFormat]s. Fixed-width extracts come with a layout file describing their columns;
+//! see [FwExtractLayout].
+//!
+//! [extract_to_files] writes the records as CSV files with a header row instead. Large
+//! extracts may be split into several numbered files with [ExtractOptions::chunk_rows], and
+//! the [ExtractManifest] returned by [extract_to_files] lists every file written.
+//!
+//! Like IPUMS extracts, an extract may be rectangular or hierarchical; see
+//! [ExtractStructure].
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::conventions::Context;
+use crate::fixed_width::FwExtractLayout;
+use crate::mderror::MdError;
+use crate::query_gen::{extract_queries, hierarchical_extract_queries, DataPlatform};
+use crate::request::{DataRequest, InputType, OutputFormat};
+use crate::tabulate::{escape_html, format_csv_record};
+
+use duckdb::types::Value;
+use duckdb::Connection;
+use serde::Serialize;
+
+/// How the records of an extract are laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExtractStructure {
+    /// One row per record of the unit of analysis, with the variables of the record types
+    /// above it attached. For example, each person row repeats its household's variables.
+    #[default]
+    Rectangular,
+    /// Each record of the unit of analysis' parent record type followed by its records of
+    /// the unit of analysis, like a household and then its persons. The first column is
+    /// "RECTYPE", and each row only has values for the variables on its own record type.
+    Hierarchical,
+}
+
+/// Options which adjust how [extract_to_files] writes an extract.
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Split the extract into files with at most this many data rows each, named like
+    /// "usa_00001.0.csv", "usa_00001.1.csv" and so on. By default the whole extract is
+    /// written to a single file named like "usa_00001.csv".
+    pub chunk_rows: Option<usize>,
+    /// Whether to write a rectangular or hierarchical extract. The default is rectangular.
+    pub structure: ExtractStructure,
+}
+
+/// A record of the files written for an extract.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ExtractManifest {
+    /// The paths of the files written, in order.
+    pub files: Vec<PathBuf>,
+    /// The total number of data rows across all of the files.
+    pub rows: usize,
+}
+
+/// The records of an extract, one row of values per record with a value for each column.
+/// See [extract].
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Extract {
+    /// The names of the columns, in request order.
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+    /// The layout of the records for [OutputFormat::FW]. [extract] sets it when every
+    /// request variable has a width in its metadata, and returns the error when it can't lay
+    /// out a request for fixed-width output.
+    #[serde(skip)]
+    pub fixed_width_layout: Option<FwExtractLayout>,
+}
+
+impl Extract {
+    /// Format the records, one line or element per record with the columns in order.
+    ///
+    /// * CSV has a header row of column names, and quotes fields as in RFC 4180.
+    /// * JSON is an object with the `columns` and the `rows` as arrays of values.
+    /// * HTML is a `<table>` with a `<thead>` row of column names.
+    /// * Fixed-width output follows the [Extract::fixed_width_layout]. Hierarchical extracts
+    ///   start each record with its RECTYPE.
+    pub fn output(&self, format: &OutputFormat) -> Result<String, MdError> {
+        match format {
+            OutputFormat::CSV => {
+                let mut out = format_csv_record(&self.columns);
+                for row in &self.rows {
+                    out.push_str(&format_csv_record(row));
+                }
+                Ok(out)
+            }
+            OutputFormat::Json => serde_json::to_string_pretty(self)
+                .map_err(|err| MdError::Msg(format!("Cannot serialize extract into json: {err}"))),
+            OutputFormat::Html => {
+                let mut out = String::from("<table>\n<thead>\n<tr>");
+                for name in &self.columns {
+                    out.push_str(&format!("<th>{}</th>", escape_html(name)));
+                }
+                out.push_str("</tr>\n</thead>\n<tbody>\n");
+                for row in &self.rows {
+                    out.push_str("<tr>");
+                    for value in row {
+                        out.push_str(&format!("<td>{}</td>", escape_html(value)));
+                    }
+                    out.push_str("</tr>\n");
+                }
+                out.push_str("</tbody>\n</table>\n");
+                Ok(out)
+            }
+            OutputFormat::FW => {
+                let Some(ref layout) = self.fixed_width_layout else {
+                    return Err(MdError::Msg(
+                        "Can't format the extract as fixed-width without a layout.".to_string(),
+                    ));
+                };
+                let mut out = String::new();
+                for row in &self.rows {
+                    out += &layout.format_row(row)?;
+                    out.push('\n');
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    /// Return true if no records met the request's conditions.
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+}
+
+/// Run an extract request and return its records, like [tabulate](crate::tabulate::tabulate)
+/// for a tabulation. Each record of the request's unit of analysis which meets the
+/// request's conditions is a row with the values of the request variables in order. Records
+/// from each requested dataset follow those from the previous one, in the order of the
+/// request's samples.
+pub fn extract<R>(ctx: &Context, rq: R) -> Result<Extract, MdError>
+where
+    R: DataRequest,
+{
+    extract_with_options(ctx, rq, &ExtractOptions::default())
+}
+
+/// Run an extract request like [extract] with the given [ExtractStructure]. The whole
+/// extract is returned, so [ExtractOptions::chunk_rows] doesn't apply.
+pub fn extract_with_options<R>(
+    ctx: &Context,
+    rq: R,
+    options: &ExtractOptions,
+) -> Result<Extract, MdError>
+where
+    R: DataRequest,
+{
+    // Requests for fixed-width output can't be met without a layout, so they fail with the
+    // reason there isn't one. Other extracts just can't be formatted as fixed-width.
+    let fixed_width_layout = match fixed_width_layout(ctx, &rq, options.structure) {
+        Ok(layout) => Some(layout),
+        Err(err) if rq.effective_output_format() == OutputFormat::FW => return Err(err),
+        Err(_) => None,
+    };
+    let mut extract = Extract {
+        columns: extract_heading(&rq, options.structure),
+        rows: Vec::new(),
+        fixed_width_layout,
+    };
+    for_each_record(ctx, rq, options.structure, extract.columns.len(), |row| {
+        extract.rows.push(row);
+        Ok(())
+    })?;
+    Ok(extract)
+}
+
+/// Run an extract request and write its records to CSV files named after `base_name` in
+/// `output_dir`. Records from each requested dataset follow those from the previous one,
+/// in the order of the request's samples.
+pub fn extract_to_files<R>(
+    ctx: &Context,
+    rq: R,
+    output_dir: &Path,
+    base_name: &str,
+    options: &ExtractOptions,
+) -> Result<ExtractManifest, MdError>
+where
+    R: DataRequest,
+{
+    let heading = extract_heading(&rq, options.structure);
+    let columns = heading.len();
+    let mut writer = ChunkedCsvWriter::new(output_dir, base_name, heading, options.chunk_rows)?;
+    for_each_record(ctx, rq, options.structure, columns, |row| {
+        writer.write_row(&row)
+    })?;
+    writer.finish()
+}
+
+// Lay out the records of an extract of `rq` for fixed-width output. A hierarchical extract
+// has the unit of analysis' parent record type and then the unit of analysis.
+fn fixed_width_layout(
+    ctx: &Context,
+    rq: &impl DataRequest,
+    structure: ExtractStructure,
+) -> Result<FwExtractLayout, MdError> {
+    let uoa = rq.unit_of_analysis();
+    let variables = rq.get_request_variables();
+    match structure {
+        ExtractStructure::Rectangular => FwExtractLayout::try_rectangular(&uoa, &variables),
+        ExtractStructure::Hierarchical => {
+            let parent = ctx
+                .settings
+                .record_hierarchy
+                .levels
+                .get(&uoa)
+                .and_then(|level| level.parent.clone())
+                .ok_or_else(|| {
+                    MdError::Msg(format!(
+                        "Unit of analysis '{uoa}' has no parent record type."
+                    ))
+                })?;
+            FwExtractLayout::try_hierarchical(&[parent, uoa], &variables)
+        }
+    }
+}
+
+// The names of the columns of an extract of `rq`.
+fn extract_heading(rq: &impl DataRequest, structure: ExtractStructure) -> Vec<String> {
+    let mut heading = Vec::new();
+    if structure == ExtractStructure::Hierarchical {
+        heading.push("RECTYPE".to_string());
+    }
+    heading.extend(rq.get_request_variables().iter().map(|v| {
+        if v.is_bucketed() {
+            format!("{}_bucketed", v.name)
+        } else {
+            v.name.clone()
+        }
+    }));
+    heading
+}
+
+// Run the queries for an extract, passing the first `columns` values of each record to
+// `on_record` in order.
+fn for_each_record<R>(
+    ctx: &Context,
+    rq: R,
+    structure: ExtractStructure,
+    columns: usize,
+    mut on_record: impl FnMut(Vec<String>) -> Result<(), MdError>,
+) -> Result<(), MdError>
+where
+    R: DataRequest,
+{
+    let queries = match structure {
+        ExtractStructure::Rectangular => {
+            extract_queries(ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)?
+        }
+        ExtractStructure::Hierarchical => {
+            hierarchical_extract_queries(ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)?
+        }
+    };
+    let conn = Connection::open_in_memory()?;
+    for q in queries {
+        let mut stmt = conn.prepare(&q)?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let mut this_row = Vec::with_capacity(columns);
+            for column_number in 0..columns {
+                let value: Value = row.get(column_number)?;
+                this_row.push(format_value(value));
+            }
+            on_record(this_row)?;
+        }
+    }
+    Ok(())
+}
+
+// Format a value read from a query for a CSV file. Missing values are empty.
+fn format_value(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Boolean(b) => b.to_string(),
+        Value::TinyInt(i) => i.to_string(),
+        Value::SmallInt(i) => i.to_string(),
+        Value::Int(i) => i.to_string(),
+        Value::BigInt(i) => i.to_string(),
+        Value::HugeInt(i) => i.to_string(),
+        Value::UTinyInt(i) => i.to_string(),
+        Value::USmallInt(i) => i.to_string(),
+        Value::UInt(i) => i.to_string(),
+        Value::UBigInt(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Double(f) => f.to_string(),
+        Value::Text(s) => s,
+        other => format!("{other:?}"),
+    }
+}
+
+// Writes CSV rows to a file, rolling over to a new numbered file after every `chunk_rows`
+// rows when given. Every file starts with the header row.
+struct ChunkedCsvWriter {
+    output_dir: PathBuf,
+    base_name: String,
+    heading: Vec<String>,
+    chunk_rows: Option<usize>,
+    current: Option<BufWriter<File>>,
+    rows_in_current: usize,
+    manifest: ExtractManifest,
+}
+
+impl ChunkedCsvWriter {
+    fn new(
+        output_dir: &Path,
+        base_name: &str,
+        heading: Vec<String>,
+        chunk_rows: Option<usize>,
+    ) -> Result<Self, MdError> {
+        if chunk_rows == Some(0) {
+            return Err(MdError::Msg(
+                "chunk_rows must be at least 1 when given".to_string(),
+            ));
+        }
+        Ok(Self {
+            output_dir: output_dir.to_path_buf(),
+            base_name: base_name.to_string(),
+            heading,
+            chunk_rows,
+            current: None,
+            rows_in_current: 0,
+            manifest: ExtractManifest::default(),
+        })
+    }
+
+    fn write_row(&mut self, row: &[String]) -> Result<(), MdError> {
+        let full = self
+            .chunk_rows
+            .is_some_and(|chunk_rows| self.rows_in_current >= chunk_rows);
+        if self.current.is_none() || full {
+            self.start_file()?;
+        }
+        if let Some(ref mut out) = self.current {
+            out.write_all(format_csv_record(row).as_bytes())?;
+        }
+        self.rows_in_current += 1;
+        self.manifest.rows += 1;
+        Ok(())
+    }
+
+    fn start_file(&mut self) -> Result<(), MdError> {
+        if let Some(mut out) = self.current.take() {
+            out.flush()?;
+        }
+        let filename = match self.chunk_rows {
+            Some(_) => format!("{}.{}.csv", self.base_name, self.manifest.files.len()),
+            None => format!("{}.csv", self.base_name),
+        };
+        let path = self.output_dir.join(filename);
+        let mut out = BufWriter::new(File::create(&path)?);
+        out.write_all(format_csv_record(&self.heading).as_bytes())?;
+        self.current = Some(out);
+        self.rows_in_current = 0;
+        self.manifest.files.push(path);
+        Ok(())
+    }
+
+    // Flush the last file and return the manifest. An extract with no rows still gets a
+    // file with the header row.
+    fn finish(mut self) -> Result<ExtractManifest, MdError> {
+        if self.current.is_none() {
+            self.start_file()?;
+        }
+        if let Some(mut out) = self.current.take() {
+            out.flush()?;
+        }
+        Ok(self.manifest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::request::SimpleRequest;
+
+    fn temp_output_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cimdea_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("should create the output directory");
+        dir
+    }
+
+    #[test]
+    fn test_chunked_writer_rolls_over() {
+        let dir = temp_output_dir("chunks");
+        let mut writer = ChunkedCsvWriter::new(&dir, "usa_00001", vec!["AGE".to_string()], Some(2))
+            .expect("should create the writer");
+        for age in ["30", "31", "32", "33", "34"] {
+            writer
+                .write_row(&[age.to_string()])
+                .expect("should write a row");
+        }
+        let manifest = writer.finish().expect("should finish the extract");
+
+        assert_eq!(manifest.rows, 5);
+        assert_eq!(
+            manifest.files,
+            vec![
+                dir.join("usa_00001.0.csv"),
+                dir.join("usa_00001.1.csv"),
+                dir.join("usa_00001.2.csv"),
+            ]
+        );
+        let last = std::fs::read_to_string(&manifest.files[2]).expect("should read the file");
+        assert_eq!(last, "AGE\n34\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_extract_output_formats() {
+        let extract = Extract {
+            columns: vec!["AGE".to_string(), "NAMEFRST".to_string()],
+            rows: vec![
+                vec!["30".to_string(), "Ann".to_string()],
+                vec!["31".to_string(), "Lee, Jr.".to_string()],
+            ],
+            ..Extract::default()
+        };
+
+        let csv = extract
+            .output(&OutputFormat::CSV)
+            .expect("should format as CSV");
+        assert_eq!(csv, "AGE,NAMEFRST\n30,Ann\n31,\"Lee, Jr.\"\n");
+
+        let json = extract
+            .output(&OutputFormat::Json)
+            .expect("should format as JSON");
+        let parsed: serde_json::Value =
+            serde_json::from_str(&json).expect("should format valid JSON");
+        assert_eq!(parsed["columns"][1], "NAMEFRST");
+        assert_eq!(parsed["rows"][1][1], "Lee, Jr.");
+
+        let html = extract
+            .output(&OutputFormat::Html)
+            .expect("should format as HTML");
+        assert!(html.contains("<tr><td>30</td><td>Ann</td></tr>"));
+    }
+
+    #[test]
+    fn test_extract_fixed_width_output_error() {
+        let result = Extract::default().output(&OutputFormat::FW);
+        assert!(result.is_err(), "expected an error but got {result:?}");
+    }
+
+    /// A household extract has no parent records to put before each household, so it can't
+    /// be laid out as a hierarchical fixed-width file.
+    #[test]
+    fn test_extract_fixed_width_layout_error() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ"],
+            Some("H".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.output_format = OutputFormat::FW;
+        let options = ExtractOptions {
+            structure: ExtractStructure::Hierarchical,
+            ..ExtractOptions::default()
+        };
+
+        let err = extract_with_options(&ctx, rq, &options)
+            .expect_err("a fixed-width extract needs a layout");
+        assert_eq!(
+            err.to_string(),
+            "Unit of analysis 'H' has no parent record type."
+        );
+    }
+
+    #[test]
+    fn test_extract_fixed_width_output() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ", "AGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let structure = ExtractStructure::Hierarchical;
+        let extract = Extract {
+            columns: extract_heading(&rq, structure),
+            rows: vec![
+                vec!["H".to_string(), "1".to_string(), String::new()],
+                vec!["P".to_string(), String::new(), "42".to_string()],
+                vec!["P".to_string(), String::new(), "7".to_string()],
+            ],
+            fixed_width_layout: Some(
+                fixed_width_layout(&ctx, &rq, structure).expect("should lay out the extract"),
+            ),
+        };
+
+        let fw = extract
+            .output(&OutputFormat::FW)
+            .expect("should format as fixed-width");
+        assert_eq!(fw, "H1\nP042\nP007\n");
+    }
+
+    #[test]
+    fn test_extract() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us1850a"],
+            &["SEX"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let extract = extract(&ctx, rq).expect("should run the extract");
+        assert_eq!(extract.columns, vec!["SEX"]);
+        // us1850a has five person records.
+        assert_eq!(extract.rows.len(), 5);
+    }
+
+    #[test]
+    fn test_chunk_rows_zero_error() {
+        let result = ChunkedCsvWriter::new(Path::new("."), "usa_00001", Vec::new(), Some(0));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_extract_to_chunked_files() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us1850a"],
+            &["SEX"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let dir = temp_output_dir("extract");
+        let options = ExtractOptions {
+            chunk_rows: Some(2),
+            ..ExtractOptions::default()
+        };
+        let result = extract_to_files(&ctx, rq, &dir, "usa_00001", &options);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // us1850a has five person records split across two part files.
+        let manifest = result.expect("should write the extract");
+        assert_eq!(manifest.rows, 5);
+        assert_eq!(manifest.files.len(), 3);
+    }
+
+    #[test]
+    fn test_extract_structures() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ", "SEX"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let dir = temp_output_dir("structures");
+        let rectangular = extract_to_files(
+            &ctx,
+            rq.clone(),
+            &dir,
+            "rectangular",
+            &ExtractOptions::default(),
+        );
+        let hierarchical = extract_to_files(
+            &ctx,
+            rq,
+            &dir,
+            "hierarchical",
+            &ExtractOptions {
+                structure: ExtractStructure::Hierarchical,
+                ..ExtractOptions::default()
+            },
+        );
+        let read = |manifest: &ExtractManifest| {
+            std::fs::read_to_string(&manifest.files[0]).expect("should read the extract")
+        };
+        let rectangular = rectangular.expect("should write a rectangular extract");
+        let hierarchical = hierarchical.expect("should write a hierarchical extract");
+        let (rectangular_text, hierarchical_text) = (read(&rectangular), read(&hierarchical));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // One row per person, each with its household's GQ.
+        assert_eq!(rectangular.rows, 30767);
+        let lines: Vec<&str> = rectangular_text.lines().collect();
+        assert_eq!(lines[0], "GQ,SEX");
+        assert!(lines[1..].iter().all(|l| !l.starts_with(',')));
+
+        // Each household comes before its persons, and only has its own variables.
+        assert_eq!(hierarchical.rows, 30767 + 16633);
+        let lines: Vec<&str> = hierarchical_text.lines().collect();
+        assert_eq!(lines[0], "RECTYPE,GQ,SEX");
+        assert!(lines[1].starts_with("H,") && lines[1].ends_with(','));
+        assert!(lines[2].starts_with("P,,"));
+        assert!(lines[1..]
+            .iter()
+            .all(|l| l.starts_with("H,") || l.starts_with("P,")));
+    }
+}
@@ -2,11 +2,14 @@
 //!
 //! Layouts are required as a minimum level of metadata to do all advanced Abacus tabulations and formatting.
 //!  The 'HFLR" type models the "Hierarchical Fixed-Length Record" data IPUMS uses.
+use crate::ipums_metadata_model::IpumsDataType;
 use crate::layout;
 use crate::mderror::MdError;
+use crate::request::RequestVariable;
 //use duckdb::arrow::datatypes::ToByteSlice;
 use ascii;
 use std::ffi::OsString;
+use std::io::Write;
 use std::path;
 
 const TRACE: bool = false;
@@ -142,6 +145,322 @@ pub fn make_zero_padded_numeric(code: &[u8]) -> Vec<u8> {
     new_code
 }
 
+/// The position of one column in a fixed-width extract record.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FwColumn {
+    pub name: String,
+    /// The 1-based start column.
+    pub start: usize,
+    pub width: usize,
+    /// Strings are padded with spaces, and everything else with zeros.
+    pub data_type: IpumsDataType,
+}
+
+/// Writes extract records as fixed-width lines, one column per request variable.
+///
+/// A variable's `extract_start` and `extract_width` from the request decide where it
+/// goes in the record. Variables without them are placed right after the previous
+/// column, using the width from metadata.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FwWriter {
+    columns: Vec<FwColumn>,
+    record_width: usize,
+}
+
+impl FwWriter {
+    pub fn try_from_request_variables(variables: &[RequestVariable]) -> Result<Self, MdError> {
+        Self::try_with_columns(Vec::new(), variables)
+    }
+
+    /// Lay out the records of one record type in a hierarchical extract. "RECTYPE" comes
+    /// first, in columns 1 through `rectype_width`, followed by the request variables.
+    pub fn try_for_record_type(
+        rectype_width: usize,
+        variables: &[RequestVariable],
+    ) -> Result<Self, MdError> {
+        let rectype = FwColumn {
+            name: "RECTYPE".to_string(),
+            start: 1,
+            width: rectype_width,
+            data_type: IpumsDataType::String,
+        };
+        Self::try_with_columns(vec![rectype], variables)
+    }
+
+    // Add the request variables after the given columns.
+    fn try_with_columns(
+        mut columns: Vec<FwColumn>,
+        variables: &[RequestVariable],
+    ) -> Result<Self, MdError> {
+        let mut next_start = columns.iter().map(|c| c.start + c.width).max().unwrap_or(1);
+        for v in variables {
+            let (start, width) = match v.extract_position() {
+                Some(position) => position,
+                None => (next_start, v.requested_width()?),
+            };
+            if start == 0 || width == 0 {
+                return Err(MdError::Msg(format!(
+                    "invalid fixed-width position for {}: start {start}, width {width}",
+                    v.name
+                )));
+            }
+
+            let end = start + width;
+            if let Some(other) = columns
+                .iter()
+                .find(|c| start < c.start + c.width && c.start < end)
+            {
+                return Err(MdError::Msg(format!(
+                    "fixed-width columns {} and {} overlap",
+                    other.name, v.name
+                )));
+            }
+
+            columns.push(FwColumn {
+                name: v.name.clone(),
+                start,
+                width,
+                data_type: v.data_type().unwrap_or(IpumsDataType::Integer),
+            });
+            next_start = next_start.max(end);
+        }
+
+        let record_width = columns.iter().map(|c| c.start + c.width - 1).max();
+        Ok(Self {
+            columns,
+            record_width: record_width.unwrap_or(0),
+        })
+    }
+
+    pub fn columns(&self) -> &[FwColumn] {
+        &self.columns
+    }
+
+    /// Format one record, with the values in the same order as the columns. Values are
+    /// zero-padded to their column width, and any gaps between columns are spaces.
+    pub fn format_record(&self, values: &[i64]) -> Result<String, MdError> {
+        if values.len() != self.columns.len() {
+            return Err(MdError::Msg(format!(
+                "expected {} values for a fixed-width record but got {}",
+                self.columns.len(),
+                values.len()
+            )));
+        }
+
+        let mut record = vec![b' '; self.record_width];
+        for (column, value) in self.columns.iter().zip(values) {
+            let formatted = format!("{value:0width$}", width = column.width);
+            if formatted.len() > column.width {
+                return Err(MdError::Msg(format!(
+                    "value {value} does not fit in the {} columns for {}",
+                    column.width, column.name
+                )));
+            }
+            let start = column.start - 1;
+            record[start..start + column.width].copy_from_slice(formatted.as_bytes());
+        }
+
+        // Only ASCII digits, '-' and spaces go into the record.
+        Ok(String::from_utf8_lossy(&record).into_owned())
+    }
+
+    /// Format one record of values as text, in the same order as the columns. Values are
+    /// right-justified in their columns. Strings are padded with spaces, and other values
+    /// with zeros as in [make_zero_padded_numeric]. Empty values are missing and leave their
+    /// columns blank.
+    pub fn format_text_record<S: AsRef<str>>(&self, values: &[S]) -> Result<String, MdError> {
+        if values.len() != self.columns.len() {
+            return Err(MdError::Msg(format!(
+                "expected {} values for a fixed-width record but got {}",
+                self.columns.len(),
+                values.len()
+            )));
+        }
+
+        let mut record = vec![b' '; self.record_width];
+        for (column, value) in self.columns.iter().zip(values) {
+            let value = value.as_ref();
+            if value.is_empty() {
+                continue;
+            }
+            if value.len() > column.width {
+                return Err(MdError::Msg(format!(
+                    "value {value} does not fit in the {} columns for {}",
+                    column.width, column.name
+                )));
+            }
+
+            let mut formatted = vec![b' '; column.width - value.len()];
+            formatted.extend_from_slice(value.as_bytes());
+            if column.data_type != IpumsDataType::String {
+                formatted = make_zero_padded_numeric(&formatted);
+            }
+            let start = column.start - 1;
+            record[start..start + column.width].copy_from_slice(&formatted);
+        }
+
+        // Values are copied in whole, so the record is still UTF-8.
+        Ok(String::from_utf8_lossy(&record).into_owned())
+    }
+
+    /// Format one record with [FwWriter::format_record] and write it as a line.
+    pub fn write_record<W: Write>(&self, out: &mut W, values: &[i64]) -> Result<(), MdError> {
+        let record = self.format_record(values)?;
+        writeln!(out, "{record}")?;
+        Ok(())
+    }
+}
+
+// The positions of the record type's variables among the request variables go with its
+// columns after RECTYPE, if it has one.
+#[derive(Clone, Debug, PartialEq)]
+struct FwRecordType {
+    rectype: String,
+    writer: FwWriter,
+    variables: Vec<usize>,
+}
+
+/// The layout of a fixed-width extract: the columns of each of its record types, with the
+/// widths of the request variables from their metadata. See [FwWriter].
+///
+/// The layout can be written as a layout file, so that the extract can be read back with
+/// [Hflr] or [layout::DatasetLayout] like any other IPUMS fixed-width data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FwExtractLayout {
+    hierarchical: bool,
+    record_types: Vec<FwRecordType>,
+}
+
+impl FwExtractLayout {
+    /// Lay out a rectangular extract, with one record per record of `unit_rectype` holding
+    /// every request variable. The records don't have a RECTYPE column.
+    pub fn try_rectangular(
+        unit_rectype: &str,
+        variables: &[RequestVariable],
+    ) -> Result<Self, MdError> {
+        let record_type = FwRecordType {
+            rectype: unit_rectype.to_string(),
+            writer: FwWriter::try_from_request_variables(variables)?,
+            variables: (0..variables.len()).collect(),
+        };
+        Ok(Self {
+            hierarchical: false,
+            record_types: vec![record_type],
+        })
+    }
+
+    /// Lay out a hierarchical extract of the record types in `rectypes`. Each record starts
+    /// with its RECTYPE, padded to the width of the longest record type, followed by the
+    /// request variables on its own record type.
+    pub fn try_hierarchical(
+        rectypes: &[String],
+        variables: &[RequestVariable],
+    ) -> Result<Self, MdError> {
+        if let Some(v) = variables
+            .iter()
+            .find(|v| !rectypes.contains(&v.variable.record_type))
+        {
+            return Err(MdError::Msg(format!(
+                "Variable {} is on record type '{}', which isn't in the fixed-width layout.",
+                v.name, v.variable.record_type
+            )));
+        }
+
+        let rectype_width = rectypes.iter().map(|rt| rt.len()).max().unwrap_or(0);
+        let mut record_types = Vec::with_capacity(rectypes.len());
+        for rectype in rectypes {
+            let positions: Vec<usize> = variables
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| &v.variable.record_type == rectype)
+                .map(|(position, _)| position)
+                .collect();
+            let own_variables: Vec<RequestVariable> =
+                positions.iter().map(|&p| variables[p].clone()).collect();
+            record_types.push(FwRecordType {
+                rectype: rectype.clone(),
+                writer: FwWriter::try_for_record_type(rectype_width, &own_variables)?,
+                variables: positions,
+            });
+        }
+        Ok(Self {
+            hierarchical: true,
+            record_types,
+        })
+    }
+
+    /// The columns of the records of the given record type.
+    pub fn columns(&self, rectype: &str) -> Option<&[FwColumn]> {
+        self.record_types
+            .iter()
+            .find(|rt| rt.rectype == rectype)
+            .map(|rt| rt.writer.columns())
+    }
+
+    /// Format one row of an extract, which has a value for each request variable in order.
+    /// In a hierarchical extract the row starts with its RECTYPE, and only the values of the
+    /// variables on that record type are written.
+    pub fn format_row<S: AsRef<str>>(&self, row: &[S]) -> Result<String, MdError> {
+        if !self.hierarchical {
+            return self.record_types[0].writer.format_text_record(row);
+        }
+
+        let Some((rectype, values)) = row.split_first() else {
+            return Err(MdError::Msg(
+                "A hierarchical fixed-width record needs a RECTYPE.".to_string(),
+            ));
+        };
+        let rectype = rectype.as_ref();
+        let record_type = self
+            .record_types
+            .iter()
+            .find(|rt| rt.rectype == rectype)
+            .ok_or_else(|| {
+                MdError::Msg(format!(
+                    "Record type '{rectype}' isn't in the fixed-width layout."
+                ))
+            })?;
+
+        let mut record_values = vec![rectype];
+        for &position in &record_type.variables {
+            let value = values.get(position).ok_or_else(|| {
+                MdError::Msg(format!(
+                    "expected {} values for a fixed-width record but got {}",
+                    self.variable_count() + 1,
+                    row.len()
+                ))
+            })?;
+            record_values.push(value.as_ref());
+        }
+        record_type.writer.format_text_record(&record_values)
+    }
+
+    /// Describe the columns in the format of an IPUMS layout file, one line per column
+    /// with its name, record type, start column, width and data type.
+    pub fn layout_text(&self) -> String {
+        let mut text = String::new();
+        for record_type in &self.record_types {
+            for column in record_type.writer.columns() {
+                text += &format!(
+                    "{} {} {} {} {}\n",
+                    column.name, record_type.rectype, column.start, column.width, column.data_type
+                );
+            }
+        }
+        text
+    }
+
+    /// Write [FwExtractLayout::layout_text] to a layout file.
+    pub fn write_layout_file(&self, filename: &path::Path) -> Result<(), MdError> {
+        std::fs::write(filename, self.layout_text())?;
+        Ok(())
+    }
+
+    fn variable_count(&self) -> usize {
+        self.record_types.iter().map(|rt| rt.variables.len()).sum()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -200,4 +519,172 @@ mod tests {
 
         assert_eq!(2, hh_layout.vars().len());
     }
+
+    /// The request puts AGE in columns 1-3 and MARST in column 5, leaving a gap.
+    #[test]
+    fn test_fw_writer_uses_extract_start_and_width() {
+        use super::*;
+        use crate::request::{AbacusRequest, DataRequest};
+
+        let json_request = include_str!("../tests/requests/fixed_width_positions.json");
+        let (_, rq) = AbacusRequest::try_from_json(json_request)
+            .expect("should be able to parse the request");
+        let writer = FwWriter::try_from_request_variables(&rq.get_request_variables())
+            .expect("should be able to lay out the request variables");
+
+        assert_eq!(
+            writer.columns(),
+            &[
+                FwColumn {
+                    name: "AGE".to_string(),
+                    start: 1,
+                    width: 3,
+                    data_type: IpumsDataType::Integer,
+                },
+                FwColumn {
+                    name: "MARST".to_string(),
+                    start: 5,
+                    width: 1,
+                    data_type: IpumsDataType::Integer,
+                },
+            ]
+        );
+
+        let mut out = Vec::new();
+        writer
+            .write_record(&mut out, &[42, 6])
+            .expect("should write the record");
+        assert_eq!(b"042 6\n", out.as_slice());
+    }
+
+    #[test]
+    fn test_fw_writer_value_too_wide_error() {
+        use super::*;
+        use crate::request::{AbacusRequest, DataRequest};
+
+        let json_request = include_str!("../tests/requests/fixed_width_positions.json");
+        let (_, rq) = AbacusRequest::try_from_json(json_request)
+            .expect("should be able to parse the request");
+        let writer = FwWriter::try_from_request_variables(&rq.get_request_variables())
+            .expect("should be able to lay out the request variables");
+
+        let result = writer.format_record(&[1000, 6]);
+        assert!(result.is_err(), "1000 doesn't fit in AGE's 3 columns");
+    }
+
+    #[test]
+    fn test_fw_writer_format_text_record() {
+        use super::*;
+        use crate::request::{AbacusRequest, DataRequest};
+
+        let json_request = include_str!("../tests/requests/fixed_width_positions.json");
+        let (_, rq) = AbacusRequest::try_from_json(json_request)
+            .expect("should be able to parse the request");
+        let writer = FwWriter::try_from_request_variables(&rq.get_request_variables())
+            .expect("should be able to lay out the request variables");
+
+        let record = writer
+            .format_text_record(&["-5", "6"])
+            .expect("should format the record");
+        assert_eq!(record, "-05 6");
+
+        // Missing values are blank.
+        let record = writer
+            .format_text_record(&["", "6"])
+            .expect("should format the record");
+        assert_eq!(record, "    6");
+
+        let result = writer.format_text_record(&["1000", "6"]);
+        assert!(result.is_err(), "1000 doesn't fit in AGE's 3 columns");
+    }
+
+    /// Households and persons each have RECTYPE in column 1 and then their own variables.
+    #[test]
+    fn test_fw_extract_layout_hierarchical() {
+        use super::*;
+        use crate::request::{DataRequest, SimpleRequest};
+
+        let (_, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ", "SEX", "AGE"],
+            Some("P".to_string()),
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should be able to set up the request");
+        let layout = FwExtractLayout::try_hierarchical(
+            &["H".to_string(), "P".to_string()],
+            &rq.get_request_variables(),
+        )
+        .expect("should lay out the extract");
+
+        let person_columns: Vec<(&str, usize, usize)> = layout
+            .columns("P")
+            .expect("should have person columns")
+            .iter()
+            .map(|c| (c.name.as_str(), c.start, c.width))
+            .collect();
+        assert_eq!(
+            person_columns,
+            vec![("RECTYPE", 1, 1), ("SEX", 2, 1), ("AGE", 3, 3)]
+        );
+
+        let household = layout
+            .format_row(&["H", "1", "", ""])
+            .expect("should format the household");
+        assert_eq!(household, "H1");
+        let person = layout
+            .format_row(&["P", "", "2", "7"])
+            .expect("should format the person");
+        assert_eq!(person, "P2007");
+
+        let result = layout.format_row(&["A", "", "", ""]);
+        assert!(result.is_err(), "A isn't a record type in the layout");
+    }
+
+    #[test]
+    fn test_fw_extract_layout_file_round_trip() {
+        use super::*;
+        use crate::request::{DataRequest, SimpleRequest};
+
+        let (_, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ", "SEX"],
+            Some("P".to_string()),
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should be able to set up the request");
+        let layout = FwExtractLayout::try_hierarchical(
+            &["H".to_string(), "P".to_string()],
+            &rq.get_request_variables(),
+        )
+        .expect("should lay out the extract");
+        assert_eq!(
+            layout.layout_text(),
+            "RECTYPE H 1 1 string\nGQ H 2 1 integer\nRECTYPE P 1 1 string\nSEX P 2 1 integer\n"
+        );
+
+        let filename = std::env::temp_dir().join(format!(
+            "cimdea_fw_extract_{}.layout.txt",
+            std::process::id()
+        ));
+        layout
+            .write_layout_file(&filename)
+            .expect("should write the layout file");
+        let read_back = layout::DatasetLayout::try_from_layout_file(&filename);
+        let _ = std::fs::remove_file(&filename);
+        let read_back = read_back.expect("should read the layout file back");
+        let person_layout = read_back
+            .for_rectype("P")
+            .expect("should have a layout for persons");
+        let names: Vec<String> = person_layout
+            .sorted_vars_by_start()
+            .into_iter()
+            .map(|v| v.name)
+            .collect();
+        assert_eq!(names, vec!["RECTYPE", "SEX"]);
+    }
 }
@@ -1,23 +1,212 @@
 //! Models and parsing logic for incoming JSON tabulation requests.
 
 use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
 
 use serde::{Deserialize, Deserializer, Serialize};
 
+use crate::defaults;
 use crate::mderror::{parsing_error, MdError};
 
+/// The version of the request schema that this module models. Requests without a
+/// `schema_version` are assumed to be this version.
+pub const CURRENT_SCHEMA_VERSION: u32 = 1;
+
+/// The request schema versions that this module can parse correctly.
+pub const SUPPORTED_SCHEMA_VERSIONS: RangeInclusive<u32> = 1..=CURRENT_SCHEMA_VERSION;
+
+/// The code of a binned variable's residual bin, which tabulations group the values outside
+/// every one of its category bins under. Bins may not use this code.
+///
+/// Variables only have a residual bin when the request asks for one. Without it, values
+/// outside every bin get an empty (NULL) code instead, so they still get a row of their own
+/// rather than being dropped.
+pub const UNBINNED_CODE: u64 = 999;
+
+/// The label of the row of values outside every category bin of a variable without a residual
+/// bin. See [UNBINNED_CODE].
+pub const UNBINNED_LABEL: &str = "unbinned";
+
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct AbacusRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u32>,
     pub product: String,
     pub data_root: Option<String>,
+    /// The unit of analysis, as a record type value like "P" or its name like "Person".
     pub uoa: String,
     pub output_format: String,
     pub subpopulation: Vec<RequestVariable>,
     pub category_bins: BTreeMap<String, Vec<CategoryBin>>,
+    /// Residual bins for binned variables and their labels, like `{"INCWAGE": "Other"}`. A
+    /// variable's residual bin holds every value outside all of its category bins under
+    /// [UNBINNED_CODE]. Only the variables listed here have one.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub residual_bins: BTreeMap<String, String>,
     pub request_samples: Vec<RequestSample>,
     pub request_variables: Vec<RequestVariable>,
+    /// The record type whose weight variable weights the counts, like "H" to count persons
+    /// with the household weight. Defaults to the unit of analysis.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight_rectype: Option<String>,
+    /// A variable to weight the counts with in place of the default weight of its record
+    /// type, like a replicate weight "REPWTP1". The default weight's divisor still applies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight_variable: Option<String>,
+    /// Units of analysis for separate tables from each dataset, like `["P", "H"]` for a
+    /// person table and a household table. Defaults to one table for `uoa`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub table_uoas: Vec<String>,
+    /// Which count columns the tables have: "unweighted" for only `ct`, "weighted" for only
+    /// `weighted_ct`, or "both", the default.
+    #[serde(default, skip_serializing_if = "CountColumns::is_both")]
+    pub count_columns: CountColumns,
 }
 
+impl AbacusRequest {
+    /// Make a blank request for the given datasets and variables, to edit and serialize
+    /// as a starting point for writing JSON requests by hand.
+    ///
+    /// The request tabulates the variables' detailed codes for the product's default unit
+    /// of analysis, with CSV output, no category bins and no subpopulation. It has no
+    /// `data_root`. Its `extract_start` and `extract_width` are 0, which means that the
+    /// request doesn't give them.
+    ///
+    /// ```
+    /// use cimdea::input_schema_tabulation::AbacusRequest;
+    ///
+    /// let request = AbacusRequest::template("usa", &["us2015b"], &["AGE", "MARST"]).unwrap();
+    /// assert_eq!(request.uoa, "P");
+    /// assert_eq!(request.request_variables.len(), 2);
+    /// ```
+    pub fn template(product: &str, datasets: &[&str], variables: &[&str]) -> Result<Self, MdError> {
+        let settings = defaults::defaults_for(product)?;
+        let request_samples = datasets
+            .iter()
+            .map(|name| RequestSample {
+                name: name.to_string(),
+                custom_sampling_ratio: None,
+                first_household_sampled: None,
+            })
+            .collect();
+        let request_variables = variables
+            .iter()
+            .map(|name| RequestVariable {
+                variable_mnemonic: name.to_string(),
+                mnemonic: name.to_string(),
+                general_detailed_selection: GeneralDetailedSelection::Detailed,
+                attached_variable_pointer: None,
+                case_selection: false,
+                request_case_selections: Vec::new(),
+                extract_start: 0,
+                extract_width: 0,
+            })
+            .collect();
+
+        Ok(Self {
+            schema_version: Some(CURRENT_SCHEMA_VERSION),
+            product: product.to_string(),
+            data_root: None,
+            uoa: settings.default_unit_of_analysis.value,
+            output_format: "csv".to_string(),
+            subpopulation: Vec::new(),
+            category_bins: BTreeMap::new(),
+            residual_bins: BTreeMap::new(),
+            request_samples,
+            request_variables,
+            weight_rectype: None,
+            weight_variable: None,
+            table_uoas: Vec::new(),
+            count_columns: CountColumns::Both,
+        })
+    }
+
+    /// Check that this request's `schema_version` is one that we support, and return
+    /// the version. A missing `schema_version` is treated as [CURRENT_SCHEMA_VERSION].
+    pub fn validated_schema_version(&self) -> Result<u32, MdError> {
+        let version = self.schema_version.unwrap_or(CURRENT_SCHEMA_VERSION);
+        if SUPPORTED_SCHEMA_VERSIONS.contains(&version) {
+            Ok(version)
+        } else {
+            Err(parsing_error!(
+                "unsupported request schema_version {version}; supported versions are {} through {}",
+                SUPPORTED_SCHEMA_VERSIONS.start(),
+                SUPPORTED_SCHEMA_VERSIONS.end()
+            ))
+        }
+    }
+
+    /// Check that the request asks for at least one sample and at least one variable.
+    /// Without them, there's nothing to tabulate.
+    pub fn validate_not_empty(&self) -> Result<(), MdError> {
+        if self.request_samples.is_empty() {
+            return Err(MdError::Msg(
+                "The request has no request_samples; it must request at least one sample."
+                    .to_string(),
+            ));
+        }
+        if self.request_variables.is_empty() {
+            return Err(MdError::Msg(
+                "The request has no request_variables; it must request at least one variable."
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that no two category bins for the same variable overlap. The error names the
+    /// variable, the labels of the first overlapping pair of bins, and the values they share.
+    /// Each bin must also have its own code, other than [UNBINNED_CODE], and each residual
+    /// bin must be for a variable with category bins.
+    pub fn validate_category_bins(&self) -> Result<(), MdError> {
+        if let Some(variable) = self
+            .residual_bins
+            .keys()
+            .find(|variable| !self.category_bins.contains_key(*variable))
+        {
+            return Err(parsing_error!(
+                "residual_bins has a bin for {variable}, but {variable} has no category_bins"
+            ));
+        }
+
+        for (variable, bins) in &self.category_bins {
+            if let Some(overlap) = find_bin_overlaps(bins).first() {
+                return Err(parsing_error!(
+                    "category_bins for {variable} overlap: '{}' and '{}' both contain the values {} through {}",
+                    overlap.first_label,
+                    overlap.second_label,
+                    overlap.low,
+                    overlap.high
+                ));
+            }
+
+            // Labels are looked up by code, so each code may only name one bin.
+            let mut labels_by_code = BTreeMap::new();
+            for bin in bins {
+                if bin.code() == UNBINNED_CODE {
+                    return Err(parsing_error!(
+                        "category_bins for {variable} can't use code {UNBINNED_CODE} for '{}'; it's reserved for values outside every bin",
+                        bin.label()
+                    ));
+                }
+                if let Some(first_label) = labels_by_code.insert(bin.code(), bin.label()) {
+                    return Err(parsing_error!(
+                        "category_bins for {variable} have more than one bin with code {}: '{first_label}' and '{}'",
+                        bin.code(),
+                        bin.label()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A bin which groups a range of a variable's values under a single code.
+///
+/// By default `Range` includes both of its bounds, and `LessThan` and `MoreThan`
+/// exclude their bound. The `*_inclusive` flags override this, for example to make
+/// a half-open range like [0, 10000) with `high_inclusive: false`.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(try_from = "CategoryBinRaw", into = "CategoryBinRaw")]
 pub enum CategoryBin {
@@ -25,17 +214,21 @@ pub enum CategoryBin {
         value: i64,
         code: u64,
         label: String,
+        inclusive: bool,
     },
     Range {
         low: i64,
         high: i64,
         code: u64,
         label: String,
+        low_inclusive: bool,
+        high_inclusive: bool,
     },
     MoreThan {
         value: i64,
         code: u64,
         label: String,
+        inclusive: bool,
     },
 }
 
@@ -55,16 +248,20 @@ impl TryFrom<CategoryBinRaw> for CategoryBin {
                 high,
                 code,
                 label: label.to_owned(),
+                low_inclusive: value.low_inclusive.unwrap_or(true),
+                high_inclusive: value.high_inclusive.unwrap_or(true),
             }),
             (None, Some(high)) => Ok(Self::LessThan {
                 value: high,
                 code,
                 label: label.to_owned(),
+                inclusive: value.high_inclusive.unwrap_or(false),
             }),
             (Some(low), None) => Ok(Self::MoreThan {
                 value: low,
                 code,
                 label: label.to_owned(),
+                inclusive: value.low_inclusive.unwrap_or(false),
             }),
             (None, None) => Err(MdError::Msg(
                 "category_bins: must have low, high, or both set to some value".to_string(),
@@ -76,11 +273,277 @@ impl TryFrom<CategoryBinRaw> for CategoryBin {
 impl CategoryBin {
     pub fn within(&self, test_value: i64) -> bool {
         match self {
-            Self::LessThan { value, .. } => test_value < *value,
-            Self::Range { low, high, .. } => test_value >= *low && test_value <= *high,
-            Self::MoreThan { value, .. } => test_value > *value,
+            Self::LessThan {
+                value, inclusive, ..
+            } => test_value < *value || (*inclusive && test_value == *value),
+            Self::Range {
+                low,
+                high,
+                low_inclusive,
+                high_inclusive,
+                ..
+            } => {
+                let above_low = test_value > *low || (*low_inclusive && test_value == *low);
+                let below_high = test_value < *high || (*high_inclusive && test_value == *high);
+                above_low && below_high
+            }
+            Self::MoreThan {
+                value, inclusive, ..
+            } => test_value > *value || (*inclusive && test_value == *value),
+        }
+    }
+
+    /// The code that values within the bin are grouped under.
+    pub fn code(&self) -> u64 {
+        match self {
+            Self::LessThan { code, .. }
+            | Self::Range { code, .. }
+            | Self::MoreThan { code, .. } => *code,
+        }
+    }
+
+    /// The label of the bin.
+    pub fn label(&self) -> &str {
+        match self {
+            Self::LessThan { label, .. }
+            | Self::Range { label, .. }
+            | Self::MoreThan { label, .. } => label,
         }
     }
+
+    /// The smallest and largest values [CategoryBin::within] the bin, or None if no value
+    /// is within it (for example a half-open range whose low and high are equal).
+    pub fn bounds(&self) -> Option<(i64, i64)> {
+        let (low, high) = match *self {
+            Self::LessThan {
+                value, inclusive, ..
+            } => {
+                let high = if inclusive {
+                    Some(value)
+                } else {
+                    value.checked_sub(1)
+                };
+                (Some(i64::MIN), high)
+            }
+            Self::Range {
+                low,
+                high,
+                low_inclusive,
+                high_inclusive,
+                ..
+            } => {
+                let low = if low_inclusive {
+                    Some(low)
+                } else {
+                    low.checked_add(1)
+                };
+                let high = if high_inclusive {
+                    Some(high)
+                } else {
+                    high.checked_sub(1)
+                };
+                (low, high)
+            }
+            Self::MoreThan {
+                value, inclusive, ..
+            } => {
+                let low = if inclusive {
+                    Some(value)
+                } else {
+                    value.checked_add(1)
+                };
+                (low, Some(i64::MAX))
+            }
+        };
+        match (low, high) {
+            (Some(low), Some(high)) if low <= high => Some((low, high)),
+            _ => None,
+        }
+    }
+}
+
+/// Assign each of `values` to the index of the first bin in `bins` that it is
+/// [within](CategoryBin::within), or None if it is not within any bin.
+///
+/// ```
+/// use cimdea::input_schema_tabulation::{assign_bins, CategoryBin};
+///
+/// let bins = vec![CategoryBin::Range {
+///     low: 1,
+///     high: 14,
+///     code: 1,
+///     label: "1 to 14 hours worked per week".to_string(),
+///     low_inclusive: true,
+///     high_inclusive: true,
+/// }];
+/// assert_eq!(assign_bins(&bins, &[0, 10, 40]), vec![None, Some(0), None]);
+/// ```
+pub fn assign_bins(bins: &[CategoryBin], values: &[i64]) -> Vec<Option<usize>> {
+    values
+        .iter()
+        .map(|value| bins.iter().position(|bin| bin.within(*value)))
+        .collect()
+}
+
+/// Check whether `value` is outside every bin in `bins`, so that it belongs in their residual
+/// bin. This is the complement of [CategoryBin::within] across all of the bins.
+///
+/// ```
+/// use cimdea::input_schema_tabulation::{outside_all_bins, CategoryBin};
+///
+/// let bins = vec![CategoryBin::Range {
+///     low: 1,
+///     high: 14,
+///     code: 1,
+///     label: "1 to 14 hours worked per week".to_string(),
+///     low_inclusive: true,
+///     high_inclusive: true,
+/// }];
+/// assert!(outside_all_bins(&bins, 40));
+/// assert!(!outside_all_bins(&bins, 10));
+/// ```
+pub fn outside_all_bins(bins: &[CategoryBin], value: i64) -> bool {
+    !bins.iter().any(|bin| bin.within(value))
+}
+
+/// How [AutoBin] divides a variable's values into bins.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AutoBinMethod {
+    /// Bins which each cover the same number of values, from the smallest value in the data
+    /// to the largest.
+    EqualWidth,
+    /// Bins which each hold about the same number of records, split at the quantiles of
+    /// the data.
+    Quantile,
+}
+
+/// Bin a continuous variable into `n` bins computed from its values in the data, instead of
+/// giving its category bins by hand. See
+/// [tabulate::auto_bins](crate::tabulate::auto_bins) for computing the bins.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AutoBin {
+    pub n: usize,
+    pub method: AutoBinMethod,
+}
+
+impl AutoBin {
+    /// The quantiles of the data which [AutoBinMethod::Quantile] splits the bins at, like
+    /// 0.25, 0.5 and 0.75 for four bins. Equal-width bins don't need any.
+    pub fn quantiles(&self) -> Vec<f64> {
+        match self.method {
+            AutoBinMethod::EqualWidth => Vec::new(),
+            AutoBinMethod::Quantile => (1..self.n).map(|k| k as f64 / self.n as f64).collect(),
+        }
+    }
+
+    /// Make the bins for data whose values run from `min` to `max`, given the values at each
+    /// of [AutoBin::quantiles]. Bins are coded 1 through `n` and labeled like "18 to 34".
+    ///
+    /// Each bin includes its high value, and the next bin starts just above it. When many
+    /// records share a value, quantiles may be equal and there are fewer than `n` bins.
+    pub fn bins(
+        &self,
+        min: i64,
+        max: i64,
+        quantile_values: &[i64],
+    ) -> Result<Vec<CategoryBin>, MdError> {
+        if self.n == 0 || self.n as u64 >= UNBINNED_CODE {
+            return Err(parsing_error!(
+                "can't automatically bin values into {} bins; there must be 1 to {}",
+                self.n,
+                UNBINNED_CODE - 1
+            ));
+        }
+        if max < min {
+            return Err(parsing_error!(
+                "can't automatically bin values from {min} to {max}: the minimum is more than the maximum"
+            ));
+        }
+
+        let highs: Vec<i64> = match self.method {
+            AutoBinMethod::EqualWidth => {
+                let (min, max, n) = (i128::from(min), i128::from(max), self.n as i128);
+                let span = max - min + 1;
+                (1..=n)
+                    .map(|k| (min - 1 + (span * k + n - 1) / n) as i64)
+                    .collect()
+            }
+            AutoBinMethod::Quantile => {
+                if quantile_values.len() + 1 != self.n {
+                    return Err(parsing_error!(
+                        "{} quantile bins need {} quantile values but got {}",
+                        self.n,
+                        self.n - 1,
+                        quantile_values.len()
+                    ));
+                }
+                quantile_values
+                    .iter()
+                    .copied()
+                    .chain(std::iter::once(max))
+                    .collect()
+            }
+        };
+
+        let mut bins = Vec::new();
+        let mut low = min;
+        for high in highs {
+            if high < low {
+                continue;
+            }
+            bins.push(CategoryBin::Range {
+                low,
+                high,
+                code: bins.len() as u64 + 1,
+                label: format!("{low} to {high}"),
+                low_inclusive: true,
+                high_inclusive: true,
+            });
+            match high.checked_add(1) {
+                Some(next) => low = next,
+                None => break,
+            }
+        }
+        Ok(bins)
+    }
+}
+
+/// Two category bins which share some values.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BinOverlap {
+    pub first_label: String,
+    pub second_label: String,
+    /// The smallest value within both bins.
+    pub low: i64,
+    /// The largest value within both bins.
+    pub high: i64,
+}
+
+/// Find every pair of bins in `bins` which overlap, along with the values they share.
+/// Pairs are reported in the order the bins appear in `bins`.
+pub fn find_bin_overlaps(bins: &[CategoryBin]) -> Vec<BinOverlap> {
+    let mut overlaps = Vec::new();
+    for (index, first) in bins.iter().enumerate() {
+        let Some((first_low, first_high)) = first.bounds() else {
+            continue;
+        };
+        for second in &bins[index + 1..] {
+            let Some((second_low, second_high)) = second.bounds() else {
+                continue;
+            };
+            let low = first_low.max(second_low);
+            let high = first_high.min(second_high);
+            if low <= high {
+                overlaps.push(BinOverlap {
+                    first_label: first.label().to_string(),
+                    second_label: second.label().to_string(),
+                    low,
+                    high,
+                });
+            }
+        }
+    }
+    overlaps
 }
 
 #[derive(Deserialize, Serialize)]
@@ -89,33 +552,56 @@ struct CategoryBinRaw {
     value_label: String,
     low: Option<i64>,
     high: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    low_inclusive: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    high_inclusive: Option<bool>,
 }
 
 impl From<CategoryBin> for CategoryBinRaw {
+    // Only serialize the inclusivity flags when they differ from the defaults.
     fn from(value: CategoryBin) -> Self {
         match value {
-            CategoryBin::LessThan { value, code, label } => Self {
+            CategoryBin::LessThan {
+                value,
+                code,
+                label,
+                inclusive,
+            } => Self {
                 code,
                 value_label: label,
                 low: None,
                 high: Some(value),
+                low_inclusive: None,
+                high_inclusive: inclusive.then_some(true),
             },
-            CategoryBin::MoreThan { value, code, label } => Self {
+            CategoryBin::MoreThan {
+                value,
+                code,
+                label,
+                inclusive,
+            } => Self {
                 code,
                 value_label: label,
                 low: Some(value),
                 high: None,
+                low_inclusive: inclusive.then_some(true),
+                high_inclusive: None,
             },
             CategoryBin::Range {
                 low,
                 high,
                 code,
                 label,
+                low_inclusive,
+                high_inclusive,
             } => Self {
                 code,
                 value_label: label,
                 low: Some(low),
                 high: Some(high),
+                low_inclusive: (!low_inclusive).then_some(false),
+                high_inclusive: (!high_inclusive).then_some(false),
             },
         }
     }
@@ -127,13 +613,23 @@ pub struct RequestVariable {
     pub mnemonic: String,
     #[serde(deserialize_with = "general_detailed_selection_from_nullable_field")]
     pub general_detailed_selection: GeneralDetailedSelection,
-    pub attached_variable_pointer: (),
+    pub attached_variable_pointer: Option<AttachedVariablePointer>,
     pub case_selection: bool,
     pub request_case_selections: Vec<RequestCaseSelection>,
     pub extract_start: usize,
     pub extract_width: usize,
 }
 
+/// Where the value of an attached variable comes from. An attached variable is on a record
+/// type above the unit of analysis, like the household variable HHINCOME in a request with
+/// persons as the unit of analysis. Each unit of analysis record gets the value from the
+/// record of `record_type` it belongs to.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AttachedVariablePointer {
+    /// The record type of the attached variable, like "H".
+    pub record_type: String,
+}
+
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct RequestSample {
     pub name: String,
@@ -219,12 +715,62 @@ impl From<RequestCaseSelection> for RequestCaseSelectionRaw {
     }
 }
 
+/// Which count columns the tables of a tabulation have. Unweighted counts, in the "ct" column,
+/// are the number of records. Weighted counts, in the "weighted_ct" column, sum the weight of
+/// the unit of analysis, like PERWT for persons and HHWT for households. When the unit of
+/// analysis has no weight, `Both` only gives unweighted counts.
+///
+/// In JSON, this is "unweighted", "weighted" or "both".
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CountColumns {
+    Unweighted,
+    Weighted,
+    #[default]
+    Both,
+}
+
+impl CountColumns {
+    /// The names of the count columns, in the order they come in each table.
+    ///
+    /// ```
+    /// use cimdea::input_schema_tabulation::CountColumns;
+    ///
+    /// assert_eq!(CountColumns::Both.column_names(), ["ct", "weighted_ct"]);
+    /// assert_eq!(CountColumns::Weighted.column_names(), ["weighted_ct"]);
+    /// ```
+    pub fn column_names(&self) -> Vec<&'static str> {
+        match self {
+            Self::Unweighted => vec!["ct"],
+            Self::Weighted => vec!["weighted_ct"],
+            Self::Both => vec!["ct", "weighted_ct"],
+        }
+    }
+
+    pub fn unweighted(&self) -> bool {
+        matches!(self, Self::Unweighted | Self::Both)
+    }
+
+    pub fn weighted(&self) -> bool {
+        matches!(self, Self::Weighted | Self::Both)
+    }
+
+    fn is_both(&self) -> bool {
+        *self == Self::Both
+    }
+}
+
+/// Whether a request variable should use its general or detailed codes.
+///
+/// In JSON, "G" selects the general codes, and "D" or "" selects the detailed codes.
+/// A null or missing `general_detailed_selection` defaults to detailed. Detailed
+/// selections serialize as "".
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub enum GeneralDetailedSelection {
     #[serde(rename = "G")]
     General,
     #[default]
-    #[serde(rename = "")]
+    #[serde(rename = "", alias = "D")]
     Detailed,
 }
 
@@ -281,6 +827,98 @@ mod tests {
         assert_eq!(deserialized1, deserialized2);
     }
 
+    #[test]
+    fn test_validate_not_empty_no_samples_error() {
+        let mut request = incwage_marst_request_with_version(None);
+        request.request_samples.clear();
+        let err = request
+            .validate_not_empty()
+            .expect_err("a request with no samples is invalid");
+        assert!(err.to_string().contains("request_samples"));
+    }
+
+    #[test]
+    fn test_validate_not_empty_no_variables_error() {
+        let mut request = incwage_marst_request_with_version(None);
+        request.request_variables.clear();
+        let err = request
+            .validate_not_empty()
+            .expect_err("a request with no variables is invalid");
+        assert!(err.to_string().contains("request_variables"));
+    }
+
+    #[test]
+    fn test_template_round_trip() {
+        let template = AbacusRequest::template("usa", &["us2015b"], &["AGE", "MARST"])
+            .expect("should make a template for USA");
+        let serialized = serde_json::to_string(&template).expect("should serialize the template");
+        let deserialized: AbacusRequest =
+            serde_json::from_str(&serialized).expect("should deserialize the template");
+        assert_eq!(template, deserialized);
+
+        // With a data root, the template is a complete request.
+        let mut json: serde_json::Value =
+            serde_json::from_str(&serialized).expect("template should be valid JSON");
+        json["data_root"] = serde_json::json!("tests/data_root");
+        let (_, rq) = crate::request::AbacusRequest::try_from_json(&json.to_string())
+            .expect("should parse the template as a request");
+        assert_eq!(rq.request_variables.len(), 2);
+        assert_eq!(rq.request_variables[0].extract_position(), None);
+    }
+
+    #[test]
+    fn test_template_unknown_product_error() {
+        let result = AbacusRequest::template("notarealcollection", &["us2015b"], &["AGE"]);
+        assert!(result.is_err());
+    }
+
+    fn incwage_marst_request_with_version(schema_version: Option<u32>) -> AbacusRequest {
+        let json_str = include_str!("../tests/requests/incwage_marst_example.json");
+        let mut request: AbacusRequest =
+            serde_json::from_str(json_str).expect("should deserialize into an AbacusRequest");
+        request.schema_version = schema_version;
+        request
+    }
+
+    #[test]
+    fn test_validated_schema_version_supported() {
+        let request = incwage_marst_request_with_version(Some(CURRENT_SCHEMA_VERSION));
+        let version = request
+            .validated_schema_version()
+            .expect("the current schema version should be supported");
+        assert_eq!(version, CURRENT_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_validated_schema_version_too_old_error() {
+        let request = incwage_marst_request_with_version(Some(0));
+        let result = request.validated_schema_version();
+        assert!(
+            result.is_err(),
+            "schema_version 0 is older than any supported version"
+        );
+    }
+
+    #[test]
+    fn test_validated_schema_version_too_new_error() {
+        let request = incwage_marst_request_with_version(Some(CURRENT_SCHEMA_VERSION + 1));
+        assert!(request.validated_schema_version().is_err());
+    }
+
+    /// Requests without a schema_version are treated as the current version.
+    #[test]
+    fn test_validated_schema_version_missing() {
+        let json_str = include_str!("../tests/requests/incwage_marst_example.json");
+        let request: AbacusRequest =
+            serde_json::from_str(json_str).expect("should deserialize into an AbacusRequest");
+        assert_eq!(request.schema_version, None);
+
+        let version = request
+            .validated_schema_version()
+            .expect("a missing schema version should be treated as the current version");
+        assert_eq!(version, CURRENT_SCHEMA_VERSION);
+    }
+
     #[test]
     fn test_category_bin_try_from_less_than() {
         let raw_bin = CategoryBinRaw {
@@ -288,6 +926,8 @@ mod tests {
             value_label: "less than 3".to_string(),
             low: None,
             high: Some(3),
+            low_inclusive: None,
+            high_inclusive: None,
         };
         let bin = CategoryBin::try_from(raw_bin)
             .expect("should successfully convert from CategoryBinRaw");
@@ -301,6 +941,8 @@ mod tests {
             value_label: "more than 3".to_string(),
             low: Some(3),
             high: None,
+            low_inclusive: None,
+            high_inclusive: None,
         };
         let bin = CategoryBin::try_from(raw_bin)
             .expect("should successfully convert from CategoryBinRaw");
@@ -314,6 +956,8 @@ mod tests {
             value_label: "between 3 and 5".to_string(),
             low: Some(3),
             high: Some(5),
+            low_inclusive: None,
+            high_inclusive: None,
         };
         let bin = CategoryBin::try_from(raw_bin)
             .expect("should successfully convert from CategoryBinRaw");
@@ -327,6 +971,8 @@ mod tests {
             value_label: "no boundaries!".to_string(),
             low: None,
             high: None,
+            low_inclusive: None,
+            high_inclusive: None,
         };
         let result = CategoryBin::try_from(raw_bin);
         assert!(
@@ -342,6 +988,8 @@ mod tests {
             value_label: "that's not possible".to_string(),
             low: Some(10),
             high: Some(2),
+            low_inclusive: None,
+            high_inclusive: None,
         };
         let result = CategoryBin::try_from(raw_bin);
         assert!(result.is_err(), "it should be an error if high < low");
@@ -356,6 +1004,266 @@ mod tests {
         assert!(matches!(category_bin, CategoryBin::Range { .. }));
     }
 
+    #[test]
+    fn test_category_bin_within_closed_range_boundaries() {
+        let json_str =
+            "{\"code\": 1, \"value_label\": \"0 to 10000\", \"low\": 0, \"high\": 10000}";
+        let bin: CategoryBin =
+            serde_json::from_str(json_str).expect("should deserialize into CategoryBin");
+        assert!(!bin.within(-1));
+        assert!(bin.within(0));
+        assert!(bin.within(10000));
+        assert!(!bin.within(10001));
+    }
+
+    #[test]
+    fn test_category_bin_within_half_open_range_boundaries() {
+        let json_str = "{\"code\": 1, \"value_label\": \"0 to under 10000\", \"low\": 0, \"high\": 10000, \"high_inclusive\": false}";
+        let bin: CategoryBin =
+            serde_json::from_str(json_str).expect("should deserialize into CategoryBin");
+        assert!(!bin.within(-1));
+        assert!(bin.within(0));
+        assert!(bin.within(9999));
+        assert!(!bin.within(10000));
+    }
+
+    #[test]
+    fn test_category_bin_within_less_than_and_more_than_boundaries() {
+        let less_than = CategoryBin::LessThan {
+            value: 0,
+            code: 0,
+            label: "less than 0".to_string(),
+            inclusive: false,
+        };
+        assert!(less_than.within(-1));
+        assert!(!less_than.within(0));
+
+        let at_most = CategoryBin::LessThan {
+            value: 0,
+            code: 0,
+            label: "0 or less".to_string(),
+            inclusive: true,
+        };
+        assert!(at_most.within(0));
+        assert!(!at_most.within(1));
+
+        let more_than = CategoryBin::MoreThan {
+            value: 100,
+            code: 2,
+            label: "more than 100".to_string(),
+            inclusive: false,
+        };
+        assert!(!more_than.within(100));
+        assert!(more_than.within(101));
+
+        let at_least = CategoryBin::MoreThan {
+            value: 100,
+            code: 2,
+            label: "100 or more".to_string(),
+            inclusive: true,
+        };
+        assert!(at_least.within(100));
+        assert!(!at_least.within(99));
+    }
+
+    /// Non-default inclusivity flags survive a round trip through JSON.
+    #[test]
+    fn test_category_bin_inclusivity_round_trip() {
+        let bin = CategoryBin::Range {
+            low: 0,
+            high: 10000,
+            code: 1,
+            label: "0 to under 10000".to_string(),
+            low_inclusive: true,
+            high_inclusive: false,
+        };
+        let serialized = serde_json::to_string(&bin).expect("should serialize CategoryBin");
+        assert!(serialized.contains("\"high_inclusive\":false"));
+        assert!(!serialized.contains("low_inclusive"));
+        let deserialized: CategoryBin =
+            serde_json::from_str(&serialized).expect("should deserialize CategoryBin");
+        assert_eq!(bin, deserialized);
+    }
+
+    fn income_bin(low: Option<i64>, high: Option<i64>, label: &str) -> CategoryBin {
+        CategoryBin::try_from(CategoryBinRaw {
+            code: 0,
+            value_label: label.to_string(),
+            low,
+            high,
+            low_inclusive: None,
+            high_inclusive: None,
+        })
+        .expect("test bin should be valid")
+    }
+
+    fn hours_worked_bins() -> Vec<CategoryBin> {
+        vec![
+            income_bin(Some(1), Some(14), "1 to 14 hours worked per week"),
+            income_bin(Some(15), Some(34), "15 to 34 hours worked per week"),
+            income_bin(Some(35), Some(99), "35 or more hours worked per week"),
+        ]
+    }
+
+    #[test]
+    fn test_assign_bins_below_all_bins() {
+        let assignments = assign_bins(&hours_worked_bins(), &[0, -5]);
+        assert_eq!(assignments, vec![None, None]);
+    }
+
+    #[test]
+    fn test_assign_bins_inside_range() {
+        let assignments = assign_bins(&hours_worked_bins(), &[1, 20, 34, 35]);
+        assert_eq!(assignments, vec![Some(0), Some(1), Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn test_assign_bins_above_all_bins() {
+        let assignments = assign_bins(&hours_worked_bins(), &[100, 999]);
+        assert_eq!(assignments, vec![None, None]);
+    }
+
+    #[test]
+    fn test_auto_bin_equal_width() {
+        let auto_bin = AutoBin {
+            n: 4,
+            method: AutoBinMethod::EqualWidth,
+        };
+        assert!(auto_bin.quantiles().is_empty());
+        let bins = auto_bin.bins(0, 95, &[]).expect("should make bins");
+        let labels: Vec<&str> = bins.iter().map(|b| b.label()).collect();
+        assert_eq!(labels, vec!["0 to 23", "24 to 47", "48 to 71", "72 to 95"]);
+        let codes: Vec<u64> = bins.iter().map(|b| b.code()).collect();
+        assert_eq!(codes, vec![1, 2, 3, 4]);
+        assert_eq!(assign_bins(&bins, &[0, 95]), vec![Some(0), Some(3)]);
+    }
+
+    /// Equal quantiles don't make empty bins.
+    #[test]
+    fn test_auto_bin_quantile() {
+        let auto_bin = AutoBin {
+            n: 4,
+            method: AutoBinMethod::Quantile,
+        };
+        assert_eq!(auto_bin.quantiles(), vec![0.25, 0.5, 0.75]);
+        let bins = auto_bin
+            .bins(0, 95, &[20, 41, 60])
+            .expect("should make bins");
+        let labels: Vec<&str> = bins.iter().map(|b| b.label()).collect();
+        assert_eq!(labels, vec!["0 to 20", "21 to 41", "42 to 60", "61 to 95"]);
+
+        let bins = auto_bin.bins(0, 95, &[0, 0, 60]).expect("should make bins");
+        let labels: Vec<&str> = bins.iter().map(|b| b.label()).collect();
+        assert_eq!(labels, vec!["0 to 0", "1 to 60", "61 to 95"]);
+        let codes: Vec<u64> = bins.iter().map(|b| b.code()).collect();
+        assert_eq!(codes, vec![1, 2, 3]);
+
+        let err = auto_bin
+            .bins(0, 95, &[20])
+            .expect_err("four quantile bins need three quantile values");
+        assert!(err.to_string().contains("need 3 quantile values but got 1"));
+    }
+
+    /// When bins overlap, a value goes to the first bin it's within.
+    #[test]
+    fn test_assign_bins_first_match_wins() {
+        let bins = vec![
+            income_bin(Some(0), Some(10), "0 to 10"),
+            income_bin(Some(5), Some(20), "5 to 20"),
+        ];
+        let assignments = assign_bins(&bins, &[7, 15]);
+        assert_eq!(assignments, vec![Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn test_find_bin_overlaps() {
+        let bins = vec![
+            income_bin(None, Some(0), "less than 0"),
+            income_bin(Some(0), Some(10000), "0 to 10000"),
+            income_bin(Some(5000), Some(20000), "5000 to 20000"),
+            income_bin(Some(20001), None, "more than 20001"),
+        ];
+        let overlaps = find_bin_overlaps(&bins);
+        assert_eq!(
+            overlaps,
+            vec![BinOverlap {
+                first_label: "0 to 10000".to_string(),
+                second_label: "5000 to 20000".to_string(),
+                low: 5000,
+                high: 10000,
+            }]
+        );
+    }
+
+    /// The validation error names both overlapping bins.
+    #[test]
+    fn test_validate_category_bins_overlap_error() {
+        let json_str = include_str!("../tests/requests/incwage_marst_example.json");
+        let mut request: AbacusRequest =
+            serde_json::from_str(json_str).expect("should deserialize into an AbacusRequest");
+        request
+            .validate_category_bins()
+            .expect("the example request's bins should not overlap");
+
+        request.category_bins.insert(
+            "INCWAGE".to_string(),
+            vec![
+                income_bin(Some(0), Some(10000), "$0 to $10,000"),
+                income_bin(Some(10000), Some(20000), "$10,000 to $20,000"),
+            ],
+        );
+        let err = request
+            .validate_category_bins()
+            .expect_err("overlapping bins should be an error");
+        let message = err.to_string();
+        assert!(message.contains("INCWAGE"));
+        assert!(message.contains("'$0 to $10,000' and '$10,000 to $20,000'"));
+        assert!(message.contains("10000 through 10000"));
+    }
+
+    #[test]
+    fn test_validate_category_bins_duplicate_code_error() {
+        let json_str = include_str!("../tests/requests/incwage_marst_example.json");
+        let mut request: AbacusRequest =
+            serde_json::from_str(json_str).expect("should deserialize into an AbacusRequest");
+
+        let bin = |code, low, high, label: &str| {
+            CategoryBin::try_from(CategoryBinRaw {
+                code,
+                value_label: label.to_string(),
+                low: Some(low),
+                high: Some(high),
+                low_inclusive: None,
+                high_inclusive: None,
+            })
+            .expect("test bin should be valid")
+        };
+        request.category_bins.insert(
+            "INCWAGE".to_string(),
+            vec![
+                bin(1, 0, 9999, "$0 to $9,999"),
+                bin(2, 10000, 19999, "$10,000 to $19,999"),
+                bin(1, 20000, 29999, "$20,000 to $29,999"),
+            ],
+        );
+        let err = request
+            .validate_category_bins()
+            .expect_err("bins sharing a code should be an error");
+        let message = err.to_string();
+        assert!(message.contains("INCWAGE"));
+        assert!(message.contains("more than one bin with code 1"));
+        assert!(message.contains("'$0 to $9,999' and '$20,000 to $29,999'"));
+
+        request.category_bins.insert(
+            "INCWAGE".to_string(),
+            vec![bin(UNBINNED_CODE, 0, 9999, "$0 to $9,999")],
+        );
+        let err = request
+            .validate_category_bins()
+            .expect_err("a bin with the unbinned code should be an error");
+        assert!(err.to_string().contains("can't use code 999"));
+    }
+
     #[test]
     fn test_category_bin_deserialize_high_less_than_low_error() {
         let json_str =
@@ -432,4 +1340,53 @@ mod tests {
             .expect("should deserialize into a GeneralDetailedSelection");
         assert_eq!(gen_det, GeneralDetailedSelection::Detailed);
     }
+
+    #[test]
+    fn test_deserialize_general_detailed_selection_d() {
+        let gen_det: GeneralDetailedSelection = serde_json::from_str("\"D\"")
+            .expect("should deserialize into a GeneralDetailedSelection");
+        assert_eq!(gen_det, GeneralDetailedSelection::Detailed);
+    }
+
+    #[test]
+    fn test_deserialize_general_detailed_selection_unknown_token_error() {
+        let result: Result<GeneralDetailedSelection, _> = serde_json::from_str("\"X\"");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_attached_variable_pointer() {
+        let json_str = r#"{"variable_mnemonic": "HHINCOME", "mnemonic": "HHINCOME", "general_detailed_selection": null, "attached_variable_pointer": {"record_type": "H"}, "case_selection": false, "request_case_selections": [], "extract_start": 0, "extract_width": 0}"#;
+        let rv: RequestVariable =
+            serde_json::from_str(json_str).expect("should parse into a RequestVariable");
+        assert_eq!(
+            rv.attached_variable_pointer,
+            Some(AttachedVariablePointer {
+                record_type: "H".to_string()
+            })
+        );
+
+        let json_str = json_str.replace(r#"{"record_type": "H"}"#, "null");
+        let rv: RequestVariable =
+            serde_json::from_str(&json_str).expect("should parse into a RequestVariable");
+        assert_eq!(rv.attached_variable_pointer, None);
+    }
+
+    #[test]
+    fn test_deserialize_count_columns() {
+        let count_columns: CountColumns =
+            serde_json::from_str("\"unweighted\"").expect("should deserialize into a CountColumns");
+        assert_eq!(count_columns, CountColumns::Unweighted);
+        assert!(serde_json::from_str::<CountColumns>("\"all\"").is_err());
+    }
+
+    /// Requests without count_columns get both counts, and don't serialize the default.
+    #[test]
+    fn test_count_columns_default_both() {
+        let request = AbacusRequest::template("usa", &["us2015b"], &["MARST"])
+            .expect("should make a template request");
+        assert_eq!(request.count_columns, CountColumns::Both);
+        let json = serde_json::to_string(&request).expect("should serialize the request");
+        assert!(!json.contains("count_columns"));
+    }
 }
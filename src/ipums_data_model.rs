@@ -90,6 +90,42 @@ impl RecordHierarchy {
         self.levels.insert(rectype.to_string(), member);
         Ok(())
     }
+
+    /// The record types above `rectype`, from its parent up to the root, like "P" then "H"
+    /// for activities in a Household - Person - Activity hierarchy.
+    pub fn ancestors(&self, rectype: &str) -> Vec<String> {
+        let mut ancestors: Vec<String> = Vec::new();
+        let mut current = rectype;
+        while let Some(parent) = self
+            .levels
+            .get(current)
+            .and_then(|level| level.parent.as_deref())
+        {
+            ancestors.push(parent.to_string());
+            current = parent;
+        }
+        ancestors
+    }
+
+    /// The record types from the root down, each level of the hierarchy before the next,
+    /// like "H" then "P". Record types on the same level are sorted.
+    pub fn ordered_rectypes(&self) -> Vec<String> {
+        let mut ordered = vec![self.root.clone()];
+        let mut next = 0;
+        while next < ordered.len() {
+            if let Some(children) = self
+                .levels
+                .get(&ordered[next])
+                .and_then(|level| level.children.as_ref())
+            {
+                let mut children: Vec<String> = children.iter().cloned().collect();
+                children.sort();
+                ordered.extend(children);
+            }
+            next += 1;
+        }
+        ordered
+    }
 }
 
 mod test {
@@ -114,6 +150,25 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_ancestors() {
+        let mut rh = RecordHierarchy::new("H");
+        rh.add_member("P", "H").unwrap();
+        rh.add_member("A", "P").unwrap();
+        assert_eq!(rh.ancestors("A"), vec!["P".to_string(), "H".to_string()]);
+        assert_eq!(rh.ancestors("P"), vec!["H".to_string()]);
+        assert!(rh.ancestors("H").is_empty());
+    }
+
+    #[test]
+    fn test_ordered_rectypes() {
+        let mut rh = RecordHierarchy::new("H");
+        rh.add_member("P", "H").unwrap();
+        rh.add_member("I", "H").unwrap();
+        rh.add_member("A", "P").unwrap();
+        assert_eq!(rh.ordered_rectypes(), vec!["H", "I", "P", "A"]);
+    }
+
     #[test]
     fn test_record_hierarchy_member_add_child_no_children_yet() {
         let mut member = RecordHierarchyMember {
@@ -50,6 +50,8 @@
 use crate::input_schema_tabulation::CategoryBin;
 use crate::layout::LayoutVar;
 use std::fmt;
+use std::mem::size_of;
+use std::sync::Arc;
 
 use compressed_string::ComprString;
 use interner::global::{GlobalPool, GlobalString};
@@ -59,33 +61,70 @@ static STRINGS: GlobalPool<String> = GlobalPool::new();
 pub type IpumsDatasetId = usize;
 #[derive(Clone, Debug)]
 pub struct IpumsDataset {
-    pub name: String,
+    pub name: Arc<str>,
     pub year: Option<usize>,
     pub month: Option<usize>,
     pub label: Option<String>,
     pub sampling_density: Option<f64>,
+    /// The number of records of the product's default unit of analysis, like persons for
+    /// USA, when the metadata source gives it. Parquet files do; layout files don't.
+    pub sample_size: Option<usize>,
     /// The 'id' fields in the models are generated when metadata structs get instantiated in order. They are
     /// used for indexing into the metadata storage.
     pub id: IpumsDatasetId, // auto-assigned in order loaded
 }
 
+impl IpumsDataset {
+    // Roughly the heap and inline memory used by the dataset. The name is interned by
+    // MetadataEntities, which counts it.
+    pub(crate) fn approx_memory_bytes(&self) -> usize {
+        size_of::<Self>() + self.label.as_ref().map_or(0, String::capacity)
+    }
+}
+
 impl From<(String, usize)> for IpumsDataset {
     fn from(value: (String, usize)) -> Self {
+        let year = year_from_dataset_name(&value.0);
         Self {
-            name: value.0,
+            name: Arc::from(value.0),
             id: value.1,
-            year: None,
+            year,
             month: None,
             label: None,
             sampling_density: None,
+            sample_size: None,
         }
     }
 }
 
+/// Parse the year out of a conventionally named dataset like "us2015b" or "mx2016h".
+///
+/// IPUMS dataset names start with a short alphabetic prefix followed by a four digit year.
+/// Returns None if the name doesn't follow that convention.
+///
+/// ```
+/// use cimdea::ipums_metadata_model::year_from_dataset_name;
+///
+/// assert_eq!(year_from_dataset_name("us2015b"), Some(2015));
+/// assert_eq!(year_from_dataset_name("cps"), None);
+/// ```
+pub fn year_from_dataset_name(name: &str) -> Option<usize> {
+    let digits = name.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    if digits.len() < 4 || !digits.is_char_boundary(4) {
+        return None;
+    }
+    let year = &digits[..4];
+    if year.chars().all(|c| c.is_ascii_digit()) {
+        year.parse().ok()
+    } else {
+        None
+    }
+}
+
 pub type IpumsVariableId = usize;
 #[derive(Clone, Debug)]
 pub struct IpumsVariable {
-    pub name: String,
+    pub name: Arc<str>,
     pub data_type: Option<IpumsDataType>,
     pub label: Option<String>,
     pub record_type: String, // a value like 'H', 'P'
@@ -97,11 +136,57 @@ pub struct IpumsVariable {
     pub id: IpumsVariableId, // auto-assigned in load order
 }
 
+impl IpumsVariable {
+    /// The integer codes which the variable's categories mark as missing, N/A or not in
+    /// universe, like INCWAGE's 999999. These codes aren't amounts of anything.
+    pub fn non_value_codes(&self) -> Vec<i64> {
+        self.categories
+            .iter()
+            .flatten()
+            .filter(|c| {
+                matches!(
+                    c.meaning,
+                    UniversalCategoryType::Missing
+                        | UniversalCategoryType::NotApplicable
+                        | UniversalCategoryType::NotInUniverse
+                )
+            })
+            .filter_map(|c| match c.value {
+                IpumsValue::Integer(code) => Some(code),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Roughly the heap and inline memory used by the variable. The description is
+    // compressed and only counted at its inline size, and the name is interned by
+    // MetadataEntities, which counts it.
+    pub(crate) fn approx_memory_bytes(&self) -> usize {
+        let categories: usize = self
+            .categories
+            .iter()
+            .flatten()
+            .map(IpumsCategory::approx_memory_bytes)
+            .sum();
+        let category_bins: usize = self
+            .category_bins
+            .iter()
+            .flatten()
+            .map(|bin| size_of::<CategoryBin>() + bin.label().len())
+            .sum();
+        size_of::<Self>()
+            + self.label.as_ref().map_or(0, String::capacity)
+            + self.record_type.capacity()
+            + categories
+            + category_bins
+    }
+}
+
 impl From<(&LayoutVar, usize)> for IpumsVariable {
     fn from(value: (&LayoutVar, usize)) -> Self {
         Self {
             id: value.1,
-            name: value.0.name.clone(),
+            name: Arc::from(value.0.name.as_str()),
             record_type: value.0.rectype.clone(),
             data_type: Some(value.0.data_type.clone()),
             label: None,
@@ -203,6 +288,17 @@ impl IpumsCategory {
         self.label_intern.as_ref()
     }
 
+    // Roughly the memory used by the category. Labels are interned, so this may count
+    // a label shared by several categories more than once.
+    pub(crate) fn approx_memory_bytes(&self) -> usize {
+        let value = match self.value {
+            IpumsValue::Float(ref v) => v.capacity(),
+            IpumsValue::String { ref value, .. } => value.capacity(),
+            IpumsValue::Integer(_) | IpumsValue::Fixed { .. } => 0,
+        };
+        size_of::<Self>() + self.label().len() + value
+    }
+
     pub fn new(label: &str, meaning: UniversalCategoryType, value: IpumsValue) -> Self {
         let symbol: GlobalString = STRINGS.get(label);
         Self {
@@ -296,6 +392,19 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_ipums_dataset_from_name_parses_year() {
+        let dataset = IpumsDataset::from(("us1940a".to_string(), 0));
+        assert_eq!(dataset.year, Some(1940));
+    }
+
+    #[test]
+    fn test_year_from_dataset_name_unconventional_names() {
+        assert_eq!(year_from_dataset_name("us"), None);
+        assert_eq!(year_from_dataset_name("us20a"), None);
+        assert_eq!(year_from_dataset_name("2015"), Some(2015));
+    }
+
     /// Layout variables have information on detailed widths, but not on general
     /// widths. So if we create an IpumsVariable from a LayoutVar, we won't have
     /// a general width.
@@ -238,6 +238,71 @@ impl DatasetLayout {
     }
 }
 
+/// The relation which [to_duckdb_fixed_width_select] reads records from.
+pub const FW_LINES_TABLE: &str = "fw_lines";
+/// The single text column of [FW_LINES_TABLE], holding one whole fixed-width record.
+pub const FW_LINE_COLUMN: &str = "line";
+
+/// Make a DuckDB query which slices the variables of one record type out of fixed-width
+/// records, using each variable's start and width from the layout.
+///
+/// The query reads whole records from a relation named [FW_LINES_TABLE] with a single
+/// [FW_LINE_COLUMN] column, which the caller sets up. For example, a compressed data file
+/// can be read one record per line with
+/// `read_csv('us2015b_usa.dat.gz', header = false, quote = '', delim = '\x01', columns = {'line': 'VARCHAR'})`.
+/// Only records of `rectype` are selected, going by the RECTYPE variable when the layout has
+/// one. Integer and fixed-point values are converted to integers, and blank ones are NULL.
+///
+/// ```
+/// use std::path::Path;
+/// use cimdea::layout::{to_duckdb_fixed_width_select, DatasetLayout};
+///
+/// let layout = DatasetLayout::try_from_layout_file(Path::new(
+///     "tests/data_root/layouts/us2015b.layout.txt",
+/// ))
+/// .unwrap();
+/// let sql = to_duckdb_fixed_width_select(&layout, "P").unwrap();
+/// assert!(sql.contains("try_cast(trim(substr(line, 58, 3)) as bigint) as AGE"));
+/// ```
+pub fn to_duckdb_fixed_width_select(
+    layout: &DatasetLayout,
+    rectype: &str,
+) -> Result<String, MdError> {
+    let Some(record_layout) = layout.for_rectype(rectype) else {
+        return Err(MdError::Msg(format!(
+            "no record type '{rectype}' in the layout"
+        )));
+    };
+
+    let columns = record_layout
+        .sorted_vars_by_start()
+        .iter()
+        .map(|v| {
+            let slice = format!("substr({FW_LINE_COLUMN}, {}, {})", v.start, v.width);
+            match v.data_type {
+                IpumsDataType::Integer | IpumsDataType::Fixed(_) => {
+                    format!("try_cast(trim({slice}) as bigint) as {}", v.name)
+                }
+                IpumsDataType::Float => format!("try_cast(trim({slice}) as double) as {}", v.name),
+                IpumsDataType::String => format!("{slice} as {}", v.name),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",\n");
+
+    let mut sql = format!("select\n{columns}\nfrom {FW_LINES_TABLE}");
+    // Hierarchical files list RECTYPE once, though every record has it.
+    if let Some(rt_var) = layout.all_variables().iter().find(|v| v.name == "RECTYPE") {
+        sql += &format!(
+            "\nwhere substr({FW_LINE_COLUMN}, {}, {}) = '{}'",
+            rt_var.start,
+            rt_var.width,
+            rectype.replace('\'', "''")
+        );
+    }
+    Ok(sql)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -253,6 +318,22 @@ mod tests {
             .from_reader(cursor)
     }
 
+    #[test]
+    fn test_to_duckdb_fixed_width_select_age_offset() {
+        let layout_file = Path::new("tests/data_root/layouts/us2015b.layout.txt");
+        let layout = DatasetLayout::try_from_layout_file(layout_file)
+            .expect("should be able to create DatasetLayout from file");
+
+        let sql = to_duckdb_fixed_width_select(&layout, "P")
+            .expect("should generate a select for person records");
+        let lines: Vec<&str> = sql.lines().collect();
+        assert!(lines.contains(&"try_cast(trim(substr(line, 58, 3)) as bigint) as AGE,"));
+        assert!(!sql.contains(" as STATEFIP"));
+        assert!(sql.ends_with("from fw_lines\nwhere substr(line, 1, 1) = 'P'"));
+
+        assert!(to_duckdb_fixed_width_select(&layout, "Z").is_err());
+    }
+
     #[test]
     fn test_dataset_layout_try_from_layout_file() {
         let layout_file = Path::new("tests/data_root/layouts/us1850a.layout.txt");
@@ -40,8 +40,10 @@
 //! variables, subpopulations, or category bins, please see
 //! [AbacusRequest](request::AbacusRequest), which also implements `DataRequest`.
 
+pub mod codebook;
 pub mod conventions;
 pub mod defaults;
+pub mod extract;
 pub mod fixed_width;
 pub mod input_schema_tabulation;
 pub mod ipums_data_model;
@@ -27,6 +27,9 @@ pub enum MdError {
     ParsingError(String),
     /// An error from the DuckDB data platform. This likely indicates a bug in cimdea.
     DuckDBError(duckdb::Error),
+    /// An error from the DataFusion data platform, with the "datafusion" feature.
+    #[cfg(feature = "datafusion")]
+    DataFusionError(datafusion::error::DataFusionError),
     /// A generic cimdea error.
     Msg(String),
 }
@@ -41,6 +44,8 @@ impl fmt::Display for MdError {
             InvalidSQLSyntax(msg) => write!(f, "SQL syntax error: {msg}"),
             ParsingError(msg) => write!(f, "parsing error: {msg}"),
             DuckDBError(err) => write!(f, "DuckDB error: {err}"),
+            #[cfg(feature = "datafusion")]
+            DataFusionError(err) => write!(f, "DataFusion error: {err}"),
             Msg(msg) => write!(f, "{msg}"),
         }
     }
@@ -60,6 +65,13 @@ impl From<duckdb::Error> for MdError {
     }
 }
 
+#[cfg(feature = "datafusion")]
+impl From<datafusion::error::DataFusionError> for MdError {
+    fn from(err: datafusion::error::DataFusionError) -> Self {
+        MdError::DataFusionError(err)
+    }
+}
+
 /// A small convenience macro, based on the format! macro in the standard library.
 ///
 /// Instead of directly constructing an `MdError::ParsingError` on a formatted
@@ -13,7 +13,9 @@
 
 use crate::conventions::Context;
 
-use crate::input_schema_tabulation::{CategoryBin, RequestCaseSelection};
+use crate::input_schema_tabulation::{
+    CategoryBin, CountColumns, RequestCaseSelection, UNBINNED_CODE,
+};
 use crate::ipums_metadata_model::{self, IpumsDataType, IpumsVariable};
 use crate::mderror::{metadata_error, MdError};
 use crate::request::CaseSelectLogic;
@@ -22,7 +24,7 @@ use crate::request::InputType;
 use crate::request::RequestVariable;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// The TabBuilder is meant to assist with one or more tabulations from the same data product.
 #[allow(dead_code)]
@@ -78,39 +80,83 @@ impl TabBuilder {
 
         let mut q = format!("{} as {}", left_platform_specific_path, left_alias);
 
-        // TODO: Handle the remaining tables. Currently the connections between the joined tables are only
-        // generated to connect any two tables where we have foreign and primary keys. Three or more
-        // correct joins aren't yet supported.
-        if self.data_sources.len() > 2 {
-            return Err(MdError::Msg(
-                "Tabulations across more than two record types not yet supported!".to_string(),
-            ));
-        }
-        for (rt, ds) in &self.data_sources {
-            if rt != uoa && all_rectypes.contains(rt) {
-                // The uoa should be the lowest record in the hierarchy of record types from requested variables by definition. The 'foreign_key' will point to the record
-                // type directly above in the hierarchy. Note this breaks down for sibling records. Variables from sibling records
-                // should not be allowed in the same tabulation.
-                let left_foreign_key = Self::help_get_connecting_foreign_key(ctx, uoa, rt)?;
-
-                let platform_specific_path = ds.for_platform(&self.platform);
-                let table_alias = ds.table_name();
-                let table_id = Self::help_get_id_for_record_type(ctx, rt)?;
-                q = q + &format!(
-                    "\n left join  {} {} on {}.{} = {}.{}",
-                    platform_specific_path,
-                    table_alias,
-                    left_alias,
-                    left_foreign_key,
-                    table_alias,
-                    table_id
-                );
-            }
+        // Each join connects a record type to its parent, starting from the unit of analysis,
+        // so record types more than one level above it join through the ones in between.
+        for step in join_plan(ctx, uoa, all_rectypes)? {
+            let (Some(from_ds), Some(to_ds)) = (
+                self.data_sources.get(&step.from_rectype),
+                self.data_sources.get(&step.to_rectype),
+            ) else {
+                return Err(MdError::Msg(format!(
+                    "no data sources to join record type '{}' to '{}'",
+                    step.from_rectype, step.to_rectype
+                )));
+            };
+
+            let platform_specific_path = to_ds.for_platform(&self.platform);
+            let table_alias = to_ds.table_name();
+            q = q + &format!(
+                "\n left join  {} {} on {}.{} = {}.{}",
+                platform_specific_path,
+                table_alias,
+                from_ds.table_name(),
+                step.foreign_key,
+                table_alias,
+                step.to_key
+            );
         }
         Ok(q)
     }
 
+    // The column holding a request variable's values. An attached variable's column is
+    // qualified with the table of its record type, since the unit of analysis may have a
+    // column of the same name.
+    fn help_column(&self, rq: &RequestVariable) -> Result<String, MdError> {
+        let Some(ref pointer) = rq.attached_variable_pointer else {
+            return Ok(rq.variable.name.to_string());
+        };
+        let Some(source) = self.data_sources.get(&pointer.record_type) else {
+            return Err(MdError::Msg(format!(
+                "no data source for record type '{}' to attach {} from",
+                pointer.record_type, rq.name
+            )));
+        };
+        Ok(format!("{}.{}", source.table_name(), rq.variable.name))
+    }
+
+    // A request variable's values for tabulating and binning. Adjusted variables are
+    // multiplied by the adjustment factor for the dataset, except for the codes which their
+    // categories mark as missing, N/A or not in universe, like INCWAGE's 999999. Those
+    // aren't amounts, so they keep their values.
+    fn help_value(&self, rq: &RequestVariable) -> Result<String, MdError> {
+        let column = self.help_column(rq)?;
+        let Some(ref adjustment) = rq.adjustment else {
+            return Ok(column);
+        };
+        let Some(factor) = adjustment.factor_for(&self.dataset) else {
+            return Err(MdError::Msg(format!(
+                "No adjustment factor for {} in dataset {}",
+                rq.name, self.dataset
+            )));
+        };
+        let unadjusted_codes: Vec<String> = rq
+            .variable
+            .non_value_codes()
+            .iter()
+            .map(|code| code.to_string())
+            .collect();
+        if unadjusted_codes.is_empty() {
+            Ok(format!("({column} * {factor})"))
+        } else {
+            Ok(format!(
+                "(case when {column} in ({}) then {column} else {column} * {factor} end)",
+                unadjusted_codes.join(", ")
+            ))
+        }
+    }
+
     fn help_bucket(&self, rq: &RequestVariable) -> Result<String, MdError> {
+        let column = self.help_value(rq)?;
         let Some(ref bins) = rq.category_bins else {
             return Err(MdError::Msg("No category bins available.".to_string()));
         };
@@ -121,23 +167,49 @@ impl TabBuilder {
         let cases = bins
             .iter()
             .map(|b| match b {
-                CategoryBin::LessThan { value, code, .. } => {
-                    format!("\twhen {} <= {} then '{:03}'", rq.name, value, code)
+                CategoryBin::LessThan {
+                    value,
+                    code,
+                    inclusive,
+                    ..
+                } => {
+                    let op = if *inclusive { "<=" } else { "<" };
+                    format!("\twhen {} {} {} then '{:03}'", column, op, value, code)
                 }
-                CategoryBin::MoreThan { value, code, .. } => {
-                    format!("\twhen {} >= {} then '{:03}'", rq.name, value, code)
+                CategoryBin::MoreThan {
+                    value,
+                    code,
+                    inclusive,
+                    ..
+                } => {
+                    let op = if *inclusive { ">=" } else { ">" };
+                    format!("\twhen {} {} {} then '{:03}'", column, op, value, code)
                 }
                 CategoryBin::Range {
-                    low, high, code, ..
-                } => format!(
-                    "\twhen {} >= {} and {} <= {} then '{:03}'",
-                    rq.name, low, rq.name, high, code
-                ),
+                    low,
+                    high,
+                    code,
+                    low_inclusive,
+                    high_inclusive,
+                    ..
+                } => {
+                    let low_op = if *low_inclusive { ">=" } else { ">" };
+                    let high_op = if *high_inclusive { "<=" } else { "<" };
+                    format!(
+                        "\twhen {} {} {} and {} {} {} then '{:03}'",
+                        column, low_op, low, column, high_op, high, code
+                    )
+                }
             })
             .collect::<Vec<String>>()
             .join("\n");
         sql.push_str(&cases);
-        sql.push_str("\nelse '999' end ");
+        // Without a residual bin, values outside every bin are NULL.
+        if rq.residual_label().is_some() {
+            sql.push_str(&format!("\nelse '{UNBINNED_CODE:03}' end "));
+        } else {
+            sql.push_str("\nend ");
+        }
         sql.push_str(&format!("as {}_bucketed", &rq.name));
         Ok(sql)
     }
@@ -145,43 +217,69 @@ impl TabBuilder {
     fn build_select_clause(
         &self,
         request_variables: &[RequestVariable],
+        count_columns: CountColumns,
         weight_name: Option<String>,
         weight_divisor: Option<usize>,
     ) -> Result<String, MdError> {
-        let mut select_clause = "count(*) as ct".to_string();
-
-        if let Some(ref wt) = weight_name {
-            select_clause += &format!(
-                ", sum({}/{}) as weighted_ct",
-                wt,
-                weight_divisor.unwrap_or(1)
-            );
+        let mut counts = Vec::new();
+        if count_columns.unweighted() {
+            counts.push("count(*) as ct".to_string());
+        }
+        if count_columns.weighted() {
+            match weight_name {
+                Some(ref wt) => counts.push(format!(
+                    "sum({}/{}) as weighted_ct",
+                    wt,
+                    weight_divisor.unwrap_or(1)
+                )),
+                None => {
+                    return Err(MdError::Msg(format!(
+                        "Can't compute weighted counts for {}: the record type has no weight variable.",
+                        self.dataset
+                    )));
+                }
+            }
         }
+        let mut select_clause = counts.join(", ");
 
         for rq in request_variables {
-            // A request variable can be 'general' or 'bucketed' but not both.
-            if rq.is_general() && rq.is_bucketed() {
-                let msg = format!(
-                    "The variable {} can't be both a general variable and use category bins.",
-                    &rq.name
-                );
-                return Err(MdError::Msg(msg));
-            }
-            select_clause += &if rq.is_general() {
-                format!(
-                    ", {}//{} as {}",
-                    &rq.variable.name, &rq.general_divisor, &rq.name
-                )
-            } else if rq.is_bucketed() {
-                format!(", {} ", &self.help_bucket(&rq)?)
-            } else {
-                format!(", {} as {}", &rq.variable.name, &rq.name)
-            };
+            select_clause += &self.help_select_variable(rq)?;
         }
 
         Ok(select_clause)
     }
 
+    fn help_select_variable(&self, rq: &RequestVariable) -> Result<String, MdError> {
+        // A request variable can be 'general' or 'bucketed' but not both.
+        if rq.is_general() && rq.is_bucketed() {
+            let msg = format!(
+                "The variable {} can't be both a general variable and use category bins.",
+                &rq.name
+            );
+            return Err(MdError::Msg(msg));
+        }
+        if rq.is_general() && rq.adjustment.is_some() {
+            let msg = format!(
+                "The variable {} can't be both a general variable and adjusted.",
+                &rq.name
+            );
+            return Err(MdError::Msg(msg));
+        }
+        Ok(if rq.is_general() {
+            format!(
+                ", {}{}{} as {}",
+                self.help_column(rq)?,
+                self.platform.integer_division_operator(),
+                &rq.general_divisor,
+                &rq.name
+            )
+        } else if rq.is_bucketed() {
+            format!(", {} ", &self.help_bucket(rq)?)
+        } else {
+            format!(", {} as {}", self.help_value(rq)?, &rq.name)
+        })
+    }
+
     fn should_use_sample_line_weights(&self, ctx: &Context) -> bool {
         matches!(ctx.settings.name.to_lowercase().as_ref(), "usa")
             && matches!(
@@ -253,6 +351,56 @@ impl TabBuilder {
         }
     }
 
+    // The record type which weights the counts of `uoa` records, and the name and divisor
+    // of its weight. This is the request's weight variable when it names one, or else the
+    // weight of the request's weight record type, which defaults to `uoa`.
+    fn help_get_request_weight(
+        &self,
+        ctx: &Context,
+        abacus_request: &impl DataRequest,
+        uoa: &str,
+    ) -> Result<(String, Option<String>, Option<usize>), MdError> {
+        if let Some(weight_variable) = abacus_request.weight_variable() {
+            let (weight_name, weight_divisor) =
+                self.help_get_weight_variable(ctx, &weight_variable)?;
+            return Ok((weight_variable.record_type, weight_name, weight_divisor));
+        }
+        let weight_rectype = abacus_request.weight_rectype().unwrap_or(uoa.to_string());
+        let (weight_name, weight_divisor) = self.help_get_weight(ctx, &weight_rectype);
+        Ok((weight_rectype, weight_name, weight_divisor))
+    }
+
+    // The name and divisor of a weight variable which replaces the default weight of its
+    // record type. It keeps the default weight's divisor, so that weighted counts stay on
+    // the same scale.
+    fn help_get_weight_variable(
+        &self,
+        ctx: &Context,
+        weight_variable: &IpumsVariable,
+    ) -> Result<(Option<String>, Option<usize>), MdError> {
+        // Datasets which aren't in the loaded metadata can't be checked.
+        if let Some(ref md) = ctx.settings.metadata {
+            if let Some(&dataset_id) = md.datasets_by_name.get(self.dataset.as_str()) {
+                let available = md.available_variables.for_dataset(dataset_id);
+                let is_available = md
+                    .variables_by_name
+                    .get(&weight_variable.name)
+                    .is_some_and(|id| available.is_some_and(|vars| vars.contains(id)));
+                if !is_available {
+                    return Err(metadata_error!(
+                        "Weight variable {} is not available in dataset {}",
+                        weight_variable.name,
+                        self.dataset
+                    ));
+                }
+            }
+        }
+        Ok((
+            Some(weight_variable.name.to_string()),
+            ctx.settings.weight_divisor(&weight_variable.record_type),
+        ))
+    }
+
     fn help_final_var_aliases(&self, request_variables: &[RequestVariable]) -> Vec<String> {
         request_variables
             .iter()
@@ -291,13 +439,21 @@ impl TabBuilder {
         HashSet::from_iter(all_rectypes.iter().cloned())
     }
 
+    /// Make a query which tabulates the records of the unit of analysis `uoa`. Variables and
+    /// conditions may come from `uoa` or the record types above it.
+    ///
+    /// The query counts the records with each combination of values of the request variables,
+    /// grouping by all of them. Its columns are the request's count columns, "ct" and
+    /// "weighted_ct" by default, followed by the request variables in order. The rows are sorted in ascending order by each request variable
+    /// from left to right, with nulls last. See [order_by_clause].
     pub fn make_query(
         &self,
         ctx: &Context,
         abacus_request: &impl DataRequest,
+        uoa: &str,
     ) -> Result<String, MdError> {
         let request_variables = abacus_request.get_request_variables();
-        let requested_conditions = abacus_request.get_conditions();
+        let requested_conditions = Some(abacus_request.all_conditions()).filter(|c| !c.is_empty());
         let case_select_logic = abacus_request.case_select_logic();
 
         if request_variables.len() == 0 {
@@ -313,37 +469,43 @@ impl TabBuilder {
             requested_conditions
         };
 
-        let rectypes = TabBuilder::help_get_required_rectypes(
+        let mut rectypes = TabBuilder::help_get_required_rectypes(
             &request_variables,
             &conditions.clone().unwrap_or(Vec::new()),
         );
 
-        // TODO: Decide the unit of analysis based on variable selection? Or, use the
-        // UOA in the incoming Request JSON
-        let uoa = ctx.settings.default_unit_of_analysis.value.clone();
+        let uoa = uoa.to_string();
+
+        // Weighting by another record type's weight joins that record type.
+        let (weight_rectype, weight_name, weight_divisor) =
+            self.help_get_request_weight(ctx, abacus_request, &uoa)?;
+        rectypes.insert(weight_rectype);
 
         if !self.data_sources.contains_key(&uoa) {
             let msg = format!("Can't use unit of analysis '{}' to generate 'from' clause, not in set of record types in '{}'", uoa, ctx.settings.name);
             return Err(MdError::Msg(msg));
         }
 
-        let (weight_name, weight_divisor) = self.help_get_weight(ctx, &uoa);
-
-        let select_clause =
-            self.build_select_clause(&request_variables, weight_name, weight_divisor);
+        let count_columns = tabulated_count_columns(ctx, abacus_request, None)?;
+        let select_clause = self.build_select_clause(
+            &request_variables,
+            count_columns,
+            weight_name,
+            weight_divisor,
+        );
         let from_clause = &self.build_from_clause(ctx, &self.dataset, &uoa, &rectypes)?;
 
         let vars_in_order = self.help_final_var_aliases(&request_variables);
 
-        /// The first column in the query that is a request variable. Column 1
-        /// is ct and column 2 is weighted_ct.
-        const FIRST_RQV_COLUMN: usize = 3;
+        // The first column in the query that is a request variable, after the count
+        // columns. Columns are numbered from 1.
+        let first_rqv_column = count_columns.column_names().len() + 1;
         let group_by_columns: Vec<_> = (0..vars_in_order.len())
-            .map(|index| index + FIRST_RQV_COLUMN)
+            .map(|index| index + first_rqv_column)
             .map(|x| x.to_string())
             .collect();
         let group_by_clause = group_by_columns.join(", ");
-        let order_by_clause = vars_in_order.join(", ");
+        let order_by_clause = order_by_clause(&vars_in_order);
 
         if let Some(ref conds) = conditions {
             let where_clause = &self.build_where_clause(&conds, case_select_logic)?;
@@ -359,6 +521,327 @@ impl TabBuilder {
         }
     }
 
+    /// Make a query which counts the records of the unit of analysis `uoa` that meet the
+    /// request's conditions, without grouping them. The result is a single row with the
+    /// columns "ct" and "weighted_ct", which together are the totals of the columns of the
+    /// same names in the query from [TabBuilder::make_query]. Without a weight,
+    /// "weighted_ct" is the unweighted count.
+    pub fn make_count_query(
+        &self,
+        ctx: &Context,
+        abacus_request: &impl DataRequest,
+        uoa: &str,
+    ) -> Result<String, MdError> {
+        let request_variables = abacus_request.get_request_variables();
+        let requested_conditions = abacus_request.all_conditions();
+        let conditions = if self.should_use_selfwtsl(ctx) {
+            self.help_conditions_with_selfwtsl_filter(ctx, Some(requested_conditions))?
+        } else {
+            requested_conditions
+        };
+
+        // Join the same record types as the tabulation so that the counts match it.
+        let mut rectypes = TabBuilder::help_get_required_rectypes(&request_variables, &conditions);
+        let (weight_rectype, weight_name, weight_divisor) =
+            self.help_get_request_weight(ctx, abacus_request, uoa)?;
+        rectypes.insert(weight_rectype);
+
+        if !self.data_sources.contains_key(uoa) {
+            let msg = format!("Can't use unit of analysis '{}' to generate 'from' clause, not in set of record types in '{}'", uoa, ctx.settings.name);
+            return Err(MdError::Msg(msg));
+        }
+
+        let select_clause = match (weight_name, weight_divisor) {
+            (Some(weight_name), weight_divisor) => format!(
+                "count(*) as ct, sum({}/{}) as weighted_ct",
+                weight_name,
+                weight_divisor.unwrap_or(1)
+            ),
+            (None, _) => "count(*) as ct, count(*) as weighted_ct".to_string(),
+        };
+        let from_clause = self.build_from_clause(ctx, &self.dataset, uoa, &rectypes)?;
+
+        let mut q = format!("select \n{select_clause}\nfrom {from_clause}");
+        if !conditions.is_empty() {
+            q += &format!(
+                "\nwhere {}",
+                self.build_where_clause(&conditions, abacus_request.case_select_logic())?
+            );
+        }
+        Ok(q)
+    }
+
+    /// Make a query which selects the request variables from every record of the request's
+    /// unit of analysis that meets the request's conditions, one row per record. The columns
+    /// are the request variables in order.
+    pub fn make_extract_query(
+        &self,
+        ctx: &Context,
+        abacus_request: &impl DataRequest,
+    ) -> Result<String, MdError> {
+        let request_variables = abacus_request.get_request_variables();
+        if request_variables.is_empty() {
+            return Err(MdError::Msg(
+                "Must supply at least one request variable.".to_string(),
+            ));
+        }
+
+        let conditions = abacus_request.all_conditions();
+        let rectypes = TabBuilder::help_get_required_rectypes(&request_variables, &conditions);
+        let uoa = abacus_request.unit_of_analysis();
+        if !self.data_sources.contains_key(&uoa) {
+            let msg = format!("Can't use unit of analysis '{}' to generate 'from' clause, not in set of record types in '{}'", uoa, ctx.settings.name);
+            return Err(MdError::Msg(msg));
+        }
+
+        let mut select_clause = String::new();
+        for rq in &request_variables {
+            select_clause += &self.help_select_variable(rq)?;
+        }
+        let select_clause = select_clause.trim_start_matches(", ");
+        let from_clause = self.build_from_clause(ctx, &self.dataset, &uoa, &rectypes)?;
+
+        let mut q = format!("select \n{select_clause}\nfrom {from_clause}");
+        if !conditions.is_empty() {
+            q += &format!(
+                "\nwhere {}",
+                self.build_where_clause(&conditions, abacus_request.case_select_logic())?
+            );
+        }
+        Ok(q)
+    }
+
+    /// Make a query which selects the records of the unit of analysis that meet the request's
+    /// conditions, like [TabBuilder::make_extract_query], interleaved with the records of
+    /// their parent record type. Each parent record comes right before its members.
+    ///
+    /// The first column is "RECTYPE" with the record type of each row, followed by the request
+    /// variables. Each row only has values for the variables on its own record type; the
+    /// others are null. Two more columns at the end, "hier_key" and "record_order", order the
+    /// records. When there are conditions, only parent records with at least one selected
+    /// member are included.
+    pub fn make_hierarchical_extract_query(
+        &self,
+        ctx: &Context,
+        abacus_request: &impl DataRequest,
+    ) -> Result<String, MdError> {
+        let request_variables = abacus_request.get_request_variables();
+        if request_variables.is_empty() {
+            return Err(MdError::Msg(
+                "Must supply at least one request variable.".to_string(),
+            ));
+        }
+
+        let uoa = abacus_request.unit_of_analysis();
+        let parent_rectype = ctx
+            .settings
+            .record_hierarchy
+            .levels
+            .get(&uoa)
+            .and_then(|level| level.parent.clone())
+            .ok_or_else(|| {
+                metadata_error!(
+                    "Unit of analysis '{uoa}' has no parent record type for a hierarchical extract."
+                )
+            })?;
+        if let Some(v) = request_variables
+            .iter()
+            .find(|v| v.variable.record_type != uoa && v.variable.record_type != parent_rectype)
+        {
+            return Err(MdError::Msg(format!(
+                "Variable {} is not on record type '{uoa}' or '{parent_rectype}', so it can't be in a hierarchical extract.",
+                v.name
+            )));
+        }
+
+        let (Some(parent_source), Some(member_source)) = (
+            self.data_sources.get(&parent_rectype),
+            self.data_sources.get(&uoa),
+        ) else {
+            return Err(MdError::Msg(format!(
+                "No data sources for record types '{parent_rectype}' and '{uoa}' in dataset {}",
+                self.dataset
+            )));
+        };
+        let member_key = Self::help_get_connecting_foreign_key(ctx, &uoa, &parent_rectype)?;
+        let parent_id = Self::help_get_id_for_record_type(ctx, &parent_rectype)?;
+
+        // Each side selects its own variables and nulls in place of the other side's.
+        let mut parent_select = format!("'{parent_rectype}' as RECTYPE");
+        let mut member_select = format!("'{uoa}' as RECTYPE");
+        let aliases = self.help_final_var_aliases(&request_variables);
+        for (rq, alias) in request_variables.iter().zip(&aliases) {
+            let column = self.help_select_variable(rq)?;
+            if rq.variable.record_type == parent_rectype {
+                parent_select += &column;
+                member_select += &format!(", null as {alias}");
+            } else {
+                parent_select += &format!(", null as {alias}");
+                member_select += &column;
+            }
+        }
+
+        let conditions = abacus_request.all_conditions();
+        let rectypes = TabBuilder::help_get_required_rectypes(&[], &conditions);
+        let member_alias = member_source.table_name();
+        let member_from = self.build_from_clause(ctx, &self.dataset, &uoa, &rectypes)?;
+        let mut member_where = String::new();
+        if !conditions.is_empty() {
+            member_where = format!(
+                "\nwhere {}",
+                self.build_where_clause(&conditions, abacus_request.case_select_logic())?
+            );
+        }
+
+        let parent_alias = parent_source.table_name();
+        let mut q = format!(
+            "select {parent_select}, {parent_alias}.{parent_id} as hier_key, 0 as record_order\nfrom {} as {parent_alias}",
+            parent_source.for_platform(&self.platform)
+        );
+        if !conditions.is_empty() {
+            q += &format!(
+                "\nwhere {parent_alias}.{parent_id} in (\nselect {member_alias}.{member_key}\nfrom {member_from}{member_where})"
+            );
+        }
+        q += &format!(
+            "\nunion all\nselect {member_select}, {member_alias}.{member_key} as hier_key, 1 as record_order\nfrom {member_from}{member_where}"
+        );
+        q += "\norder by hier_key, record_order";
+        Ok(q)
+    }
+
+    /// Make a two-stage query which counts the `member_rectype` records belonging to each
+    /// record of its parent record type, then tabulates the parent records by that count
+    /// and the request variables. For example with "P" this is the distribution of
+    /// household sizes. The request variables must all come from the parent record type.
+    pub fn make_member_count_query(
+        &self,
+        ctx: &Context,
+        abacus_request: &impl DataRequest,
+        member_rectype: &str,
+    ) -> Result<String, MdError> {
+        let parent_rectype = ctx
+            .settings
+            .record_hierarchy
+            .levels
+            .get(member_rectype)
+            .and_then(|level| level.parent.clone())
+            .ok_or_else(|| {
+                metadata_error!(
+                    "Record type '{member_rectype}' has no parent record type to count it within."
+                )
+            })?;
+
+        let request_variables = abacus_request.get_request_variables();
+        if let Some(v) = request_variables
+            .iter()
+            .find(|v| v.variable.record_type != parent_rectype)
+        {
+            return Err(MdError::Msg(format!(
+                "Variable {} is not on record type '{}', so it can't be tabulated with counts of '{}' records.",
+                v.name, parent_rectype, member_rectype
+            )));
+        }
+
+        let (parent_conditions, member_conditions): (Vec<Condition>, Vec<Condition>) =
+            abacus_request
+                .all_conditions()
+                .into_iter()
+                .partition(|c| c.var.record_type == parent_rectype);
+        if matches!(abacus_request.case_select_logic(), CaseSelectLogic::Or)
+            && !parent_conditions.is_empty()
+            && !member_conditions.is_empty()
+        {
+            return Err(MdError::Msg(
+                "Can't combine conditions on different record types with 'or' when counting members."
+                    .to_string(),
+            ));
+        }
+
+        let (Some(parent_source), Some(member_source)) = (
+            self.data_sources.get(&parent_rectype),
+            self.data_sources.get(member_rectype),
+        ) else {
+            return Err(MdError::Msg(format!(
+                "No data sources for record types '{parent_rectype}' and '{member_rectype}' in dataset {}",
+                self.dataset
+            )));
+        };
+
+        let member_key =
+            Self::help_get_connecting_foreign_key(ctx, member_rectype, &parent_rectype)?;
+        let parent_id = Self::help_get_id_for_record_type(ctx, &parent_rectype)?;
+        let count_column = member_count_column(ctx, member_rectype)?;
+
+        let mut member_counts = format!(
+            "select {member_key}, count(*) as {count_column}\nfrom {} as {}",
+            member_source.for_platform(&self.platform),
+            member_source.table_name()
+        );
+        if !member_conditions.is_empty() {
+            member_counts += &format!(
+                "\nwhere {}",
+                self.build_where_clause(&member_conditions, abacus_request.case_select_logic())?
+            );
+        }
+        member_counts += &format!("\ngroup by {member_key}");
+
+        // The parent records are counted, so only their weight can weight the counts.
+        let (weight_name, weight_divisor) = match abacus_request.weight_variable() {
+            Some(weight_variable) if weight_variable.record_type != parent_rectype => {
+                return Err(metadata_error!(
+                    "Can't weight counts of record type '{parent_rectype}' with weight variable {}, which is on record type '{}'",
+                    weight_variable.name,
+                    weight_variable.record_type
+                ));
+            }
+            Some(weight_variable) => self.help_get_weight_variable(ctx, &weight_variable)?,
+            None => self.help_get_weight(ctx, &parent_rectype),
+        };
+        let count_columns = tabulated_count_columns(ctx, abacus_request, Some(member_rectype))?;
+        let mut select_clause =
+            self.build_select_clause(&[], count_columns, weight_name, weight_divisor)?;
+        select_clause += &format!(", coalesce(member_counts.{count_column}, 0) as {count_column}");
+        for rq in &request_variables {
+            select_clause += &self.help_select_variable(rq)?;
+        }
+
+        let parent_alias = parent_source.table_name();
+        let from_clause = format!(
+            "{} as {}\n left join member_counts on {}.{} = member_counts.{}",
+            parent_source.for_platform(&self.platform),
+            parent_alias,
+            parent_alias,
+            parent_id,
+            member_key
+        );
+
+        let vars_in_order = [
+            vec![count_column],
+            self.help_final_var_aliases(&request_variables),
+        ]
+        .concat();
+        // The count columns come first, and columns are numbered from 1.
+        let first_group_column = count_columns.column_names().len() + 1;
+        let group_by_clause = (0..vars_in_order.len())
+            .map(|index| (index + first_group_column).to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let order_by_clause = order_by_clause(&vars_in_order);
+
+        let mut q = format!(
+            "with member_counts as (\n{member_counts}\n)\nselect \n{select_clause}\nfrom {from_clause}"
+        );
+        if !parent_conditions.is_empty() {
+            q += &format!(
+                "\nwhere {}",
+                self.build_where_clause(&parent_conditions, abacus_request.case_select_logic())?
+            );
+        }
+        q += &format!("\ngroup by {group_by_clause}\norder by {order_by_clause}");
+        Ok(q)
+    }
+
     fn help_get_connecting_foreign_key(
         ctx: &Context,
         from_rt: &str,
@@ -394,6 +877,70 @@ impl TabBuilder {
     }
 }
 
+/// One join in a [join_plan]. Records of `from_rectype` join their parent records of
+/// `to_rectype` where the `foreign_key` of the first equals the `to_key` of the second, like
+/// person records joining households on SERIALP = SERIAL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JoinStep {
+    pub from_rectype: String,
+    pub foreign_key: String,
+    pub to_rectype: String,
+    pub to_key: String,
+}
+
+/// The joins which connect records of the unit of analysis `uoa` to the record types in
+/// `rectypes`, in the order queries make them.
+///
+/// Each step joins a record type to its parent in the record hierarchy, starting from `uoa`
+/// and going up as far as the highest record type in `rectypes`. So a tabulation of activity
+/// records with household variables joins activities to persons and then persons to
+/// households. Every record type in `rectypes` must be `uoa` or above it in the hierarchy.
+pub fn join_plan(
+    ctx: &Context,
+    uoa: &str,
+    rectypes: &HashSet<String>,
+) -> Result<Vec<JoinStep>, MdError> {
+    // The unit of analysis followed by each of its ancestors.
+    let mut lineage = vec![uoa.to_string()];
+    while let Some(parent) = ctx
+        .settings
+        .record_hierarchy
+        .levels
+        .get(
+            lineage
+                .last()
+                .expect("the lineage starts with the unit of analysis"),
+        )
+        .and_then(|level| level.parent.clone())
+    {
+        lineage.push(parent);
+    }
+
+    let mut highest = 0;
+    let mut sorted_rectypes: Vec<&String> = rectypes.iter().collect();
+    sorted_rectypes.sort();
+    for rt in sorted_rectypes {
+        let Some(level) = lineage.iter().position(|ancestor| ancestor == rt) else {
+            return Err(metadata_error!(
+                "Record type '{rt}' is not above the unit of analysis '{uoa}' in the record hierarchy, so it can't be joined."
+            ));
+        };
+        highest = highest.max(level);
+    }
+
+    lineage[..=highest]
+        .windows(2)
+        .map(|pair| {
+            Ok(JoinStep {
+                from_rectype: pair[0].clone(),
+                foreign_key: TabBuilder::help_get_connecting_foreign_key(ctx, &pair[0], &pair[1])?,
+                to_rectype: pair[1].clone(),
+                to_key: TabBuilder::help_get_id_for_record_type(ctx, &pair[1])?,
+            })
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub enum DataSource {
     Parquet { name: String, full_path: PathBuf },
@@ -401,10 +948,33 @@ pub enum DataSource {
     Csv { name: String, full_path: PathBuf },
 }
 
-#[derive(Clone, Debug)]
+// A path as a SQL string literal. Paths may contain spaces and quotes, so the path is
+// single-quoted with any single quotes in it doubled.
+fn sql_quoted_path(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', "''"))
+}
+
+/// The database engine which runs the generated queries. Queries differ slightly in
+/// syntax between platforms.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum DataPlatform {
+    #[default]
     Duckdb,
     DataFusion,
+    /// SQLite can't read data files, so queries refer to tables already loaded into the
+    /// database under their [DataSource::table_name].
+    Sqlite,
+}
+
+impl DataPlatform {
+    // The operator for dividing integers without a fractional result. DuckDB's '/' always
+    // gives a floating point result, but the others truncate when both sides are integers.
+    fn integer_division_operator(&self) -> &'static str {
+        match self {
+            Self::Duckdb => "//",
+            Self::DataFusion | Self::Sqlite => "/",
+        }
+    }
 }
 
 impl DataSource {
@@ -417,7 +987,12 @@ impl DataSource {
         let mut data_sources = HashMap::new();
         for rt in ctx.settings.record_types.keys() {
             let table_alias = ctx.settings.default_table_name(dataset, rt)?;
-            let p = paths_by_rectypes.get(rt).cloned();
+            // Native tables are already in the database under their alias, so they
+            // don't have a path.
+            let p = match input_format {
+                InputType::NativeDb => None,
+                _ => paths_by_rectypes.get(rt).cloned(),
+            };
             let ds = DataSource::new(table_alias, p)?;
             data_sources.insert(rt.to_string(), ds);
         }
@@ -429,7 +1004,9 @@ impl DataSource {
         if let Some(p) = full_path {
             if p.to_string_lossy().ends_with(".parquet") {
                 Ok(Self::Parquet { name, full_path: p })
-            } else if p.to_string_lossy().ends_with(".csv") {
+            } else if p.to_string_lossy().ends_with(".csv")
+                || p.to_string_lossy().ends_with(".csv.gz")
+            {
                 Ok(Self::Csv { name, full_path: p })
             } else {
                 let msg = format!(
@@ -451,17 +1028,25 @@ impl DataSource {
         match platform {
             DataPlatform::Duckdb => match self {
                 Self::Parquet { full_path, .. } => {
-                    // Check if full path points to a directory
-                    if full_path.is_dir() {
+                    if full_path.to_string_lossy().contains('*') {
+                        // A glob matching the part files of a record type split into
+                        // several files.
+                        format!("read_parquet({})", sql_quoted_path(full_path))
+                    } else if full_path.is_dir() {
+                        // The full path points to a directory.
                         // Duckdb can query a directory of parquet files
                         // as if they're a single logical file as long as
                         // the schema matches on all of them.
-                        format!("'{}/*.parquet'", &full_path.display())
+                        sql_quoted_path(&full_path.join("*.parquet"))
                     } else {
-                        format!("'{}'", &full_path.display())
+                        sql_quoted_path(full_path)
                     }
                 }
-                Self::Csv { full_path, .. } => format!("'{}'", &full_path.display()),
+                // read_csv_auto() detects the header, delimiter and column types, and
+                // decompresses gzipped files.
+                Self::Csv { full_path, .. } => {
+                    format!("read_csv_auto({})", sql_quoted_path(full_path))
+                }
                 Self::NativeTable { name } => name.to_owned(),
             },
             // DataFusion expects the data tables to have been registered already
@@ -473,6 +1058,7 @@ impl DataSource {
                     todo!("No native table type for '{}' in DataFusion yet.", &name)
                 }
             },
+            DataPlatform::Sqlite => self.table_name(),
         }
     }
 
@@ -497,6 +1083,16 @@ pub enum CompareOperation {
     NotEqual(String),
     Between(String, String),
     In(Vec<String>),
+    /// The value matches a SQL `LIKE` pattern, where `%` matches any sequence of
+    /// characters, `_` matches any single character, and `\` escapes the next character.
+    /// Matching is case-sensitive on [DataPlatform::Duckdb] and [DataPlatform::DataFusion].
+    /// SQLite's `LIKE` ignores the case of ASCII letters, so on [DataPlatform::Sqlite] "a%"
+    /// matches "Abc" too.
+    Like(String),
+    /// The value is missing (NULL). This doesn't compare to any value.
+    IsNull,
+    /// The value is present (not NULL). This doesn't compare to any value.
+    IsNotNull,
 }
 
 impl CompareOperation {
@@ -510,6 +1106,9 @@ impl CompareOperation {
             Self::GreaterEqual(_) => "greater or equal to",
             Self::LessEqual(_) => "less than or equal to",
             Self::NotEqual(_) => "not equal to",
+            Self::Like(_) => "like",
+            Self::IsNull => "missing",
+            Self::IsNotNull => "not missing",
         }
         .to_string()
     }
@@ -525,12 +1124,19 @@ impl CompareOperation {
             Self::NotEqual(rhs) => vec![rhs.to_string()],
             Self::Between(rhsl, rhsr) => vec![rhsl.to_string(), rhsr.to_string()],
             Self::In(rhs_list) => rhs_list.to_vec(),
+            Self::Like(pattern) => vec![pattern.to_string()],
+            Self::IsNull | Self::IsNotNull => Vec::new(),
         }
     }
 
     // The human readable version of the comparison.
     pub fn print(&self) -> String {
-        format!("{} {}", &self.name(), self.values().join(", "))
+        let values = self.values();
+        if values.is_empty() {
+            self.name()
+        } else {
+            format!("{} {}", &self.name(), values.join(", "))
+        }
     }
 
     /// Convert the `CompareOperation` to a SQL string.
@@ -546,6 +1152,12 @@ impl CompareOperation {
     ///
     /// let op_btwn = CompareOperation::Between("10".to_string(), "50".to_string());
     /// assert_eq!(op_btwn.to_sql("AGE"), "AGE between 10 and 50");
+    ///
+    /// assert_eq!(CompareOperation::IsNull.to_sql("AGE"), "AGE is null");
+    ///
+    /// // Like patterns are quoted, with any quotes in them escaped.
+    /// let op_like = CompareOperation::Like("O'B%".to_string());
+    /// assert_eq!(op_like.to_sql("NAME"), r"NAME like 'O''B%' escape '\'");
     /// ```
     pub fn to_sql(&self, lhs: &str) -> String {
         match self {
@@ -557,6 +1169,11 @@ impl CompareOperation {
             Self::NotEqual(rhs) => format!("{} != {}", lhs, &rhs),
             Self::Between(rhsl, rhsr) => format!("{} between {} and {}", lhs, &rhsl, &rhsr),
             Self::In(rhs_list) => format!("{} in ({})", lhs, &rhs_list.join(",")),
+            Self::Like(pattern) => {
+                format!("{} like '{}' escape '\\'", lhs, pattern.replace('\'', "''"))
+            }
+            Self::IsNull => format!("{} is null", lhs),
+            Self::IsNotNull => format!("{} is not null", lhs),
         }
     }
 }
@@ -588,6 +1205,45 @@ impl Condition {
         })
     }
 
+    /// A condition selecting records where the variable is missing (NULL).
+    pub fn is_null(var: &ipums_metadata_model::IpumsVariable) -> Result<Self, MdError> {
+        Self::new(var, &[CompareOperation::IsNull])
+    }
+
+    /// A condition selecting records where the variable is present (not NULL).
+    pub fn is_not_null(var: &ipums_metadata_model::IpumsVariable) -> Result<Self, MdError> {
+        Self::new(var, &[CompareOperation::IsNotNull])
+    }
+
+    /// A condition selecting records where the string variable matches the `LIKE`
+    /// pattern. See [CompareOperation::Like].
+    pub fn like(var: &ipums_metadata_model::IpumsVariable, pattern: &str) -> Result<Self, MdError> {
+        if !matches!(var.data_type, Some(IpumsDataType::String)) {
+            return Err(MdError::Msg(format!(
+                "Can't match the pattern '{pattern}' against {}, which is not a string variable.",
+                var.name
+            )));
+        }
+        Self::new(var, &[CompareOperation::Like(pattern.to_string())])
+    }
+
+    /// A condition selecting records where the string variable starts with `prefix`. Any
+    /// `LIKE` wildcards in `prefix` match literally.
+    pub fn starts_with(
+        var: &ipums_metadata_model::IpumsVariable,
+        prefix: &str,
+    ) -> Result<Self, MdError> {
+        let mut pattern = String::new();
+        for c in prefix.chars() {
+            if matches!(c, '%' | '_' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('%');
+        Self::like(var, &pattern)
+    }
+
     pub fn try_from_request_case_selections(
         var: &IpumsVariable,
         rcs: &[RequestCaseSelection],
@@ -626,6 +1282,29 @@ impl Condition {
         }
     }
 
+    /// The request case selections which [Condition::try_from_request_case_selections] reads
+    /// back into this condition, or `None` when a comparison can't be one, like
+    /// [CompareOperation::Like] or a comparison to a code that isn't an unsigned integer.
+    pub fn to_request_case_selections(&self) -> Option<Vec<RequestCaseSelection>> {
+        let code = |c: &str| c.parse::<u64>().ok();
+        self.comparison
+            .iter()
+            .map(|op| match op {
+                CompareOperation::Equal(c) => code(c).map(|c| RequestCaseSelection::Between(c, c)),
+                CompareOperation::Between(low, high) => {
+                    code(low).zip(code(high)).and_then(|(low, high)| {
+                        RequestCaseSelection::try_new(Some(low), Some(high)).ok()
+                    })
+                }
+                CompareOperation::LessEqual(c) => code(c).map(RequestCaseSelection::LessEqual),
+                CompareOperation::GreaterEqual(c) => {
+                    code(c).map(RequestCaseSelection::GreaterEqual)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
     // Pretty sure we'll need this at some point not too far off
     #[allow(dead_code)]
     fn lit(&self, v: &str) -> String {
@@ -645,7 +1324,9 @@ impl Condition {
     }
 }
 
-// Returns one query per dataset in the request; if you wanted to tabulate across
+// Returns one query per dataset in the request, or one per dataset and unit of analysis
+// when the request has several (see DataRequest::table_units_of_analysis), with the units
+// for each dataset in order. If you wanted to tabulate across
 // datasets that would be a different query that unions thetables of the same record type...
 // You can accomplish the same thing by combining the results of each query.
 pub fn tab_queries<R>(
@@ -657,33 +1338,203 @@ pub fn tab_queries<R>(
 where
     R: DataRequest,
 {
+    let units = request.table_units_of_analysis();
     let mut queries = Vec::new();
     for dataset in request.get_request_samples() {
-        let tb = TabBuilder::new(ctx, &dataset.name, platform, input_format)?;
-        let q = tb.make_query(ctx, &request)?;
-        queries.push(q);
+        let mut tb = TabBuilder::new(ctx, &dataset.name, platform, input_format)?;
+        tb.unweighted_count_only = request.count_columns() == CountColumns::Unweighted;
+        for uoa in &units {
+            queries.push(tb.make_query(ctx, &request, uoa)?);
+        }
     }
     Ok(queries)
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::input_schema_tabulation;
-    use crate::request::context_from_names_helper;
-    use crate::request::SimpleRequest;
+/// The count columns which tabulations of the request actually have. Without a weight for
+/// the record type being counted there's nothing to weight the counts with, so
+/// [CountColumns::Both] only counts records. All of the request's tables share one heading,
+/// so either all of them or none of them must have a weight. `member_rectype` is for counts
+/// of members, as in [member_count_queries], which count the member's parent records.
+pub(crate) fn tabulated_count_columns<R>(
+    ctx: &Context,
+    request: &R,
+    member_rectype: Option<&str>,
+) -> Result<CountColumns, MdError>
+where
+    R: DataRequest + ?Sized,
+{
+    let count_columns = request.count_columns();
+    if count_columns != CountColumns::Both || request.weight_variable().is_some() {
+        return Ok(count_columns);
+    }
 
-    #[test]
-    fn test_bucketing() {
-        let data_root = String::from("tests/data_root");
-        let (ctx, _, _) = context_from_names_helper(
-            "usa",
-            &["us2015b"],
-            &["AGE", "MARST", "GQ", "YEAR", "UHRSWORK"],
-            None,
-            Some(data_root),
-        )
-        .expect("Should be able to construct this test context.");
+    let counted_rectypes: Vec<String> = match member_rectype {
+        Some(member_rectype) => ctx
+            .settings
+            .record_hierarchy
+            .levels
+            .get(member_rectype)
+            .and_then(|level| level.parent.clone())
+            .into_iter()
+            .collect(),
+        None => request
+            .table_units_of_analysis()
+            .into_iter()
+            .map(|uoa| request.weight_rectype().unwrap_or(uoa))
+            .collect(),
+    };
+    let (weighted, unweighted): (Vec<String>, Vec<String>) = counted_rectypes
+        .into_iter()
+        .partition(|rt| ctx.settings.weight_for_rectype(rt).is_some());
+    match (weighted.is_empty(), unweighted.is_empty()) {
+        (_, true) => Ok(count_columns),
+        (true, false) => Ok(CountColumns::Unweighted),
+        (false, false) => Err(MdError::Msg(format!(
+            "Record type(s) {} have a weight but {} don't, so their tables can't have the same count columns. Request unweighted counts instead.",
+            weighted.join(", "),
+            unweighted.join(", ")
+        ))),
+    }
+}
+
+/// The "order by" clause of a tabulation query: the `columns` in order, each ascending with
+/// nulls last. Tabulations spell out the null ordering since DuckDB's default has changed
+/// between versions.
+pub fn order_by_clause(columns: &[String]) -> String {
+    columns
+        .iter()
+        .map(|column| format!("{column} asc nulls last"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Like [tab_queries], but count the records of the request's unit of analysis in each
+/// dataset without grouping them. See [TabBuilder::make_count_query].
+pub fn count_queries<R>(
+    ctx: &Context,
+    request: R,
+    input_format: &InputType,
+    platform: &DataPlatform,
+) -> Result<Vec<String>, MdError>
+where
+    R: DataRequest,
+{
+    let uoa = request.unit_of_analysis();
+    let mut queries = Vec::new();
+    for dataset in request.get_request_samples() {
+        let tb = TabBuilder::new(ctx, &dataset.name, platform, input_format)?;
+        queries.push(tb.make_count_query(ctx, &request, &uoa)?);
+    }
+    Ok(queries)
+}
+
+/// Like [tab_queries], but select the request variables from each record rather than
+/// counting records. See [TabBuilder::make_extract_query].
+pub fn extract_queries<R>(
+    ctx: &Context,
+    request: R,
+    input_format: &InputType,
+    platform: &DataPlatform,
+) -> Result<Vec<String>, MdError>
+where
+    R: DataRequest,
+{
+    let mut queries = Vec::new();
+    for dataset in request.get_request_samples() {
+        let tb = TabBuilder::new(ctx, &dataset.name, platform, input_format)?;
+        queries.push(tb.make_extract_query(ctx, &request)?);
+    }
+    Ok(queries)
+}
+
+/// Like [extract_queries], but with the parent records of the unit of analysis interleaved
+/// with their members. See [TabBuilder::make_hierarchical_extract_query].
+pub fn hierarchical_extract_queries<R>(
+    ctx: &Context,
+    request: R,
+    input_format: &InputType,
+    platform: &DataPlatform,
+) -> Result<Vec<String>, MdError>
+where
+    R: DataRequest,
+{
+    let mut queries = Vec::new();
+    for dataset in request.get_request_samples() {
+        let tb = TabBuilder::new(ctx, &dataset.name, platform, input_format)?;
+        queries.push(tb.make_hierarchical_extract_query(ctx, &request)?);
+    }
+    Ok(queries)
+}
+
+/// The name of the output column holding the number of `member_rectype` records which belong
+/// to each parent record, like "person_count" for "P".
+pub fn member_count_column(ctx: &Context, member_rectype: &str) -> Result<String, MdError> {
+    let Some(record_type) = ctx.settings.record_types.get(member_rectype) else {
+        return Err(metadata_error!(
+            "No record type '{member_rectype}' in current context."
+        ));
+    };
+    Ok(format!("{}_count", record_type.name.to_lowercase()))
+}
+
+/// Like [tab_queries], but tabulate the parents of `member_rectype` records by how many
+/// `member_rectype` records each one has. See [TabBuilder::make_member_count_query].
+pub fn member_count_queries<R>(
+    ctx: &Context,
+    request: R,
+    member_rectype: &str,
+    input_format: &InputType,
+    platform: &DataPlatform,
+) -> Result<Vec<String>, MdError>
+where
+    R: DataRequest,
+{
+    let mut queries = Vec::new();
+    for dataset in request.get_request_samples() {
+        let tb = TabBuilder::new(ctx, &dataset.name, platform, input_format)?;
+        let q = tb.make_member_count_query(ctx, &request, member_rectype)?;
+        queries.push(q);
+    }
+    Ok(queries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input_schema_tabulation;
+    use crate::input_schema_tabulation::GeneralDetailedSelection;
+    use crate::request::context_from_names_helper;
+    use crate::request::SimpleRequest;
+
+    #[test]
+    fn test_from_clause_three_levels_deep() {
+        let ctx =
+            Context::from_ipums_collection_name("atus", None, Some("tests/data_root".to_string()))
+                .expect("should have defaults for ATUS");
+        let tab_builder =
+            TabBuilder::new(&ctx, "at2019a", &DataPlatform::Duckdb, &InputType::NativeDb)
+                .expect("should be able to set up the tab builder");
+
+        let from_clause = tab_builder
+            .build_from_clause(&ctx, "at2019a", "A", &HashSet::from(["H".to_string()]))
+            .expect("should join activities to households through persons");
+        assert_eq!(
+            from_clause,
+            "at2019a_atus_activity as at2019a_atus_activity\n left join  at2019a_atus_person at2019a_atus_person on at2019a_atus_activity.PSERIALA = at2019a_atus_person.PSERIAL\n left join  at2019a_atus_household at2019a_atus_household on at2019a_atus_person.SERIALP = at2019a_atus_household.SERIAL"
+        );
+    }
+
+    #[test]
+    fn test_bucketing() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, _, _) = context_from_names_helper(
+            "usa",
+            &["us2015b"],
+            &["AGE", "MARST", "GQ", "YEAR", "UHRSWORK"],
+            None,
+            Some(data_root),
+        )
+        .expect("Should be able to construct this test context.");
 
         let tab_builder =
             TabBuilder::new(&ctx, "us2015b", &DataPlatform::Duckdb, &InputType::Parquet)
@@ -704,12 +1555,15 @@ mod test {
             value: 0,
             code: 0,
             label: "N/A".to_string(),
+            inclusive: true,
         });
         bins.push(CategoryBin::Range {
             low: 1,
             high: 14,
             code: 1,
             label: "1 to 14 hours worked per week".to_string(),
+            low_inclusive: true,
+            high_inclusive: true,
         });
 
         bins.push(CategoryBin::Range {
@@ -717,6 +1571,8 @@ mod test {
             high: 34,
             code: 2,
             label: "15 to 34 hours worked per week".to_string(),
+            low_inclusive: true,
+            high_inclusive: true,
         });
 
         bins.push(CategoryBin::Range {
@@ -724,6 +1580,8 @@ mod test {
             high: 99,
             code: 3,
             label: "35 or more hours worked per week".to_string(),
+            low_inclusive: true,
+            high_inclusive: true,
         });
 
         uhrswork_rq.category_bins = Some(bins);
@@ -736,10 +1594,164 @@ mod test {
 	when UHRSWORK >= 1 and UHRSWORK <= 14 then '001'
 	when UHRSWORK >= 15 and UHRSWORK <= 34 then '002'
 	when UHRSWORK >= 35 and UHRSWORK <= 99 then '003'
-else '999' end as UHRSWORK_bucketed";
+end as UHRSWORK_bucketed";
 
             assert_eq!(correct, &sql);
         }
+
+        // A residual bin catches the values outside every bin.
+        uhrswork_rq.residual_label = Some("Other".to_string());
+        let sql = tab_builder
+            .help_bucket(&uhrswork_rq)
+            .expect("should bucket UHRSWORK");
+        assert!(sql.ends_with("then '003'\nelse '999' end as UHRSWORK_bucketed"));
+    }
+
+    /// Activities have no weight, so by default their tables only count them. With the person
+    /// weight they're weighted by the weight of the person they belong to.
+    #[test]
+    fn test_tab_queries_atus_activities() {
+        // The ATUS layouts are apart from the USA ones, which are all loaded by some tests.
+        let data_root = String::from("tests/atus_data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "atus",
+            &["at2019a"],
+            &["ACTIVITY"],
+            Some("A".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let queries = tab_queries(
+            &ctx,
+            rq.clone(),
+            &InputType::NativeDb,
+            &DataPlatform::Duckdb,
+        )
+        .expect("should generate the queries");
+        assert_eq!(
+            queries,
+            vec!["select \ncount(*) as ct, ACTIVITY as ACTIVITY\nfrom at2019a_atus_activity as at2019a_atus_activity\ngroup by 2\norder by ACTIVITY asc nulls last"]
+        );
+
+        rq.weight_rectype = Some("P".to_string());
+        let queries = tab_queries(&ctx, rq, &InputType::NativeDb, &DataPlatform::Duckdb)
+            .expect("should generate the weighted queries");
+        let q = &queries[0];
+        assert!(
+            q.starts_with(
+                "select \ncount(*) as ct, sum(WT06/1) as weighted_ct, ACTIVITY as ACTIVITY\n"
+            ),
+            "{q}"
+        );
+        assert!(q.contains("left join  at2019a_atus_person"), "{q}");
+        assert!(q.contains("\ngroup by 3\n"), "{q}");
+    }
+
+    /// Without a weight, the default count columns only count records, and the query groups
+    /// by the columns after the single count.
+    #[test]
+    fn test_tab_queries_without_weight_groups_after_count() {
+        let data_root = String::from("tests/data_root");
+        let (mut ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+        ctx.settings
+            .record_types
+            .get_mut("P")
+            .expect("should have a person record type")
+            .weight = None;
+
+        let queries = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate the queries");
+        assert!(queries[0].starts_with("select \ncount(*) as ct, MARST as MARST\nfrom "));
+        assert!(queries[0].contains("\ngroup by 2\n"), "{}", queries[0]);
+    }
+
+    /// Tables of a weighted and an unweighted record type can't share count columns.
+    #[test]
+    fn test_tab_queries_some_units_without_weight_error() {
+        let data_root = String::from("tests/data_root");
+        let (mut ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+        rq.table_units = vec!["P".to_string(), "H".to_string()];
+        ctx.settings
+            .record_types
+            .get_mut("H")
+            .expect("should have a household record type")
+            .weight = None;
+
+        let err = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect_err("the household table has no weight");
+        assert!(
+            err.to_string().contains("have a weight but H don't"),
+            "{err}"
+        );
+    }
+
+    /// LessThan and MoreThan bins are strict unless they're inclusive.
+    #[test]
+    fn test_bucketing_less_than_and_more_than_inclusivity() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, _, _) =
+            context_from_names_helper("usa", &["us2015b"], &["AGE"], None, Some(data_root))
+                .expect("Should be able to construct this test context.");
+        let tab_builder =
+            TabBuilder::new(&ctx, "us2015b", &DataPlatform::Duckdb, &InputType::Parquet)
+                .expect("TabBuilder new() for testing should never error out.");
+        let age = ctx
+            .get_md_variable_by_name("AGE")
+            .expect("Expected AGE to be in the test context.");
+        let mut age_rq = RequestVariable::try_from_ipums_variable(
+            &age,
+            input_schema_tabulation::GeneralDetailedSelection::Detailed,
+        )
+        .expect("AGE should be in the test context.");
+
+        let bin = |value, code, inclusive, less| {
+            let label = String::new();
+            if less {
+                CategoryBin::LessThan {
+                    value,
+                    code,
+                    label,
+                    inclusive,
+                }
+            } else {
+                CategoryBin::MoreThan {
+                    value,
+                    code,
+                    label,
+                    inclusive,
+                }
+            }
+        };
+        age_rq.category_bins = Some(vec![
+            bin(18, 1, false, true),
+            bin(21, 2, true, true),
+            bin(64, 3, false, false),
+            bin(60, 4, true, false),
+        ]);
+
+        let sql = tab_builder.help_bucket(&age_rq).expect("should bucket AGE");
+        assert_eq!(
+            sql,
+            "case\n\twhen AGE < 18 then '001'\n\twhen AGE <= 21 then '002'\n\twhen AGE > 64 then '003'\n\twhen AGE >= 60 then '004'\nend as AGE_bucketed"
+        );
     }
 
     #[test]
@@ -794,6 +1806,145 @@ else '999' end as UHRSWORK_bucketed";
         assert!(cond4_age.is_ok());
     }
 
+    #[test]
+    fn test_null_conditions_to_sql() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, _) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["INCWAGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+        let incwage = ctx
+            .get_md_variable_by_name("INCWAGE")
+            .expect("'INCWAGE' variable required for tests.");
+
+        let missing = Condition::is_null(&incwage).unwrap();
+        assert_eq!(missing.to_sql(), "(INCWAGE is null)");
+        assert_eq!(missing.comparison[0].print(), "missing");
+
+        let present = Condition::is_not_null(&incwage).unwrap();
+        assert_eq!(present.to_sql(), "(INCWAGE is not null)");
+        assert_eq!(present.comparison[0].print(), "not missing");
+    }
+
+    #[test]
+    fn test_like_conditions_to_sql() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, _) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["RECTYPEP", "AGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+        let rectype = ctx
+            .get_md_variable_by_name("RECTYPEP")
+            .expect("'RECTYPEP' variable required for tests.");
+
+        let like = Condition::like(&rectype, "P%").unwrap();
+        assert_eq!(like.to_sql(), r"(RECTYPEP like 'P%' escape '\')");
+
+        let prefix = Condition::starts_with(&rectype, "50%_it's").unwrap();
+        assert_eq!(
+            prefix.to_sql(),
+            r"(RECTYPEP like '50\%\_it''s%' escape '\')"
+        );
+
+        let age = ctx
+            .get_md_variable_by_name("AGE")
+            .expect("'AGE' variable required for tests.");
+        assert!(Condition::like(&age, "1%").is_err());
+    }
+
+    #[test]
+    fn test_general_variable_sql_for_platform() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, _) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["RELATE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+        let mut relate = ctx
+            .get_md_variable_by_name("RELATE")
+            .expect("'RELATE' variable required for tests.");
+        relate.general_width = Some(2);
+        let relate =
+            RequestVariable::try_from_ipums_variable(&relate, GeneralDetailedSelection::General)
+                .expect("should convert into a general RequestVariable");
+
+        let select_for = |platform: DataPlatform| {
+            TabBuilder::new(&ctx, "us2015b", &platform, &InputType::Parquet)
+                .expect("TabBuilder new() for testing should never error out.")
+                .help_select_variable(&relate)
+                .expect("should select the general variable")
+        };
+        assert_eq!(select_for(DataPlatform::Duckdb), ", RELATE//100 as RELATE");
+        assert_eq!(
+            select_for(DataPlatform::DataFusion),
+            ", RELATE/100 as RELATE"
+        );
+        assert_eq!(select_for(DataPlatform::Sqlite), ", RELATE/100 as RELATE");
+    }
+
+    #[test]
+    fn test_tab_queries_general_selection_groups_by_general_codes() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["RELATE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.variables[0].general_width = Some(2);
+        rq.use_general_variables = GeneralDetailedSelection::General;
+
+        let queries = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate the query");
+        assert_eq!(queries.len(), 1);
+        assert!(
+            queries[0].contains(", RELATE//100 as RELATE"),
+            "expected the general RELATE codes in the query {}",
+            queries[0]
+        );
+        assert!(
+            queries[0].contains("group by 3"),
+            "expected grouping on the general RELATE column in the query {}",
+            queries[0]
+        );
+    }
+
+    #[test]
+    fn test_sqlite_query_uses_table_names() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+
+        let queries = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Sqlite)
+            .expect("should generate queries for SQLite");
+        assert!(queries[0].contains("from us2015b_usa_person as us2015b_usa_person"));
+        assert!(!queries[0].contains(".parquet"));
+    }
+
     #[test]
     fn test_build_where_clause() {
         let data_root = String::from("tests/data_root");
@@ -881,4 +2032,384 @@ else '999' end as UHRSWORK_bucketed";
             assert!(qs[0].contains("from"));
         }
     }
+
+    #[test]
+    fn test_parquet_glob_for_duckdb() {
+        let glob = PathBuf::from("tests/data_root/parquet/us1850a/us1850a_usa.P*.parquet");
+        let ds = DataSource::new("us1850a_P".to_string(), Some(glob))
+            .expect("a glob ending in .parquet should be a Parquet data source");
+        assert_eq!(
+            ds.for_platform(&DataPlatform::Duckdb),
+            "read_parquet('tests/data_root/parquet/us1850a/us1850a_usa.P*.parquet')"
+        );
+    }
+
+    #[test]
+    fn test_native_db_query_uses_table_names() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+
+        let queries = tab_queries(&ctx, rq, &InputType::NativeDb, &DataPlatform::Duckdb)
+            .expect("should generate queries against native tables");
+        assert!(queries[0].contains("from us2015b_usa_person as us2015b_usa_person"));
+        assert!(!queries[0].contains(".parquet"));
+    }
+
+    #[test]
+    fn test_household_weight_for_person_counts() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+        rq.set_weight_rectype(&ctx, "H")
+            .expect("H should be usable for weighting");
+
+        let queries = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate queries");
+        assert!(queries[0].contains("sum(HHWT/100) as weighted_ct"));
+        assert!(queries[0].contains("left join"));
+        assert!(!queries[0].contains("PERWT"));
+    }
+
+    #[test]
+    fn test_replicate_weight_variable() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+        rq.set_weight_variable(&ctx, "REPWTP1")
+            .expect("REPWTP1 should be usable for weighting");
+
+        let queries = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate queries");
+        // The divisor of PERWT, the default person weight, still applies.
+        assert!(queries[0].contains("sum(REPWTP1/100) as weighted_ct"));
+        assert!(!queries[0].contains("PERWT"));
+    }
+
+    #[test]
+    fn test_weight_variable_not_in_dataset_error() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us1850a"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+        rq.set_weight_variable(&ctx, "REPWTP1")
+            .expect("REPWTP1 should be usable for weighting");
+
+        let err = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect_err("us1850a has no replicate weights");
+        assert_eq!(
+            err.to_string(),
+            "metadata error: Weight variable REPWTP1 is not available in dataset us1850a"
+        );
+    }
+
+    #[test]
+    fn test_quoted_paths_with_spaces_and_quotes() {
+        let path = PathBuf::from("/data/O'Brien data/us2015b_usa.P.parquet");
+        let ds = DataSource::new("us2015b_usa_person".to_string(), Some(path))
+            .expect("should be a Parquet data source");
+        assert_eq!(
+            ds.for_platform(&DataPlatform::Duckdb),
+            "'/data/O''Brien data/us2015b_usa.P.parquet'"
+        );
+
+        let glob = PathBuf::from("/data/O'Brien data/us2015b_usa.P*.parquet");
+        let ds = DataSource::new("us2015b_usa_person".to_string(), Some(glob))
+            .expect("should be a Parquet data source");
+        assert_eq!(
+            ds.for_platform(&DataPlatform::Duckdb),
+            "read_parquet('/data/O''Brien data/us2015b_usa.P*.parquet')"
+        );
+    }
+
+    #[test]
+    fn test_compressed_csv_data_source() {
+        let path = PathBuf::from("/data/us2015b_usa.P.csv.gz");
+        let ds = DataSource::new("us2015b_usa_person".to_string(), Some(path))
+            .expect("should be a CSV data source");
+        assert!(matches!(ds, DataSource::Csv { .. }));
+        assert_eq!(
+            ds.for_platform(&DataPlatform::Duckdb),
+            "read_csv_auto('/data/us2015b_usa.P.csv.gz')"
+        );
+    }
+
+    #[test]
+    fn test_tab_queries_csv_input() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let queries = tab_queries(&ctx, rq, &InputType::Csv, &DataPlatform::Duckdb)
+            .expect("should generate queries");
+        assert!(queries[0].contains(
+            "from read_csv_auto('tests/data_root/csv/us2015b/us2015b_usa.P.csv') as us2015b_usa_person"
+        ));
+    }
+
+    #[test]
+    fn test_tab_queries_unweighted_only() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST", "GQ"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.count_columns = CountColumns::Unweighted;
+        let queries = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate queries");
+        assert!(queries[0].starts_with("select \ncount(*) as ct, MARST as MARST"));
+        assert!(!queries[0].contains("weighted_ct"));
+        assert!(queries[0].contains("group by 2, 3\n"));
+    }
+
+    /// Weighted counts of households use the household weight.
+    #[test]
+    fn test_tab_queries_weighted_only_household() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ"],
+            Some("H".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.count_columns = CountColumns::Weighted;
+        let queries = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate queries");
+        assert!(queries[0].starts_with("select \nsum(HHWT/100) as weighted_ct, GQ as GQ"));
+        assert!(!queries[0].contains("count(*)"));
+        assert!(queries[0].contains("group by 2\n"));
+    }
+
+    #[test]
+    fn test_extract_query() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["AGE", "MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+
+        let queries = extract_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate extract queries");
+        assert_eq!(queries.len(), 1);
+        assert!(queries[0].starts_with("select \nAGE as AGE, MARST as MARST\nfrom "));
+        assert!(!queries[0].contains("count(*)"));
+        assert!(!queries[0].contains("group by"));
+    }
+
+    #[test]
+    fn test_extract_query_unit_of_analysis_and_conditions() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ", "HHINCOME"],
+            Some("H".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+        let gq_var = ctx
+            .get_md_variable_by_name("GQ")
+            .expect("'GQ' variable required for tests.");
+        rq.conditions = Some(vec![Condition::new(
+            &gq_var,
+            &[CompareOperation::Equal("1".to_string())],
+        )
+        .expect("Condition should always be constructed for testing.")]);
+
+        let queries = extract_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate extract queries");
+        assert_eq!(queries.len(), 1);
+        let q = &queries[0];
+        // One row per household, without joining in the persons.
+        assert!(q.starts_with("select \nGQ as GQ, HHINCOME as HHINCOME\nfrom "));
+        assert!(q.contains("household"));
+        assert!(!q.contains("person"));
+        assert!(q.ends_with("\nwhere ((GQ = 1))"));
+    }
+
+    #[test]
+    fn test_hierarchical_extract_query() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ", "SEX"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+
+        let queries =
+            hierarchical_extract_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+                .expect("should generate hierarchical extract queries");
+        assert_eq!(queries.len(), 1);
+        let q = &queries[0];
+        assert!(q.starts_with("select 'H' as RECTYPE, GQ as GQ, null as SEX, "));
+        assert!(q.contains("\nunion all\nselect 'P' as RECTYPE, null as GQ, SEX as SEX, "));
+        assert!(q.contains(".SERIALP as hier_key, 1 as record_order"));
+        assert!(q.ends_with("\norder by hier_key, record_order"));
+    }
+
+    #[test]
+    fn test_cross_tab_query_groups_and_orders_by_each_variable() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST", "SEX", "AGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+
+        let queries = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate a query");
+        assert_eq!(queries.len(), 1);
+        let q = &queries[0];
+        assert!(q.contains(", MARST as MARST, SEX as SEX, AGE as AGE\n"));
+        assert!(q.ends_with(
+            "group by 3, 4, 5\norder by MARST asc nulls last, SEX asc nulls last, AGE asc nulls last"
+        ));
+    }
+
+    #[test]
+    fn test_count_query() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+
+        let queries = count_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate count queries");
+        assert_eq!(queries.len(), 1);
+        let q = &queries[0];
+        assert!(q.starts_with("select \ncount(*) as ct, sum(PERWT/100) as weighted_ct\nfrom "));
+        assert!(!q.contains("MARST"));
+        assert!(!q.contains("group by"));
+    }
+
+    #[test]
+    fn test_tab_queries_per_unit_of_analysis() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["STATEFIP"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+        rq.table_units = vec!["P".to_string(), "H".to_string()];
+
+        let queries = tab_queries(&ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)
+            .expect("should generate a query for each unit of analysis");
+        assert_eq!(queries.len(), 2);
+        assert!(queries[0].contains("sum(PERWT/100)"));
+        assert!(queries[0].contains("left join"));
+        assert!(queries[1].contains("sum(HHWT/100)"));
+        assert!(!queries[1].contains("left join"));
+    }
+
+    #[test]
+    fn test_member_count_query() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["STATEFIP"],
+            Some("H".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+
+        let queries =
+            member_count_queries(&ctx, rq, "P", &InputType::Parquet, &DataPlatform::Duckdb)
+                .expect("should generate member count queries");
+        assert_eq!(1, queries.len());
+        let q = &queries[0];
+        assert!(q.starts_with("with member_counts as ("));
+        assert!(q.contains("select SERIALP, count(*) as person_count"));
+        assert!(q.contains("group by SERIALP"));
+        assert!(q.contains("sum(HHWT/100) as weighted_ct"));
+        assert!(q.contains("coalesce(member_counts.person_count, 0) as person_count"));
+        assert!(q.contains("left join member_counts on"));
+        assert!(q.ends_with(
+            "group by 3, 4\norder by person_count asc nulls last, STATEFIP asc nulls last"
+        ));
+    }
+
+    #[test]
+    fn test_member_count_query_rejects_member_variables() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["AGE"],
+            Some("H".to_string()),
+            None,
+            Some(data_root),
+        )
+        .unwrap();
+
+        let result =
+            member_count_queries(&ctx, rq, "P", &InputType::Parquet, &DataPlatform::Duckdb);
+        assert!(result.is_err());
+    }
 }
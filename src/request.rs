@@ -9,15 +9,26 @@
 //! the request object to get handed off to "Extract" or "Tabulate" code.
 //!
 //use serde_json::{to_string, Error};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
 use crate::ipums_data_model::{self, RecordType};
 use crate::{
+    codebook::Codebook,
     conventions,
     conventions::Context,
-    input_schema_tabulation,
-    input_schema_tabulation::{CategoryBin, GeneralDetailedSelection},
-    ipums_metadata_model::{IpumsDataType, IpumsDataset, IpumsVariable},
+    defaults, extract, input_schema_tabulation,
+    input_schema_tabulation::{
+        AttachedVariablePointer, AutoBin, CategoryBin, CountColumns, GeneralDetailedSelection,
+        RequestCaseSelection, UNBINNED_CODE, UNBINNED_LABEL,
+    },
+    ipums_metadata_model::{IpumsDataType, IpumsDataset, IpumsValue, IpumsVariable},
     mderror::{metadata_error, parsing_error, MdError},
-    query_gen::Condition,
+    query_gen::{self, Condition, JoinStep},
+    tabulate::{self, TabulateOptions},
 };
 
 // Given a set of variable and dataset names and a product name, produce a context loaded
@@ -67,6 +78,22 @@ pub fn context_from_names_helper(
     Ok((ctx, variables, datasets))
 }
 
+/// A multiplier for each dataset which standardizes the values of a monetary variable, like
+/// CPI-based factors which express INCWAGE in constant dollars across years. See
+/// [ComplexRequest::adjust_variable].
+#[derive(Clone, Debug, PartialEq)]
+pub struct MonetaryAdjustment {
+    /// The factor for each dataset, by dataset name.
+    pub factors: BTreeMap<String, f64>,
+}
+
+impl MonetaryAdjustment {
+    /// The factor which values from the dataset named `dataset` are multiplied by.
+    pub fn factor_for(&self, dataset: &str) -> Option<f64> {
+        self.factors.get(dataset).copied()
+    }
+}
+
 #[allow(unused)]
 #[derive(Clone, Debug)]
 pub struct RequestVariable {
@@ -75,8 +102,16 @@ pub struct RequestVariable {
     pub general_divisor: usize, // for instance, 100 for RELATE vs RELATED
     pub name: String,
     pub case_selection: Option<Condition>,
-    pub attached_variable_pointer: Option<IpumsVariable>,
+    /// Set for variables attached to the records of a unit of analysis below their record
+    /// type. See [RequestVariable::attach].
+    pub attached_variable_pointer: Option<AttachedVariablePointer>,
+    /// Set for monetary variables whose values are multiplied by a factor for their dataset
+    /// before they're tabulated or binned.
+    pub adjustment: Option<MonetaryAdjustment>,
     pub category_bins: Option<Vec<CategoryBin>>,
+    /// The label of the residual bin of values outside every category bin, or None for no
+    /// residual bin. See [RequestVariable::residual_label].
+    pub residual_label: Option<String>,
     // extract_start is only useful to help order the request variables and
     // for producing a fixed-width output which we generally don't want.
     extract_start: Option<usize>,
@@ -98,8 +133,11 @@ impl RequestVariable {
     ) -> Result<Self, MdError> {
         let mut var = ctx.get_md_variable_by_name(&input_rq.variable_mnemonic)?;
         // Currently we determine the general width from the input request, not
-        // from metadata
-        var.general_width = Some(input_rq.extract_width);
+        // from metadata. Positions are 1-based and widths are positive, so a zero
+        // extract_start or extract_width means the request doesn't give one.
+        if input_rq.extract_width > 0 {
+            var.general_width = Some(input_rq.extract_width);
+        }
         let mut rq = Self::try_from_ipums_variable(&var, input_rq.general_detailed_selection)?;
 
         // This is optional; the category bins could have been attached already by way of the IpumsVariable from ctx. If
@@ -108,6 +146,11 @@ impl RequestVariable {
             rq.category_bins = Some(bins.to_vec().clone());
         }
 
+        // The request may also specify where the variable goes in a fixed-width extract.
+        if input_rq.extract_start > 0 {
+            rq.extract_start = Some(input_rq.extract_start);
+        }
+
         if input_rq.case_selection {
             rq.case_selection = Condition::try_from_request_case_selections(
                 &var,
@@ -161,10 +204,12 @@ impl RequestVariable {
             variable: var.clone(),
             general_detailed_selection: use_general,
             general_divisor,
-            name: var.name.clone(),
+            name: var.name.to_string(),
             case_selection: None,
             attached_variable_pointer: None,
+            adjustment: None,
             category_bins: var.category_bins.clone(),
+            residual_label: None,
             extract_start: None,
             extract_width: var.general_width,
         })
@@ -174,6 +219,40 @@ impl RequestVariable {
         GeneralDetailedSelection::General == self.general_detailed_selection
     }
 
+    /// Attach the variable to the records of `unit_of_analysis`, so that each of them gets the
+    /// value from the record of `record_type` it belongs to, like the HHINCOME of each
+    /// person's household. `record_type` must be the variable's record type, and it must be
+    /// above `unit_of_analysis` in the record hierarchy.
+    pub fn attach(
+        &mut self,
+        ctx: &Context,
+        unit_of_analysis: &str,
+        record_type: &str,
+    ) -> Result<(), MdError> {
+        if self.variable.record_type != record_type {
+            return Err(metadata_error!(
+                "Can't attach {} from record type '{record_type}': the variable is on record type '{}'",
+                self.name,
+                self.variable.record_type
+            ));
+        }
+        let ancestors = ctx.settings.record_hierarchy.ancestors(unit_of_analysis);
+        if !ancestors.iter().any(|rt| rt == record_type) {
+            return Err(metadata_error!(
+                "Can't attach {} to record type '{unit_of_analysis}': record type '{record_type}' isn't above it in the record hierarchy",
+                self.name
+            ));
+        }
+        self.attached_variable_pointer = Some(AttachedVariablePointer {
+            record_type: record_type.to_string(),
+        });
+        Ok(())
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.attached_variable_pointer.is_some()
+    }
+
     pub fn detailed_width(&self) -> Result<usize, MdError> {
         if let Some((_, w)) = self.variable.formatting {
             Ok(w)
@@ -205,17 +284,87 @@ impl RequestVariable {
         }
     }
 
+    /// The 1-based start column and the width of this variable in fixed-width extract
+    /// output, if the request specified them.
+    pub fn extract_position(&self) -> Option<(usize, usize)> {
+        match (self.extract_start, self.extract_width) {
+            (Some(start), Some(width)) => Some((start, width)),
+            _ => None,
+        }
+    }
+
     pub fn data_type(&self) -> Option<IpumsDataType> {
         self.variable.data_type.clone()
     }
 
     pub fn variable_name(&self) -> String {
-        self.variable.name.clone()
+        self.variable.name.to_string()
     }
 
     pub fn is_bucketed(&self) -> bool {
         self.category_bins.is_some()
     }
+
+    /// Return true if the variable's codes have labels, either from its category bins or
+    /// from its categories.
+    pub fn has_labels(&self) -> bool {
+        self.category_bins.is_some() || self.variable.categories.is_some()
+    }
+
+    /// The label of the residual bin, like "Other", if the bucketed variable has one. The
+    /// residual bin holds the values outside every category bin under [UNBINNED_CODE].
+    pub fn residual_label(&self) -> Option<&str> {
+        self.residual_label.as_deref()
+    }
+
+    /// Look up the label for one of the variable's codes as it appears in tabulation output.
+    /// Labels come from the category bins for bucketed variables and from the variable's
+    /// categories otherwise. Values outside every bin get the [residual
+    /// label](RequestVariable::residual_label), or [UNBINNED_LABEL] for their empty code
+    /// when there's no residual bin.
+    pub fn category_label(&self, code: &str) -> Option<String> {
+        if let Some(ref bins) = self.category_bins {
+            if code.is_empty() {
+                return Some(UNBINNED_LABEL.to_string());
+            }
+            let code: u64 = code.parse().ok()?;
+            if code == UNBINNED_CODE {
+                return self.residual_label().map(str::to_string);
+            }
+            return bins
+                .iter()
+                .find(|b| b.code() == code)
+                .map(|b| b.label().to_string());
+        }
+
+        let code: i64 = code.parse().ok()?;
+        self.variable
+            .categories
+            .as_ref()?
+            .iter()
+            .find(|c| matches!(c.value, IpumsValue::Integer(value) if value == code))
+            .map(|c| c.label().to_string())
+    }
+
+    // Everything about the variable which affects the result of a request.
+    fn canonical_form(&self) -> String {
+        let mut form = format!(
+            "{}|{}|{}|{:?}",
+            self.name,
+            self.is_general(),
+            self.general_divisor,
+            self.category_bins
+        );
+        // Only a custom residual label changes the output, so requests without one keep
+        // the same form.
+        if let Some(ref label) = self.residual_label {
+            form += &format!("|{label}");
+        }
+        if let Some(ref adjustment) = self.adjustment {
+            form += &format!("|{:?}", adjustment.factors);
+        }
+        form
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -228,16 +377,42 @@ impl RequestSample {
     pub fn from_ipums_dataset(ds: &IpumsDataset) -> Self {
         Self {
             sample: ds.clone(),
-            name: ds.name.clone(),
+            name: ds.name.to_string(),
         }
     }
 }
 
+/// How a request's conditions combine: cases must match all of them, or any of them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub enum CaseSelectLogic {
+    #[default]
     And,
     Or,
 }
 
+impl FromStr for CaseSelectLogic {
+    type Err = MdError;
+
+    /// Parse a `CaseSelectLogic` from the `case_select_logic` of a JSON request.
+    ///
+    /// The parsing is case-insensitive and accepts the strings "and" and "or".
+    ///
+    /// ```
+    /// use cimdea::request::CaseSelectLogic;
+    /// use std::str::FromStr;
+    ///
+    /// let logic = CaseSelectLogic::from_str("OR").unwrap();
+    /// assert_eq!(logic, CaseSelectLogic::Or);
+    /// ```
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "and" => Ok(Self::And),
+            "or" => Ok(Self::Or),
+            _ => Err(parsing_error!("unknown case_select_logic '{name}'")),
+        }
+    }
+}
+
 // We only ever apply CaseSelectUnit  to household-person but theoretically this is a way
 // to select all members of a given unit of analysis contained in the 'unit' if it's
 // not the current unit when one record matches. For instance 'EntireHousehold' means
@@ -260,6 +435,22 @@ pub trait DataRequest {
     fn get_request_samples(&self) -> Vec<RequestSample>;
     fn get_conditions(&self) -> Option<Vec<Condition>>;
 
+    /// Every condition that queries for this request apply, in the order they're combined
+    /// with the request's [CaseSelectLogic]. These are the [DataRequest::get_conditions],
+    /// like the subpopulation, followed by the case selections on request variables.
+    ///
+    /// Query generation may add dataset-specific conditions which aren't listed here, like the
+    /// `SELFWTSL` filter for unweighted counts from us1940a.
+    fn all_conditions(&self) -> Vec<Condition> {
+        let mut conditions = self.get_conditions().unwrap_or_default();
+        conditions.extend(
+            self.get_request_variables()
+                .into_iter()
+                .filter_map(|v| v.case_selection),
+        );
+        conditions
+    }
+
     /// Convert to the Tractor / generic IPUMS representation
     fn serialize_to_ipums_json(&self) -> String;
 
@@ -288,11 +479,238 @@ pub trait DataRequest {
     /// Print a human readable codebook
     fn print_codebook(&self) -> String;
 
+    /// Build a codebook which includes the unweighted frequency of each category of each
+    /// request variable. This runs a univariate tabulation for every request variable.
+    fn detailed_codebook(&self, ctx: &conventions::Context) -> Result<Codebook, MdError> {
+        Codebook::detailed(ctx, self)
+    }
+
+    /// The name and type of each column in the tables a tabulation of this request with
+    /// the given options produces, in order. This is known before running the tabulation.
+    fn output_schema(
+        &self,
+        ctx: &conventions::Context,
+        options: &TabulateOptions,
+    ) -> Result<Vec<(String, IpumsDataType)>, MdError> {
+        tabulate::output_heading(ctx, self, options)?
+            .iter()
+            .map(|column| Ok((column.name(), column.data_type()?)))
+            .collect()
+    }
+
     /// Print a machine readable Stata codebook
     fn print_stata(&self) -> String;
 
     fn case_select_logic(&self) -> CaseSelectLogic;
     fn case_select_unit(&self) -> CaseSelectUnit;
+
+    /// A hash of the canonical form of the request, for caching and deduplicating requests.
+    ///
+    /// The canonical form covers everything which changes the result of the request: the
+    /// product, unit of analysis and request type, the variables, samples and conditions,
+    /// the case select logic, the weight, the count columns and the units of analysis of the
+    /// tables. It sorts the request variables, samples and conditions, so requests which
+    /// differ only in the order of those hash equal. The hash is stable for a given build of
+    /// this library, but may change between versions.
+    fn canonical_hash(&self) -> u64 {
+        let mut variables: Vec<String> = self
+            .get_request_variables()
+            .iter()
+            .map(RequestVariable::canonical_form)
+            .collect();
+        variables.sort();
+
+        let mut samples: Vec<String> = self
+            .get_request_samples()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        samples.sort();
+
+        let mut conditions: Vec<String> = self
+            .all_conditions()
+            .iter()
+            .map(|c| {
+                // The comparisons in a condition are combined with 'or', so their order
+                // doesn't matter either.
+                let mut comparisons: Vec<String> = c
+                    .comparison
+                    .iter()
+                    .map(|op| op.to_sql(&c.var.name))
+                    .collect();
+                comparisons.sort();
+                comparisons.join(" or ")
+            })
+            .collect();
+        conditions.sort();
+
+        let mut hasher = DefaultHasher::new();
+        self.product_name().to_ascii_lowercase().hash(&mut hasher);
+        self.unit_of_analysis().hash(&mut hasher);
+        format!("{:?}", self.request_type()).hash(&mut hasher);
+        variables.hash(&mut hasher);
+        samples.hash(&mut hasher);
+        conditions.hash(&mut hasher);
+        matches!(self.case_select_logic(), CaseSelectLogic::Or).hash(&mut hasher);
+        self.weight_rectype().hash(&mut hasher);
+        self.weight_variable()
+            .map(|v| (v.name.to_string(), v.record_type))
+            .hash(&mut hasher);
+        format!("{:?}", self.count_columns()).hash(&mut hasher);
+        // Tables come in this order, so it matters.
+        self.table_units_of_analysis().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The format that output for this request will be written in. This is the format
+    /// given in the request when there was one, or else the default for the request type.
+    fn effective_output_format(&self) -> OutputFormat;
+
+    /// The record type whose weight variable weights the counts, like "H" to count persons
+    /// with the household weight. `None` means the weight of the unit of analysis.
+    fn weight_rectype(&self) -> Option<String>;
+
+    /// A variable which weights the counts in place of the default weight of its record
+    /// type, like a replicate weight. It takes precedence over [DataRequest::weight_rectype].
+    /// `None` means the default weight.
+    fn weight_variable(&self) -> Option<IpumsVariable>;
+
+    /// Which count columns tabulations of this request produce. Only the requested counts
+    /// are computed.
+    fn count_columns(&self) -> CountColumns;
+
+    /// The unit of analysis of each table to compute from each dataset, in order. By
+    /// default this is only the request's unit of analysis, which gives one table per
+    /// dataset.
+    fn table_units_of_analysis(&self) -> Vec<String>;
+
+    /// The name of the data collection the request is for, like "usa".
+    fn product_name(&self) -> String;
+
+    /// The value of the request's unit of analysis record type, like "P".
+    fn unit_of_analysis(&self) -> String;
+
+    /// Whether the request is for a tabulation or an extract. Requests are tabulations unless
+    /// they say otherwise.
+    fn request_type(&self) -> RequestType {
+        RequestType::Tabulation
+    }
+
+    /// The arguments to the `abacus` command line tool which recreate this request, to
+    /// record how the request was made. These run the `tab` command on the product, the
+    /// dataset and each variable, with the unit of analysis and output format, like
+    /// `tab usa us2015b MARST AGE --uoa P --format csv`.
+    ///
+    /// `tab` counts detailed or general variables from a single dataset with the default
+    /// weight and count columns. This returns an error for requests it can't recreate, like
+    /// those with several datasets, conditions or category bins.
+    fn to_cli_args(&self) -> Result<Vec<String>, MdError> {
+        let unsupported = |what: &str| {
+            Err(MdError::Msg(format!(
+                "The abacus command line can't recreate a request with {what}."
+            )))
+        };
+
+        let samples = self.get_request_samples();
+        let [sample] = samples.as_slice() else {
+            return unsupported("more than one dataset");
+        };
+        if !self.all_conditions().is_empty() {
+            return unsupported("conditions");
+        }
+        if self.weight_rectype().is_some() || self.weight_variable().is_some() {
+            return unsupported("a weight other than the default");
+        }
+        if self.count_columns() != CountColumns::default() {
+            return unsupported("count columns other than the default");
+        }
+        if self.table_units_of_analysis() != [self.unit_of_analysis()] {
+            return unsupported("tables for other units of analysis");
+        }
+
+        let variables = self.get_request_variables();
+        if variables.iter().any(|rv| rv.category_bins.is_some()) {
+            return unsupported("category bins");
+        }
+        if variables
+            .iter()
+            .any(|rv| rv.attached_variable_pointer.is_some() || rv.adjustment.is_some())
+        {
+            return unsupported("attached or adjusted variables");
+        }
+        let general = variables
+            .iter()
+            .any(|rv| rv.general_detailed_selection == GeneralDetailedSelection::General);
+        let detailed = variables
+            .iter()
+            .any(|rv| rv.general_detailed_selection == GeneralDetailedSelection::Detailed);
+        if general && detailed {
+            return unsupported("both general and detailed variables");
+        }
+
+        let format = match self.effective_output_format() {
+            OutputFormat::FW => return unsupported("fixed-width output"),
+            format => format.as_str().to_string(),
+        };
+
+        let mut args = vec!["tab".to_string(), self.product_name(), sample.name.clone()];
+        args.extend(variables.into_iter().map(|rv| rv.name));
+        args.extend(["--uoa".to_string(), self.unit_of_analysis()]);
+        if general {
+            args.push("--general".to_string());
+        }
+        args.extend(["--format".to_string(), format]);
+        Ok(args)
+    }
+
+    /// List every data file that executing this request will read, for example to stage
+    /// the data before running the request.
+    ///
+    /// This is [DataRequest::required_files_for_dataset] for each requested dataset, in order.
+    fn required_files(
+        &self,
+        ctx: &conventions::Context,
+        input_type: &InputType,
+    ) -> Result<Vec<PathBuf>, MdError> {
+        let mut files = Vec::new();
+        for rs in self.get_request_samples() {
+            files.extend(self.required_files_for_dataset(ctx, &rs.name, input_type)?);
+        }
+        Ok(files)
+    }
+
+    /// List the data files that executing this request will read for one dataset: the
+    /// files for the context's unit of analysis and any other record types that the
+    /// request variables or conditions come from, sorted by record type.
+    fn required_files_for_dataset(
+        &self,
+        ctx: &conventions::Context,
+        dataset_name: &str,
+        input_type: &InputType,
+    ) -> Result<Vec<PathBuf>, MdError> {
+        let mut rectypes = BTreeSet::new();
+        rectypes.extend(self.table_units_of_analysis());
+        for v in self.get_request_variables() {
+            rectypes.insert(v.variable.record_type);
+        }
+        for c in self.all_conditions() {
+            rectypes.insert(c.var.record_type);
+        }
+
+        let mut paths = ctx.paths_from_dataset_name(dataset_name, input_type)?;
+        let mut files = Vec::new();
+        for rt in &rectypes {
+            match paths.remove(rt) {
+                Some(path) => files.push(path),
+                None => {
+                    return Err(metadata_error!(
+                        "no data file for record type {rt} in dataset {dataset_name}"
+                    ))
+                }
+            }
+        }
+        Ok(files)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -301,7 +719,7 @@ pub enum RequestType {
     Extract,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum OutputFormat {
     CSV,
     FW,
@@ -309,7 +727,45 @@ pub enum OutputFormat {
     Html,
 }
 
-#[derive(Clone, Debug)]
+impl FromStr for OutputFormat {
+    type Err = MdError;
+
+    /// Parse an `OutputFormat` from the `output_format` of a JSON request.
+    ///
+    /// The parsing is case-insensitive and accepts the strings "csv", "fw", "json", and "html".
+    ///
+    /// ```
+    /// use cimdea::request::OutputFormat;
+    /// use std::str::FromStr;
+    ///
+    /// let format = OutputFormat::from_str("FW").unwrap();
+    /// assert_eq!(format, OutputFormat::FW);
+    /// ```
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let format = match name.to_ascii_lowercase().as_str() {
+            "csv" => Self::CSV,
+            "fw" => Self::FW,
+            "json" => Self::Json,
+            "html" => Self::Html,
+            _ => return Err(parsing_error!("unknown output_format '{name}'")),
+        };
+        Ok(format)
+    }
+}
+
+impl OutputFormat {
+    /// The name of the format in requests, which [OutputFormat::from_str] parses.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::CSV => "csv",
+            Self::FW => "fw",
+            Self::Json => "json",
+            Self::Html => "html",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum InputType {
     Fw,
     Parquet,
@@ -318,6 +774,17 @@ pub enum InputType {
 }
 
 impl InputType {
+    /// The conventional file extension for data of this type, without a leading '.'.
+    /// [Context](conventions::Context) uses these unless configured otherwise.
+    pub fn default_extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Parquet => "parquet",
+            Self::Fw => "dat.gz",
+            Self::NativeDb => "",
+        }
+    }
+
     pub fn data_sub_directory(&self) -> Option<String> {
         match self {
             Self::Csv => Some("csv".to_string()),
@@ -328,12 +795,67 @@ impl InputType {
     }
 }
 
-// The key point is you can take an impl of a DataRequest and do something with it.
-#[allow(unused)]
-pub fn perform_request(rq: impl DataRequest) -> Result<(), MdError> {
-    todo!("Implement");
+/// The result of [perform_request]: the tables of a tabulation or the record of the files
+/// written for an extract.
+#[derive(Debug)]
+pub enum RequestOutput {
+    Tabulation(tabulate::Tabulation),
+    Extract(extract::ExtractManifest),
+}
+
+impl RequestOutput {
+    /// The tabulation, if the request was a tabulation.
+    pub fn tabulation(&self) -> Option<&tabulate::Tabulation> {
+        match self {
+            Self::Tabulation(tab) => Some(tab),
+            Self::Extract(_) => None,
+        }
+    }
+
+    /// The record of the extract's files, if the request was an extract.
+    pub fn extract(&self) -> Option<&extract::ExtractManifest> {
+        match self {
+            Self::Tabulation(_) => None,
+            Self::Extract(manifest) => Some(manifest),
+        }
+    }
+
+    /// The type of request that produced this output.
+    pub fn request_type(&self) -> RequestType {
+        match self {
+            Self::Tabulation(_) => RequestType::Tabulation,
+            Self::Extract(_) => RequestType::Extract,
+        }
+    }
+}
+
+/// Carry out any request according to its [DataRequest::request_type]. Tabulations run with
+/// [tabulate::tabulate]. Extracts are written to `output_dir` with
+/// [extract::extract_to_files] and the default [extract::ExtractOptions], in files named after
+/// the product, like "usa.csv".
+pub fn perform_request(
+    ctx: &Context,
+    rq: impl DataRequest,
+    output_dir: &Path,
+) -> Result<RequestOutput, MdError> {
+    match rq.request_type() {
+        RequestType::Tabulation => tabulate::tabulate(ctx, rq).map(RequestOutput::Tabulation),
+        RequestType::Extract => {
+            let base_name = rq.product_name();
+            extract::extract_to_files(
+                ctx,
+                rq,
+                output_dir,
+                &base_name,
+                &extract::ExtractOptions::default(),
+            )
+            .map(RequestOutput::Extract)
+        }
+    }
 }
 
+// The unit of analysis may be given as a record type value like "P" or as its name like
+// "Person". Names are matched without regard to case.
 fn validated_unit_of_analysis(
     ctx: &Context,
     unit_of_analysis: Option<String>,
@@ -341,7 +863,13 @@ fn validated_unit_of_analysis(
     let uoa = unit_of_analysis.unwrap_or(ctx.settings.default_unit_of_analysis.value.clone());
 
     // Check that uoa is present for the current context
-    let unit_rectype = match ctx.settings.record_types.get(&uoa) {
+    let by_name = || {
+        ctx.settings
+            .record_types
+            .values()
+            .find(|rt| rt.name.eq_ignore_ascii_case(&uoa))
+    };
+    let unit_rectype = match ctx.settings.record_types.get(&uoa).or_else(by_name) {
         Some(urt) => urt.clone(),
         None => {
             let mut rectype_names = ctx
@@ -362,6 +890,80 @@ fn validated_unit_of_analysis(
     Ok(unit_rectype)
 }
 
+// Check that the record type exists, that it's the unit of analysis or one of its ancestors,
+// and that its weight variable is on it.
+fn validated_weight_rectype(
+    ctx: &Context,
+    unit_of_analysis: &str,
+    weight_rectype: Option<String>,
+) -> Result<Option<String>, MdError> {
+    let Some(rt) = weight_rectype else {
+        return Ok(None);
+    };
+    if !ctx.settings.record_types.contains_key(&rt) {
+        return Err(metadata_error!(
+            "Record type '{rt}' not available for weighting; the record type is not present in the current context"
+        ));
+    }
+    if !is_unit_or_ancestor(ctx, unit_of_analysis, &rt) {
+        return Err(metadata_error!(
+            "Record type '{rt}' can't weight counts of '{unit_of_analysis}' records; it must be the unit of analysis or one of its ancestors"
+        ));
+    }
+    let Some(weight) = ctx.settings.weight_for_rectype(&rt) else {
+        return Err(metadata_error!(
+            "Record type '{rt}' has no weight variable to weight counts with"
+        ));
+    };
+    if ctx.settings.metadata.is_some() {
+        let weight_variable = ctx.get_md_variable_by_name(&weight)?;
+        if weight_variable.record_type != rt {
+            return Err(metadata_error!(
+                "Weight variable {weight} is on record type '{}', not '{rt}'",
+                weight_variable.record_type
+            ));
+        }
+    }
+    Ok(Some(rt))
+}
+
+// Find the weight variable in the loaded metadata and check that it's numeric and on the unit
+// of analysis or one of its ancestors.
+fn validated_weight_variable(
+    ctx: &Context,
+    unit_of_analysis: &str,
+    weight_variable: Option<String>,
+) -> Result<Option<IpumsVariable>, MdError> {
+    let Some(name) = weight_variable else {
+        return Ok(None);
+    };
+    let variable = ctx.get_md_variable_by_name(&name)?;
+    if variable.data_type == Some(IpumsDataType::String) {
+        return Err(metadata_error!(
+            "Weight variable {name} is a string variable, so it can't weight counts"
+        ));
+    }
+    if !is_unit_or_ancestor(ctx, unit_of_analysis, &variable.record_type) {
+        return Err(metadata_error!(
+            "Weight variable {name} is on record type '{}', so it can't weight counts of '{unit_of_analysis}' records; it must be on the unit of analysis or one of its ancestors",
+            variable.record_type
+        ));
+    }
+    Ok(Some(variable))
+}
+
+// Whether `rectype` is the unit of analysis or above it in the record hierarchy, so that each
+// record of the unit of analysis has exactly one `rectype` record to take a weight from.
+fn is_unit_or_ancestor(ctx: &Context, unit_of_analysis: &str, rectype: &str) -> bool {
+    rectype == unit_of_analysis
+        || ctx
+            .settings
+            .record_hierarchy
+            .ancestors(unit_of_analysis)
+            .iter()
+            .any(|ancestor| ancestor == rectype)
+}
+
 /// The Abacus Request type contains variables to tabulate, variables used for conditions and datasets.
 #[derive(Clone, Debug)]
 pub struct AbacusRequest {
@@ -373,6 +975,15 @@ pub struct AbacusRequest {
     pub output_format: OutputFormat,
     pub use_general_variables: bool,
     pub data_root: Option<String>,
+    /// See [DataRequest::weight_rectype].
+    pub weight_rectype: Option<String>,
+    /// See [DataRequest::weight_variable].
+    pub weight_variable: Option<IpumsVariable>,
+    /// Units of analysis for separate tables from each dataset, like "P" and "H" for a
+    /// person table and a household table. Empty means one table for `unit_rectype`.
+    pub table_units: Vec<String>,
+    /// See [DataRequest::count_columns].
+    pub count_columns: CountColumns,
 }
 
 impl DataRequest for AbacusRequest {
@@ -380,6 +991,38 @@ impl DataRequest for AbacusRequest {
         CaseSelectLogic::And
     }
 
+    fn effective_output_format(&self) -> OutputFormat {
+        self.output_format.clone()
+    }
+
+    fn weight_rectype(&self) -> Option<String> {
+        self.weight_rectype.clone()
+    }
+
+    fn weight_variable(&self) -> Option<IpumsVariable> {
+        self.weight_variable.clone()
+    }
+
+    fn count_columns(&self) -> CountColumns {
+        self.count_columns
+    }
+
+    fn table_units_of_analysis(&self) -> Vec<String> {
+        if self.table_units.is_empty() {
+            vec![self.unit_rectype.value.clone()]
+        } else {
+            self.table_units.clone()
+        }
+    }
+
+    fn product_name(&self) -> String {
+        self.product.clone()
+    }
+
+    fn unit_of_analysis(&self) -> String {
+        self.unit_rectype.value.clone()
+    }
+
     fn case_select_unit(&self) -> CaseSelectUnit {
         CaseSelectUnit::Individual
     }
@@ -517,6 +1160,10 @@ impl DataRequest for AbacusRequest {
                 subpopulation: Vec::new(),
                 use_general_variables: false,
                 data_root: optional_data_root,
+                weight_rectype: None,
+                weight_variable: None,
+                table_units: Vec::new(),
+                count_columns: CountColumns::Both,
             },
         ))
     }
@@ -539,6 +1186,15 @@ impl AbacusRequest {
                 )));
             }
         };
+        request.validated_schema_version()?;
+        request.validate_not_empty()?;
+        request.validate_category_bins()?;
+        // Abacus has always produced JSON, so that's the default when no format is given.
+        let output_format = if request.output_format.is_empty() {
+            OutputFormat::Json
+        } else {
+            OutputFormat::from_str(&request.output_format)?
+        };
 
         let mut ctx = conventions::Context::from_ipums_collection_name(
             &request.product,
@@ -586,10 +1242,23 @@ impl AbacusRequest {
             // The category_bins can also come from the IpumsVariable as it's properly part of metadata. However in the request
             // for Abacus we pass category bins on each request for all request variables that need them.
             let bins = request.category_bins.get(&v.variable_mnemonic);
-            let request_var = RequestVariable::try_from_input_request_variable(&ctx, &bins, v)?;
+            let residual_label = request.residual_bins.get(&v.variable_mnemonic).cloned();
+            let pointer = v.attached_variable_pointer.clone();
+            let mut request_var = RequestVariable::try_from_input_request_variable(&ctx, &bins, v)?;
+            request_var.residual_label = residual_label;
+            if let Some(pointer) = pointer {
+                request_var.attach(&ctx, &uoa.value, &pointer.record_type)?;
+            }
             rqv.push(request_var);
         }
 
+        let weight_rectype = validated_weight_rectype(&ctx, &uoa.value, request.weight_rectype)?;
+        let weight_variable = validated_weight_variable(&ctx, &uoa.value, request.weight_variable)?;
+        let mut table_units = Vec::new();
+        for unit in request.table_uoas {
+            table_units.push(validated_unit_of_analysis(&ctx, Some(unit))?.value);
+        }
+
         let mut subpop = Vec::new();
         for s in request.subpopulation {
             let bins = request.category_bins.get(&s.variable_mnemonic);
@@ -604,10 +1273,14 @@ impl AbacusRequest {
                 request_variables: rqv,
                 request_samples: rqs,
                 subpopulation: subpop,
-                output_format: OutputFormat::Json,
+                output_format,
                 use_general_variables: true,
                 unit_rectype: uoa.clone(),
                 data_root: request.data_root,
+                weight_rectype,
+                weight_variable,
+                table_units,
+                count_columns: request.count_columns,
             },
         ))
     }
@@ -615,10 +1288,10 @@ impl AbacusRequest {
 
 /// A simple tabulation request which can probably describe 90% of use cases.
 ///
-/// In a ComplexRequest, Variables could have attached variables or monetary standardization
-/// adjustment factors, datasets could have sub-sample sizes or other attrributes. Here with a
-/// SimpleRequest we're requesting either a tabulation from the given sources or an extract of data
-/// of same.
+/// In a [ComplexRequest], Variables can be attached variables or have monetary
+/// standardization adjustment factors, and datasets could have sub-sample sizes or other
+/// attrributes. Here with a SimpleRequest we're requesting either a tabulation from the given
+/// sources or an extract of data of same.
 ///
 /// When constructing a request or simple request, we may begin with only variable names and
 /// dataset names. We must have a minimum set of metadata to build the IpumsVariable and
@@ -637,13 +1310,68 @@ pub struct SimpleRequest {
     pub output_format: OutputFormat,
     pub conditions: Option<Vec<Condition>>,
     pub use_general_variables: GeneralDetailedSelection,
+    /// See [DataRequest::weight_rectype] and [SimpleRequest::set_weight_rectype].
+    pub weight_rectype: Option<String>,
+    /// See [DataRequest::weight_variable] and [SimpleRequest::set_weight_variable].
+    pub weight_variable: Option<IpumsVariable>,
+    /// Units of analysis for separate tables from each dataset, like "P" and "H" for a
+    /// person table and a household table. Empty means one table for `unit_rectype`.
+    pub table_units: Vec<String>,
+    /// See [DataRequest::count_columns].
+    pub count_columns: CountColumns,
+    /// How the [SimpleRequest::conditions] and [SimpleRequest::case_selections] combine.
+    pub case_select_logic: CaseSelectLogic,
+    /// Case selections on request variables by variable name, like the
+    /// `request_case_selections` of IPUMS JSON. They're the `case_selection` of the
+    /// request variables from [SimpleRequest::get_request_variables].
+    pub case_selections: BTreeMap<String, Condition>,
+    /// General or detailed selections of request variables by variable name, like the
+    /// `general_detailed_selection` of each request variable in IPUMS JSON. They take
+    /// precedence over [SimpleRequest::use_general_variables].
+    pub general_detailed_selections: BTreeMap<String, GeneralDetailedSelection>,
 }
 
 // The new() and some setup stuff is particular to the SimpleRequest or the more complex types of requests.
 
 impl DataRequest for SimpleRequest {
     fn case_select_logic(&self) -> CaseSelectLogic {
-        CaseSelectLogic::And
+        self.case_select_logic
+    }
+
+    fn effective_output_format(&self) -> OutputFormat {
+        self.output_format.clone()
+    }
+
+    fn weight_rectype(&self) -> Option<String> {
+        self.weight_rectype.clone()
+    }
+
+    fn weight_variable(&self) -> Option<IpumsVariable> {
+        self.weight_variable.clone()
+    }
+
+    fn count_columns(&self) -> CountColumns {
+        self.count_columns
+    }
+
+    fn table_units_of_analysis(&self) -> Vec<String> {
+        if self.table_units.is_empty() {
+            vec![self.unit_rectype.value.clone()]
+        } else {
+            self.table_units.clone()
+        }
+    }
+
+    fn product_name(&self) -> String {
+        self.product.clone()
+    }
+
+    fn unit_of_analysis(&self) -> String {
+        self.unit_rectype.value.clone()
+    }
+
+    fn request_type(&self) -> RequestType {
+        self.request_type.clone()
     }
 
     fn case_select_unit(&self) -> CaseSelectUnit {
@@ -680,6 +1408,13 @@ impl DataRequest for SimpleRequest {
                 output_format: OutputFormat::CSV,
                 conditions: None,
                 use_general_variables: GeneralDetailedSelection::Detailed,
+                weight_rectype: None,
+                weight_variable: None,
+                table_units: Vec::new(),
+                count_columns: CountColumns::Both,
+                case_select_logic: CaseSelectLogic::And,
+                case_selections: BTreeMap::new(),
+                general_detailed_selections: BTreeMap::new(),
             },
         ))
     }
@@ -691,8 +1426,14 @@ impl DataRequest for SimpleRequest {
         self.variables
             .iter()
             .map(|v| {
-                RequestVariable::try_from_ipums_variable(v, self.use_general_variables.clone())
-                    .expect("Broken metadata.")
+                let use_general = self
+                    .general_detailed_selections
+                    .get(&*v.name)
+                    .unwrap_or(&self.use_general_variables);
+                let mut rv = RequestVariable::try_from_ipums_variable(v, use_general.clone())
+                    .expect("Broken metadata.");
+                rv.case_selection = self.case_selections.get(&*v.name).cloned();
+                rv
             })
             .collect()
     }
@@ -727,6 +1468,12 @@ impl DataRequest for SimpleRequest {
         let Some(product) = parsed["product"].as_str() else {
             return Err(parsing_error!("no 'product' in request"));
         };
+        if !product.eq_ignore_ascii_case(&ctx.name) {
+            return Err(MdError::Msg(format!(
+                "The request is for product '{product}' but the context is for product '{}'",
+                ctx.name
+            )));
+        }
 
         let Some(details) = parsed["details"].as_object() else {
             return Err(parsing_error!("no 'details' in request"));
@@ -740,29 +1487,62 @@ impl DataRequest for SimpleRequest {
             return Err(parsing_error!("expected a request_variables array"));
         };
 
-        let Some(_output_format) = details["output_format"].as_str() else {
+        let Some(output_format) = details["output_format"].as_str() else {
             return Err(parsing_error!("no 'output_format' in request"));
         };
+        let output_format = OutputFormat::from_str(output_format)?;
 
-        let Some(_case_select_logic) = details["case_select_logic"].as_str() else {
+        let Some(case_select_logic) = details["case_select_logic"].as_str() else {
             return Err(parsing_error!("no 'case_select_logic' in request"));
         };
+        let case_select_logic = CaseSelectLogic::from_str(case_select_logic)?;
 
         let variables = if let Some(ref md) = ctx.settings.metadata {
             let mut checked_vars = Vec::new();
             for (index, v) in request_variables.iter().enumerate() {
-                let Some(variable_mnemonic) = v["variable_mnemonic"].as_str() else {
-                    return Err(parsing_error!(
-                        "no 'variable_mnemonic' for request variable {index}"
-                    ));
+                let variable_mnemonic = v["variable_mnemonic"].as_str();
+                // Variables may be identified by their stable numeric id instead of, or in
+                // addition to, their mnemonic.
+                let variable_id = match v["variable_id"] {
+                    serde_json::Value::Null => None,
+                    ref value => match value.as_u64() {
+                        Some(id) => Some(id as usize),
+                        None => {
+                            return Err(parsing_error!(
+                                "'variable_id' for request variable {index} must be a non-negative integer"
+                            ))
+                        }
+                    },
                 };
 
-                if let Some(var_value) = md.cloned_variable_from_name(variable_mnemonic) {
-                    checked_vars.push(var_value);
-                } else {
-                    return Err(metadata_error!(
-                        "No variable '{variable_mnemonic}' in metadata."
-                    ));
+                match (variable_id, variable_mnemonic) {
+                    (Some(var_id), _) if var_id >= md.variables_index.len() => {
+                        return Err(metadata_error!("No variable with id {var_id} in metadata."));
+                    }
+                    (Some(var_id), mnemonic) => {
+                        let var_value = md.cloned_variable_from_id(var_id);
+                        if let Some(mnemonic) = mnemonic {
+                            if mnemonic != &*var_value.name {
+                                return Err(parsing_error!(
+                                    "request variable {index} has variable_id {var_id}, which is variable '{}', but its variable_mnemonic is '{mnemonic}'",
+                                    var_value.name
+                                ));
+                            }
+                        }
+                        checked_vars.push(var_value);
+                    }
+                    (None, Some(mnemonic)) => {
+                        if let Some(var_value) = md.cloned_variable_from_name(mnemonic) {
+                            checked_vars.push(var_value);
+                        } else {
+                            return Err(metadata_error!("No variable '{mnemonic}' in metadata."));
+                        }
+                    }
+                    (None, None) => {
+                        return Err(parsing_error!(
+                            "no 'variable_mnemonic' or 'variable_id' for request variable {index}"
+                        ));
+                    }
                 }
             }
             checked_vars
@@ -770,6 +1550,47 @@ impl DataRequest for SimpleRequest {
             return Err(metadata_error!("Metadata for context not yet set up."));
         };
 
+        // Like an AbacusRequest, the request gives the general width of a variable as its
+        // extract_width, since general widths aren't in all metadata.
+        let mut variables = variables;
+        let mut general_detailed_selections = BTreeMap::new();
+        for (index, (v, var)) in request_variables.iter().zip(&mut variables).enumerate() {
+            if let Some(width) = v["extract_width"].as_u64().filter(|w| *w > 0) {
+                var.general_width = Some(width as usize);
+            }
+            let selection: Option<GeneralDetailedSelection> =
+                serde_json::from_value(v["general_detailed_selection"].clone()).map_err(|err| {
+                    parsing_error!(
+                        "invalid 'general_detailed_selection' for request variable {index}: {err}"
+                    )
+                })?;
+            let selection = selection.unwrap_or_default();
+            // Check for a general width now, since get_request_variables() can't fail.
+            RequestVariable::try_from_ipums_variable(var, selection.clone())?;
+            general_detailed_selections.insert(var.name.to_string(), selection);
+        }
+
+        // Each request variable with case selections gets one condition, which becomes the
+        // case_selection of its RequestVariable. The conditions combine with the request's
+        // case_select_logic.
+        let mut variable_case_selections = BTreeMap::new();
+        for (index, (v, var)) in request_variables.iter().zip(&variables).enumerate() {
+            if !v["case_selection"].as_bool().unwrap_or(false) {
+                continue;
+            }
+            let case_selections: Vec<RequestCaseSelection> =
+                serde_json::from_value(v["request_case_selections"].clone()).map_err(|err| {
+                    parsing_error!(
+                        "invalid 'request_case_selections' for request variable {index}: {err}"
+                    )
+                })?;
+            if let Some(condition) =
+                Condition::try_from_request_case_selections(var, &case_selections)?
+            {
+                variable_case_selections.insert(var.name.to_string(), condition);
+            }
+        }
+
         let datasets = if let Some(ref md) = ctx.settings.metadata {
             let mut checked_samples = Vec::new();
             for (index, d) in request_samples.iter().enumerate() {
@@ -788,9 +1609,13 @@ impl DataRequest for SimpleRequest {
             return Err(metadata_error!("Metadata for context not yet set up."));
         };
 
-        let output_format = OutputFormat::CSV;
-
-        let unit_of_analysis = None;
+        let unit_of_analysis = match details.get("focal_record_type") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(rectype) => match rectype.as_str() {
+                Some(rectype) => Some(rectype.to_string()),
+                None => return Err(parsing_error!("'focal_record_type' must be a string")),
+            },
+        };
         let unit_rectype = validated_unit_of_analysis(&ctx, unit_of_analysis)?;
 
         Ok(Self {
@@ -802,54 +1627,1240 @@ impl DataRequest for SimpleRequest {
             output_format,
             conditions: None,
             use_general_variables: GeneralDetailedSelection::Detailed,
+            weight_rectype: None,
+            weight_variable: None,
+            table_units: Vec::new(),
+            count_columns: CountColumns::Both,
+            case_select_logic,
+            case_selections: variable_case_selections,
+            general_detailed_selections,
         })
     }
 
+    /// The request as the generic IPUMS JSON that [SimpleRequest::deserialize_from_ipums_json]
+    /// reads: the `product` and a `details` object with the `request_samples`,
+    /// `request_variables`, `output_format`, `case_select_logic` and `focal_record_type`.
+    /// Each request variable has its general or detailed selection and case selections, and a
+    /// null `attached_variable_pointer`.
+    /// Case selections which `request_case_selections` can't give, like a
+    /// [crate::query_gen::CompareOperation::IsNull], are left out, as are the
+    /// [SimpleRequest::conditions].
     fn serialize_to_ipums_json(&self) -> String {
-        "".to_string()
+        ipums_json(self)
     }
 
     fn print_stata(&self) -> String {
         "".to_string()
     }
 
+    /// List each request variable with its label, width and data type, followed by the
+    /// labels of its codes. Variables are grouped by record type in the order of the
+    /// product's record hierarchy, so household variables come before person variables.
+    /// Variables loaded only from layout files have no labels, and their sections say so.
     fn print_codebook(&self) -> String {
-        "".to_string()
+        codebook_text(&self.product, &self.get_request_variables())
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    pub fn test_deserialize_into_simple_request() {
-        let data_root = String::from("tests/data_root");
-        let mut ctx =
-            conventions::Context::from_ipums_collection_name("usa", None, Some(data_root))
-                .expect("should be able to load context for USA");
-
-        // Load the mentioned datasets and all their associated variables into metadata
-        ctx.load_metadata_for_datasets(&["us2016c", "us2014d"])
-            .expect("should be able to load metadata for datasets");
-        if let Some(ref md) = ctx.settings.metadata {
-            println!("loaded {} variables.", md.variables_index.len());
+// The generic IPUMS JSON of a request, which both SimpleRequest::serialize_to_ipums_json and
+// ComplexRequest::serialize_to_ipums_json write. The attached_variable_pointer of each request
+// variable is only ever set for a ComplexRequest.
+fn ipums_json<R: DataRequest>(rq: &R) -> String {
+    let case_select_logic = match rq.case_select_logic() {
+        CaseSelectLogic::And => "and",
+        CaseSelectLogic::Or => "or",
+    };
+    let request_samples: Vec<serde_json::Value> = rq
+        .get_request_samples()
+        .iter()
+        .map(|s| serde_json::json!({ "name": s.name }))
+        .collect();
+    let request_variables: Vec<serde_json::Value> = rq
+        .get_request_variables()
+        .iter()
+        .map(|v| {
+            let case_selections = v
+                .case_selection
+                .as_ref()
+                .and_then(|c| c.to_request_case_selections());
+            let general_width = v.is_general().then_some(v.variable.general_width).flatten();
+            serde_json::json!({
+                "variable_mnemonic": v.name,
+                "mnemonic": v.name,
+                "general_detailed_selection": v.general_detailed_selection,
+                "attached_variable_pointer": v.attached_variable_pointer,
+                "case_selection": case_selections.is_some(),
+                "request_case_selections": case_selections.unwrap_or_default(),
+                "extract_width": general_width.unwrap_or(0),
+            })
+        })
+        .collect();
+
+    let request = serde_json::json!({
+        "product": rq.product_name(),
+        "details": {
+            "focal_record_type": rq.unit_of_analysis(),
+            "output_format": rq.effective_output_format().as_str(),
+            "case_select_logic": case_select_logic,
+            "request_samples": request_samples,
+            "request_variables": request_variables,
+        },
+    });
+    request.to_string()
+}
 
-            for _v in &md.variables_index {
-                //println!("{}",v.name);
+// The text of a codebook listing each request variable with its label, width and data type,
+// followed by the labels of its codes. See SimpleRequest::print_codebook.
+fn codebook_text(product: &str, request_variables: &[RequestVariable]) -> String {
+    let settings = defaults::defaults_for(product).ok();
+    let mut rectypes = settings
+        .as_ref()
+        .map(|s| s.record_hierarchy.ordered_rectypes())
+        .unwrap_or_default();
+    let mut other_rectypes: Vec<String> = request_variables
+        .iter()
+        .map(|v| v.variable.record_type.clone())
+        .filter(|rt| !rectypes.contains(rt))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    rectypes.append(&mut other_rectypes);
+
+    let mut lines = vec![format!("Codebook for {product}")];
+    for rt in rectypes {
+        let variables: Vec<&RequestVariable> = request_variables
+            .iter()
+            .filter(|v| v.variable.record_type == rt)
+            .collect();
+        if variables.is_empty() {
+            continue;
+        }
+        let rectype_name = settings
+            .as_ref()
+            .and_then(|s| s.record_types.get(&rt))
+            .map(|r| r.name.clone())
+            .unwrap_or_else(|| rt.clone());
+        lines.push(String::new());
+        lines.push(format!("{rectype_name} variables ({rt}):"));
+
+        for v in variables {
+            lines.push(String::new());
+            match v.variable.label {
+                Some(ref label) => lines.push(format!("{}\t{label}", v.name)),
+                None => lines.push(v.name.clone()),
+            }
+            let width = v
+                .requested_width()
+                .map(|w| w.to_string())
+                .unwrap_or_else(|_| "unknown".to_string());
+            let data_type = v
+                .data_type()
+                .map(|t| t.to_string())
+                .unwrap_or_else(|| "unknown".to_string());
+            lines.push(format!("\tWidth: {width}, Type: {data_type}"));
+
+            if let Some(ref bins) = v.category_bins {
+                for bin in bins {
+                    lines.push(format!("\t{}\t{}", bin.code(), bin.label()));
+                }
+                match v.residual_label() {
+                    Some(label) => lines.push(format!("\t{UNBINNED_CODE}\t{label}")),
+                    None => lines.push(format!("\t\t{UNBINNED_LABEL}")),
+                }
+            } else if let Some(ref categories) = v.variable.categories {
+                for category in categories {
+                    lines.push(format!(
+                        "\t{}\t{}",
+                        category_code(&category.value),
+                        category.label()
+                    ));
+                }
+            } else {
+                lines.push("\tLabels unavailable: only layout metadata is loaded.".to_string());
             }
         }
+    }
+
+    lines.join("\n")
+}
+
+// A category's code as it appears in data, like "6" or "1.5".
+fn category_code(value: &IpumsValue) -> String {
+    match value {
+        IpumsValue::Integer(code) => code.to_string(),
+        IpumsValue::Float(code) => code.clone(),
+        IpumsValue::String { value, .. } => String::from_utf8_lossy(value).to_string(),
+        IpumsValue::Fixed { point, base } => {
+            format!("{:.*}", *point, *base as f64 / 10f64.powi(*point as i32))
+        }
+    }
+}
+
+impl SimpleRequest {
+    /// Weight the counts with the weight variable of the given record type instead of the
+    /// unit of analysis, like "H" to count persons with the household weight. The record
+    /// type must be the unit of analysis or one of its ancestors, and its weight variable
+    /// must be on it.
+    pub fn set_weight_rectype(
+        &mut self,
+        ctx: &Context,
+        weight_rectype: &str,
+    ) -> Result<(), MdError> {
+        self.weight_rectype = validated_weight_rectype(
+            ctx,
+            &self.unit_rectype.value,
+            Some(weight_rectype.to_string()),
+        )?;
+        Ok(())
+    }
+
+    /// Weight the counts with the variable named `weight_variable` instead of the default
+    /// weight of its record type, like the replicate weight "REPWTP1". The variable must be
+    /// in the loaded metadata and on the unit of analysis or one of its ancestors. Tabulating
+    /// a dataset without it is an error.
+    pub fn set_weight_variable(
+        &mut self,
+        ctx: &Context,
+        weight_variable: &str,
+    ) -> Result<(), MdError> {
+        self.weight_variable = validated_weight_variable(
+            ctx,
+            &self.unit_rectype.value,
+            Some(weight_variable.to_string()),
+        )?;
+        Ok(())
+    }
+
+    /// Replace the category bins of the request variable named `variable` with bins
+    /// computed from its values in the data, like four quantile bins of AGE. The `options`
+    /// say where the data is, as for tabulating. See [tabulate::auto_bins].
+    pub fn auto_bin(
+        &mut self,
+        ctx: &Context,
+        variable: &str,
+        auto_bin: &AutoBin,
+        options: &TabulateOptions,
+    ) -> Result<(), MdError> {
+        let bins = tabulate::auto_bins(ctx, self, variable, auto_bin, options)?;
+        let Some(v) = self.variables.iter_mut().find(|v| &*v.name == variable) else {
+            return Err(metadata_error!(
+                "Can't automatically bin {variable}: it isn't a request variable"
+            ));
+        };
+        v.category_bins = Some(bins);
+        Ok(())
+    }
+
+    /// The joins a query for this request makes to connect its unit of analysis to the
+    /// record types of its variables, conditions and weight, in order. See
+    /// [query_gen::join_plan].
+    pub fn join_plan(&self, ctx: &Context) -> Result<Vec<JoinStep>, MdError> {
+        let uoa = self.unit_of_analysis();
+        let mut rectypes: HashSet<String> = self
+            .variables
+            .iter()
+            .map(|v| v.record_type.clone())
+            .chain(self.all_conditions().into_iter().map(|c| c.var.record_type))
+            .collect();
+        rectypes.insert(self.weight_rectype.clone().unwrap_or(uoa.clone()));
+        query_gen::join_plan(ctx, &uoa, &rectypes)
+    }
+}
+
+/// A request whose variables can have more to them than a [SimpleRequest]'s:
+///
+/// * Attached variables, which are on a record type above the unit of analysis. Each unit of
+///   analysis record gets their values from the record it belongs to, like the household's
+///   HHINCOME on each person record. See [ComplexRequest::attach_variable].
+/// * Monetary standardization, which multiplies the values of a variable by a factor for
+///   each dataset, like INCWAGE in constant dollars. See [ComplexRequest::adjust_variable].
+#[derive(Clone, Debug)]
+pub struct ComplexRequest {
+    pub product: String, // name of data collection
+    pub datasets: Vec<IpumsDataset>,
+    pub request_variables: Vec<RequestVariable>,
+    pub unit_rectype: ipums_data_model::RecordType,
+    pub request_type: RequestType,
+    pub output_format: OutputFormat,
+    pub conditions: Option<Vec<Condition>>,
+    /// See [DataRequest::weight_rectype].
+    pub weight_rectype: Option<String>,
+    /// See [DataRequest::weight_variable].
+    pub weight_variable: Option<IpumsVariable>,
+    /// Units of analysis for separate tables from each dataset, like "P" and "H" for a
+    /// person table and a household table. Empty means one table for `unit_rectype`.
+    pub table_units: Vec<String>,
+    /// See [DataRequest::count_columns].
+    pub count_columns: CountColumns,
+    /// How the case selections of the request variables and the [ComplexRequest::conditions]
+    /// combine.
+    pub case_select_logic: CaseSelectLogic,
+}
+
+impl From<SimpleRequest> for ComplexRequest {
+    fn from(rq: SimpleRequest) -> Self {
+        Self {
+            request_variables: rq.get_request_variables(),
+            product: rq.product,
+            datasets: rq.datasets,
+            unit_rectype: rq.unit_rectype,
+            request_type: rq.request_type,
+            output_format: rq.output_format,
+            conditions: rq.conditions,
+            weight_rectype: rq.weight_rectype,
+            weight_variable: rq.weight_variable,
+            table_units: rq.table_units,
+            count_columns: rq.count_columns,
+            case_select_logic: rq.case_select_logic,
+        }
+    }
+}
+
+impl DataRequest for ComplexRequest {
+    fn case_select_logic(&self) -> CaseSelectLogic {
+        self.case_select_logic
+    }
+
+    fn effective_output_format(&self) -> OutputFormat {
+        self.output_format.clone()
+    }
+
+    fn weight_rectype(&self) -> Option<String> {
+        self.weight_rectype.clone()
+    }
+
+    fn weight_variable(&self) -> Option<IpumsVariable> {
+        self.weight_variable.clone()
+    }
+
+    fn count_columns(&self) -> CountColumns {
+        self.count_columns
+    }
+
+    fn table_units_of_analysis(&self) -> Vec<String> {
+        if self.table_units.is_empty() {
+            vec![self.unit_rectype.value.clone()]
+        } else {
+            self.table_units.clone()
+        }
+    }
+
+    fn product_name(&self) -> String {
+        self.product.clone()
+    }
+
+    fn unit_of_analysis(&self) -> String {
+        self.unit_rectype.value.clone()
+    }
+
+    fn request_type(&self) -> RequestType {
+        self.request_type.clone()
+    }
+
+    fn case_select_unit(&self) -> CaseSelectUnit {
+        CaseSelectUnit::Individual
+    }
+
+    fn from_names(
+        product: &str,
+        requested_datasets: &[&str],
+        requested_variables: &[&str],
+        unit_of_analysis: Option<String>,
+        optional_product_root: Option<String>,
+        optional_data_root: Option<String>,
+    ) -> Result<(conventions::Context, Self), MdError> {
+        let (ctx, rq) = SimpleRequest::from_names(
+            product,
+            requested_datasets,
+            requested_variables,
+            unit_of_analysis,
+            optional_product_root,
+            optional_data_root,
+        )?;
+        Ok((ctx, rq.into()))
+    }
+
+    fn get_request_variables(&self) -> Vec<RequestVariable> {
+        self.request_variables.clone()
+    }
+
+    fn get_request_samples(&self) -> Vec<RequestSample> {
+        self.datasets
+            .iter()
+            .map(RequestSample::from_ipums_dataset)
+            .collect()
+    }
+
+    fn get_conditions(&self) -> Option<Vec<Condition>> {
+        self.conditions.clone()
+    }
+
+    /// Read the request like [SimpleRequest::deserialize_from_ipums_json], and also attach
+    /// the request variables which have an `attached_variable_pointer` like
+    /// `{"record_type": "H"}`.
+    #[allow(refining_impl_trait)]
+    fn deserialize_from_ipums_json(
+        ctx: &conventions::Context,
+        request_type: RequestType,
+        json_request: &str,
+    ) -> Result<Self, MdError> {
+        let mut rq: Self =
+            SimpleRequest::deserialize_from_ipums_json(ctx, request_type, json_request)?.into();
+
+        let parsed: serde_json::Value = serde_json::from_str(json_request)
+            .map_err(|err| parsing_error!("Error deserializing request: '{err}'"))?;
+        let uoa = rq.unit_of_analysis();
+        let request_variables = parsed["details"]["request_variables"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        for (index, (v, request_var)) in request_variables
+            .into_iter()
+            .zip(rq.request_variables.iter_mut())
+            .enumerate()
+        {
+            let pointer: Option<AttachedVariablePointer> =
+                serde_json::from_value(v["attached_variable_pointer"].clone()).map_err(|err| {
+                    parsing_error!(
+                        "invalid 'attached_variable_pointer' for request variable {index}: {err}"
+                    )
+                })?;
+            if let Some(pointer) = pointer {
+                request_var.attach(ctx, &uoa, &pointer.record_type)?;
+            }
+        }
+        Ok(rq)
+    }
+
+    /// The request as the generic IPUMS JSON that [ComplexRequest::deserialize_from_ipums_json]
+    /// reads. It's the JSON that [SimpleRequest::serialize_to_ipums_json] writes, except that
+    /// attached variables have an `attached_variable_pointer`.
+    fn serialize_to_ipums_json(&self) -> String {
+        ipums_json(self)
+    }
+
+    fn print_stata(&self) -> String {
+        "".to_string()
+    }
+
+    /// List each request variable like [SimpleRequest::print_codebook]. Attached variables
+    /// are listed with the other variables of their record type.
+    fn print_codebook(&self) -> String {
+        codebook_text(&self.product, &self.request_variables)
+    }
+}
+
+impl ComplexRequest {
+    /// Add the variable named `variable` to the request, attached to the records of the
+    /// unit of analysis. The variable must be on a record type above the unit of analysis,
+    /// like the household variable HHINCOME when the unit of analysis is persons. Queries
+    /// join each unit of analysis record to the record the variable's value comes from.
+    pub fn attach_variable(&mut self, ctx: &Context, variable: &str) -> Result<(), MdError> {
+        let var = ctx.get_md_variable_by_name(variable)?;
+        let mut request_var =
+            RequestVariable::try_from_ipums_variable(&var, GeneralDetailedSelection::Detailed)?;
+        request_var.attach(ctx, &self.unit_rectype.value, &var.record_type)?;
+        self.request_variables.push(request_var);
+        Ok(())
+    }
+
+    /// Multiply the values of the request variable named `variable` by a factor for each
+    /// dataset before tabulating or binning them, so that monetary values like INCWAGE are
+    /// comparable across datasets. `factors` maps dataset names to factors, and must have a
+    /// positive factor for every dataset in the request. The category bins of the variable
+    /// apply to the adjusted values. Codes which the variable's categories mark as missing,
+    /// N/A or not in universe, like INCWAGE's 999999, aren't adjusted.
+    pub fn adjust_variable(
+        &mut self,
+        variable: &str,
+        factors: BTreeMap<String, f64>,
+    ) -> Result<(), MdError> {
+        if let Some(ds) = self
+            .datasets
+            .iter()
+            .find(|ds| !factors.contains_key(&*ds.name))
+        {
+            return Err(metadata_error!(
+                "Can't adjust {variable}: there's no adjustment factor for dataset {}",
+                ds.name
+            ));
+        }
+        if let Some((dataset, factor)) = factors
+            .iter()
+            .find(|(_, factor)| !(factor.is_finite() && **factor > 0.0))
+        {
+            return Err(metadata_error!(
+                "Can't adjust {variable}: the adjustment factor {factor} for dataset {dataset} isn't a positive number"
+            ));
+        }
+        let Some(v) = self
+            .request_variables
+            .iter_mut()
+            .find(|v| v.name == variable)
+        else {
+            return Err(metadata_error!(
+                "Can't adjust {variable}: it isn't a request variable"
+            ));
+        };
+        if v.data_type() == Some(IpumsDataType::String) {
+            return Err(metadata_error!(
+                "Can't adjust {variable}: it's a string variable"
+            ));
+        }
+        if v.is_general() {
+            return Err(metadata_error!(
+                "Can't adjust {variable}: only the detailed version of a variable can be adjusted"
+            ));
+        }
+        v.adjustment = Some(MonetaryAdjustment { factors });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ipums_metadata_model::{IpumsCategory, UniversalCategoryType};
+    use crate::tabulate::PercentBase;
+
+    #[test]
+    pub fn test_deserialize_into_simple_request() {
+        let data_root = String::from("tests/data_root");
+        let mut ctx =
+            conventions::Context::from_ipums_collection_name("usa", None, Some(data_root))
+                .expect("should be able to load context for USA");
+
+        // Load the mentioned datasets and all their associated variables into metadata
+        ctx.load_metadata_for_datasets(&["us2016c", "us2014d"])
+            .expect("should be able to load metadata for datasets");
+        if let Some(ref md) = ctx.settings.metadata {
+            println!("loaded {} variables.", md.variables_index.len());
+
+            for _v in &md.variables_index {
+                //println!("{}",v.name);
+            }
+        }
+
+        let json_request = include_str!("../tests/requests/usa_extract.json");
+        let simple_request =
+            SimpleRequest::deserialize_from_ipums_json(&ctx, RequestType::Extract, &json_request);
+        if let Err(ref e) = simple_request {
+            eprintln!("Parsing error in test: '{}'", e);
+        }
+        assert!(simple_request.is_ok());
+        if let Ok(rq) = simple_request {
+            assert_eq!(rq.product, "usa");
+        }
+    }
+
+    #[test]
+    fn test_serialize_to_ipums_json_round_trip() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us2016b"],
+            &["MARST", "AGE", "RELATE"],
+            None,
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.unit_rectype = ctx.settings.record_types["H"].clone();
+        rq.variables[2].general_width = Some(2);
+        rq.general_detailed_selections
+            .insert("RELATE".to_string(), GeneralDetailedSelection::General);
+        let marst_selection = Condition::try_from_request_case_selections(
+            &rq.variables[0],
+            &[
+                RequestCaseSelection::Between(1, 1),
+                RequestCaseSelection::Between(3, 5),
+            ],
+        )
+        .expect("should make the case selection")
+        .expect("should have a condition");
+        rq.case_selections
+            .insert("MARST".to_string(), marst_selection);
+
+        let json = rq.serialize_to_ipums_json();
+        let parsed: serde_json::Value =
+            serde_json::from_str(&json).expect("should serialize to valid JSON");
+        assert_eq!(parsed["product"], "usa");
+        assert_eq!(
+            parsed["details"]["request_variables"][0]["variable_mnemonic"],
+            "MARST"
+        );
+
+        let round_trip =
+            SimpleRequest::deserialize_from_ipums_json(&ctx, RequestType::Tabulation, &json)
+                .expect("should deserialize the serialized request");
+        let names = |rq: &SimpleRequest| -> (Vec<String>, Vec<String>) {
+            (
+                rq.variables.iter().map(|v| v.name.to_string()).collect(),
+                rq.datasets.iter().map(|d| d.name.to_string()).collect(),
+            )
+        };
+        assert_eq!(names(&round_trip), names(&rq));
+        assert_eq!(round_trip.output_format, rq.output_format);
+        assert_eq!(round_trip.unit_of_analysis(), "H");
+
+        let selections = |rq: &SimpleRequest| -> Vec<(bool, Option<String>)> {
+            rq.get_request_variables()
+                .iter()
+                .map(|rv| {
+                    (
+                        rv.is_general(),
+                        rv.case_selection.as_ref().map(|c| c.to_sql()),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(selections(&round_trip), selections(&rq));
+        assert_eq!(
+            selections(&round_trip),
+            vec![
+                (
+                    false,
+                    Some("(MARST = 1) or (MARST between 3 and 5)".to_string())
+                ),
+                (false, None),
+                (true, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deserialize_mismatched_product_error() {
+        let ctx = conventions::Context::from_ipums_collection_name(
+            "usa",
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should be able to load context for USA");
+
+        let json_request = include_str!("../tests/requests/usa_extract.json").replacen(
+            "\"product\": \"usa\"",
+            "\"product\": \"cps\"",
+            1,
+        );
+        let err =
+            SimpleRequest::deserialize_from_ipums_json(&ctx, RequestType::Extract, &json_request)
+                .expect_err("should not deserialize a CPS request with a USA context");
+        assert_eq!(
+            err.to_string(),
+            "The request is for product 'cps' but the context is for product 'usa'"
+        );
+    }
+
+    #[test]
+    fn test_set_weight_rectype_unknown_record_type_error() {
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should be able to set up the request");
+        assert!(rq.set_weight_rectype(&ctx, "Z").is_err());
+        assert_eq!(rq.weight_rectype, None);
+
+        rq.set_weight_rectype(&ctx, "H")
+            .expect("HHWT should be on the household record");
+        assert_eq!(rq.weight_rectype(), Some("H".to_string()));
+    }
+
+    #[test]
+    fn test_set_weight_rectype_below_unit_of_analysis_error() {
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ"],
+            Some("H".to_string()),
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should be able to set up the request");
+        let err = rq
+            .set_weight_rectype(&ctx, "P")
+            .expect_err("persons are below the household unit of analysis");
+        assert_eq!(
+            err.to_string(),
+            "metadata error: Record type 'P' can't weight counts of 'H' records; it must be the unit of analysis or one of its ancestors"
+        );
+        assert_eq!(rq.weight_rectype, None);
+
+        rq.set_weight_rectype(&ctx, "H")
+            .expect("the unit of analysis can weight its own counts");
+        assert_eq!(rq.weight_rectype(), Some("H".to_string()));
+    }
+
+    #[test]
+    fn test_set_weight_variable_below_unit_of_analysis_error() {
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ"],
+            Some("H".to_string()),
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should be able to set up the request");
+        let err = rq
+            .set_weight_variable(&ctx, "REPWTP1")
+            .expect_err("REPWTP1 is on the person record, below the household unit of analysis");
+        assert_eq!(
+            err.to_string(),
+            "metadata error: Weight variable REPWTP1 is on record type 'P', so it can't weight counts of 'H' records; it must be on the unit of analysis or one of its ancestors"
+        );
+        assert!(rq.weight_variable.is_none());
+    }
+
+    #[test]
+    fn test_abacus_request_weight_variable_below_unit_of_analysis_error() {
+        let mut request =
+            input_schema_tabulation::AbacusRequest::template("usa", &["us2015b"], &["GQ"])
+                .expect("should make a template request");
+        request.data_root = Some("tests/data_root".to_string());
+        request.uoa = "H".to_string();
+        request.weight_variable = Some("REPWTP1".to_string());
+        let json = serde_json::to_string(&request).expect("should serialize the request");
+
+        let err =
+            AbacusRequest::try_from_json(&json).expect_err("REPWTP1 can't weight household counts");
+        assert!(
+            err.to_string()
+                .contains("Weight variable REPWTP1 is on record type 'P'"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_set_weight_variable_not_in_metadata_error() {
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should be able to set up the request");
+        assert!(rq.set_weight_variable(&ctx, "NOTAWEIGHT").is_err());
+        assert!(rq.weight_variable().is_none());
+
+        rq.set_weight_variable(&ctx, "REPWTP1")
+            .expect("REPWTP1 should be in the metadata");
+        let weight_variable = rq.weight_variable().expect("should have a weight variable");
+        assert_eq!(&*weight_variable.name, "REPWTP1");
+        assert_eq!(weight_variable.record_type, "P");
+    }
+
+    #[test]
+    fn test_all_conditions_subpopulation_and_case_selection() {
+        // Select married persons with the MARST request variable, on top of the EDUC
+        // subpopulation.
+        let json = include_str!("../tests/requests/incwage_marst_example.json").replace(
+            r#""mnemonic":"MARST","general_detailed_selection":"","standardization_index":null,"attached_variable_pointer":null,"case_selection":false,"request_case_selections":[]"#,
+            r#""mnemonic":"MARST","general_detailed_selection":"","standardization_index":null,"attached_variable_pointer":null,"case_selection":true,"request_case_selections":[{"low_code":"1","high_code":"2"}]"#,
+        );
+        let (_, rq) = AbacusRequest::try_from_json(&json).expect("should parse the request");
+
+        assert_eq!(rq.get_conditions().map(|c| c.len()), Some(1));
+        let conditions = rq.all_conditions();
+        let names: Vec<&str> = conditions.iter().map(|c| c.var.name.as_ref()).collect();
+        assert_eq!(names, vec!["EDUC", "MARST"]);
+        assert_eq!(conditions[1].to_sql(), "(MARST between 1 and 2)");
+    }
+
+    /// A minimal generic IPUMS JSON request for us2015b with the given request variables.
+    fn ipums_json_with_request_variables(request_variables: &str) -> String {
+        format!(
+            r#"{{
+                "product": "usa",
+                "details": {{
+                    "output_format": "csv",
+                    "case_select_logic": "and",
+                    "request_samples": [{{"name": "us2015b"}}],
+                    "request_variables": {request_variables}
+                }}
+            }}"#
+        )
+    }
+
+    fn us2015b_context() -> Context {
+        let mut ctx = conventions::Context::from_ipums_collection_name(
+            "usa",
+            None,
+            Some("tests/data_root".to_string()),
+        )
+        .expect("should be able to load context for USA");
+        ctx.load_metadata_for_datasets(&["us2015b"])
+            .expect("should be able to load metadata for us2015b");
+        ctx
+    }
+
+    #[test]
+    fn test_deserialize_from_ipums_json_case_selections() {
+        let ctx = us2015b_context();
+        let json_request = ipums_json_with_request_variables(
+            r#"[
+                {"variable_mnemonic": "MARST", "case_selection": true,
+                 "request_case_selections": [{"low_code": "1", "high_code": "1"}, {"low_code": "3", "high_code": "5"}]},
+                {"variable_mnemonic": "AGE", "case_selection": false,
+                 "request_case_selections": [{"low_code": "18", "high_code": "65"}]},
+                {"variable_mnemonic": "GQ", "case_selection": true,
+                 "request_case_selections": [{"low_code": "1", "high_code": null}]}
+            ]"#,
+        );
+        let rq = SimpleRequest::deserialize_from_ipums_json(
+            &ctx,
+            RequestType::Tabulation,
+            &json_request,
+        )
+        .expect("should deserialize the case selections");
+
+        // The case selections belong to their request variables, not the request-level
+        // conditions.
+        assert!(rq.get_conditions().is_none());
+        let case_selections: Vec<(String, Option<String>)> = rq
+            .get_request_variables()
+            .iter()
+            .map(|rv| {
+                (
+                    rv.name.clone(),
+                    rv.case_selection.as_ref().map(|c| c.to_sql()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            case_selections,
+            vec![
+                (
+                    "MARST".to_string(),
+                    Some("(MARST = 1) or (MARST between 3 and 5)".to_string())
+                ),
+                ("AGE".to_string(), None),
+                ("GQ".to_string(), Some("(GQ >= 1)".to_string())),
+            ]
+        );
+        assert_eq!(rq.all_conditions().len(), 2);
+    }
+
+    #[test]
+    fn test_deserialize_from_ipums_json_case_select_logic_or() {
+        let ctx = us2015b_context();
+        let json_request = ipums_json_with_request_variables(
+            r#"[
+                {"variable_mnemonic": "MARST", "case_selection": true,
+                 "request_case_selections": [{"low_code": "1", "high_code": "1"}]},
+                {"variable_mnemonic": "GQ", "case_selection": true,
+                 "request_case_selections": [{"low_code": "1", "high_code": null}]}
+            ]"#,
+        )
+        .replace(
+            r#""case_select_logic": "and""#,
+            r#""case_select_logic": "or""#,
+        );
+        let rq = SimpleRequest::deserialize_from_ipums_json(
+            &ctx,
+            RequestType::Tabulation,
+            &json_request,
+        )
+        .expect("should deserialize the request");
+
+        assert_eq!(rq.case_select_logic(), CaseSelectLogic::Or);
+        let json: serde_json::Value = serde_json::from_str(&rq.serialize_to_ipums_json())
+            .expect("should serialize to valid JSON");
+        assert_eq!(json["details"]["case_select_logic"], "or");
+    }
+
+    #[test]
+    fn test_complex_request_deserialize_from_ipums_json_case_select_logic_or() {
+        let ctx = us2015b_context();
+        let json_request = ipums_json_with_request_variables(
+            r#"[
+                {"variable_mnemonic": "MARST", "case_selection": true,
+                 "request_case_selections": [{"low_code": "1", "high_code": "1"}]},
+                {"variable_mnemonic": "GQ", "case_selection": true,
+                 "request_case_selections": [{"low_code": "1", "high_code": null}]}
+            ]"#,
+        )
+        .replace(
+            r#""case_select_logic": "and""#,
+            r#""case_select_logic": "or""#,
+        );
+        let rq = ComplexRequest::deserialize_from_ipums_json(
+            &ctx,
+            RequestType::Tabulation,
+            &json_request,
+        )
+        .expect("should deserialize the request");
+
+        assert_eq!(rq.case_select_logic(), CaseSelectLogic::Or);
+        let json: serde_json::Value = serde_json::from_str(&rq.serialize_to_ipums_json())
+            .expect("should serialize to valid JSON");
+        assert_eq!(json["details"]["case_select_logic"], "or");
+    }
+
+    #[test]
+    fn test_deserialize_from_ipums_json_general_selection_needs_general_width() {
+        let ctx = us2015b_context();
+        let json_request = ipums_json_with_request_variables(
+            r#"[{"variable_mnemonic": "RELATE", "general_detailed_selection": "G"}]"#,
+        );
+        let err = SimpleRequest::deserialize_from_ipums_json(
+            &ctx,
+            RequestType::Tabulation,
+            &json_request,
+        )
+        .expect_err("RELATE has no general width without an extract_width");
+        assert!(err.to_string().contains("no general width"), "{err}");
+
+        let json_request = ipums_json_with_request_variables(
+            r#"[{"variable_mnemonic": "RELATE", "general_detailed_selection": "G", "extract_width": 2}]"#,
+        );
+        let rq = SimpleRequest::deserialize_from_ipums_json(
+            &ctx,
+            RequestType::Tabulation,
+            &json_request,
+        )
+        .expect("should deserialize the general selection");
+        assert!(rq.get_request_variables()[0].is_general());
+    }
+
+    #[test]
+    fn test_deserialize_from_ipums_json_unknown_case_select_logic_error() {
+        let ctx = us2015b_context();
+        let json_request = ipums_json_with_request_variables(r#"[{"variable_mnemonic": "MARST"}]"#)
+            .replace(
+                r#""case_select_logic": "and""#,
+                r#""case_select_logic": "xor""#,
+            );
+        let err = SimpleRequest::deserialize_from_ipums_json(
+            &ctx,
+            RequestType::Tabulation,
+            &json_request,
+        )
+        .expect_err("'xor' isn't a case_select_logic");
+        assert!(matches!(err, MdError::ParsingError(_)), "{err}");
+    }
+
+    #[test]
+    fn test_deserialize_from_ipums_json_no_case_selections() {
+        let ctx = us2015b_context();
+        let json_request = ipums_json_with_request_variables(r#"[{"variable_mnemonic": "MARST"}]"#);
+        let rq = SimpleRequest::deserialize_from_ipums_json(
+            &ctx,
+            RequestType::Tabulation,
+            &json_request,
+        )
+        .expect("should deserialize the request");
+        assert!(rq.conditions.is_none());
+    }
+
+    #[test]
+    fn test_complex_request_attach_variable() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = ComplexRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.attach_variable(&ctx, "HHINCOME")
+            .expect("should attach the household variable HHINCOME to persons");
+
+        let hhincome = &rq.request_variables[1];
+        assert!(hhincome.is_attached());
+        assert_eq!(
+            hhincome.attached_variable_pointer,
+            Some(AttachedVariablePointer {
+                record_type: "H".to_string()
+            })
+        );
+
+        let queries = query_gen::tab_queries(
+            &ctx,
+            rq,
+            &InputType::Parquet,
+            &query_gen::DataPlatform::Duckdb,
+        )
+        .expect("should generate the query");
+        assert!(
+            queries[0].contains(", us2015b_usa_household.HHINCOME as HHINCOME"),
+            "expected HHINCOME from the household table in the query {}",
+            queries[0]
+        );
+        assert!(queries[0].contains(
+            "left join  'tests/data_root/parquet/us2015b/us2015b_usa.H.parquet' us2015b_usa_household on us2015b_usa_person.SERIALP = us2015b_usa_household.SERIAL"
+        ));
+    }
+
+    #[test]
+    fn test_complex_request_attach_variable_not_above_unit_of_analysis_error() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = ComplexRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let err = rq
+            .attach_variable(&ctx, "AGE")
+            .expect_err("AGE is on persons, so it can't be attached to persons");
+        assert_eq!(
+            err.to_string(),
+            "metadata error: Can't attach AGE to record type 'P': record type 'P' isn't above it in the record hierarchy"
+        );
+        assert_eq!(rq.request_variables.len(), 1);
+    }
+
+    #[test]
+    fn test_complex_request_ipums_json_round_trip() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = ComplexRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.attach_variable(&ctx, "HHINCOME")
+            .expect("should attach HHINCOME");
+
+        let json = rq.serialize_to_ipums_json();
+        let round_trip =
+            ComplexRequest::deserialize_from_ipums_json(&ctx, RequestType::Tabulation, &json)
+                .expect("should deserialize the serialized request");
+        let pointers: Vec<Option<AttachedVariablePointer>> = round_trip
+            .request_variables
+            .iter()
+            .map(|v| v.attached_variable_pointer.clone())
+            .collect();
+        assert_eq!(
+            pointers,
+            vec![
+                None,
+                Some(AttachedVariablePointer {
+                    record_type: "H".to_string()
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn test_complex_request_adjust_variable() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = ComplexRequest::from_names(
+            "usa",
+            &["us2015b", "us2016b"],
+            &["INCWAGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.request_variables[0].category_bins = Some(vec![
+            CategoryBin::LessThan {
+                value: 25000,
+                code: 1,
+                label: "Under $25,000".to_string(),
+                inclusive: false,
+            },
+            CategoryBin::MoreThan {
+                value: 25001,
+                code: 2,
+                label: "$25,001 or more".to_string(),
+                inclusive: true,
+            },
+        ]);
+        let factors = BTreeMap::from([("us2015b".to_string(), 1.25), ("us2016b".to_string(), 1.1)]);
+        rq.adjust_variable("INCWAGE", factors)
+            .expect("should adjust INCWAGE");
+
+        let queries = query_gen::tab_queries(
+            &ctx,
+            rq,
+            &InputType::Parquet,
+            &query_gen::DataPlatform::Duckdb,
+        )
+        .expect("should generate the queries");
+        assert_eq!(queries.len(), 2);
+        assert!(
+            queries[0].contains("when (INCWAGE * 1.25) < 25000 then '001'"),
+            "expected bins on the adjusted INCWAGE in the query {}",
+            queries[0]
+        );
+        assert!(
+            queries[1].contains("when (INCWAGE * 1.1) >= 25001 then '002'"),
+            "expected bins on the adjusted INCWAGE in the query {}",
+            queries[1]
+        );
+    }
 
-        let json_request = include_str!("../tests/requests/usa_extract.json");
-        let simple_request =
-            SimpleRequest::deserialize_from_ipums_json(&ctx, RequestType::Extract, &json_request);
-        if let Err(ref e) = simple_request {
-            eprintln!("Parsing error in test: '{}'", e);
-        }
-        assert!(simple_request.is_ok());
-        if let Ok(rq) = simple_request {
-            assert_eq!(rq.product, "usa");
-        }
+    /// INCWAGE's N/A and missing codes aren't dollar amounts, so they're left as they are.
+    #[test]
+    fn test_complex_request_adjust_variable_keeps_missing_codes() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = ComplexRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["INCWAGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.request_variables[0].variable.categories = Some(vec![
+            IpumsCategory::new(
+                "N/A",
+                UniversalCategoryType::NotApplicable,
+                IpumsValue::Integer(999999),
+            ),
+            IpumsCategory::new(
+                "Missing",
+                UniversalCategoryType::Missing,
+                IpumsValue::Integer(999998),
+            ),
+            IpumsCategory::new(
+                "Top code",
+                UniversalCategoryType::TopCode,
+                IpumsValue::Integer(638000),
+            ),
+        ]);
+        let factors = BTreeMap::from([("us2015b".to_string(), 1.25)]);
+        rq.adjust_variable("INCWAGE", factors)
+            .expect("should adjust INCWAGE");
+
+        let queries = query_gen::tab_queries(
+            &ctx,
+            rq,
+            &InputType::Parquet,
+            &query_gen::DataPlatform::Duckdb,
+        )
+        .expect("should generate the queries");
+        assert!(
+            queries[0].contains(
+                "(case when INCWAGE in (999999, 999998) then INCWAGE else INCWAGE * 1.25 end) as INCWAGE"
+            ),
+            "expected the N/A and missing codes to be left unadjusted in the query {}",
+            queries[0]
+        );
+    }
+
+    #[test]
+    fn test_complex_request_adjust_variable_missing_factor_error() {
+        let data_root = String::from("tests/data_root");
+        let (_, mut rq) = ComplexRequest::from_names(
+            "usa",
+            &["us2015b", "us2016b"],
+            &["INCWAGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let factors = BTreeMap::from([("us2015b".to_string(), 1.25)]);
+        let err = rq
+            .adjust_variable("INCWAGE", factors)
+            .expect_err("should be an error because there's no factor for us2016b");
+        assert_eq!(
+            err.to_string(),
+            "metadata error: Can't adjust INCWAGE: there's no adjustment factor for dataset us2016b"
+        );
+        assert!(rq.request_variables[0].adjustment.is_none());
+    }
+
+    #[test]
+    fn test_canonical_hash_differs_with_adjustment() {
+        let data_root = String::from("tests/data_root");
+        let (_, rq) = ComplexRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["INCWAGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let mut adjusted = rq.clone();
+        adjusted
+            .adjust_variable("INCWAGE", BTreeMap::from([("us2015b".to_string(), 1.25)]))
+            .expect("should adjust INCWAGE");
+        assert_ne!(rq.canonical_hash(), adjusted.canonical_hash());
+    }
+
+    #[test]
+    fn test_deserialize_attached_variable_pointer_wrong_record_type_error() {
+        let ctx = us2015b_context();
+        let json_request = ipums_json_with_request_variables(
+            r#"[{"variable_mnemonic": "HHINCOME", "attached_variable_pointer": {"record_type": "P"}}]"#,
+        );
+        let err = ComplexRequest::deserialize_from_ipums_json(
+            &ctx,
+            RequestType::Tabulation,
+            &json_request,
+        )
+        .expect_err("HHINCOME is on households, not persons");
+        assert!(
+            err.to_string()
+                .contains("the variable is on record type 'H'"),
+            "unexpected error {err}"
+        );
+    }
+
+    #[test]
+    fn test_deserialize_from_ipums_json_variable_id() {
+        let ctx = us2015b_context();
+        let marst_id = ctx
+            .get_md_variable_by_name("MARST")
+            .expect("MARST should be in the metadata")
+            .id;
+
+        let json_request =
+            ipums_json_with_request_variables(&format!(r#"[{{"variable_id": {marst_id}}}]"#));
+        let rq = SimpleRequest::deserialize_from_ipums_json(
+            &ctx,
+            RequestType::Tabulation,
+            &json_request,
+        )
+        .expect("should resolve the request variable from its id");
+        assert_eq!(rq.variables.len(), 1);
+        assert_eq!(&*rq.variables[0].name, "MARST");
+    }
+
+    #[test]
+    fn test_deserialize_from_ipums_json_variable_id_and_mnemonic_disagree_error() {
+        let ctx = us2015b_context();
+        let marst_id = ctx
+            .get_md_variable_by_name("MARST")
+            .expect("MARST should be in the metadata")
+            .id;
+
+        let json_request = ipums_json_with_request_variables(&format!(
+            r#"[{{"variable_id": {marst_id}, "variable_mnemonic": "AGE"}}]"#
+        ));
+        let err = SimpleRequest::deserialize_from_ipums_json(
+            &ctx,
+            RequestType::Tabulation,
+            &json_request,
+        )
+        .expect_err("should be an error because the id and mnemonic name different variables");
+        assert!(
+            err.to_string().contains("'MARST'") && err.to_string().contains("'AGE'"),
+            "error should name both variables, got '{err}'"
+        );
     }
 
     #[test]
@@ -899,6 +2910,326 @@ mod test {
         assert!(abacus_request.is_ok());
     }
 
+    #[test]
+    fn test_print_codebook_grouped_by_record_type() {
+        let data_root = String::from("tests/data_root");
+        let (_, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST", "STATEFIP"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.variables[0].label = Some("Marital status".to_string());
+        rq.variables[0].categories = Some(vec![
+            IpumsCategory::new(
+                "Married, spouse present",
+                UniversalCategoryType::Value,
+                IpumsValue::Integer(1),
+            ),
+            IpumsCategory::new(
+                "Never married/single",
+                UniversalCategoryType::Value,
+                IpumsValue::Integer(6),
+            ),
+        ]);
+
+        let codebook = rq.print_codebook();
+        let household = codebook
+            .find("Household variables (H):")
+            .expect("should have a household section");
+        let person = codebook
+            .find("Person variables (P):")
+            .expect("should have a person section");
+        assert!(household < person);
+
+        let (household_section, person_section) = codebook.split_at(person);
+        assert!(household_section.contains("STATEFIP\n\tWidth: 2, Type: integer"));
+        assert!(household_section.contains("Labels unavailable"));
+        assert!(person_section.contains("MARST\tMarital status\n\tWidth: 1, Type: integer"));
+        assert!(person_section.contains("\t1\tMarried, spouse present\n\t6\tNever married/single"));
+        assert!(!person_section.contains("Labels unavailable"));
+    }
+
+    /// Activity records are below person records, so a request for activities with a
+    /// household variable joins activities to persons and then persons to households.
+    #[test]
+    fn test_join_plan_activity_to_household() {
+        let data_root = String::from("tests/data_root");
+        let (mut ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST", "STATEFIP"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let activity = RecordType {
+            name: "Activity".to_string(),
+            value: "A".to_string(),
+            unique_id: "ASERIAL".to_string(),
+            foreign_keys: vec![("P".to_string(), "PSERIAL".to_string())],
+            weight: None,
+            sample_weight: None,
+        };
+        ctx.settings
+            .record_hierarchy
+            .add_member("A", "P")
+            .expect("should be able to add activities below persons");
+        ctx.settings
+            .record_types
+            .insert("A".to_string(), activity.clone());
+        rq.variables[0].name = "ACTIVITY".into();
+        rq.variables[0].record_type = "A".to_string();
+        rq.unit_rectype = activity;
+
+        let plan = rq.join_plan(&ctx).expect("should plan the joins");
+        assert_eq!(
+            plan,
+            vec![
+                JoinStep {
+                    from_rectype: "A".to_string(),
+                    foreign_key: "PSERIAL".to_string(),
+                    to_rectype: "P".to_string(),
+                    to_key: "PSERIAL".to_string(),
+                },
+                JoinStep {
+                    from_rectype: "P".to_string(),
+                    foreign_key: "SERIALP".to_string(),
+                    to_rectype: "H".to_string(),
+                    to_key: "SERIAL".to_string(),
+                },
+            ]
+        );
+
+        let queries = query_gen::tab_queries(
+            &ctx,
+            rq,
+            &InputType::Parquet,
+            &query_gen::DataPlatform::Duckdb,
+        )
+        .expect("should generate the query");
+        assert!(queries[0].contains(
+            "left join  'tests/data_root/parquet/us2015b/us2015b_usa.P.parquet' us2015b_usa_person on us2015b_usa_activity.PSERIAL = us2015b_usa_person.PSERIAL"
+        ));
+        assert!(queries[0].contains(
+            "left join  'tests/data_root/parquet/us2015b/us2015b_usa.H.parquet' us2015b_usa_household on us2015b_usa_person.SERIALP = us2015b_usa_household.SERIAL"
+        ));
+    }
+
+    #[test]
+    fn test_join_plan_record_type_below_unit_of_analysis_error() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST", "STATEFIP"],
+            Some("H".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let err = rq
+            .join_plan(&ctx)
+            .expect_err("person variables can't join to households");
+        assert!(err
+            .to_string()
+            .contains("'P' is not above the unit of analysis 'H'"));
+    }
+
+    #[test]
+    fn test_category_label_for_bins() {
+        let json_request = include_str!("../tests/requests/incwage_marst_example.json");
+        let (_, rq) =
+            AbacusRequest::try_from_json(json_request).expect("should deserialize the request");
+        let incwage = rq
+            .get_request_variables()
+            .into_iter()
+            .find(|v| v.name == "INCWAGE")
+            .expect("INCWAGE should be a request variable");
+
+        assert!(incwage.is_bucketed());
+        assert_eq!(
+            incwage.category_label("002").as_deref(),
+            Some("$10,000 to $14,999")
+        );
+        // Without a residual bin, values outside every bin have an empty code.
+        assert_eq!(incwage.category_label("").as_deref(), Some(UNBINNED_LABEL));
+        assert_eq!(incwage.category_label(&UNBINNED_CODE.to_string()), None);
+        assert_eq!(incwage.category_label("17"), None);
+    }
+
+    /// INCWAGE's bins stop at 999998, so larger values land in the residual bin.
+    #[test]
+    fn test_residual_bin_label() {
+        let json_request = include_str!("../tests/requests/incwage_marst_example.json");
+        let mut request: serde_json::Value =
+            serde_json::from_str(json_request).expect("should parse the example request");
+        request["residual_bins"] = serde_json::json!({"INCWAGE": "Other"});
+        let (_, rq) = AbacusRequest::try_from_json(&request.to_string())
+            .expect("should deserialize the request");
+        let incwage = rq
+            .get_request_variables()
+            .into_iter()
+            .find(|v| v.name == "INCWAGE")
+            .expect("INCWAGE should be a request variable");
+
+        let bins = incwage
+            .category_bins
+            .as_ref()
+            .expect("INCWAGE should have bins");
+        assert!(input_schema_tabulation::outside_all_bins(bins, 1_000_000));
+        assert!(!input_schema_tabulation::outside_all_bins(bins, 999_998));
+        assert_eq!(incwage.residual_label(), Some("Other"));
+        assert_eq!(
+            incwage
+                .category_label(&UNBINNED_CODE.to_string())
+                .as_deref(),
+            Some("Other")
+        );
+    }
+
+    #[test]
+    fn test_residual_bin_without_category_bins_error() {
+        let json_request = include_str!("../tests/requests/incwage_marst_example.json");
+        let mut request: serde_json::Value =
+            serde_json::from_str(json_request).expect("should parse the example request");
+        request["residual_bins"] = serde_json::json!({"MARST": "Other"});
+        let err = AbacusRequest::try_from_json(&request.to_string())
+            .expect_err("MARST has no category bins");
+        assert!(err
+            .to_string()
+            .contains("residual_bins has a bin for MARST, but MARST has no category_bins"));
+    }
+
+    #[test]
+    fn test_abacus_request_from_json_unsupported_schema_version_error() {
+        let json_request = include_str!("../tests/requests/usa_abacus_request.json");
+        let mut request: serde_json::Value =
+            serde_json::from_str(json_request).expect("test request should be valid JSON");
+        request["schema_version"] = serde_json::json!(0);
+
+        let result = AbacusRequest::try_from_json(&request.to_string());
+        let err = result.expect_err("schema_version 0 is not supported");
+        assert!(err.to_string().contains("schema_version 0"));
+    }
+
+    #[test]
+    fn test_abacus_request_from_json_no_samples_error() {
+        let json_request = include_str!("../tests/requests/usa_abacus_request.json");
+        let mut request: serde_json::Value =
+            serde_json::from_str(json_request).expect("test request should be valid JSON");
+        request["request_samples"] = serde_json::json!([]);
+
+        let result = AbacusRequest::try_from_json(&request.to_string());
+        let err = result.expect_err("a request with no samples is invalid");
+        assert!(matches!(err, MdError::Msg(_)));
+        assert!(err.to_string().contains("at least one sample"));
+    }
+
+    #[test]
+    fn test_abacus_request_from_json_fw_output_format() {
+        let json_request = include_str!("../tests/requests/usa_abacus_request.json");
+        let mut request: serde_json::Value =
+            serde_json::from_str(json_request).expect("test request should be valid JSON");
+        request["output_format"] = serde_json::json!("fw");
+
+        let (_, rq) = AbacusRequest::try_from_json(&request.to_string())
+            .expect("should be able to parse the request");
+        assert_eq!(rq.effective_output_format(), OutputFormat::FW);
+    }
+
+    #[test]
+    fn test_abacus_request_from_json_unknown_output_format_error() {
+        let json_request = include_str!("../tests/requests/usa_abacus_request.json");
+        let mut request: serde_json::Value =
+            serde_json::from_str(json_request).expect("test request should be valid JSON");
+        request["output_format"] = serde_json::json!("xlsx");
+
+        let result = AbacusRequest::try_from_json(&request.to_string());
+        let err = result.expect_err("xlsx is not an output format");
+        assert!(err.to_string().contains("xlsx"));
+    }
+
+    /// A request variable marked "G" in the JSON request uses its general codes,
+    /// which collapse the detailed codes by dividing by the general divisor.
+    #[test]
+    fn test_abacus_request_from_json_general_selection() {
+        let json_request = include_str!("../tests/requests/relate_general_detailed.json");
+        let (_, rq) = AbacusRequest::try_from_json(json_request)
+            .expect("should be able to parse the request");
+
+        let relate = &rq.get_request_variables()[0];
+        assert_eq!(relate.name, "RELATE");
+        assert!(relate.is_general());
+        assert_eq!(relate.general_divisor, 100);
+        assert_eq!(relate.requested_width().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_required_files_person_only_two_datasets() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us2016b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let files = rq
+            .required_files(&ctx, &InputType::Parquet)
+            .expect("should list the required files");
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("tests/data_root/parquet/us2015b/us2015b_usa.P.parquet"),
+                PathBuf::from("tests/data_root/parquet/us2016b/us2016b_usa.P.parquet"),
+            ]
+        );
+    }
+
+    /// Tabulating RELATE with its general codes collapses the detailed codes 301, 302 and
+    /// 303 into the general code 3, while the detailed selection keeps them apart.
+    #[test]
+    fn test_abacus_request_from_json_general_selection_collapses_codes() {
+        let json_request = include_str!("../tests/requests/relate_general_detailed.json");
+        let relate_counts = |json_request: &str| -> Vec<(String, String)> {
+            let (ctx, rq) = AbacusRequest::try_from_json(json_request)
+                .expect("should be able to parse the request");
+            let tab = tabulate::tabulate(&ctx, rq).expect("should tabulate");
+            tab.tables()[0]
+                .rows
+                .iter()
+                .map(|row| (row[2].clone(), row[0].clone()))
+                .collect()
+        };
+
+        let general = relate_counts(json_request);
+        assert_eq!(general.len(), 13);
+        assert!(general.contains(&("3".to_string(), "8757".to_string())));
+        assert!(general
+            .iter()
+            .all(|(code, _)| code.parse::<u32>().is_ok_and(|code| code < 100)));
+
+        let detailed = relate_counts(&json_request.replace(
+            r#""general_detailed_selection": "G""#,
+            r#""general_detailed_selection": "D""#,
+        ));
+        for (code, ct) in [("301", "8272"), ("302", "133"), ("303", "352")] {
+            assert!(
+                detailed.contains(&(code.to_string(), ct.to_string())),
+                "expected {ct} persons with RELATE {code} in {detailed:?}"
+            );
+        }
+    }
+
     /// It's an error if the given unit of analysis is not present as a record
     /// type in the context.
     #[test]
@@ -918,11 +3249,31 @@ mod test {
             .contains("Record type 'Z' not available for use as unit of analysis"));
     }
 
+    #[test]
+    fn test_validated_unit_of_analysis_value_or_name() {
+        let context =
+            Context::from_ipums_collection_name("usa", None, Some("tests/data_root".to_string()))
+                .expect("should be able to load context for USA");
+
+        let by_value = validated_unit_of_analysis(&context, Some("P".to_string()))
+            .expect("P should be a valid unit of analysis");
+        let by_name = validated_unit_of_analysis(&context, Some("Person".to_string()))
+            .expect("Person should be a valid unit of analysis");
+        assert_eq!(by_value.value, "P");
+        assert_eq!(by_name.value, "P");
+
+        let err = validated_unit_of_analysis(&context, Some("Family".to_string()))
+            .expect_err("expected an error because USA has no Family record type");
+        assert!(err
+            .to_string()
+            .contains("Record type 'Family' not available for use as unit of analysis"));
+    }
+
     #[test]
     fn test_request_variable_from_ipums_variable_invalid_widths_error() {
         let variable = IpumsVariable {
             id: 0,
-            name: "RELATE".to_string(),
+            name: "RELATE".into(),
             data_type: None,
             label: None,
             record_type: "P".to_string(),
@@ -944,7 +3295,7 @@ mod test {
     fn test_request_variable_from_ipums_variable_valid_general_width() {
         let variable = IpumsVariable {
             id: 0,
-            name: "RELATE".to_string(),
+            name: "RELATE".into(),
             data_type: None,
             label: None,
             record_type: "P".to_string(),
@@ -968,7 +3319,7 @@ mod test {
     fn test_request_variable_from_ipums_variable_equal_widths() {
         let variable = IpumsVariable {
             id: 0,
-            name: "AGE".to_string(),
+            name: "AGE".into(),
             data_type: None,
             label: None,
             record_type: "P".to_string(),
@@ -992,7 +3343,7 @@ mod test {
     fn test_request_variable_from_ipums_variable_no_formatting() {
         let variable = IpumsVariable {
             id: 0,
-            name: "AGE".to_string(),
+            name: "AGE".into(),
             data_type: None,
             label: None,
             record_type: "P".to_string(),
@@ -1018,7 +3369,7 @@ mod test {
     fn test_request_variable_from_ipums_variable_no_general_width_use_detailed() {
         let variable = IpumsVariable {
             id: 0,
-            name: "AGE".to_string(),
+            name: "AGE".into(),
             data_type: None,
             label: None,
             record_type: "P".to_string(),
@@ -1047,7 +3398,7 @@ mod test {
     fn test_request_variable_from_ipums_variable_no_general_width_use_general() {
         let variable = IpumsVariable {
             id: 0,
-            name: "AGE".to_string(),
+            name: "AGE".into(),
             data_type: None,
             label: None,
             record_type: "P".to_string(),
@@ -1065,4 +3416,235 @@ mod test {
             general width but requested the general version of the variable",
         );
     }
+
+    #[test]
+    fn test_canonical_hash_covers_request_settings() {
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["GQ"],
+            Some("P".to_string()),
+            None,
+            Some(String::from("tests/data_root")),
+        )
+        .expect("should be able to set up the request");
+        let hash = rq.canonical_hash();
+
+        let mut household = rq.clone();
+        household.unit_rectype = ctx.settings.record_types["H"].clone();
+        let mut extract = rq.clone();
+        extract.request_type = RequestType::Extract;
+        let mut weighted_by_household = rq.clone();
+        weighted_by_household
+            .set_weight_rectype(&ctx, "H")
+            .expect("should weight by the household weight");
+        let mut replicate_weight = rq.clone();
+        replicate_weight
+            .set_weight_variable(&ctx, "REPWTP1")
+            .expect("should weight by REPWTP1");
+        let mut unweighted = rq.clone();
+        unweighted.count_columns = CountColumns::Unweighted;
+        let mut table_units = rq.clone();
+        table_units.table_units = vec!["P".to_string(), "H".to_string()];
+        let mut or_logic = rq.clone();
+        or_logic.case_select_logic = CaseSelectLogic::Or;
+        let mut other_product = rq.clone();
+        other_product.product = "ipumsi".to_string();
+
+        for (name, changed) in [
+            ("unit of analysis", household),
+            ("request type", extract),
+            ("weight record type", weighted_by_household),
+            ("weight variable", replicate_weight),
+            ("count columns", unweighted),
+            ("table units", table_units),
+            ("case select logic", or_logic),
+            ("product", other_product),
+        ] {
+            assert_ne!(
+                changed.canonical_hash(),
+                hash,
+                "the {name} should change the hash"
+            );
+        }
+    }
+
+    #[test]
+    fn test_canonical_hash_ignores_variable_order() {
+        let data_root = Some(String::from("tests/data_root"));
+        let (_, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us2016b"],
+            &["AGE", "MARST"],
+            Some("P".to_string()),
+            None,
+            data_root.clone(),
+        )
+        .expect("should be able to set up the request");
+        let (_, reordered) = SimpleRequest::from_names(
+            "usa",
+            &["us2016b", "us2015b"],
+            &["MARST", "AGE"],
+            Some("P".to_string()),
+            None,
+            data_root.clone(),
+        )
+        .expect("should be able to set up the request");
+        let (_, different) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us2016b"],
+            &["AGE", "SEX"],
+            Some("P".to_string()),
+            None,
+            data_root,
+        )
+        .expect("should be able to set up the request");
+
+        assert_eq!(rq.canonical_hash(), reordered.canonical_hash());
+        assert_ne!(rq.canonical_hash(), different.canonical_hash());
+    }
+
+    #[test]
+    fn test_output_schema_with_percentages() {
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(String::from("tests/data_root")),
+        )
+        .expect("should be able to set up the request");
+        let options = TabulateOptions {
+            percentages: Some(PercentBase::Total),
+            ..TabulateOptions::default()
+        };
+
+        let schema = rq
+            .output_schema(&ctx, &options)
+            .expect("should know the output schema");
+        assert_eq!(
+            schema,
+            vec![
+                ("ct".to_string(), IpumsDataType::Integer),
+                ("weighted_ct".to_string(), IpumsDataType::Integer),
+                ("pct".to_string(), IpumsDataType::Float),
+                ("MARST".to_string(), IpumsDataType::Integer),
+            ]
+        );
+    }
+
+    /// Without a weight there's no weighted count column, like in the queries.
+    #[test]
+    fn test_output_schema_without_weight() {
+        let (mut ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(String::from("tests/data_root")),
+        )
+        .expect("should be able to set up the request");
+        ctx.settings
+            .record_types
+            .get_mut("P")
+            .expect("should have a person record type")
+            .weight = None;
+
+        let schema = rq
+            .output_schema(&ctx, &TabulateOptions::default())
+            .expect("should know the output schema");
+        assert_eq!(
+            schema,
+            vec![
+                ("ct".to_string(), IpumsDataType::Integer),
+                ("MARST".to_string(), IpumsDataType::Integer),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_perform_request_tabulation_and_extract() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let dir = std::env::temp_dir().join(format!("cimdea_perform_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("should create the output directory");
+
+        let tabulation = perform_request(&ctx, rq.clone(), &dir);
+        rq.request_type = RequestType::Extract;
+        let extract = perform_request(&ctx, rq, &dir);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        match tabulation.expect("should tabulate") {
+            RequestOutput::Tabulation(tab) => assert_eq!(tab.tables()[0].rows.len(), 6),
+            other => panic!("expected a tabulation, got {other:?}"),
+        }
+        match extract.expect("should extract") {
+            RequestOutput::Extract(manifest) => {
+                assert_eq!(manifest.files, vec![dir.join("usa.csv")]);
+                assert_eq!(manifest.rows, 30767);
+            }
+            other => panic!("expected an extract, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_request_output_for_extract() {
+        let manifest = extract::ExtractManifest {
+            files: vec![PathBuf::from("usa.csv")],
+            rows: 10,
+        };
+        let output = RequestOutput::Extract(manifest.clone());
+        assert!(matches!(output.request_type(), RequestType::Extract));
+        assert_eq!(output.extract(), Some(&manifest));
+        assert!(output.tabulation().is_none());
+    }
+
+    #[test]
+    fn test_to_cli_args() {
+        let (_, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST", "AGE"],
+            Some("P".to_string()),
+            None,
+            Some(String::from("tests/data_root")),
+        )
+        .expect("should be able to set up the request");
+
+        let args = rq
+            .to_cli_args()
+            .expect("the command line should recreate the request");
+        assert_eq!(
+            args,
+            vec!["tab", "usa", "us2015b", "MARST", "AGE", "--uoa", "P", "--format", "csv"]
+        );
+    }
+
+    #[test]
+    fn test_to_cli_args_unsupported_request_error() {
+        let (_, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us2016b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(String::from("tests/data_root")),
+        )
+        .expect("should be able to set up the request");
+
+        let err = rq
+            .to_cli_args()
+            .expect_err("the command line only tabulates one dataset");
+        assert!(err.to_string().contains("more than one dataset"), "{err}");
+    }
 }
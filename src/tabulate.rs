@@ -4,22 +4,32 @@
 //! carry some metadata information with them to be used by formatters or even codebook
 //! generators.
 //!
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
 
-use crate::conventions::Context;
+use crate::conventions::{data_path_exists, Context};
+use crate::input_schema_tabulation::{AutoBin, CategoryBin, UNBINNED_LABEL};
 use crate::ipums_metadata_model::IpumsDataType;
 use crate::mderror::{metadata_error, MdError};
-use crate::query_gen::tab_queries;
-use crate::query_gen::DataPlatform;
+use crate::query_gen::{
+    count_queries, member_count_column, member_count_queries, tab_queries, tabulated_count_columns,
+};
+use crate::query_gen::{DataPlatform, DataSource};
 use crate::request::DataRequest;
 use crate::request::InputType;
 use crate::request::RequestVariable;
 
+use duckdb::types::ValueRef;
 use duckdb::Connection;
 use serde::ser::Error;
 use serde::Serialize;
 
 const DEBUG: bool = false;
+// The name of the attached database when reading from a native DuckDB database file.
+const NATIVE_DB_ALIAS: &str = "native_db";
 
 /// The format of an output table.
 #[derive(Clone, Copy, Debug)]
@@ -62,8 +72,13 @@ pub enum OutputColumn {
         name: String,
         width: usize,
         data_type: IpumsDataType,
+        /// A note about the column, like "weighted by PERWT/100", which text output
+        /// prints as a numbered footnote beneath the table.
+        note: Option<String>,
     },
     RequestVar(RequestVariable),
+    /// The labels for the codes of a request variable, which follow the column of codes.
+    Label(RequestVariable),
 }
 
 /// The RequestVar variant on OutputColumn has a real RequestVariable struct because there is a lot of useful information in there
@@ -81,18 +96,24 @@ impl Serialize for OutputColumn {
                 name,
                 width,
                 data_type,
+                note,
             } => {
                 let mut ser =
-                    serializer.serialize_struct_variant("OutputColumn", 0, "Constructed", 3)?;
+                    serializer.serialize_struct_variant("OutputColumn", 0, "Constructed", 4)?;
                 ser.serialize_field("name", &name)?;
                 ser.serialize_field("width", &width)?;
                 ser.serialize_field("data_type", &format!("{}", data_type))?;
+                if let Some(note) = note {
+                    ser.serialize_field("note", note)?;
+                } else {
+                    ser.skip_field("note")?;
+                }
                 ser.end()
             }
             Self::RequestVar(ref v) => {
                 let mut ser =
                     serializer.serialize_struct_variant("OutputColumn", 1, "RequestVar", 3)?;
-                let width = v.requested_width().map_err(S::Error::custom)?;
+                let width = self.width().map_err(S::Error::custom)?;
                 let data_type = match v.variable.data_type {
                     Some(ref data_type) => data_type.to_string(),
                     None => {
@@ -106,287 +127,3822 @@ impl Serialize for OutputColumn {
                 ser.serialize_field("data_type", &data_type)?;
                 ser.end()
             }
+            Self::Label(_) => {
+                let mut ser = serializer.serialize_struct_variant("OutputColumn", 2, "Label", 3)?;
+                let width = self.width().map_err(S::Error::custom)?;
+                ser.serialize_field("name", &self.name())?;
+                ser.serialize_field("width", &width)?;
+                ser.serialize_field("data_type", &IpumsDataType::String.to_string())?;
+                ser.end()
+            }
         }
     } // serialize trait
 } // impl
 
-impl OutputColumn {
-    pub fn name(&self) -> String {
-        match self {
-            Self::Constructed { ref name, .. } => name.clone(),
-            Self::RequestVar(ref v) => v.name.clone(),
-        }
+impl OutputColumn {
+    pub fn name(&self) -> String {
+        match self {
+            Self::Constructed { ref name, .. } => name.clone(),
+            Self::RequestVar(ref v) => v.name.clone(),
+            Self::Label(ref v) => format!("{}_label", v.name),
+        }
+    }
+
+    /// The column's note, if it has one. Only constructed columns have notes.
+    pub fn note(&self) -> Option<&str> {
+        match self {
+            Self::Constructed { ref note, .. } => note.as_deref(),
+            Self::RequestVar(_) | Self::Label(_) => None,
+        }
+    }
+
+    /// The type of the column's values. Label columns are strings, and request variable
+    /// columns have the type of their variable.
+    pub fn data_type(&self) -> Result<IpumsDataType, MdError> {
+        match self {
+            Self::Constructed { ref data_type, .. } => Ok(data_type.clone()),
+            Self::Label(_) => Ok(IpumsDataType::String),
+            Self::RequestVar(ref v) => v
+                .variable
+                .data_type
+                .clone()
+                .ok_or_else(|| metadata_error!("missing data type for variable {}", v.name)),
+        }
+    }
+
+    pub fn width(&self) -> Result<usize, MdError> {
+        match self {
+            Self::Constructed { ref width, .. } => Ok(*width),
+            Self::Label(ref v) => {
+                let bin_labels = v
+                    .category_bins
+                    .iter()
+                    .flatten()
+                    .map(|b| b.label())
+                    .chain(v.category_bins.as_ref().map(|_| UNBINNED_LABEL))
+                    .chain(v.residual_label())
+                    .map(str::len);
+                let category_labels = v
+                    .variable
+                    .categories
+                    .iter()
+                    .flatten()
+                    .map(|c| c.label().len());
+                Ok(bin_labels.chain(category_labels).max().unwrap_or(0))
+            }
+            Self::RequestVar(ref v) => {
+                if !v.is_general() {
+                    // Without a width from metadata, as when the variable's type came from
+                    // the data's schema, text tables size the column by its values.
+                    match v.variable.formatting {
+                        Some((_, wid)) => Ok(wid),
+                        None => Ok(v.name.len()),
+                    }
+                } else {
+                    if let Some(general_width) = v.variable.general_width {
+                        Ok(general_width)
+                    } else {
+                        Err(MdError::Msg(format!(
+                            "cannot determine general width for variable {}",
+                            self.name()
+                        )))
+                    }
+                }
+            }
+        }
+    }
+} // impl
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Table {
+    /// A name for the table; [tabulate] sets this to the name of the dataset the table
+    /// was computed from.
+    pub title: Option<String>,
+    pub heading: Vec<OutputColumn>, // variable name columns
+    pub rows: Vec<Vec<String>>,
+}
+
+/// The text printed in place of data rows when a table has no rows, for example because its
+/// subpopulation filters excluded every record. Every format of [Table] shows it: the text
+/// and HTML formats in a row spanning the table, the CSV format in the first field of a row
+/// with the other fields empty, and the JSON format as the `message` along with empty `rows`.
+pub const NO_RECORDS_MESSAGE: &str = "No records";
+
+/// The marker after a code which has no label in [Table::with_code_labels], like
+/// "9 (no label)".
+pub const NO_LABEL_MARKER: &str = "(no label)";
+
+impl Table {
+    /// Format the table as a text table with a header row.
+    ///
+    /// If the table has no rows, for example because its subpopulation filters
+    /// excluded every record, a single row spanning the table reads [NO_RECORDS_MESSAGE]
+    /// instead.
+    ///
+    /// Columns with notes get a footnote number after their name in the header, like
+    /// "weighted_ct[1]", and the numbered notes follow the table.
+    pub fn format_as_text(&self) -> Result<String, MdError> {
+        let mut out = self.format_text_table()?;
+        out.push_str(&self.format_footnotes());
+        Ok(out)
+    }
+
+    // The text table without any footnotes.
+    fn format_text_table(&self) -> Result<String, MdError> {
+        let mut out = String::new();
+        let widths = self.column_widths()?;
+        for (column, label) in self.header_labels().iter().enumerate() {
+            let column_header = format!("| {n:>w$} ", n = label, w = widths[column]);
+            out.push_str(&column_header);
+        }
+        out.push_str("|\n");
+        out.push_str(&format!(
+            "|{:}|",
+            str::repeat(&"-", self.text_table_width()? - 2)
+        ));
+        out.push_str("\n");
+
+        for r in &self.rows {
+            for (column, item) in r.iter().enumerate() {
+                let w = widths[column];
+                let formatted_item = format!("| {value:>width$} ", value = &item, width = w);
+                out.push_str(&formatted_item);
+            }
+            out.push_str("|\n");
+        }
+
+        if self.is_empty() {
+            let inner_width = self.text_table_width()?.saturating_sub(4);
+            out.push_str(&format!("| {NO_RECORDS_MESSAGE:<inner_width$} |\n"));
+        }
+        Ok(out)
+    }
+
+    // The column names for the header, with footnote numbers for columns with notes.
+    fn header_labels(&self) -> Vec<String> {
+        let mut footnote = 0;
+        self.heading
+            .iter()
+            .map(|column| {
+                if column.note().is_some() {
+                    footnote += 1;
+                    format!("{}[{footnote}]", column.name())
+                } else {
+                    column.name()
+                }
+            })
+            .collect()
+    }
+
+    // The numbered notes for the columns which have them, one per line.
+    fn format_footnotes(&self) -> String {
+        self.heading
+            .iter()
+            .filter_map(OutputColumn::note)
+            .enumerate()
+            .map(|(index, note)| format!("[{}] {note}\n", index + 1))
+            .collect()
+    }
+
+    /// Format the table as CSV, with a header row of column names. Fields which contain
+    /// commas, double quotes or line breaks are quoted as in RFC 4180.
+    ///
+    /// If the table has no rows, a single row reads [NO_RECORDS_MESSAGE] instead.
+    pub fn format_as_csv(&self) -> String {
+        let names: Vec<String> = self.heading.iter().map(OutputColumn::name).collect();
+        let mut out = format_csv_record(&names);
+        for row in &self.rows {
+            out.push_str(&format_csv_record(row));
+        }
+        if self.is_empty() {
+            let mut row = vec![String::new(); names.len().max(1)];
+            row[0] = NO_RECORDS_MESSAGE.to_string();
+            out.push_str(&format_csv_record(&row));
+        }
+        out
+    }
+
+    /// Format the table as an HTML `<table>` fragment for embedding in a page, with the title
+    /// as its `<caption>`, a `<thead>` row of column names and a `<tbody>` row for each row.
+    ///
+    /// Each header and data cell has a CSS class named after its column, like "MARST" or
+    /// "weighted_ct", for styling particular columns. Cells of numeric columns are right
+    /// aligned. `<`, `>`, `&` and double quotes are escaped in all text.
+    ///
+    /// If the table has no rows, a single row spanning the table reads [NO_RECORDS_MESSAGE]
+    /// instead.
+    pub fn format_as_html(&self) -> String {
+        let columns: Vec<(String, &str)> = self
+            .heading
+            .iter()
+            .map(|column| {
+                let numeric = matches!(
+                    column.data_type(),
+                    Ok(IpumsDataType::Integer | IpumsDataType::Float | IpumsDataType::Fixed(_))
+                );
+                let style = if numeric {
+                    " style=\"text-align: right\""
+                } else {
+                    ""
+                };
+                (escape_html(&column.name()), style)
+            })
+            .collect();
+
+        let mut out = String::from("<table>\n");
+        if let Some(ref title) = self.title {
+            out.push_str(&format!("<caption>{}</caption>\n", escape_html(title)));
+        }
+        out.push_str("<thead>\n<tr>");
+        for (name, style) in &columns {
+            out.push_str(&format!("<th class=\"{name}\"{style}>{name}</th>"));
+        }
+        out.push_str("</tr>\n</thead>\n<tbody>\n");
+        for row in &self.rows {
+            out.push_str("<tr>");
+            for ((name, style), value) in columns.iter().zip(row) {
+                out.push_str(&format!(
+                    "<td class=\"{name}\"{style}>{}</td>",
+                    escape_html(value)
+                ));
+            }
+            out.push_str("</tr>\n");
+        }
+        if self.is_empty() {
+            out.push_str(&format!(
+                "<tr><td colspan=\"{}\">{NO_RECORDS_MESSAGE}</td></tr>\n",
+                columns.len().max(1)
+            ));
+        }
+        out.push_str("</tbody>\n</table>\n");
+        out
+    }
+
+    /// Format the table as a JSON object with its `title`, the names of its `columns` in order,
+    /// and its `rows` as objects keyed by column name.
+    ///
+    /// The counts and other constructed numeric columns, like "ct" and "weighted_ct", are JSON
+    /// numbers. The codes of request variables stay strings as they are in the data, and
+    /// label columns are strings too. Since JSON objects are unordered, use `columns` to put
+    /// the fields of each row back in order.
+    ///
+    /// If the table has no rows, the object also has a `message` reading
+    /// [NO_RECORDS_MESSAGE].
+    pub fn format_as_json(&self) -> Result<String, MdError> {
+        serde_json::to_string_pretty(&self.json_value())
+            .map_err(|err| MdError::Msg(format!("Cannot serialize table into json: {err}")))
+    }
+
+    // The JSON for Table::format_as_json().
+    fn json_value(&self) -> serde_json::Value {
+        let columns: Vec<String> = self.heading.iter().map(OutputColumn::name).collect();
+        let rows: Vec<serde_json::Map<String, serde_json::Value>> = self
+            .rows
+            .iter()
+            .map(|row| {
+                self.heading
+                    .iter()
+                    .zip(&columns)
+                    .zip(row)
+                    .map(|((column, name), value)| (name.clone(), json_table_value(column, value)))
+                    .collect()
+            })
+            .collect();
+
+        let mut value = serde_json::json!({
+            "title": self.title,
+            "columns": columns,
+            "rows": rows,
+        });
+        if self.is_empty() {
+            value["message"] = NO_RECORDS_MESSAGE.into();
+        }
+        value
+    }
+
+    /// Return true if the table has no data rows. Tables always have a heading, so this
+    /// is the case when a request matched no records.
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Format the table as text like [Table::format_as_text], followed by a footer with
+    /// summary statistics for the aggregate columns (like "ct" and "weighted_ct"): the
+    /// column total and the mean, minimum and maximum over all rows.
+    pub fn format_as_text_with_summary(&self) -> Result<String, MdError> {
+        let mut out = self.format_text_table()?;
+        out.push_str(&format!("|{}|\n", "-".repeat(self.text_table_width()? - 2)));
+
+        for (column, heading) in self.heading.iter().enumerate() {
+            if !matches!(
+                heading,
+                OutputColumn::Constructed {
+                    data_type: IpumsDataType::Integer,
+                    ..
+                }
+            ) {
+                continue;
+            }
+
+            let mut values = Vec::with_capacity(self.rows.len());
+            for row in &self.rows {
+                let value: i64 = row[column].parse().map_err(|err| {
+                    MdError::Msg(format!(
+                        "cannot summarize column '{}': value '{}' is not an integer: {err}",
+                        heading.name(),
+                        row[column]
+                    ))
+                })?;
+                values.push(value);
+            }
+
+            let total: i64 = values.iter().sum();
+            match (values.iter().min(), values.iter().max()) {
+                (Some(min), Some(max)) => {
+                    let mean = total as f64 / values.len() as f64;
+                    out.push_str(&format!(
+                        "{}: total {total}, mean {mean:.2}, min {min}, max {max}\n",
+                        heading.name()
+                    ));
+                }
+                _ => out.push_str(&format!("{}: total {total}\n", heading.name())),
+            }
+        }
+        out.push_str(&self.format_footnotes());
+        Ok(out)
+    }
+
+    pub fn text_table_width(&self) -> Result<usize, MdError> {
+        Ok(1 + 3 * self.heading.len() + self.column_widths()?.iter().sum::<usize>())
+    }
+
+    // Each column is wide enough for its name, its nominal width and every value in it, so
+    // a count with more digits than the nominal width widens its column instead of pushing
+    // the rest of the row out of line.
+    fn column_widths(&self) -> Result<Vec<usize>, MdError> {
+        let mut widths = Vec::new();
+        for (column, (var, label)) in self.heading.iter().zip(self.header_labels()).enumerate() {
+            let data_width = self.width_from_data(column).unwrap_or(0);
+            widths.push(label.len().max(var.width()?).max(data_width));
+        }
+        Ok(widths)
+    }
+
+    fn width_from_data(&self, column: usize) -> Option<usize> {
+        self.rows
+            .iter()
+            .filter_map(|r| r.get(column))
+            .map(|v| v.len())
+            .max()
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            title: None,
+            rows: Vec::new(),
+            heading: Vec::new(),
+        }
+    }
+
+    /// A copy of the table with the codes of each request variable replaced by their labels,
+    /// so that MARST code 1 reads "Married, spouse present". Labels come from the variable's
+    /// category bins or categories, as with [RequestVariable::category_label]. A code without
+    /// a label, as when only layout metadata is loaded, keeps the code followed by
+    /// [NO_LABEL_MARKER], like "9 (no label)". Missing values stay empty.
+    ///
+    /// The label columns from [TabulateOptions::codes_and_labels] are left out, since they
+    /// would repeat the labels.
+    pub fn with_code_labels(&self) -> Table {
+        let keep: Vec<bool> = self
+            .heading
+            .iter()
+            .map(|column| !matches!(column, OutputColumn::Label(_)))
+            .collect();
+        let rows = self
+            .rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .zip(&self.heading)
+                    .zip(&keep)
+                    .filter(|(_, &keep)| keep)
+                    .map(|((value, column), _)| match column {
+                        OutputColumn::RequestVar(v) if !value.is_empty() => v
+                            .category_label(value)
+                            .unwrap_or_else(|| format!("{value} {NO_LABEL_MARKER}")),
+                        _ => value.clone(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let heading = self
+            .heading
+            .iter()
+            .zip(&keep)
+            .filter(|(_, &keep)| keep)
+            .map(|(column, _)| column.clone())
+            .collect();
+        Table {
+            title: self.title.clone(),
+            heading,
+            rows,
+        }
+    }
+
+    /// Combine tables with the same heading into a single table, for example to pool the
+    /// per-dataset tables from a tabulation.
+    ///
+    /// Rows with the same values in the grouping (request variable) columns are merged, and
+    /// their count columns like "ct" and "weighted_ct" are summed. Rows are in the order in
+    /// which their grouping values first appear. The merged table has no title.
+    pub fn merge_sum(tables: &[Table]) -> Result<Table, MdError> {
+        let Some(first) = tables.first() else {
+            return Err(MdError::Msg(
+                "cannot merge an empty list of tables".to_string(),
+            ));
+        };
+
+        let names: Vec<String> = first.heading.iter().map(|c| c.name()).collect();
+        for table in &tables[1..] {
+            let other_names: Vec<String> = table.heading.iter().map(|c| c.name()).collect();
+            if other_names != names {
+                return Err(MdError::Msg(format!(
+                    "cannot merge tables with different headings: [{}] and [{}]",
+                    names.join(", "),
+                    other_names.join(", ")
+                )));
+            }
+        }
+
+        let is_count: Vec<bool> = first
+            .heading
+            .iter()
+            .map(|c| matches!(c, OutputColumn::Constructed { .. }))
+            .collect();
+
+        let mut merged = Table {
+            title: None,
+            heading: first.heading.clone(),
+            rows: Vec::new(),
+        };
+        let mut sums: Vec<Vec<i64>> = Vec::new();
+        let mut row_for_key: HashMap<Vec<String>, usize> = HashMap::new();
+        for table in tables {
+            for row in &table.rows {
+                let key: Vec<String> = row
+                    .iter()
+                    .zip(&is_count)
+                    .filter(|(_, is_count)| !**is_count)
+                    .map(|(item, _)| item.clone())
+                    .collect();
+                let index = *row_for_key.entry(key).or_insert_with(|| {
+                    merged.rows.push(row.clone());
+                    sums.push(vec![0; row.len()]);
+                    merged.rows.len() - 1
+                });
+
+                for (column, item) in row.iter().enumerate() {
+                    if is_count[column] {
+                        let count: i64 = item.parse().map_err(|err| {
+                            MdError::Msg(format!(
+                                "cannot sum column '{}': value '{item}' is not an integer: {err}",
+                                names[column]
+                            ))
+                        })?;
+                        sums[index][column] += count;
+                    }
+                }
+            }
+        }
+
+        for (row, row_sums) in merged.rows.iter_mut().zip(sums) {
+            for (column, sum) in row_sums.into_iter().enumerate() {
+                if is_count[column] {
+                    row[column] = sum.to_string();
+                }
+            }
+        }
+        Ok(merged)
+    }
+}
+
+// Format the tables one after another in the given format, for [Tabulation::output].
+fn format_tables(tables: &[Table], format: TableFormat) -> Result<String, MdError> {
+    let output = match format {
+        TableFormat::Html => {
+            let mut output = String::new();
+            for table in tables {
+                output.push_str(&table.format_as_html());
+            }
+            output
+        }
+        TableFormat::Csv => {
+            let mut output = String::new();
+            for table in tables {
+                output.push_str(&table.format_as_csv());
+            }
+            output
+        }
+        TableFormat::Json => {
+            let tables: Vec<serde_json::Value> = tables.iter().map(Table::json_value).collect();
+            match serde_json::to_string_pretty(&tables) {
+                Ok(output) => output,
+                Err(err) => {
+                    return Err(MdError::Msg(format!(
+                        "Cannot serialize result into json: {err}"
+                    )));
+                }
+            }
+        }
+        TableFormat::TextTable => {
+            let mut output = String::new();
+            for table in tables {
+                let table_text = table.format_as_text()?;
+                output.push_str(&format!("{table_text}\n"));
+            }
+            output
+        }
+    };
+
+    Ok(output)
+}
+
+/// The result of a tabulation: one [Table] per dataset in the request, along with
+/// the rendered codebook for the request if [TabulateOptions::include_codebook] was set.
+#[derive(Debug)]
+pub struct Tabulation {
+    tables: Vec<Table>,
+    codebook: Option<String>,
+    warnings: Vec<String>,
+}
+
+impl Tabulation {
+    pub fn tables(&self) -> &[Table] {
+        &self.tables
+    }
+
+    /// The codebook describing the datasets, variables and subpopulation filters that went
+    /// into this tabulation. This is only present when the tabulation was computed with
+    /// [TabulateOptions::include_codebook] set.
+    pub fn codebook(&self) -> Option<&str> {
+        self.codebook.as_deref()
+    }
+
+    /// Problems that didn't stop the tabulation but that the caller may want to know
+    /// about, like datasets skipped because of [TabulateOptions::skip_missing_datasets].
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    pub fn output(&self, format: TableFormat) -> Result<String, MdError> {
+        format_tables(&self.tables, format)
+    }
+
+    /// Format the tables like [Tabulation::output], but with the labels of the request
+    /// variables' codes in place of the codes. See [Table::with_code_labels].
+    pub fn output_with_labels(&self, format: TableFormat) -> Result<String, MdError> {
+        let tables: Vec<Table> = self.tables.iter().map(Table::with_code_labels).collect();
+        format_tables(&tables, format)
+    }
+
+    pub fn into_inner(self) -> Vec<Table> {
+        self.tables
+    }
+
+    /// Return true if every table in the tabulation is empty. See [Table::is_empty].
+    pub fn is_empty(&self) -> bool {
+        self.tables.iter().all(Table::is_empty)
+    }
+
+    /// Compare this tabulation with another, for example the same request run against two
+    /// versions of the data.
+    ///
+    /// Tables are matched by title, and rows within them by the values of their grouping
+    /// (request variable) columns. The diff lists the rows found in only one of the
+    /// tabulations and the count cells which differ between matching rows. Every row of a
+    /// table with no match in the other tabulation, or whose heading differs from its match,
+    /// is reported as found in only one tabulation.
+    pub fn diff(&self, other: &Tabulation) -> TabulationDiff {
+        let mut diff = TabulationDiff::default();
+        for table in &self.tables {
+            match other.tables.iter().find(|t| t.title == table.title) {
+                Some(other_table) if same_heading(table, other_table) => {
+                    diff_tables(table, other_table, &mut diff);
+                }
+                _ => diff.only_in_self.extend(diff_rows(table)),
+            }
+        }
+        for other_table in &other.tables {
+            match self.tables.iter().find(|t| t.title == other_table.title) {
+                Some(table) if same_heading(table, other_table) => (),
+                _ => diff.only_in_other.extend(diff_rows(other_table)),
+            }
+        }
+        diff
+    }
+}
+
+/// The differences between two tabulations. See [Tabulation::diff].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TabulationDiff {
+    /// Rows of the first tabulation with no matching row in the second.
+    pub only_in_self: Vec<DiffRow>,
+    /// Rows of the second tabulation with no matching row in the first.
+    pub only_in_other: Vec<DiffRow>,
+    /// Count cells which differ between matching rows.
+    pub changed_cells: Vec<DiffCell>,
+}
+
+impl TabulationDiff {
+    /// Return true if the tabulations have the same rows and counts.
+    pub fn is_empty(&self) -> bool {
+        self.only_in_self.is_empty()
+            && self.only_in_other.is_empty()
+            && self.changed_cells.is_empty()
+    }
+}
+
+/// A row found in only one of two diffed tabulations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiffRow {
+    /// The title of the row's table.
+    pub table: Option<String>,
+    /// The values of the row's grouping columns.
+    pub key: Vec<String>,
+    pub row: Vec<String>,
+}
+
+/// A count which differs between matching rows of two diffed tabulations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiffCell {
+    /// The title of the row's table.
+    pub table: Option<String>,
+    /// The values of the row's grouping columns.
+    pub key: Vec<String>,
+    /// The name of the count column, like "ct".
+    pub column: String,
+    pub value: String,
+    pub other_value: String,
+}
+
+fn same_heading(table: &Table, other: &Table) -> bool {
+    table.heading.len() == other.heading.len()
+        && table
+            .heading
+            .iter()
+            .zip(&other.heading)
+            .all(|(c, o)| c.name() == o.name())
+}
+
+// The values of the grouping columns of a row, which identify it within its table. As in
+// Table::merge_sum(), the constructed columns hold counts and the rest are grouping columns.
+fn row_key(heading: &[OutputColumn], row: &[String]) -> Vec<String> {
+    heading
+        .iter()
+        .zip(row)
+        .filter(|(c, _)| !matches!(c, OutputColumn::Constructed { .. }))
+        .map(|(_, item)| item.clone())
+        .collect()
+}
+
+fn diff_rows(table: &Table) -> impl Iterator<Item = DiffRow> + '_ {
+    table.rows.iter().map(|row| DiffRow {
+        table: table.title.clone(),
+        key: row_key(&table.heading, row),
+        row: row.clone(),
+    })
+}
+
+// Add the differences between two tables with the same heading to the diff.
+fn diff_tables(table: &Table, other: &Table, diff: &mut TabulationDiff) {
+    let other_rows: HashMap<Vec<String>, &Vec<String>> = other
+        .rows
+        .iter()
+        .map(|row| (row_key(&other.heading, row), row))
+        .collect();
+
+    for row in diff_rows(table) {
+        let Some(other_row) = other_rows.get(&row.key) else {
+            diff.only_in_self.push(row);
+            continue;
+        };
+        for (column, (value, other_value)) in row.row.iter().zip(other_row.iter()).enumerate() {
+            if value != other_value {
+                diff.changed_cells.push(DiffCell {
+                    table: table.title.clone(),
+                    key: row.key.clone(),
+                    column: table.heading[column].name(),
+                    value: value.clone(),
+                    other_value: other_value.clone(),
+                });
+            }
+        }
+    }
+
+    let keys: HashSet<Vec<String>> = table
+        .rows
+        .iter()
+        .map(|row| row_key(&table.heading, row))
+        .collect();
+    diff.only_in_other
+        .extend(diff_rows(other).filter(|row| !keys.contains(&row.key)));
+}
+
+/// Options which adjust how [tabulate_with_options] computes and packages a tabulation.
+#[derive(Clone, Debug, Default)]
+pub struct TabulateOptions {
+    /// Render the request's codebook and attach it to the [Tabulation]. See
+    /// [Tabulation::codebook].
+    pub include_codebook: bool,
+    /// Skip datasets whose data files are missing instead of failing, and record a
+    /// warning for each one on the [Tabulation]. See [Tabulation::warnings].
+    pub skip_missing_datasets: bool,
+    /// Tabulate the parent records of this record type by how many of its records each
+    /// one has, like "P" for the distribution of household sizes. The output tables get
+    /// a count column after `weighted_ct`, named like "person_count". All request variables
+    /// must be on the parent record type.
+    pub count_members: Option<String>,
+    /// The directory where DuckDB writes temporary files when a query's data doesn't fit
+    /// in memory. DuckDB's default is a ".tmp" directory in the working directory, which
+    /// may not be writable in a container.
+    pub temp_directory: Option<PathBuf>,
+    /// The most memory DuckDB may use before spilling to disk, like "4GB". DuckDB's
+    /// default is 80% of the system's memory.
+    pub max_memory: Option<String>,
+    /// Follow the column of codes for each request variable with labels with a column of
+    /// its labels, named like "MARST_label".
+    pub codes_and_labels: bool,
+    /// Read the data from tables in this DuckDB database file instead of from Parquet
+    /// files. The database is attached read-only, and its tables must be named like
+    /// [MicroDataCollection::default_table_name](crate::conventions::MicroDataCollection::default_table_name),
+    /// for example "us2015b_usa_person".
+    pub native_db: Option<PathBuf>,
+    /// Read the data from CSV files in the data root's "csv" directory instead of from
+    /// Parquet files, like "csv/us2015b/us2015b_usa.P.csv". The files may be gzip-compressed,
+    /// like "us2015b_usa.P.csv.gz", which DuckDB decompresses as it reads them. See
+    /// [Context::paths_from_dataset_name]. [TabulateOptions::native_db] takes precedence.
+    pub csv: bool,
+    /// The platform to generate queries for. [DataPlatform::Duckdb], the default, can run
+    /// tabulations, and so can [DataPlatform::DataFusion] with the "datafusion" feature; use
+    /// [crate::query_gen::tab_queries] to get the queries for the others.
+    pub platform: DataPlatform,
+    /// A correlation id for this run, like an id from the caller's service. When given,
+    /// every message the tabulation logs through the `log` crate starts with it in
+    /// brackets, like "[req-42] running query for us2015b".
+    pub request_id: Option<String>,
+    /// Add a "pct" column after `weighted_ct` with each row's share of a weighted count,
+    /// as a percentage rounded to [TabulateOptions::percent_decimals] decimal places
+    /// ([DEFAULT_PERCENT_DECIMALS] by default). The [PercentBase] says which count each row
+    /// is a share of.
+    pub percentages: Option<PercentBase>,
+    /// The number of decimal places in the percentages from [TabulateOptions::percentages],
+    /// [DEFAULT_PERCENT_DECIMALS] by default and at most [MAX_PERCENT_DECIMALS]. Every
+    /// percentage has exactly this many, with trailing zeros, and halves round away from zero,
+    /// so 12.345 becomes "12.35" with two decimal places.
+    pub percent_decimals: Option<u8>,
+    /// With [TabulateOptions::codes_and_labels], add a warning to [Tabulation::warnings]
+    /// for each code in the output which has no label, like "MARST code 9 has no label",
+    /// to find incomplete metadata.
+    pub warn_missing_labels: bool,
+}
+
+/// The number of decimal places in percentages when [TabulateOptions::percent_decimals] isn't
+/// given.
+pub const DEFAULT_PERCENT_DECIMALS: u8 = 2;
+
+/// The most decimal places [TabulateOptions::percent_decimals] may ask for.
+pub const MAX_PERCENT_DECIMALS: u8 = 15;
+
+/// What the percentages from [TabulateOptions::percentages] are percentages of. In a cross-tab
+/// of MARST by SEX, the rows are the MARST values and the columns are the SEX values.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PercentBase {
+    /// The weighted count of the whole table.
+    #[default]
+    Total,
+    /// The weighted count of the rows with the same values of every request variable but
+    /// the last, like all rows with the same MARST. With a single request variable, this is
+    /// the same as [PercentBase::Total].
+    Row,
+    /// The weighted count of the rows with the same value of the last request variable,
+    /// like all rows with the same SEX.
+    Column,
+}
+
+impl PercentBase {
+    // The columns whose values group rows for this base, given the positions of the
+    // request variable columns.
+    fn group_columns(&self, variable_columns: &[usize]) -> Vec<usize> {
+        match (self, variable_columns.split_last()) {
+            (Self::Row, Some((_, rest))) => rest.to_vec(),
+            (Self::Column, Some((last, _))) => vec![*last],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl TabulateOptions {
+    // The statements which configure a DuckDB connection for these options.
+    fn duckdb_settings_sql(&self) -> Vec<String> {
+        let mut settings = Vec::new();
+        if let Some(ref dir) = self.temp_directory {
+            settings.push(format!(
+                "SET temp_directory = '{}'",
+                dir.display().to_string().replace('\'', "''")
+            ));
+        }
+        if let Some(ref max_memory) = self.max_memory {
+            settings.push(format!(
+                "SET max_memory = '{}'",
+                max_memory.replace('\'', "''")
+            ));
+        }
+        if let Some(ref db) = self.native_db {
+            settings.push(format!(
+                "ATTACH '{}' AS {NATIVE_DB_ALIAS} (READ_ONLY)",
+                db.display().to_string().replace('\'', "''")
+            ));
+            settings.push(format!("USE {NATIVE_DB_ALIAS}"));
+        }
+        settings
+    }
+
+    // The start of each log message for a run with these options.
+    fn log_prefix(&self) -> String {
+        match self.request_id {
+            Some(ref id) => format!("[{id}] "),
+            None => String::new(),
+        }
+    }
+
+    // The type of input the tabulation reads its data from.
+    fn input_type(&self) -> InputType {
+        if self.native_db.is_some() {
+            InputType::NativeDb
+        } else if self.csv {
+            InputType::Csv
+        } else {
+            InputType::Parquet
+        }
+    }
+
+    // Open the engine which runs the queries for the platform of these options.
+    fn open_query_engine(&self) -> Result<QueryEngine<'static>, MdError> {
+        match self.platform {
+            DataPlatform::Duckdb => Ok(QueryEngine::Duckdb(DuckdbConnection::Owned(
+                self.open_duckdb_connection()?,
+            ))),
+            #[cfg(feature = "datafusion")]
+            DataPlatform::DataFusion => Ok(QueryEngine::DataFusion(
+                datafusion_engine::DataFusionEngine::new()?,
+            )),
+            _ => Err(MdError::Msg(format!(
+                "Can't run tabulations on {:?} yet; only DuckDB is supported, and DataFusion with the \"datafusion\" feature.",
+                self.platform
+            ))),
+        }
+    }
+
+    // Open the DuckDB connection for a tabulation and apply the settings for these options.
+    fn open_duckdb_connection(&self) -> Result<Connection, MdError> {
+        let conn = Connection::open_in_memory()?;
+        for setting in self.duckdb_settings_sql() {
+            conn.execute_batch(&setting)?;
+        }
+        Ok(conn)
+    }
+}
+
+/// Compute the result of a tabulation request.
+///
+/// A single request can result in multiple tables. Normally there is one table per IPUMS dataset
+/// in the request, in the same order as the request's samples, and titled with the dataset name.
+///
+/// Each table is a cross-tab of all of the request variables, with a row for each combination
+/// of their values that occurs in the data. The heading is "ct" and "weighted_ct" followed by
+/// the request variables in the order requested, or only one of the counts when the request's
+/// [DataRequest::count_columns] asks for it. Rows are sorted in ascending order by the
+/// request variables from left to right, with missing values last, so that a tabulation gives
+/// the same rows in the same order every time.
+///
+/// Right now `InputType::Parquet` and `DataPlatform::Duckdb` are the defaults; they're the main
+/// use-case for now. See [TabulateOptions] for the alternatives. `InputType::Csv` ought to be
+/// pretty interchangable except for performance implications. `DataPlatform::DataFusion` runs
+/// the same queries without DuckDB when cimdea is built with the "datafusion" feature.
+/// `DataPlatform::Polars` is also planned and shouldn't require too many additional query gen
+/// updates, but it is unimplemented for now.
+pub fn tabulate<R>(ctx: &Context, rq: R) -> Result<Tabulation, MdError>
+where
+    R: DataRequest,
+{
+    tabulate_with_options(ctx, rq, &TabulateOptions::default())
+}
+
+/// Count the records of the request's unit of analysis which meet its conditions, without
+/// grouping them by the request variables. Returns the unweighted and weighted totals over
+/// all of the request's datasets, the same as the sums of the "ct" and "weighted_ct" columns
+/// of [tabulate], but with a single quick query per dataset. [Context::count] calls this.
+pub fn count<R>(ctx: &Context, rq: R) -> Result<(usize, f64), MdError>
+where
+    R: DataRequest,
+{
+    let options = TabulateOptions::default();
+    let queries = count_queries(ctx, rq, &options.input_type(), &options.platform)?;
+    let conn = options.open_duckdb_connection()?;
+
+    let mut total = (0, 0.0);
+    for q in queries {
+        log::debug!("running count query: {q}");
+        let (ct, weighted_ct): (i64, Option<f64>) =
+            conn.query_row(&q, [], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        total.0 += usize::try_from(ct).unwrap_or_default();
+        total.1 += weighted_ct.unwrap_or_default();
+    }
+    Ok(total)
+}
+
+/// Compute the category bins for the request variable named `variable` from its values in
+/// the request's datasets, as the [AutoBin] says. This queries the smallest and largest values
+/// of the variable, and its quantiles for quantile bins, over all of the records of its record
+/// type. Codes which the variable's categories mark as missing, N/A or not in universe aren't
+/// values, so they're left out. The `options` give the input data and its location, as for
+/// [tabulate_with_options]. See
+/// [SimpleRequest::auto_bin](crate::request::SimpleRequest::auto_bin) to bin a request
+/// variable this way.
+pub fn auto_bins<R>(
+    ctx: &Context,
+    rq: &R,
+    variable: &str,
+    auto_bin: &AutoBin,
+    options: &TabulateOptions,
+) -> Result<Vec<CategoryBin>, MdError>
+where
+    R: DataRequest + ?Sized,
+{
+    let Some(rv) = rq
+        .get_request_variables()
+        .into_iter()
+        .find(|v| v.name == variable)
+    else {
+        return Err(metadata_error!(
+            "Can't automatically bin {variable}: it isn't a request variable"
+        ));
+    };
+    let column = rv.variable.name.to_string();
+    let non_value_codes: Vec<String> = rv
+        .variable
+        .non_value_codes()
+        .iter()
+        .map(|code| code.to_string())
+        .collect();
+    let filter = if non_value_codes.is_empty() {
+        String::new()
+    } else {
+        format!(" where {column} not in ({})", non_value_codes.join(", "))
+    };
+
+    let mut selects = Vec::new();
+    for sample in rq.get_request_samples() {
+        let sources = DataSource::for_dataset(ctx, &sample.name, &options.input_type())?;
+        let Some(source) = sources.get(&rv.variable.record_type) else {
+            return Err(metadata_error!(
+                "No data for record type '{}' of variable {variable} in dataset {}",
+                rv.variable.record_type,
+                sample.name
+            ));
+        };
+        // The query always runs on DuckDB, whatever the platform for tabulating.
+        selects.push(format!(
+            "select {column} from {}{filter}",
+            source.for_platform(&DataPlatform::Duckdb)
+        ));
+    }
+
+    let mut aggregates = vec![format!("min({column})"), format!("max({column})")];
+    aggregates.extend(
+        auto_bin
+            .quantiles()
+            .iter()
+            .map(|q| format!("quantile_disc({column}, {q})")),
+    );
+    let q = format!(
+        "select {}\nfrom (\n{}\n)",
+        aggregates.join(", "),
+        selects.join("\nunion all\n")
+    );
+    log::debug!("running auto bin query: {q}");
+
+    let conn = options.open_duckdb_connection()?;
+    let values: Vec<Option<i64>> = conn.query_row(&q, [], |row| {
+        (0..aggregates.len()).map(|i| row.get(i)).collect()
+    })?;
+    let (Some(Some(min)), Some(Some(max))) = (values.first(), values.get(1)) else {
+        return Err(metadata_error!(
+            "Can't automatically bin {variable}: it has no values in the data"
+        ));
+    };
+    let quantile_values: Vec<i64> = values[2..].iter().flatten().copied().collect();
+    auto_bin.bins(*min, *max, &quantile_values)
+}
+
+/// Compute the result of a tabulation request, as with [tabulate], but adjusted by the
+/// given [TabulateOptions].
+pub fn tabulate_with_options<R>(
+    ctx: &Context,
+    rq: R,
+    options: &TabulateOptions,
+) -> Result<Tabulation, MdError>
+where
+    R: DataRequest,
+{
+    let codebook = if options.include_codebook {
+        Some(rq.print_codebook())
+    } else {
+        None
+    };
+
+    let mut tables: Vec<Table> = Vec::new();
+    let warnings = run_tabulation(ctx, rq, options, None, &mut tables)?;
+
+    Ok(Tabulation {
+        tables,
+        codebook,
+        warnings,
+    })
+}
+
+/// Compute the result of a tabulation request like [tabulate_with_options], running its
+/// queries on an existing DuckDB connection instead of opening a new one. This lets the
+/// caller configure the connection, with settings like `threads` or `memory_limit`, and
+/// reuse it for many tabulations.
+///
+/// The options' [TabulateOptions::temp_directory], [TabulateOptions::max_memory] and
+/// [TabulateOptions::native_db] settings aren't applied to the connection; set them on the
+/// connection instead. With [TabulateOptions::native_db], the database must already be
+/// attached and in use, as with `USE`. The tabulation creates a temporary view for the data
+/// of each record type of each dataset, named like "us2015b_usa_person", which replaces any
+/// earlier view or temporary table with the same name.
+///
+/// Only [DataPlatform::Duckdb] runs on a DuckDB connection, so the options' platform must be
+/// the default.
+pub fn tabulate_with_connection<R>(
+    ctx: &Context,
+    rq: R,
+    options: &TabulateOptions,
+    conn: &Connection,
+) -> Result<Tabulation, MdError>
+where
+    R: DataRequest,
+{
+    let codebook = if options.include_codebook {
+        Some(rq.print_codebook())
+    } else {
+        None
+    };
+
+    let mut tables: Vec<Table> = Vec::new();
+    let warnings = run_tabulation(ctx, rq, options, Some(conn), &mut tables)?;
+
+    Ok(Tabulation {
+        tables,
+        codebook,
+        warnings,
+    })
+}
+
+/// The formats that [tabulate_to_writer] can stream tabulation output in.
+#[derive(Clone, Copy, Debug)]
+pub enum StreamFormat {
+    /// Each table as a CSV header row followed by its data rows, like [Table::format_as_csv].
+    Csv,
+    /// One JSON object per line for each data row, with a "dataset" key for the table's
+    /// title and a key for each column. A table without rows has one object with the
+    /// "dataset" and a "message" reading [NO_RECORDS_MESSAGE] instead.
+    Ndjson,
+}
+
+/// Compute the result of a tabulation request like [tabulate], writing each row to
+/// `writer` in the given format as it's read instead of building the whole [Table] first.
+/// This keeps memory use down for very large tabulations.
+pub fn tabulate_to_writer<R, W>(
+    ctx: &Context,
+    rq: R,
+    format: StreamFormat,
+    writer: W,
+) -> Result<(), MdError>
+where
+    R: DataRequest,
+    W: Write,
+{
+    let mut sink = StreamSink {
+        format,
+        writer,
+        title: None,
+        column_names: Vec::new(),
+        rows_in_table: 0,
+    };
+    run_tabulation(ctx, rq, &TabulateOptions::default(), None, &mut sink)?;
+    sink.writer.flush()?;
+    Ok(())
+}
+
+// The columns of each table a tabulation of the request produces with these options: the
+// count columns, the percentage and member count columns when asked for, then the request
+// variables, each followed by its labels when asked for. The count columns are only those
+// which every table can have. See [tabulated_count_columns].
+pub(crate) fn output_heading<R>(
+    ctx: &Context,
+    rq: &R,
+    options: &TabulateOptions,
+) -> Result<Vec<OutputColumn>, MdError>
+where
+    R: DataRequest + ?Sized,
+{
+    let count_column = |name: &str, data_type| OutputColumn::Constructed {
+        name: name.to_string(),
+        width: 10,
+        data_type,
+        note: None,
+    };
+
+    let count_columns = tabulated_count_columns(ctx, rq, options.count_members.as_deref())?;
+    if options.percentages.is_some() && !count_columns.weighted() {
+        return Err(MdError::Msg(
+            "Can't compute percentages without weighted counts; request weighted counts too."
+                .to_string(),
+        ));
+    }
+
+    let mut heading: Vec<OutputColumn> = count_columns
+        .column_names()
+        .into_iter()
+        .map(|name| count_column(name, IpumsDataType::Integer))
+        .collect();
+    if options.percentages.is_some() {
+        heading.push(count_column("pct", IpumsDataType::Float));
+    }
+    if let Some(ref member_rectype) = options.count_members {
+        heading.push(count_column(
+            &member_count_column(ctx, member_rectype)?,
+            IpumsDataType::Integer,
+        ));
+    }
+    for v in rq.get_request_variables() {
+        let label_column = options.codes_and_labels && v.has_labels();
+        heading.push(OutputColumn::RequestVar(v.clone()));
+        if label_column {
+            heading.push(OutputColumn::Label(v));
+        }
+    }
+    Ok(heading)
+}
+
+// Make sure that every variable the request's conditions use, like the variables of its
+// subpopulation, is in each dataset. A condition on a variable that a dataset doesn't have
+// can't be applied, and ignoring it would give the wrong counts. Datasets which aren't in
+// the loaded metadata can't be checked.
+fn check_condition_variables<R: DataRequest>(
+    ctx: &Context,
+    rq: &R,
+    datasets: &[&str],
+) -> Result<(), MdError> {
+    let conditions = rq.all_conditions();
+    let Some(ref md) = ctx.settings.metadata else {
+        return Ok(());
+    };
+
+    let mut reasons = Vec::new();
+    for &dataset in datasets {
+        let Some(&dataset_id) = md.datasets_by_name.get(dataset) else {
+            continue;
+        };
+        let available = md.available_variables.for_dataset(dataset_id);
+        for condition in &conditions {
+            let name = &condition.var.name;
+            let is_available = md
+                .variables_by_name
+                .get(name)
+                .is_some_and(|id| available.is_some_and(|vars| vars.contains(id)));
+            let reason = format!("variable {name} is not available in dataset {dataset}");
+            if !is_available && !reasons.contains(&reason) {
+                reasons.push(reason);
+            }
+        }
+    }
+
+    if reasons.is_empty() {
+        Ok(())
+    } else {
+        Err(metadata_error!(
+            "Can't apply the request's conditions: {}.",
+            reasons.join(", ")
+        ))
+    }
+}
+
+// Fill in the data type of each request variable column which doesn't have one, as when the
+// request was made without metadata, from the type of its column in the dataset's data.
+fn fill_types_from_schema(
+    ctx: &Context,
+    conn: &Connection,
+    dataset_name: &str,
+    input_type: &InputType,
+    heading: &mut [OutputColumn],
+) -> Result<(), MdError> {
+    let untyped = |column: &OutputColumn| matches!(column, OutputColumn::RequestVar(v) if v.variable.data_type.is_none());
+    if !heading.iter().any(untyped) {
+        return Ok(());
+    }
+
+    let sources = DataSource::for_dataset(ctx, dataset_name, input_type)?;
+    for column in heading.iter_mut() {
+        let OutputColumn::RequestVar(ref mut v) = column else {
+            continue;
+        };
+        if v.variable.data_type.is_some() {
+            continue;
+        }
+        let Some(source) = sources.get(&v.variable.record_type) else {
+            return Err(metadata_error!(
+                "No data for record type '{}' of variable {} in dataset {dataset_name}",
+                v.variable.record_type,
+                v.name
+            ));
+        };
+
+        let q = format!(
+            "DESCRIBE SELECT {} FROM {}",
+            v.variable.name,
+            source.for_platform(&DataPlatform::Duckdb)
+        );
+        let mut stmt = conn.prepare(&q)?;
+        let mut rows = stmt.query([])?;
+        let Some(row) = rows.next()? else {
+            return Err(metadata_error!(
+                "No column for variable {} in the data for dataset {dataset_name}",
+                v.name
+            ));
+        };
+        // DESCRIBE gives the column name followed by its type.
+        let column_type: String = row.get(1)?;
+        v.variable.data_type = Some(data_type_from_duckdb(&column_type));
+    }
+    Ok(())
+}
+
+// The IPUMS data type for a DuckDB column type like "BIGINT" or "DECIMAL(10,2)".
+fn data_type_from_duckdb(column_type: &str) -> IpumsDataType {
+    let column_type = column_type.to_ascii_uppercase();
+    if column_type.starts_with("DECIMAL")
+        || ["DOUBLE", "FLOAT", "REAL"].contains(&column_type.as_str())
+    {
+        IpumsDataType::Float
+    } else if column_type.contains("INT") {
+        IpumsDataType::Integer
+    } else {
+        IpumsDataType::String
+    }
+}
+
+// Insert each row's percentage of the total weighted count of the rows with the same values
+// in `group_columns` after its weighted_ct column, which is at `weighted_column`. With no
+// group columns, that's the total of all of the rows.
+fn with_percentages(
+    rows: Vec<Vec<String>>,
+    weighted_column: usize,
+    group_columns: &[usize],
+    decimals: u8,
+) -> Result<Vec<Vec<String>>, MdError> {
+    if decimals > MAX_PERCENT_DECIMALS {
+        return Err(MdError::Msg(format!(
+            "cannot compute percentages with {decimals} decimal places; the most is {MAX_PERCENT_DECIMALS}"
+        )));
+    }
+
+    // Weighted counts are fractional when the weights are scaled down, like sum(PERWT/100).
+    // Whole counts get exact integer arithmetic, and fractional ones floating point.
+    let integer_counts: Option<Vec<i64>> = rows
+        .iter()
+        .map(|row| row[weighted_column].parse().ok())
+        .collect();
+    let percentages = match integer_counts {
+        Some(counts) => group_percentages(&rows, &counts, group_columns, |part, total| {
+            format_percentage(part, total, decimals)
+        }),
+        None => {
+            let mut counts = Vec::with_capacity(rows.len());
+            for row in &rows {
+                let weighted_ct: f64 = row[weighted_column].parse().map_err(|err| {
+                    MdError::Msg(format!(
+                        "cannot compute percentages: weighted count '{}' is not a number: {err}",
+                        row[weighted_column]
+                    ))
+                })?;
+                counts.push(weighted_ct);
+            }
+            group_percentages(&rows, &counts, group_columns, |part, total| {
+                format_fractional_percentage(part, total, decimals)
+            })
+        }
+    };
+
+    Ok(rows
+        .into_iter()
+        .zip(percentages)
+        .map(|(mut row, percentage)| {
+            row.insert(weighted_column + 1, percentage);
+            row
+        })
+        .collect())
+}
+
+// Format each row's weighted count in `counts` as a percentage of the total of the counts of
+// the rows with the same values in `group_columns`.
+fn group_percentages<T>(
+    rows: &[Vec<String>],
+    counts: &[T],
+    group_columns: &[usize],
+    format: impl Fn(T, T) -> String,
+) -> Vec<String>
+where
+    T: Copy + Default + std::ops::AddAssign,
+{
+    let group_of = |row: &Vec<String>| -> Vec<String> {
+        group_columns.iter().map(|&c| row[c].clone()).collect()
+    };
+    let mut totals: HashMap<Vec<String>, T> = HashMap::new();
+    for (row, &count) in rows.iter().zip(counts) {
+        *totals.entry(group_of(row)).or_default() += count;
+    }
+
+    rows.iter()
+        .zip(counts)
+        .map(|(row, &count)| format(count, totals[&group_of(row)]))
+        .collect()
+}
+
+// Like [format_percentage] for fractional weighted counts.
+fn format_fractional_percentage(part: f64, total: f64, decimals: u8) -> String {
+    let percentage = if total == 0.0 {
+        0.0
+    } else {
+        100.0 * part / total
+    };
+    format!("{percentage:.*}", usize::from(decimals))
+}
+
+// Format `part` as a percentage of `total` with exactly `decimals` decimal places, rounding
+// halves away from zero. This is done in integer arithmetic so that ties round the same way
+// regardless of how they'd be represented as floating point numbers.
+fn format_percentage(part: i64, total: i64, decimals: u8) -> String {
+    let scale = 10_i128.pow(u32::from(decimals));
+    let scaled = if total == 0 {
+        0
+    } else {
+        let numerator = 100 * i128::from(part) * scale;
+        let denominator = i128::from(total);
+        let rounded = (2 * numerator.abs() + denominator.abs()) / (2 * denominator.abs());
+        if (numerator < 0) != (denominator < 0) {
+            -rounded
+        } else {
+            rounded
+        }
+    };
+
+    let sign = if scaled < 0 { "-" } else { "" };
+    let (whole, fraction) = (scaled.abs() / scale, scaled.abs() % scale);
+    if decimals == 0 {
+        format!("{sign}{whole}")
+    } else {
+        format!(
+            "{sign}{whole}.{fraction:0width$}",
+            width = usize::from(decimals)
+        )
+    }
+}
+
+// Fill in the label columns of a row of query results. The codes which have no label are
+// added to `unlabeled` as a message like "MARST code 9 has no label", once each.
+fn with_labels(
+    heading: &[OutputColumn],
+    values: Vec<String>,
+    unlabeled: &mut Vec<String>,
+) -> Vec<String> {
+    if !heading.iter().any(|c| matches!(c, OutputColumn::Label(_))) {
+        return values;
+    }
+
+    let mut values = values.into_iter();
+    let mut row: Vec<String> = Vec::with_capacity(heading.len());
+    for column in heading {
+        match column {
+            OutputColumn::Label(v) => {
+                let code = row.last().map(String::as_str).unwrap_or_default();
+                let label = v.category_label(code);
+                if label.is_none() {
+                    let message = format!("{} code {code} has no label", v.name);
+                    if !unlabeled.contains(&message) {
+                        unlabeled.push(message);
+                    }
+                }
+                row.push(label.unwrap_or_default());
+            }
+            _ => row.extend(values.next()),
+        }
+    }
+    row
+}
+
+// Receives the output of a tabulation as it's read from the query results: the title and
+// heading of each table, followed by its rows.
+trait TableSink {
+    fn start_table(&mut self, title: &str, heading: &[OutputColumn]) -> Result<(), MdError>;
+    fn row(&mut self, row: Vec<String>) -> Result<(), MdError>;
+
+    // Called after the last row of each table.
+    fn end_table(&mut self) -> Result<(), MdError> {
+        Ok(())
+    }
+}
+
+impl TableSink for Vec<Table> {
+    fn start_table(&mut self, title: &str, heading: &[OutputColumn]) -> Result<(), MdError> {
+        self.push(Table {
+            title: Some(title.to_string()),
+            heading: heading.to_vec(),
+            rows: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn row(&mut self, row: Vec<String>) -> Result<(), MdError> {
+        match self.last_mut() {
+            Some(table) => {
+                table.rows.push(row);
+                Ok(())
+            }
+            None => Err(MdError::Msg("got a row before any table".to_string())),
+        }
+    }
+}
+
+struct StreamSink<W: Write> {
+    format: StreamFormat,
+    writer: W,
+    title: Option<String>,
+    column_names: Vec<String>,
+    rows_in_table: usize,
+}
+
+impl<W: Write> TableSink for StreamSink<W> {
+    fn start_table(&mut self, title: &str, heading: &[OutputColumn]) -> Result<(), MdError> {
+        self.title = Some(title.to_string());
+        self.column_names = heading.iter().map(OutputColumn::name).collect();
+        self.rows_in_table = 0;
+        if let StreamFormat::Csv = self.format {
+            self.writer
+                .write_all(format_csv_record(&self.column_names).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn row(&mut self, row: Vec<String>) -> Result<(), MdError> {
+        self.rows_in_table += 1;
+        let line = match self.format {
+            StreamFormat::Csv => format_csv_record(&row),
+            StreamFormat::Ndjson => {
+                let mut fields = Vec::with_capacity(row.len() + 1);
+                if let Some(ref title) = self.title {
+                    fields.push(format!("\"dataset\":{}", json_value(title)));
+                }
+                for (name, value) in self.column_names.iter().zip(&row) {
+                    fields.push(format!(
+                        "{}:{}",
+                        json_value(name),
+                        json_number_or_string(value)
+                    ));
+                }
+                format!("{{{}}}\n", fields.join(","))
+            }
+        };
+        self.writer.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    // A table without rows gets NO_RECORDS_MESSAGE, as in Table::format_as_csv() and
+    // Table::format_as_json().
+    fn end_table(&mut self) -> Result<(), MdError> {
+        if self.rows_in_table > 0 {
+            return Ok(());
+        }
+        let line = match self.format {
+            StreamFormat::Csv => {
+                let mut row = vec![String::new(); self.column_names.len().max(1)];
+                row[0] = NO_RECORDS_MESSAGE.to_string();
+                format_csv_record(&row)
+            }
+            StreamFormat::Ndjson => {
+                let mut fields = Vec::with_capacity(2);
+                if let Some(ref title) = self.title {
+                    fields.push(format!("\"dataset\":{}", json_value(title)));
+                }
+                fields.push(format!("\"message\":{}", json_value(NO_RECORDS_MESSAGE)));
+                format!("{{{}}}\n", fields.join(","))
+            }
+        };
+        self.writer.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn json_value(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+// The table values are all integer counts and codes, which are JSON numbers.
+fn json_number_or_string(value: &str) -> String {
+    match value.parse::<i64>() {
+        Ok(number) => number.to_string(),
+        Err(_) => json_value(value),
+    }
+}
+
+// Escape text for HTML element content and attribute values.
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// A value from a table as JSON for Table::format_as_json(). Values of constructed numeric
+// columns are numbers; everything else is a string.
+fn json_table_value(column: &OutputColumn, value: &str) -> serde_json::Value {
+    let number = match column {
+        OutputColumn::Constructed {
+            data_type: IpumsDataType::Integer,
+            ..
+        } => value.parse::<i64>().ok().map(serde_json::Value::from),
+        OutputColumn::Constructed {
+            data_type: IpumsDataType::Float,
+            ..
+        } => value
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(serde_json::Value::Number),
+        _ => None,
+    };
+    number.unwrap_or_else(|| serde_json::Value::String(value.to_string()))
+}
+
+/// Format fields as one line of CSV. Fields which contain commas, double quotes or line
+/// breaks are quoted, with double quotes inside them doubled, as in RFC 4180.
+pub(crate) fn format_csv_record<S: AsRef<str>>(fields: &[S]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|field| {
+            let field = field.as_ref();
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+    line.push('\n');
+    line
+}
+
+// The engine which runs a tabulation's queries, chosen by [TabulateOptions::platform].
+enum QueryEngine<'conn> {
+    Duckdb(DuckdbConnection<'conn>),
+    #[cfg(feature = "datafusion")]
+    DataFusion(datafusion_engine::DataFusionEngine),
+}
+
+// A DuckDB connection opened for a single tabulation, or one the caller opened and passed to
+// [tabulate_with_connection].
+enum DuckdbConnection<'conn> {
+    Owned(Connection),
+    Borrowed(&'conn Connection),
+}
+
+impl std::ops::Deref for DuckdbConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            Self::Owned(conn) => conn,
+            Self::Borrowed(conn) => conn,
+        }
+    }
+}
+
+impl QueryEngine<'_> {
+    // Set up the engine to read the data of a dataset, once before running any of its queries.
+    // DuckDB gets a temporary view over the data files of each record type, named like the
+    // record type's [DataSource::table_name], so that the queries don't each resolve the
+    // files again. DataFusion registers the files as tables with the same names.
+    fn prepare_dataset(
+        &self,
+        ctx: &Context,
+        dataset_name: &str,
+        input_type: &InputType,
+    ) -> Result<(), MdError> {
+        match self {
+            // Native tables are already in the database.
+            QueryEngine::Duckdb(_) if *input_type == InputType::NativeDb => Ok(()),
+            QueryEngine::Duckdb(conn) => {
+                for source in DataSource::for_dataset(ctx, dataset_name, input_type)?.values() {
+                    let (DataSource::Parquet { full_path, .. } | DataSource::Csv { full_path, .. }) =
+                        source
+                    else {
+                        continue;
+                    };
+                    // Record types without data files, like households in a person-only
+                    // dataset, don't get a view.
+                    if !data_path_exists(full_path) {
+                        continue;
+                    }
+                    conn.execute_batch(&format!(
+                        "CREATE OR REPLACE TEMP VIEW {} AS SELECT * FROM {}",
+                        source.table_name(),
+                        source.for_platform(&DataPlatform::Duckdb)
+                    ))?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "datafusion")]
+            QueryEngine::DataFusion(df) => df.register_dataset(ctx, dataset_name, input_type),
+        }
+    }
+
+    // The input type to generate queries for once [QueryEngine::prepare_dataset] has set up the
+    // datasets. DuckDB's views stand in for the data files just like tables in a native
+    // database, so its queries refer to them by name.
+    fn query_input_type(&self, input_type: &InputType) -> InputType {
+        if matches!(self, QueryEngine::Duckdb(_)) {
+            InputType::NativeDb
+        } else {
+            input_type.clone()
+        }
+    }
+}
+
+// Running tabulation queries with DataFusion, a pure Rust query engine, for environments
+// where DuckDB's native library is hard to ship.
+#[cfg(feature = "datafusion")]
+mod datafusion_engine {
+    use datafusion::arrow::array::{Array, AsArray};
+    use datafusion::arrow::datatypes::{
+        DataType, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Int8Type, UInt16Type,
+        UInt32Type, UInt64Type, UInt8Type,
+    };
+    use datafusion::arrow::util::display::array_value_to_string;
+    use datafusion::datasource::file_format::file_compression_type::FileCompressionType;
+    use datafusion::prelude::{CsvReadOptions, ParquetReadOptions, SessionContext};
+
+    use crate::conventions::{data_path_exists, Context};
+    use crate::mderror::MdError;
+    use crate::query_gen::DataSource;
+    use crate::request::InputType;
+
+    use super::integer_code_or_text;
+
+    pub(super) struct DataFusionEngine {
+        session: SessionContext,
+        // DataFusion is async, so its futures run to completion on this runtime.
+        runtime: tokio::runtime::Runtime,
+    }
+
+    impl DataFusionEngine {
+        pub(super) fn new() -> Result<Self, MdError> {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            Ok(Self {
+                session: SessionContext::new(),
+                runtime,
+            })
+        }
+
+        /// Register the data files of each record type of the dataset as tables named like
+        /// their [DataSource::table_name], which the generated queries refer to. Record types
+        /// without data files aren't registered.
+        pub(super) fn register_dataset(
+            &self,
+            ctx: &Context,
+            dataset_name: &str,
+            input_type: &InputType,
+        ) -> Result<(), MdError> {
+            for source in DataSource::for_dataset(ctx, dataset_name, input_type)?.values() {
+                match source {
+                    DataSource::Parquet { name, full_path } if data_path_exists(full_path) => {
+                        let path = full_path.to_string_lossy();
+                        self.runtime.block_on(self.session.register_parquet(
+                            name,
+                            &path,
+                            ParquetReadOptions::default(),
+                        ))?;
+                    }
+                    DataSource::Csv { name, full_path } if full_path.exists() => {
+                        let path = full_path.to_string_lossy();
+                        let options = if path.ends_with(".gz") {
+                            CsvReadOptions::new()
+                                .file_extension(".csv.gz")
+                                .file_compression_type(FileCompressionType::GZIP)
+                        } else {
+                            CsvReadOptions::new()
+                        };
+                        self.runtime
+                            .block_on(self.session.register_csv(name, &path, options))?;
+                    }
+                    DataSource::NativeTable { name } => {
+                        return Err(MdError::Msg(format!(
+                            "Can't read native table '{name}' with DataFusion."
+                        )));
+                    }
+                    _ => (),
+                }
+            }
+            Ok(())
+        }
+
+        /// Run the query and read the values of its results as strings, as with DuckDB; see
+        /// [arrow_value_string]. Arrow results are columnar, so the rows are read across the
+        /// columns of each record batch.
+        pub(super) fn query_rows(&self, q: &str) -> Result<Vec<Vec<String>>, MdError> {
+            let batches = self
+                .runtime
+                .block_on(async { self.session.sql(q).await?.collect().await })?;
+
+            let mut rows = Vec::new();
+            for batch in batches {
+                for row_number in 0..batch.num_rows() {
+                    let mut this_row = Vec::with_capacity(batch.num_columns());
+                    for (column_number, column) in batch.columns().iter().enumerate() {
+                        let Some(item) = arrow_value_string(column.as_ref(), row_number) else {
+                            return Err(MdError::Msg(format!(
+                                "Can't extract value for '{}', it has an unsupported type",
+                                batch.schema().field(column_number).name()
+                            )));
+                        };
+                        this_row.push(item);
+                    }
+                    rows.push(this_row);
+                }
+            }
+            Ok(rows)
+        }
+    }
+
+    /// Format a value of an Arrow array like DuckDB values are formatted: integers and
+    /// floating point numbers as they are, text which is an integer code as the integer, and
+    /// NULL as an empty string. `None` means the type isn't supported.
+    pub(super) fn arrow_value_string(column: &dyn Array, row: usize) -> Option<String> {
+        if column.is_null(row) {
+            return Some(String::new());
+        }
+        let item = match column.data_type() {
+            DataType::Boolean => column.as_boolean().value(row).to_string(),
+            DataType::Int8 => column.as_primitive::<Int8Type>().value(row).to_string(),
+            DataType::Int16 => column.as_primitive::<Int16Type>().value(row).to_string(),
+            DataType::Int32 => column.as_primitive::<Int32Type>().value(row).to_string(),
+            DataType::Int64 => column.as_primitive::<Int64Type>().value(row).to_string(),
+            DataType::UInt8 => column.as_primitive::<UInt8Type>().value(row).to_string(),
+            DataType::UInt16 => column.as_primitive::<UInt16Type>().value(row).to_string(),
+            DataType::UInt32 => column.as_primitive::<UInt32Type>().value(row).to_string(),
+            DataType::UInt64 => column.as_primitive::<UInt64Type>().value(row).to_string(),
+            DataType::Float32 => column.as_primitive::<Float32Type>().value(row).to_string(),
+            DataType::Float64 => column.as_primitive::<Float64Type>().value(row).to_string(),
+            DataType::Decimal128(_, _) | DataType::Decimal256(_, _) => {
+                array_value_to_string(column, row).ok()?
+            }
+            DataType::Utf8 => integer_code_or_text(column.as_string::<i32>().value(row)),
+            DataType::LargeUtf8 => integer_code_or_text(column.as_string::<i64>().value(row)),
+            _ => return None,
+        };
+        Some(item)
+    }
+}
+
+// Read the values of a row of DuckDB query results as strings. Integers and floating point
+// numbers are formatted as they are, so a weighted count of 12.5 is "12.5", and SQL NULL is
+// an empty string.
+fn duckdb_row_values(row: &duckdb::Row) -> Result<Vec<String>, MdError> {
+    let mut this_row = Vec::new();
+    // Must do this here on row rather than getting column_names() from
+    // stmt.column_names() because of a bug in the DuckDB API -- it
+    // works on rsqlite but not DuckDB.
+    // See https://github.com/duckdb/duckdb-rs/issues/251
+    let column_names = row.as_ref().column_names();
+    for (column_number, column_name) in column_names.iter().enumerate() {
+        let value = match row.get_ref(column_number) {
+            Ok(value) => value,
+            Err(e) => {
+                return Err(MdError::Msg(format!(
+                    "Can't extract value for '{}', error was '{}'",
+                    &column_name, e
+                )))
+            }
+        };
+        let Some(item) = duckdb_value_string(value) else {
+            return Err(MdError::Msg(format!(
+                "Can't extract value for '{}', it has an unsupported type",
+                &column_name
+            )));
+        };
+        this_row.push(item);
+    }
+    Ok(this_row)
+}
+
+// Format a DuckDB value for a table, or None if it's a type tabulations don't produce.
+fn duckdb_value_string(value: ValueRef) -> Option<String> {
+    let item = match value {
+        ValueRef::Null => String::new(),
+        ValueRef::Boolean(b) => b.to_string(),
+        ValueRef::TinyInt(i) => i.to_string(),
+        ValueRef::SmallInt(i) => i.to_string(),
+        ValueRef::Int(i) => i.to_string(),
+        ValueRef::BigInt(i) => i.to_string(),
+        ValueRef::HugeInt(i) => i.to_string(),
+        ValueRef::UTinyInt(i) => i.to_string(),
+        ValueRef::USmallInt(i) => i.to_string(),
+        ValueRef::UInt(i) => i.to_string(),
+        ValueRef::UBigInt(i) => i.to_string(),
+        ValueRef::Float(f) => f.to_string(),
+        ValueRef::Double(f) => f.to_string(),
+        ValueRef::Decimal(d) => d.to_string(),
+        ValueRef::Text(text) => integer_code_or_text(&String::from_utf8_lossy(text)),
+        _ => return None,
+    };
+    Some(item)
+}
+
+// Bucketed variables come back as zero-padded codes like '001'; report them as the integer
+// codes they stand for, as with unbucketed variables. Other text stays as it is.
+fn integer_code_or_text(text: &str) -> String {
+    match text.parse::<i64>() {
+        Ok(code) => code.to_string(),
+        Err(_) => text.to_string(),
+    }
+}
+
+// Send a row of query results to the sink, or hold it for later when `hold` is true.
+fn emit_or_hold<S: TableSink>(
+    sink: &mut S,
+    heading: &[OutputColumn],
+    row: Vec<String>,
+    hold: bool,
+    held_rows: &mut Vec<Vec<String>>,
+    unlabeled: &mut Vec<String>,
+) -> Result<(), MdError> {
+    if hold {
+        held_rows.push(row);
+        Ok(())
+    } else {
+        sink.row(with_labels(heading, row, unlabeled))
+    }
+}
+
+// Run the queries for a tabulation request, sending each table and its rows to the sink
+// as they're read. The queries run on `conn` when it's given, and otherwise on a new query
+// engine for the options' platform. Returns any warnings about skipped datasets.
+fn run_tabulation<R, S>(
+    ctx: &Context,
+    rq: R,
+    options: &TabulateOptions,
+    conn: Option<&Connection>,
+    sink: &mut S,
+) -> Result<Vec<String>, MdError>
+where
+    R: DataRequest,
+    S: TableSink,
+{
+    let mut heading = output_heading(ctx, &rq, options)?;
+
+    // tab_queries() produces one query per request sample, in the same order as the
+    // request samples, so each output table is titled with the name of its dataset. When
+    // the request has several units of analysis there's a query for each unit within each
+    // sample, and the titles name the unit too, like "us2015b household".
+    let dataset_names = rq
+        .get_request_samples()
+        .into_iter()
+        .map(|s| s.name)
+        .collect::<Vec<String>>();
+    let units = match options.count_members {
+        Some(_) => Vec::new(),
+        None => rq.table_units_of_analysis(),
+    };
+    let mut tables = Vec::new();
+    for dataset_name in &dataset_names {
+        if units.len() <= 1 {
+            tables.push((dataset_name.clone(), dataset_name.clone()));
+            continue;
+        }
+        for unit in &units {
+            let Some(record_type) = ctx.settings.record_types.get(unit) else {
+                return Err(metadata_error!(
+                    "No record type '{unit}' in current context."
+                ));
+            };
+            let title = format!("{dataset_name} {}", record_type.name.to_lowercase());
+            tables.push((dataset_name.clone(), title));
+        }
+    }
+
+    let engine = match conn {
+        Some(conn) if options.platform == DataPlatform::Duckdb => {
+            QueryEngine::Duckdb(DuckdbConnection::Borrowed(conn))
+        }
+        Some(_) => {
+            return Err(MdError::Msg(format!(
+                "Can't run {:?} queries on a DuckDB connection.",
+                options.platform
+            )))
+        }
+        None => options.open_query_engine()?,
+    };
+
+    let log_prefix = options.log_prefix();
+    log::info!(
+        "{log_prefix}tabulating {} table(s) from {} dataset(s)",
+        tables.len(),
+        dataset_names.len()
+    );
+
+    let input_type = options.input_type();
+    let mut warnings = Vec::new();
+    let mut missing_datasets = Vec::new();
+    // Tables in a native database aren't files, so they're never skipped.
+    if options.skip_missing_datasets && input_type != InputType::NativeDb {
+        for dataset_name in &dataset_names {
+            let files = rq.required_files_for_dataset(ctx, dataset_name, &input_type)?;
+            if let Some(missing) = files.iter().find(|f| !data_path_exists(f)) {
+                let warning = format!(
+                    "skipped dataset {dataset_name} because its data file {} is missing",
+                    missing.display()
+                );
+                log::warn!("{log_prefix}{warning}");
+                warnings.push(warning);
+                missing_datasets.push(dataset_name.clone());
+            }
+        }
+    }
+
+    let present_datasets: Vec<&str> = dataset_names
+        .iter()
+        .filter(|name| !missing_datasets.contains(name))
+        .map(String::as_str)
+        .collect();
+    check_condition_variables(ctx, &rq, &present_datasets)?;
+
+    // The request variables follow the counts in each row of query results.
+    let count_names =
+        tabulated_count_columns(ctx, &rq, options.count_members.as_deref())?.column_names();
+    let first_variable_column = if options.count_members.is_some() {
+        count_names.len() + 1
+    } else {
+        count_names.len()
+    };
+    let weighted_column = count_names
+        .iter()
+        .position(|&name| name == "weighted_ct")
+        .unwrap_or_default();
+    let variable_count = heading
+        .iter()
+        .filter(|c| matches!(c, OutputColumn::RequestVar(_)))
+        .count();
+    let variable_columns: Vec<usize> =
+        (first_variable_column..first_variable_column + variable_count).collect();
+
+    // Set up each dataset once, however many queries read it.
+    let mut prepared_datasets: Vec<&str> = Vec::new();
+    for &dataset_name in &present_datasets {
+        if !prepared_datasets.contains(&dataset_name) {
+            engine.prepare_dataset(ctx, dataset_name, &input_type)?;
+            prepared_datasets.push(dataset_name);
+        }
+    }
+    let query_input_type = engine.query_input_type(&input_type);
+
+    let sql_queries = match options.count_members {
+        Some(ref member_rectype) => member_count_queries(
+            ctx,
+            rq,
+            member_rectype,
+            &query_input_type,
+            &options.platform,
+        )?,
+        None => tab_queries(ctx, rq, &query_input_type, &options.platform)?,
+    };
+    let mut unlabeled = Vec::new();
+    if let Some(dataset_name) = present_datasets.first() {
+        match engine {
+            QueryEngine::Duckdb(ref conn) => {
+                fill_types_from_schema(ctx, conn, dataset_name, &query_input_type, &mut heading)?
+            }
+            // Without types, columns fall back to the width of their names.
+            #[cfg(feature = "datafusion")]
+            QueryEngine::DataFusion(_) => (),
+        }
+    }
+    // Percentages need the table's total, so their rows are held until it's known.
+    let hold_rows = options.percentages.is_some();
+    for (q, (dataset_name, title)) in sql_queries.into_iter().zip(tables) {
+        if missing_datasets.contains(&dataset_name) {
+            continue;
+        }
+
+        if DEBUG {
+            println!("{}", &q);
+        }
+        log::debug!("{log_prefix}running query for {title}: {q}");
+
+        let mut held_rows = Vec::new();
+        let mut row_count = 0;
+        match engine {
+            QueryEngine::Duckdb(ref conn) => {
+                let mut stmt = conn.prepare(&q)?;
+                let mut rows = stmt.query([])?;
+
+                sink.start_table(&title, &heading)?;
+                while let Some(row) = rows.next()? {
+                    row_count += 1;
+                    let this_row = duckdb_row_values(row)?;
+                    emit_or_hold(
+                        sink,
+                        &heading,
+                        this_row,
+                        hold_rows,
+                        &mut held_rows,
+                        &mut unlabeled,
+                    )?;
+                }
+            }
+            #[cfg(feature = "datafusion")]
+            QueryEngine::DataFusion(ref df) => {
+                let rows = df.query_rows(&q)?;
+
+                sink.start_table(&title, &heading)?;
+                for this_row in rows {
+                    row_count += 1;
+                    emit_or_hold(
+                        sink,
+                        &heading,
+                        this_row,
+                        hold_rows,
+                        &mut held_rows,
+                        &mut unlabeled,
+                    )?;
+                }
+            }
+        }
+        if let Some(base) = options.percentages {
+            let group_columns = base.group_columns(&variable_columns);
+            let decimals = options.percent_decimals.unwrap_or(DEFAULT_PERCENT_DECIMALS);
+            for row in with_percentages(held_rows, weighted_column, &group_columns, decimals)? {
+                sink.row(with_labels(&heading, row, &mut unlabeled))?;
+            }
+        }
+        sink.end_table()?;
+        log::info!("{log_prefix}read {row_count} row(s) for {title}");
+    }
+
+    if options.warn_missing_labels {
+        for warning in unlabeled {
+            log::warn!("{log_prefix}{warning}");
+            warnings.push(warning);
+        }
+    }
+    Ok(warnings)
+}
+
+/// Runs tabulation requests against a [Context], and may be shared across threads.
+///
+/// Each call to [Tabulator::tabulate] runs its own DuckDB queries, so many concurrent
+/// calls can use a lot of memory. A `Tabulator` constructed with a `max_in_flight` limit
+/// runs at most that many tabulations at once, and further calls wait for one of them to
+/// finish. By default there's no limit.
+pub struct Tabulator {
+    ctx: Context,
+    options: TabulateOptions,
+    in_flight: Option<InFlightLimit>,
+}
+
+impl Tabulator {
+    /// Create a `Tabulator` which tabulates with the given options. `max_in_flight`
+    /// limits how many tabulations may run at once; `None` means no limit.
+    pub fn new(
+        ctx: Context,
+        options: TabulateOptions,
+        max_in_flight: Option<usize>,
+    ) -> Result<Self, MdError> {
+        if max_in_flight == Some(0) {
+            return Err(MdError::Msg(
+                "max_in_flight must allow at least one tabulation at a time".to_string(),
+            ));
+        }
+        Ok(Self {
+            ctx,
+            options,
+            in_flight: max_in_flight.map(InFlightLimit::new),
+        })
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.ctx
+    }
+
+    /// The most tabulations that may run at once, or `None` if there's no limit.
+    pub fn max_in_flight(&self) -> Option<usize> {
+        self.in_flight.as_ref().map(|limit| limit.max)
+    }
+
+    /// Compute the result of a tabulation request like [tabulate_with_options], waiting
+    /// first if the maximum number of tabulations are already running.
+    pub fn tabulate<R>(&self, rq: R) -> Result<Tabulation, MdError>
+    where
+        R: DataRequest,
+    {
+        let _permit = self.in_flight.as_ref().map(InFlightLimit::acquire);
+        tabulate_with_options(&self.ctx, rq, &self.options)
+    }
+}
+
+// A counting semaphore. Each InFlightPermit holds one of the max slots until it's dropped.
+struct InFlightLimit {
+    max: usize,
+    running: Mutex<usize>,
+    released: Condvar,
+}
+
+impl InFlightLimit {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            running: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> InFlightPermit<'_> {
+        // A panic while holding the lock can't leave the count inconsistent, so it's
+        // fine to keep going with a poisoned lock.
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        while *running >= self.max {
+            running = self
+                .released
+                .wait(running)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        *running += 1;
+        InFlightPermit { limit: self }
+    }
+}
+
+struct InFlightPermit<'a> {
+    limit: &'a InFlightLimit,
+}
+
+impl Drop for InFlightPermit<'_> {
+    fn drop(&mut self) {
+        let mut running = self.limit.running.lock().unwrap_or_else(|e| e.into_inner());
+        *running -= 1;
+        self.limit.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input_schema_tabulation::{AutoBinMethod, CountColumns, GeneralDetailedSelection};
+    use crate::ipums_metadata_model::IpumsDataset;
+    use crate::ipums_metadata_model::{
+        IpumsCategory, IpumsValue, IpumsVariable, UniversalCategoryType,
+    };
+    use crate::query_gen::{CompareOperation, Condition};
+    use crate::request::{
+        AbacusRequest, CaseSelectLogic, OutputFormat, RequestType, SimpleRequest,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::*;
+
+    #[test]
+    fn test_complex_tabulation() {
+        let tabtime = Instant::now();
+        let json_request = include_str!("../tests/requests/incwage_marst_example.json");
+
+        let (ctx, rq) = AbacusRequest::try_from_json(&json_request)
+            .expect("Error loading test context and deserializing test request.");
+
+        //println!("Codebook: {}", rq.print_codebook());
+
+        let result = tabulate(&ctx, rq);
+        if let Err(ref e) = result {
+            eprintln!("Error setting up test: {:?}", e);
+        }
+        println!("Test tabulation took {} ms", tabtime.elapsed().as_millis());
+        assert!(result.is_ok());
+        if let Ok(tab) = result {
+            if let Ok(output) = tab.output(TableFormat::TextTable) {
+                println!("{output}");
+            }
+
+            let tables = tab.into_inner();
+            assert_eq!(2, tables.len());
+
+            for (index, table) in tables.iter().enumerate() {
+                // There are some category combinations  rare enough not to exist on every sample
+                if index == 0 {
+                    assert_eq!(
+                        79,
+                        table.rows.len(),
+                        "6 marst X 15 incwage - a few combinations"
+                    );
+                }
+                if index == 1 {
+                    assert_eq!(
+                        77,
+                        table.rows.len(),
+                        "6 marst X 15 incwage - a few combinations"
+                    );
+                }
+            }
+        }
+    }
+
+    fn table_with_counts(counts: &[(&str, &str)]) -> Table {
+        let mut table = Table::empty();
+        table.heading.push(OutputColumn::Constructed {
+            name: "ct".to_string(),
+            width: 10,
+            data_type: IpumsDataType::Integer,
+            note: None,
+        });
+        table.heading.push(OutputColumn::Constructed {
+            name: "weighted_ct".to_string(),
+            width: 10,
+            data_type: IpumsDataType::Integer,
+            note: None,
+        });
+        for (ct, weighted_ct) in counts {
+            table
+                .rows
+                .push(vec![ct.to_string(), weighted_ct.to_string()]);
+        }
+        table
+    }
+
+    fn marst_table(rows: &[[&str; 3]]) -> Table {
+        let marst = IpumsVariable {
+            id: 0,
+            name: "MARST".into(),
+            data_type: Some(IpumsDataType::Integer),
+            label: None,
+            record_type: "P".to_string(),
+            categories: None,
+            formatting: Some((65, 1)),
+            general_width: None,
+            description: None,
+            category_bins: None,
+        };
+        let marst_rq =
+            RequestVariable::try_from_ipums_variable(&marst, GeneralDetailedSelection::Detailed)
+                .expect("should convert into a RequestVariable");
+
+        let mut table = table_with_counts(&[]);
+        table.heading.push(OutputColumn::RequestVar(marst_rq));
+        for row in rows {
+            table.rows.push(row.iter().map(|s| s.to_string()).collect());
+        }
+        table
+    }
+
+    #[test]
+    fn test_merge_sum() {
+        let mut first = marst_table(&[["10", "1000", "1"], ["5", "400", "2"]]);
+        first.title = Some("us2015b".to_string());
+        let mut second = marst_table(&[["3", "250", "2"], ["7", "600", "6"]]);
+        second.title = Some("us2016b".to_string());
+
+        let merged = Table::merge_sum(&[first, second]).expect("should merge tables");
+        assert_eq!(merged.title, None);
+        assert_eq!(
+            merged.rows,
+            vec![
+                vec!["10", "1000", "1"],
+                vec!["8", "650", "2"],
+                vec!["7", "600", "6"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tabulation_diff_one_cell() {
+        let mut before = marst_table(&[["10", "1000", "1"], ["5", "400", "2"], ["7", "600", "6"]]);
+        before.title = Some("us2015b".to_string());
+        let mut after = marst_table(&[["10", "1000", "1"], ["5", "450", "2"], ["7", "600", "6"]]);
+        after.title = Some("us2015b".to_string());
+        let tab = |table: Table| Tabulation {
+            tables: vec![table],
+            codebook: None,
+            warnings: Vec::new(),
+        };
+
+        let diff = tab(before.clone()).diff(&tab(after));
+        assert!(diff.only_in_self.is_empty());
+        assert!(diff.only_in_other.is_empty());
+        assert_eq!(
+            diff.changed_cells,
+            vec![DiffCell {
+                table: Some("us2015b".to_string()),
+                key: vec!["2".to_string()],
+                column: "weighted_ct".to_string(),
+                value: "400".to_string(),
+                other_value: "450".to_string(),
+            }]
+        );
+
+        assert!(tab(before.clone()).diff(&tab(before)).is_empty());
+    }
+
+    #[test]
+    fn test_merge_sum_different_headings_error() {
+        let first = table_with_counts(&[("1", "100")]);
+        let second = marst_table(&[["1", "100", "1"]]);
+        let result = Table::merge_sum(&[first, second]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_format_as_text_with_summary() {
+        let table = table_with_counts(&[("10", "1000"), ("25", "2600"), ("5", "400")]);
+        let text = table
+            .format_as_text_with_summary()
+            .expect("should format with summary");
+
+        assert!(text.starts_with(&table.format_as_text().unwrap()));
+        assert!(text.contains("ct: total 40, mean 13.33, min 5, max 25\n"));
+        assert!(text.contains("weighted_ct: total 4000, mean 1333.33, min 400, max 2600\n"));
+    }
+
+    #[test]
+    fn test_format_as_text_with_summary_no_rows() {
+        let table = table_with_counts(&[]);
+        let text = table
+            .format_as_text_with_summary()
+            .expect("should format with summary");
+        assert!(text.contains("ct: total 0\n"));
+    }
+
+    #[test]
+    fn test_format_as_text_empty_table() {
+        let table = table_with_counts(&[]);
+        assert!(table.is_empty());
+
+        let text = table.format_as_text().expect("should format as text");
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(3, lines.len(), "header, separator and the no records row");
+        assert!(lines[2].starts_with("| No records "));
+        assert!(lines[2].ends_with(" |"));
+        assert_eq!(
+            lines[0].len(),
+            lines[2].len(),
+            "the no records row should span the table"
+        );
+    }
+
+    #[test]
+    fn test_format_as_text_column_note_footnote() {
+        let mut table = table_with_counts(&[("10", "1000"), ("5", "400")]);
+        table.heading[1] = OutputColumn::Constructed {
+            name: "weighted_ct".to_string(),
+            width: 10,
+            data_type: IpumsDataType::Integer,
+            note: Some("weighted by PERWT/100".to_string()),
+        };
+
+        let text = table.format_as_text().expect("should format as text");
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "|         ct | weighted_ct[1] |");
+        assert_eq!(lines[2], "|         10 |           1000 |");
+        assert_eq!(lines.last(), Some(&"[1] weighted by PERWT/100"));
+
+        let summary = table
+            .format_as_text_with_summary()
+            .expect("should format as text with a summary");
+        assert!(summary.ends_with("[1] weighted by PERWT/100\n"));
+    }
+
+    #[test]
+    fn test_format_as_text_count_wider_than_column() {
+        let table = table_with_counts(&[("10", "1000"), ("5", "123456789012")]);
+        let text = table.format_as_text().expect("should format as text");
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "|         ct |  weighted_ct |");
+        assert_eq!(lines[2], "|         10 |         1000 |");
+        assert_eq!(lines[3], "|          5 | 123456789012 |");
+        assert_eq!(lines[1].len(), lines[0].len());
+        assert_eq!(table.text_table_width().unwrap(), lines[0].len());
+    }
+
+    #[test]
+    fn test_with_percentages() {
+        let rows = vec![
+            vec!["10".to_string(), "300".to_string(), "1".to_string()],
+            vec!["20".to_string(), "600".to_string(), "2".to_string()],
+            vec!["5".to_string(), "100".to_string(), "6".to_string()],
+        ];
+        let rows = with_percentages(rows, 1, &[], 2).expect("should compute percentages");
+        let pct: Vec<&str> = rows.iter().map(|r| r[2].as_str()).collect();
+        assert_eq!(pct, vec!["30.00", "60.00", "10.00"]);
+        assert_eq!(rows[0], vec!["10", "300", "30.00", "1"]);
+    }
+
+    #[test]
+    fn test_with_percentages_decimals() {
+        let rows = vec![
+            vec!["1".to_string(), "1".to_string(), "1".to_string()],
+            vec!["2".to_string(), "2".to_string(), "2".to_string()],
+        ];
+        let pct = |decimals| -> Vec<String> {
+            with_percentages(rows.clone(), 1, &[], decimals)
+                .expect("should compute percentages")
+                .into_iter()
+                .map(|row| row[2].clone())
+                .collect()
+        };
+        assert_eq!(pct(0), vec!["33", "67"]);
+        assert_eq!(pct(1), vec!["33.3", "66.7"]);
+        assert_eq!(pct(4), vec!["33.3333", "66.6667"]);
+        assert!(with_percentages(rows.clone(), 1, &[], MAX_PERCENT_DECIMALS + 1).is_err());
+
+        // Halves round away from zero, and trailing zeros are kept.
+        assert_eq!(format_percentage(12345, 100000, 2), "12.35");
+        assert_eq!(format_percentage(1, 8, 1), "12.5");
+        assert_eq!(format_percentage(1, 8, 0), "13");
+        assert_eq!(format_percentage(1, 4, 3), "25.000");
+        assert_eq!(format_percentage(5, 0, 2), "0.00");
+    }
+
+    #[test]
+    fn test_with_percentages_fractional_weighted_counts() {
+        // Weights like PERWT/100 give fractional weighted counts.
+        let rows = vec![
+            vec!["1".to_string(), "12.5".to_string(), "1".to_string()],
+            vec!["3".to_string(), "37.5".to_string(), "2".to_string()],
+        ];
+        let rows = with_percentages(rows, 1, &[], 2).expect("should compute percentages");
+        let pct: Vec<&str> = rows.iter().map(|r| r[2].as_str()).collect();
+        assert_eq!(pct, vec!["25.00", "75.00"]);
+
+        assert_eq!(format_fractional_percentage(1.0, 3.0, 1), "33.3");
+        assert_eq!(format_fractional_percentage(2.5, 0.0, 2), "0.00");
+
+        let rows = vec![vec!["1".to_string(), "many".to_string()]];
+        assert!(with_percentages(rows, 1, &[], 2).is_err());
+    }
+
+    #[test]
+    fn test_with_percentages_row_and_column_bases() {
+        // ct, weighted_ct, MARST, SEX
+        let rows: Vec<Vec<String>> = [
+            ["4", "300", "1", "1"],
+            ["6", "100", "1", "2"],
+            ["1", "200", "6", "1"],
+            ["3", "200", "6", "2"],
+        ]
+        .iter()
+        .map(|r| r.iter().map(|v| v.to_string()).collect())
+        .collect();
+        let variable_columns = [2, 3];
+
+        let row_groups = PercentBase::Row.group_columns(&variable_columns);
+        let by_row = with_percentages(rows.clone(), 1, &row_groups, 2).expect("should compute");
+        let pct: Vec<&str> = by_row.iter().map(|r| r[2].as_str()).collect();
+        assert_eq!(pct, vec!["75.00", "25.00", "50.00", "50.00"]);
+
+        let column_groups = PercentBase::Column.group_columns(&variable_columns);
+        let by_column = with_percentages(rows, 1, &column_groups, 2).expect("should compute");
+        let pct: Vec<&str> = by_column.iter().map(|r| r[2].as_str()).collect();
+        assert_eq!(pct, vec!["60.00", "33.33", "40.00", "66.67"]);
+    }
+
+    #[test]
+    fn test_tabulate_row_percentages() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST", "SEX"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let options = TabulateOptions {
+            percentages: Some(PercentBase::Row),
+            ..TabulateOptions::default()
+        };
+        let tab = tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+        let table = &tab.tables()[0];
+        assert_eq!(table.heading[2].name(), "pct");
+
+        // The columns are ct, weighted_ct, pct, MARST, then SEX.
+        let mut row_totals: HashMap<String, f64> = HashMap::new();
+        for row in &table.rows {
+            let pct: f64 = row[2].parse().expect("pct should be a number");
+            *row_totals.entry(row[3].clone()).or_insert(0.0) += pct;
+        }
+        assert_eq!(row_totals.len(), 6);
+        for (marst, total) in row_totals {
+            assert!(
+                (total - 100.0).abs() < 0.02,
+                "percentages for MARST {marst} add up to {total}"
+            );
+        }
+    }
+
+    /// Unweighted-only tabulations have the same "ct" column as tabulations with both counts.
+    #[test]
+    fn test_tabulate_unweighted_only() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let both = tabulate(&ctx, rq.clone()).expect("should tabulate both counts");
+
+        rq.count_columns = CountColumns::Unweighted;
+        let unweighted = tabulate(&ctx, rq).expect("should tabulate unweighted counts");
+        let table = &unweighted.tables()[0];
+        let names: Vec<String> = table.heading.iter().map(OutputColumn::name).collect();
+        assert_eq!(names, ["ct", "MARST"]);
+
+        let expected: Vec<Vec<String>> = both.tables()[0]
+            .rows
+            .iter()
+            .map(|row| vec![row[0].clone(), row[2].clone()])
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(table.rows, expected);
+    }
+
+    #[test]
+    fn test_tabulate_percentages_without_weighted_counts_error() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.count_columns = CountColumns::Unweighted;
+        let options = TabulateOptions {
+            percentages: Some(PercentBase::Total),
+            ..TabulateOptions::default()
+        };
+        let err = tabulate_with_options(&ctx, rq, &options)
+            .expect_err("percentages need weighted counts");
+        assert!(err.to_string().contains("without weighted counts"));
+    }
+
+    #[test]
+    fn test_data_type_from_duckdb() {
+        assert_eq!(data_type_from_duckdb("BIGINT"), IpumsDataType::Integer);
+        assert_eq!(data_type_from_duckdb("UTINYINT"), IpumsDataType::Integer);
+        assert_eq!(data_type_from_duckdb("DECIMAL(10,2)"), IpumsDataType::Float);
+        assert_eq!(data_type_from_duckdb("DOUBLE"), IpumsDataType::Float);
+        assert_eq!(data_type_from_duckdb("VARCHAR"), IpumsDataType::String);
+    }
+
+    #[test]
+    fn test_duckdb_value_string() {
+        assert_eq!(Some(String::new()), duckdb_value_string(ValueRef::Null));
+        assert_eq!(
+            Some("42".to_string()),
+            duckdb_value_string(ValueRef::BigInt(42))
+        );
+        assert_eq!(
+            Some("12.5".to_string()),
+            duckdb_value_string(ValueRef::Double(12.5))
+        );
+        assert_eq!(
+            Some("1".to_string()),
+            duckdb_value_string(ValueRef::Text(b"001"))
+        );
+        assert_eq!(
+            Some("abc".to_string()),
+            duckdb_value_string(ValueRef::Text(b"abc"))
+        );
+    }
+
+    #[test]
+    fn test_tabulate_without_metadata_uses_schema_types() {
+        let ctx =
+            Context::from_ipums_collection_name("usa", None, Some(String::from("tests/data_root")))
+                .expect("should be able to create USA context");
+        assert!(ctx.settings.metadata.is_none());
+
+        // Only the name and record type of the variable are known.
+        let marst = IpumsVariable {
+            name: "MARST".into(),
+            data_type: None,
+            label: None,
+            record_type: "P".to_string(),
+            categories: None,
+            formatting: None,
+            general_width: None,
+            description: None,
+            category_bins: None,
+            id: 0,
+        };
+        let rq = SimpleRequest {
+            product: "usa".to_string(),
+            datasets: vec![IpumsDataset::from(("us2015b".to_string(), 0))],
+            variables: vec![marst],
+            unit_rectype: ctx.settings.default_unit_of_analysis.clone(),
+            request_type: RequestType::Tabulation,
+            output_format: OutputFormat::Json,
+            conditions: None,
+            use_general_variables: GeneralDetailedSelection::Detailed,
+            weight_rectype: None,
+            weight_variable: None,
+            table_units: Vec::new(),
+            count_columns: CountColumns::Both,
+            case_select_logic: CaseSelectLogic::And,
+            case_selections: BTreeMap::new(),
+            general_detailed_selections: BTreeMap::new(),
+        };
+
+        let tab = tabulate(&ctx, rq).expect("should tabulate using the data's types");
+        let table = &tab.tables()[0];
+        assert_eq!(
+            table.heading[2].data_type().unwrap(),
+            IpumsDataType::Integer
+        );
+        assert_eq!(table.rows.len(), 6);
+        assert!(table.format_as_text().is_ok());
+    }
+
+    #[test]
+    fn test_format_csv_record_quoting() {
+        let record = format_csv_record(&["1", "a,b", "say \"hi\"", "two\nlines"]);
+        assert_eq!(record, "1,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\"\n");
+    }
+
+    #[test]
+    fn test_format_as_csv() {
+        let table = marst_table(&[["10", "1000", "1"], ["5", "400", "2"]]);
+        assert_eq!(
+            table.format_as_csv(),
+            "ct,weighted_ct,MARST\n10,1000,1\n5,400,2\n"
+        );
+    }
+
+    #[test]
+    fn test_format_as_html() {
+        let mut table = marst_table(&[["10", "1000", "1"]]);
+        table.title = Some("us2015b <sample>".to_string());
+        table.heading.push(OutputColumn::Constructed {
+            name: "note".to_string(),
+            width: 10,
+            data_type: IpumsDataType::String,
+            note: None,
+        });
+        table.rows[0].push("A & B".to_string());
+
+        let html = table.format_as_html();
+        assert!(html.starts_with("<table>\n<caption>us2015b &lt;sample&gt;</caption>\n<thead>\n"));
+        assert!(html.contains(r#"<th class="ct" style="text-align: right">ct</th>"#));
+        assert!(html.contains(r#"<td class="MARST" style="text-align: right">1</td>"#));
+        assert!(html.contains(r#"<td class="note">A &amp; B</td>"#));
+        assert!(html.ends_with("</tbody>\n</table>\n"));
+        assert!(!html.contains("<html>"));
+    }
+
+    #[test]
+    fn test_format_as_json() {
+        let mut table = marst_table(&[["10", "1000", "1"], ["5", "400", "2"]]);
+        table.title = Some("us2015b".to_string());
+        let output = table.format_as_json().expect("should format as JSON");
+        let json: serde_json::Value =
+            serde_json::from_str(&output).expect("output should be valid JSON");
+
+        assert_eq!(json["title"], "us2015b");
+        assert_eq!(
+            json["columns"],
+            serde_json::json!(["ct", "weighted_ct", "MARST"])
+        );
+        assert_eq!(
+            json["rows"][1],
+            serde_json::json!({"ct": 5, "weighted_ct": 400, "MARST": "2"})
+        );
+    }
+
+    #[test]
+    fn test_format_as_csv_empty_table() {
+        let table = marst_table(&[]);
+        assert_eq!(
+            table.format_as_csv(),
+            format!("ct,weighted_ct,MARST\n{NO_RECORDS_MESSAGE},,\n")
+        );
+    }
+
+    #[test]
+    fn test_format_as_html_empty_table() {
+        let table = marst_table(&[]);
+        let html = table.format_as_html();
+        assert!(html.contains(&format!(
+            "<tbody>\n<tr><td colspan=\"3\">{NO_RECORDS_MESSAGE}</td></tr>\n</tbody>"
+        )));
+    }
+
+    #[test]
+    fn test_format_as_json_empty_table() {
+        let table = marst_table(&[]);
+        let output = table.format_as_json().expect("should format as JSON");
+        let json: serde_json::Value =
+            serde_json::from_str(&output).expect("output should be valid JSON");
+        assert_eq!(json["rows"], serde_json::json!([]));
+        assert_eq!(json["message"], NO_RECORDS_MESSAGE);
+
+        let table = marst_table(&[["10", "1000", "1"]]);
+        let output = table.format_as_json().expect("should format as JSON");
+        assert!(!output.contains("message"));
+    }
+
+    /// JSON output of a tabulation is an array with each table as in Table::format_as_json().
+    #[test]
+    fn test_tabulation_output_json() {
+        let mut table = marst_table(&[["10", "1000", "1"], ["5", "400", "2"]]);
+        table.title = Some("us2015b".to_string());
+        let tab = Tabulation {
+            tables: vec![table.clone(), table],
+            codebook: None,
+            warnings: Vec::new(),
+        };
+        let output = tab
+            .output(TableFormat::Json)
+            .expect("should format as JSON");
+        let json: serde_json::Value =
+            serde_json::from_str(&output).expect("output should be valid JSON");
+
+        assert_eq!(json.as_array().map(Vec::len), Some(2));
+        assert_eq!(json[1]["title"], "us2015b");
+        assert_eq!(
+            json[0]["rows"][0],
+            serde_json::json!({"ct": 10, "weighted_ct": 1000, "MARST": "1"})
+        );
+    }
+
+    #[test]
+    fn test_stream_sink_ndjson() {
+        let table = marst_table(&[["10", "1000", "1"], ["5", "400", "2"]]);
+        let mut sink = StreamSink {
+            format: StreamFormat::Ndjson,
+            writer: Vec::new(),
+            title: None,
+            column_names: Vec::new(),
+            rows_in_table: 0,
+        };
+        sink.start_table("us2015b", &table.heading)
+            .expect("should start the table");
+        for row in &table.rows {
+            sink.row(row.clone()).expect("should write the row");
+        }
+
+        let output = String::from_utf8(sink.writer).expect("output should be UTF-8");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"dataset":"us2015b","ct":10,"weighted_ct":1000,"MARST":1}"#,
+                r#"{"dataset":"us2015b","ct":5,"weighted_ct":400,"MARST":2}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stream_sink_empty_table() {
+        let table = marst_table(&[]);
+        for (format, expected) in [
+            (StreamFormat::Csv, table.format_as_csv()),
+            (
+                StreamFormat::Ndjson,
+                format!("{{\"dataset\":\"us2015b\",\"message\":\"{NO_RECORDS_MESSAGE}\"}}\n"),
+            ),
+        ] {
+            let mut sink = StreamSink {
+                format,
+                writer: Vec::new(),
+                title: None,
+                column_names: Vec::new(),
+                rows_in_table: 0,
+            };
+            sink.start_table("us2015b", &table.heading)
+                .expect("should start the table");
+            sink.end_table().expect("should end the table");
+            let output = String::from_utf8(sink.writer).expect("output should be UTF-8");
+            assert_eq!(output, expected);
+        }
+    }
+
+    /// Streaming CSV gives the same output as formatting the whole tabulation as CSV.
+    #[test]
+    fn test_tabulate_to_writer_matches_batch_csv() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us2016b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let batch = tabulate(&ctx, rq.clone())
+            .expect("should tabulate")
+            .output(TableFormat::Csv)
+            .expect("should format as CSV");
+
+        let mut streamed = Vec::new();
+        tabulate_to_writer(&ctx, rq, StreamFormat::Csv, &mut streamed)
+            .expect("should stream the tabulation");
+        let streamed = String::from_utf8(streamed).expect("output should be UTF-8");
+
+        assert_eq!(streamed, batch);
+        assert!(streamed.starts_with("ct,weighted_ct,MARST\n"));
+    }
+
+    #[test]
+    fn test_duckdb_settings_sql() {
+        assert!(TabulateOptions::default().duckdb_settings_sql().is_empty());
+
+        let options = TabulateOptions {
+            temp_directory: Some(PathBuf::from("/scratch/o'brien")),
+            max_memory: Some("4GB".to_string()),
+            ..TabulateOptions::default()
+        };
+        assert_eq!(
+            options.duckdb_settings_sql(),
+            vec![
+                "SET temp_directory = '/scratch/o''brien'",
+                "SET max_memory = '4GB'"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duckdb_connection_settings_applied() {
+        let temp_directory = std::env::temp_dir().join("cimdea_spill");
+        let options = TabulateOptions {
+            temp_directory: Some(temp_directory.clone()),
+            max_memory: Some("1GB".to_string()),
+            ..TabulateOptions::default()
+        };
+        let conn = options
+            .open_duckdb_connection()
+            .expect("should open a configured connection");
+
+        let setting: String = conn
+            .query_row("select current_setting('temp_directory')", [], |row| {
+                row.get(0)
+            })
+            .expect("should read the temp_directory setting");
+        assert_eq!(setting, temp_directory.display().to_string());
+    }
+
+    /// Data roots may have spaces and quotes in their paths.
+    #[test]
+    fn test_tabulate_data_root_with_space() {
+        let data_root =
+            std::env::temp_dir().join(format!("cimdea data root's {}", std::process::id()));
+        let parquet_dir = data_root.join("parquet").join("us2015b");
+        std::fs::create_dir_all(&parquet_dir).expect("should create the parquet directory");
+        std::fs::create_dir_all(data_root.join("layouts"))
+            .expect("should create the layouts directory");
+        std::fs::copy(
+            "tests/data_root/layouts/us2015b.layout.txt",
+            data_root.join("layouts").join("us2015b.layout.txt"),
+        )
+        .expect("should copy the layout");
+        for rt in ["H", "P"] {
+            let file = format!("us2015b_usa.{rt}.parquet");
+            std::fs::copy(
+                PathBuf::from("tests/data_root/parquet/us2015b").join(&file),
+                parquet_dir.join(&file),
+            )
+            .expect("should copy the data");
+        }
+
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root.display().to_string()),
+        )
+        .expect("should be able to set up the request");
+        let result = tabulate(&ctx, rq);
+        let _ = std::fs::remove_dir_all(&data_root);
+
+        let tab = result.expect("should tabulate from a data root with a space in it");
+        assert_eq!(tab.tables()[0].rows[0], vec!["10050", "998208", "1"]);
+    }
+
+    #[test]
+    fn test_tabulate_native_db() {
+        // The fixture has us2015b_usa_person and us2015b_usa_household tables with a few of
+        // the columns of the us2015b Parquet files.
+        let db = PathBuf::from("tests/data_root/duckdb/us2015b_usa.duckdb");
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let options = TabulateOptions {
+            native_db: Some(db),
+            ..TabulateOptions::default()
+        };
+
+        let tab = tabulate_with_options(&ctx, rq, &options)
+            .expect("should tabulate from the native database");
+        let table = &tab.tables()[0];
+        assert_eq!(table.rows.len(), 6);
+        assert_eq!(table.rows[0], vec!["10050", "998208", "1"]);
+    }
+
+    #[test]
+    fn test_tabulate_age_quantile_auto_bins() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["AGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let auto_bin = AutoBin {
+            n: 4,
+            method: AutoBinMethod::Quantile,
+        };
+        let options = TabulateOptions {
+            codes_and_labels: true,
+            ..TabulateOptions::default()
+        };
+        rq.auto_bin(&ctx, "AGE", &auto_bin, &options)
+            .expect("should compute the bins from the data");
+
+        let tab = tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+        let table = &tab.tables()[0];
+        assert_eq!(table.rows.len(), 4);
+        let codes: Vec<&str> = table.rows.iter().map(|row| row[2].as_str()).collect();
+        assert_eq!(codes, vec!["1", "2", "3", "4"]);
+        assert!(table
+            .rows
+            .iter()
+            .all(|row| row[3].contains(" to ") && row[3] != UNBINNED_LABEL));
+    }
+
+    /// Codes marked missing aren't values, so they don't stretch the bins. AGE runs from 0
+    /// to 95 in us2015b, and the next largest age is 91.
+    #[test]
+    fn test_auto_bins_leave_out_missing_codes() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["AGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let auto_bin = AutoBin {
+            n: 1,
+            method: AutoBinMethod::EqualWidth,
+        };
+        let options = TabulateOptions::default();
+        let bins = auto_bins(&ctx, &rq, "AGE", &auto_bin, &options)
+            .expect("should compute the bins from the data");
+        assert_eq!(bins[0].label(), "0 to 95");
+
+        rq.variables[0].categories = Some(vec![IpumsCategory::new(
+            "Missing",
+            UniversalCategoryType::Missing,
+            IpumsValue::Integer(95),
+        )]);
+        let bins = auto_bins(&ctx, &rq, "AGE", &auto_bin, &options)
+            .expect("should compute the bins from the data");
+        assert_eq!(bins[0].label(), "0 to 91");
+    }
+
+    /// LessThan and MoreThan bins follow their inclusivity flags at their boundaries, like
+    /// CategoryBin::within. The MoreThan bin comes first so that it would take the persons
+    /// aged 64 if it were inclusive.
+    #[test]
+    fn test_tabulate_less_than_and_more_than_boundaries() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["AGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.variables[0].category_bins = Some(vec![
+            CategoryBin::MoreThan {
+                value: 64,
+                code: 3,
+                label: "65 and over".to_string(),
+                inclusive: false,
+            },
+            CategoryBin::LessThan {
+                value: 18,
+                code: 1,
+                label: "Under 18".to_string(),
+                inclusive: false,
+            },
+            CategoryBin::Range {
+                low: 18,
+                high: 64,
+                code: 2,
+                label: "18 to 64".to_string(),
+                low_inclusive: true,
+                high_inclusive: true,
+            },
+        ]);
+
+        let options = TabulateOptions {
+            codes_and_labels: true,
+            ..TabulateOptions::default()
+        };
+        let tab = tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+        let counts: HashMap<&str, &str> = tab.tables()[0]
+            .rows
+            .iter()
+            .map(|row| (row[2].as_str(), row[0].as_str()))
+            .collect();
+        // us2015b has 472 persons aged 18 and 417 aged 64.
+        assert_eq!(
+            counts,
+            HashMap::from([("1", "5583"), ("2", "17937"), ("3", "7247")])
+        );
+    }
+
+    /// Persons aged 64 are just past the exclusive end of the only bin, so like the persons
+    /// under 18 and over 64 they're outside every bin. They get an empty code labeled
+    /// "unbinned" unless the variable has a residual bin.
+    #[test]
+    fn test_tabulate_values_outside_every_bin() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = AbacusRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["AGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.request_variables[0].category_bins = Some(vec![CategoryBin::Range {
+            low: 18,
+            high: 64,
+            code: 1,
+            label: "18 to 63".to_string(),
+            low_inclusive: true,
+            high_inclusive: false,
+        }]);
+
+        let options = TabulateOptions {
+            codes_and_labels: true,
+            ..TabulateOptions::default()
+        };
+        let counts = |tab: &Tabulation| -> HashMap<(String, String), String> {
+            tab.tables()[0]
+                .rows
+                .iter()
+                .map(|row| ((row[2].clone(), row[3].clone()), row[0].clone()))
+                .collect()
+        };
+        let key = |code: &str, label: &str| (code.to_string(), label.to_string());
+
+        // us2015b has 5583 persons under 18, 417 aged 64 and 7247 over 64.
+        let tab = tabulate_with_options(&ctx, rq.clone(), &options).expect("should tabulate");
+        assert_eq!(
+            counts(&tab),
+            HashMap::from([
+                (key("1", "18 to 63"), "17520".to_string()),
+                (key("", UNBINNED_LABEL), "13247".to_string()),
+            ])
+        );
+
+        rq.request_variables[0].residual_label = Some("Other".to_string());
+        let tab = tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+        assert_eq!(
+            counts(&tab),
+            HashMap::from([
+                (key("1", "18 to 63"), "17520".to_string()),
+                (key("999", "Other"), "13247".to_string()),
+            ])
+        );
+    }
+
+    /// The fixture CSV files have the first 1000 us2015b persons, uncompressed and compressed.
+    #[test]
+    fn test_tabulate_compressed_csv() {
+        let data_root = String::from("tests/data_root");
+        let (mut ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let options = TabulateOptions {
+            csv: true,
+            ..TabulateOptions::default()
+        };
+        let uncompressed = tabulate_with_options(&ctx, rq.clone(), &options)
+            .expect("should tabulate from the CSV file");
+
+        ctx.set_file_extension(InputType::Csv, "csv.gz");
+        let compressed = tabulate_with_options(&ctx, rq, &options)
+            .expect("should tabulate from the compressed CSV file");
+
+        let table = &compressed.tables()[0];
+        assert_eq!(table.rows, uncompressed.tables()[0].rows);
+        assert_eq!(table.rows.len(), 6);
+        assert_eq!(table.rows[0][0], "328");
+        let total: usize = table
+            .rows
+            .iter()
+            .map(|row| row[0].parse::<usize>().expect("counts should be integers"))
+            .sum();
+        assert_eq!(total, 1000);
+    }
+
+    #[test]
+    fn test_tabulate_person_counts_by_household_weight() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let mut hh_weighted_rq = rq.clone();
+        hh_weighted_rq
+            .set_weight_rectype(&ctx, "H")
+            .expect("should weight by HHWT");
+
+        let perwt = tabulate(&ctx, rq).expect("should tabulate with PERWT");
+        let hhwt = tabulate(&ctx, hh_weighted_rq).expect("should tabulate with HHWT");
+        let perwt_rows = &perwt.tables()[0].rows;
+        let hhwt_rows = &hhwt.tables()[0].rows;
+
+        assert_eq!(perwt_rows.len(), hhwt_rows.len());
+        // The unweighted counts and the codes match, but the weighted counts don't.
+        assert_eq!(perwt_rows[0][0], hhwt_rows[0][0]);
+        assert_eq!(perwt_rows[0][2], hhwt_rows[0][2]);
+        assert_ne!(perwt_rows[0][1], hhwt_rows[0][1]);
+    }
+
+    #[test]
+    fn test_tabulate_unsupported_platform_error() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let options = TabulateOptions {
+            platform: DataPlatform::Sqlite,
+            ..TabulateOptions::default()
+        };
+        let result = tabulate_with_options(&ctx, rq, &options);
+        assert!(result.is_err());
+    }
+
+    /// Several tabulations can share a connection the caller configured, and give the same
+    /// tables as tabulations on connections of their own.
+    #[test]
+    fn test_tabulate_with_connection() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us2016b"],
+            &["MARST", "GQ"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let options = TabulateOptions::default();
+        let expected = tabulate_with_options(&ctx, rq.clone(), &options)
+            .expect("should tabulate on a new connection");
+
+        let conn = Connection::open_in_memory().expect("should open a connection");
+        conn.execute_batch("SET threads = 1")
+            .expect("should configure the connection");
+        for _ in 0..2 {
+            let tabulation = tabulate_with_connection(&ctx, rq.clone(), &options, &conn)
+                .expect("should tabulate on the shared connection");
+            assert_eq!(tabulation.tables.len(), expected.tables.len());
+            for (table, expected_table) in tabulation.tables.iter().zip(&expected.tables) {
+                assert_eq!(table.title, expected_table.title);
+                assert_eq!(table.rows, expected_table.rows);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tabulate_with_connection_other_platform_error() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let options = TabulateOptions {
+            platform: DataPlatform::Sqlite,
+            ..TabulateOptions::default()
+        };
+        let conn = Connection::open_in_memory().expect("should open a connection");
+        let err = tabulate_with_connection(&ctx, rq, &options, &conn)
+            .expect_err("should only run DuckDB queries on a DuckDB connection");
+        assert!(err.to_string().contains("Can't run Sqlite queries"));
+    }
+
+    #[cfg(feature = "datafusion")]
+    #[test]
+    fn test_tabulate_datafusion_matches_duckdb() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let options = TabulateOptions {
+            platform: DataPlatform::DataFusion,
+            ..TabulateOptions::default()
+        };
+        let datafusion_tab = tabulate_with_options(&ctx, rq.clone(), &options)
+            .expect("should tabulate with DataFusion");
+        let duckdb_tab = tabulate(&ctx, rq).expect("should tabulate with DuckDB");
+
+        let table = &datafusion_tab.tables()[0];
+        assert_eq!(table.rows[0], vec!["10050", "998208", "1"]);
+        assert_eq!(table.rows, duckdb_tab.tables()[0].rows);
+    }
+
+    #[cfg(feature = "datafusion")]
+    #[test]
+    fn test_arrow_value_string() {
+        use datafusion::arrow::array::{Float64Array, Int64Array, StringArray};
+
+        use super::datafusion_engine::arrow_value_string;
+
+        let counts = Int64Array::from(vec![Some(12), None]);
+        assert_eq!(arrow_value_string(&counts, 0).unwrap(), "12");
+        assert_eq!(arrow_value_string(&counts, 1).unwrap(), "");
+
+        let weighted = Float64Array::from(vec![12.5]);
+        assert_eq!(arrow_value_string(&weighted, 0).unwrap(), "12.5");
+
+        let codes = StringArray::from(vec![Some("001"), Some("Other"), None]);
+        assert_eq!(arrow_value_string(&codes, 0).unwrap(), "1");
+        assert_eq!(arrow_value_string(&codes, 1).unwrap(), "Other");
+        assert_eq!(arrow_value_string(&codes, 2).unwrap(), "");
+    }
+
+    #[test]
+    fn test_tabulate_person_and_household_tables() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["STATEFIP"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.table_units = vec!["P".to_string(), "H".to_string()];
+
+        let tab = tabulate(&ctx, rq).expect("should tabulate");
+        let titles: Vec<_> = tab.tables().iter().map(|t| t.title.clone()).collect();
+        assert_eq!(
+            titles,
+            vec![
+                Some("us2015b person".to_string()),
+                Some("us2015b household".to_string())
+            ]
+        );
+        // Puerto Rico persons, then Puerto Rico households, weighted by PERWT and HHWT.
+        assert_eq!(tab.tables()[0].rows, vec![vec!["30767", "3474182", "72"]]);
+        assert_eq!(tab.tables()[1].rows, vec![vec!["16633", "1612992", "72"]]);
+    }
+
+    #[test]
+    fn test_tabulate_with_codebook() {
+        let json_request =
+            include_str!("../tests/requests/single_condition_subpop_abacus_request.json");
+
+        let (ctx, rq) = AbacusRequest::try_from_json(json_request)
+            .expect("Error loading test context and deserializing test request.");
+        let expected_codebook = rq.print_codebook();
+
+        let options = TabulateOptions {
+            include_codebook: true,
+            ..TabulateOptions::default()
+        };
+        let tab = tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+        let codebook = tab
+            .codebook()
+            .expect("codebook should be present when requested");
+        assert_eq!(expected_codebook, codebook);
+        assert!(codebook.contains("SCHOOL"));
+    }
+
+    #[test]
+    fn test_tabulate_without_codebook() {
+        let json_request =
+            include_str!("../tests/requests/single_condition_subpop_abacus_request.json");
+
+        let (ctx, rq) = AbacusRequest::try_from_json(json_request)
+            .expect("Error loading test context and deserializing test request.");
+
+        let tab = tabulate(&ctx, rq).expect("should tabulate");
+        assert!(tab.codebook().is_none());
+    }
+
+    /// There's metadata for us2015a but no data, so it's skipped with a warning.
+    #[test]
+    fn test_skip_missing_datasets() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us2015a"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let options = TabulateOptions {
+            skip_missing_datasets: true,
+            ..TabulateOptions::default()
+        };
+        let tab = tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+
+        let titles: Vec<_> = tab.tables().iter().map(|t| t.title.as_deref()).collect();
+        assert_eq!(titles, vec![Some("us2015b")]);
+        assert_eq!(tab.warnings().len(), 1);
+        assert!(tab.warnings()[0].contains("us2015a"));
+    }
+
+    /// Count the persons in each household and tabulate households in Puerto Rico by size.
+    #[test]
+    fn test_household_size_distribution() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["STATEFIP"],
+            Some("H".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let options = TabulateOptions {
+            count_members: Some("P".to_string()),
+            ..TabulateOptions::default()
+        };
+        let tab = tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+        let table = &tab.tables()[0];
+
+        let names: Vec<_> = table.heading.iter().map(|c| c.name()).collect();
+        assert_eq!(names, vec!["ct", "weighted_ct", "person_count", "STATEFIP"]);
+        // Household sizes 0 (vacant units) through 12
+        assert_eq!(table.rows.len(), 13);
+        assert_eq!(table.rows[0], vec!["3328", "351683", "0", "72"]);
+        assert_eq!(table.rows[1], vec!["4370", "370265", "1", "72"]);
+        assert_eq!(table.rows[12], vec!["3", "179", "12", "72"]);
+    }
+
+    #[test]
+    fn test_count_matches_tabulation_totals() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us2016b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+
+        let (ct, weighted_ct) = ctx.count(rq.clone()).expect("should count");
+        let tab = tabulate(&ctx, rq).expect("should tabulate");
+        let rows = tab.tables().iter().flat_map(|table| &table.rows);
+        let tab_ct: usize = rows
+            .clone()
+            .map(|row| row[0].parse::<usize>().unwrap())
+            .sum();
+        let tab_weighted_ct: f64 = rows.map(|row| row[1].parse::<f64>().unwrap()).sum();
+
+        assert!(ct > 0);
+        assert_eq!(ct, tab_ct);
+        // Tabulations round the weighted count of each row.
+        assert!((weighted_ct - tab_weighted_ct).abs() < 12.0);
     }
 
-    pub fn width(&self) -> Result<usize, MdError> {
-        match self {
-            Self::Constructed { ref width, .. } => Ok(*width),
-            Self::RequestVar(ref v) => {
-                if !v.is_general() {
-                    if let Some((_, wid)) = v.variable.formatting {
-                        Ok(wid)
-                    } else {
-                        Err(metadata_error!("width from metadata variable required"))
-                    }
-                } else {
-                    if let Some(general_width) = v.variable.general_width {
-                        Ok(general_width)
-                    } else {
-                        Err(MdError::Msg(format!(
-                            "cannot determine general width for variable {}",
-                            self.name()
-                        )))
-                    }
-                }
-            }
-        }
+    #[test]
+    fn test_tabulate_condition_variable_missing_from_dataset_error() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b", "us1850a"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        // us1850a has no INCWAGE, so this subpopulation can't be applied to it.
+        let incwage = ctx
+            .get_md_variable_by_name("INCWAGE")
+            .expect("INCWAGE should be in the us2015b metadata");
+        rq.conditions = Some(vec![Condition::new(
+            &incwage,
+            &[CompareOperation::Greater("0".to_string())],
+        )
+        .expect("should make the condition")]);
+
+        let err = tabulate(&ctx, rq).expect_err("the condition can't apply to us1850a");
+        let message = err.to_string();
+        assert!(message.contains("INCWAGE"), "{message}");
+        assert!(message.contains("us1850a"), "{message}");
+        assert!(!message.contains("us2015b"), "{message}");
     }
-} // impl
 
-// If we want we can use the IpumsVariable categories to replace the numbers in the results (rows)
-// with category labels and use the data type and width information to better format the table.
+    #[test]
+    fn test_tabulate_three_way_cross_tab() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST", "SEX", "AGE"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
 
-#[derive(Clone, Debug, Serialize)]
-pub struct Table {
-    pub heading: Vec<OutputColumn>, // variable name columns
-    pub rows: Vec<Vec<String>>,
-}
+        let tab = tabulate(&ctx, rq).expect("should tabulate");
+        let table = &tab.tables()[0];
+        let names: Vec<_> = table.heading.iter().map(|c| c.name()).collect();
+        assert_eq!(names, vec!["ct", "weighted_ct", "MARST", "SEX", "AGE"]);
 
-impl Table {
-    pub fn format_as_text(&self) -> Result<String, MdError> {
-        let mut out = String::new();
-        let widths = self.column_widths()?;
-        for (column, _v) in self.heading.iter().enumerate() {
-            let name = self.heading[column].name();
-            let column_header = format!("| {n:>w$} ", n = &name, w = widths[column]);
-            out.push_str(&column_header);
-        }
-        out.push_str("|\n");
-        out.push_str(&format!(
-            "|{:}|",
-            str::repeat(&"-", self.text_table_width()? - 2)
-        ));
-        out.push_str("\n");
+        let cells: Vec<(i64, i64, i64)> = table
+            .rows
+            .iter()
+            .map(|row| {
+                let code = |column: usize| row[column].parse::<i64>().unwrap();
+                (code(2), code(3), code(4))
+            })
+            .collect();
+        let mut sorted = cells.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(
+            cells, sorted,
+            "one row per cell, ordered by MARST, SEX, AGE"
+        );
 
-        for r in &self.rows {
-            for (column, item) in r.iter().enumerate() {
-                let w = widths[column];
-                let formatted_item = format!("| {value:>width$} ", value = &item, width = w);
-                out.push_str(&formatted_item);
-            }
-            out.push_str("|\n");
-        }
-        Ok(out)
+        let ct: usize = table
+            .rows
+            .iter()
+            .map(|r| r[0].parse::<usize>().unwrap())
+            .sum();
+        assert_eq!(ct, 30767);
     }
 
-    pub fn text_table_width(&self) -> Result<usize, MdError> {
-        Ok(1 + 3 * self.heading.len() + self.column_widths()?.iter().sum::<usize>())
-    }
+    #[test]
+    fn test_tabulate_codes_and_labels() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.variables[0].categories = Some(vec![
+            IpumsCategory::new(
+                "Married, spouse present",
+                UniversalCategoryType::Value,
+                IpumsValue::Integer(1),
+            ),
+            IpumsCategory::new(
+                "Never married/single",
+                UniversalCategoryType::Value,
+                IpumsValue::Integer(6),
+            ),
+        ]);
 
-    fn column_widths(&self) -> Result<Vec<usize>, MdError> {
-        let mut widths = Vec::new();
-        for (_column, var) in self.heading.iter().enumerate() {
-            let name_width = var.name().len();
-            let width = var.width()?;
-            if name_width < width {
-                widths.push(width);
-            } else {
-                widths.push(name_width);
-            }
-            /*
-            else  if let Some(w) = self.width_from_data(column) {
-                    if name_width < w {
-                        widths.push(w);
-                    } else {
-                        widths.push(name_width);
-                    }
-                } else {
-                    return Err(MdError::Msg("Can't determine column width of data.".to_string()));
-                }
-            }
-            */
-        }
-        Ok(widths)
-    }
+        let options = TabulateOptions {
+            codes_and_labels: true,
+            ..TabulateOptions::default()
+        };
+        let tab = tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+        let table = &tab.tables()[0];
 
-    #[allow(unused)]
-    fn width_from_data(&self, column: usize) -> Option<usize> {
-        self.rows.iter().map(|r| r[column].len()).max()
+        let names: Vec<_> = table.heading.iter().map(|c| c.name()).collect();
+        assert_eq!(names, vec!["ct", "weighted_ct", "MARST", "MARST_label"]);
+        assert_eq!(
+            table.rows[0],
+            vec!["10050", "998208", "1", "Married, spouse present"]
+        );
+        // Codes without a category get an empty label
+        assert_eq!(table.rows[1][3], "");
     }
 
-    pub fn empty() -> Self {
-        Self {
-            rows: Vec::new(),
-            heading: Vec::new(),
-        }
-    }
-}
+    #[test]
+    fn test_tabulate_with_value_labels_csv() {
+        let labels_file =
+            std::env::temp_dir().join(format!("cimdea_value_labels_{}.csv", std::process::id()));
+        std::fs::write(
+            &labels_file,
+            "variable,code,label\n\
+             MARST,1,\"Married, spouse present\"\n\
+             marst,6,Never married/single\n\
+             NOTAVAR,1,Skipped\n",
+        )
+        .expect("should write the labels file");
 
-#[derive(Debug)]
-pub struct Tabulation(pub Vec<Table>);
+        let mut ctx =
+            Context::from_ipums_collection_name("usa", None, Some(String::from("tests/data_root")))
+                .expect("should be able to create USA context");
+        ctx.load_metadata_for_datasets(&["us2015b"])
+            .expect("should load metadata");
+        let loaded = ctx.load_value_labels_csv(&labels_file);
+        let _ = std::fs::remove_file(&labels_file);
+        loaded.expect("should load the value labels");
 
-impl Tabulation {
-    pub fn output(&self, format: TableFormat) -> Result<String, MdError> {
-        let output = match format {
-            TableFormat::Html | TableFormat::Csv => {
-                todo!("Output format {:?} not implemented yet.", format)
-            }
-            TableFormat::Json => match serde_json::to_string_pretty(&self.0) {
-                Ok(output) => output,
-                Err(err) => {
-                    return Err(MdError::Msg(format!(
-                        "Cannot serialize result into json: {err}"
-                    )));
-                }
-            },
-            TableFormat::TextTable => {
-                let mut output = String::new();
-                for table in &self.0 {
-                    let table_text = table.format_as_text()?;
-                    output.push_str(&format!("{table_text}\n"));
-                }
-                output
-            }
+        let marst = ctx
+            .get_md_variable_by_name("MARST")
+            .expect("MARST should be in the metadata");
+        let us2015b = ctx.settings.metadata.as_ref().unwrap().datasets_index[0].clone();
+        let rq = SimpleRequest {
+            product: "usa".to_string(),
+            datasets: vec![us2015b],
+            variables: vec![marst],
+            unit_rectype: ctx.settings.default_unit_of_analysis.clone(),
+            request_type: RequestType::Tabulation,
+            output_format: OutputFormat::Json,
+            conditions: None,
+            use_general_variables: GeneralDetailedSelection::Detailed,
+            weight_rectype: None,
+            weight_variable: None,
+            table_units: Vec::new(),
+            count_columns: CountColumns::Both,
+            case_select_logic: CaseSelectLogic::And,
+            case_selections: BTreeMap::new(),
+            general_detailed_selections: BTreeMap::new(),
         };
 
-        Ok(output)
-    }
+        let options = TabulateOptions {
+            codes_and_labels: true,
+            ..TabulateOptions::default()
+        };
+        let tab = tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+        let table = &tab.tables()[0];
 
-    pub fn into_inner(self) -> Vec<Table> {
-        self.0
+        let names: Vec<_> = table.heading.iter().map(|c| c.name()).collect();
+        assert_eq!(names, vec!["ct", "weighted_ct", "MARST", "MARST_label"]);
+        assert_eq!(table.rows[0][2..], ["1", "Married, spouse present"]);
+        // Codes without a label in the file are unlabeled
+        assert_eq!(table.rows[1][3], "");
+        assert_eq!(table.rows[5][2..], ["6", "Never married/single"]);
     }
-}
 
-/// Compute the result of a tabulation request.
-///
-/// A single request can result in multiple tables. Normally there is one table per IPUMS dataset
-/// in the request. Right now `InputType::Parquet` and `DataPlatform::Duckdb` are hard-coded in;
-/// they're the main use-case for now. `InputType::Csv` ought to be pretty interchangable except
-/// for performance implications. The `DataPlatform::DataFusion` alternative would require minor
-/// additions to the query generation module. `DataPlatform::Polars` is also planned and shouldn't
-/// require too many additional query gen updates, but it is unimplemented for now.
-pub fn tabulate<R>(ctx: &Context, rq: R) -> Result<Tabulation, MdError>
-where
-    R: DataRequest,
-{
-    let requested_output_columns = rq
-        .get_request_variables()
-        .iter()
-        .map(|v| OutputColumn::RequestVar(v.clone()))
-        .collect::<Vec<OutputColumn>>();
+    #[test]
+    fn test_tabulate_warn_missing_labels() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, mut rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        rq.variables[0].categories = Some(
+            [1, 2, 3, 4, 6]
+                .into_iter()
+                .map(|code| {
+                    IpumsCategory::new(
+                        &format!("Label {code}"),
+                        UniversalCategoryType::Value,
+                        IpumsValue::Integer(code),
+                    )
+                })
+                .collect(),
+        );
 
-    let mut tables: Vec<Table> = Vec::new();
-    let sql_queries = tab_queries(ctx, rq, &InputType::Parquet, &DataPlatform::Duckdb)?;
-    let conn = Connection::open_in_memory()?;
-    for q in sql_queries {
-        if DEBUG {
-            println!("{}", &q);
-        }
-        let mut stmt = conn.prepare(&q)?;
-        let mut rows = stmt.query([])?;
+        let options = TabulateOptions {
+            codes_and_labels: true,
+            warn_missing_labels: true,
+            ..TabulateOptions::default()
+        };
+        let tab = tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+        assert_eq!(tab.warnings(), ["MARST code 5 has no label"]);
+    }
 
-        let mut output = Table {
-            heading: Vec::new(),
-            rows: Vec::new(),
+    #[test]
+    fn test_with_labels_collects_unlabeled_codes() {
+        let mut table = marst_table(&[]);
+        let OutputColumn::RequestVar(ref marst) = table.heading[2] else {
+            panic!("MARST should be a request variable column");
         };
-        output.heading.push(OutputColumn::Constructed {
-            name: "ct".to_string(),
-            width: 10,
-            data_type: IpumsDataType::Integer,
-        });
-        output.heading.push(OutputColumn::Constructed {
-            name: "weighted_ct".to_string(),
-            width: 10,
-            data_type: IpumsDataType::Integer,
-        });
-        output.heading.extend(requested_output_columns.clone());
-
-        while let Some(row) = rows.next()? {
-            let mut this_row = Vec::new();
-            // Must do this here on row rather than getting column_names() from
-            // stmt.column_names() because of a bug in the DuckDB API -- it
-            // works on rsqlite but not DuckDB.
-            // See https://github.com/duckdb/duckdb-rs/issues/251
-            let column_names = row.as_ref().column_names();
-            for (column_number, column_name) in column_names.iter().enumerate() {
-                /*
-                // Leaving this here as a reminder of how to debug the DuckDB result
-                // set values; it's different than Rqlite.
-                match row.get_ref(column_number) {
-                    Ok(d) =>println!("{}: {:?}", &column_name, &d),
-                    Err(e) => println!("{}: error: {}", &column_name, e),
+        let mut marst = marst.clone();
+        marst.variable.categories = Some(vec![IpumsCategory::new(
+            "Married, spouse present",
+            UniversalCategoryType::Value,
+            IpumsValue::Integer(1),
+        )]);
+        table.heading.push(OutputColumn::Label(marst));
 
-                }
-                */
-                let item: isize = match row.get(column_number) {
-                    Ok(i) => i,
-                    Err(e) => {
-                        return Err(MdError::Msg(format!(
-                            "Can't extract value for '{}', error was '{}'",
-                            &column_name, e
-                        )))
-                    }
-                };
-                this_row.push(format!("{}", item));
-            }
-            output.rows.push(this_row);
+        let mut unlabeled = Vec::new();
+        for row in [["10", "1000", "1"], ["5", "400", "9"], ["2", "100", "9"]] {
+            let row = row.iter().map(|s| s.to_string()).collect();
+            with_labels(&table.heading, row, &mut unlabeled);
         }
-        tables.push(output);
+        assert_eq!(unlabeled, vec!["MARST code 9 has no label"]);
     }
 
-    Ok(Tabulation(tables))
-}
+    #[test]
+    fn test_table_with_code_labels() {
+        let mut table = marst_table(&[["10", "1000", "1"], ["5", "400", "9"], ["2", "100", ""]]);
+        let OutputColumn::RequestVar(ref mut marst) = table.heading[2] else {
+            panic!("MARST should be a request variable column");
+        };
+        marst.variable.categories = Some(vec![IpumsCategory::new(
+            "Married, spouse present",
+            UniversalCategoryType::Value,
+            IpumsValue::Integer(1),
+        )]);
+        let marst = marst.clone();
+        table.heading.push(OutputColumn::Label(marst));
+        for row in &mut table.rows {
+            row.push(String::new());
+        }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::request::{AbacusRequest, SimpleRequest};
-    use std::time::*;
+        let labeled = table.with_code_labels();
+        let names: Vec<String> = labeled.heading.iter().map(OutputColumn::name).collect();
+        assert_eq!(names, ["ct", "weighted_ct", "MARST"]);
+        assert_eq!(labeled.rows[0], ["10", "1000", "Married, spouse present"]);
+        assert_eq!(labeled.rows[1], ["5", "400", "9 (no label)"]);
+        assert_eq!(labeled.rows[2], ["2", "100", ""]);
+
+        let tab = Tabulation {
+            tables: vec![table],
+            codebook: None,
+            warnings: Vec::new(),
+        };
+        let csv = tab
+            .output_with_labels(TableFormat::Csv)
+            .expect("should format the labeled tables");
+        assert_eq!(
+            csv,
+            "ct,weighted_ct,MARST\n10,1000,\"Married, spouse present\"\n5,400,9 (no label)\n2,100,\n"
+        );
+    }
 
+    /// Many threads contend for two slots; no more than two ever hold one at once.
     #[test]
-    fn test_complex_tabulation() {
-        let tabtime = Instant::now();
-        let json_request = include_str!("../tests/requests/incwage_marst_example.json");
+    fn test_in_flight_limit_under_contention() {
+        let limit = InFlightLimit::new(2);
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
 
-        let (ctx, rq) = AbacusRequest::try_from_json(&json_request)
-            .expect("Error loading test context and deserializing test request.");
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let _permit = limit.acquire();
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
 
-        //println!("Codebook: {}", rq.print_codebook());
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(*limit.running.lock().unwrap(), 0);
+    }
 
-        let result = tabulate(&ctx, rq);
-        if let Err(ref e) = result {
-            eprintln!("Error setting up test: {:?}", e);
-        }
-        println!("Test tabulation took {} ms", tabtime.elapsed().as_millis());
-        assert!(result.is_ok());
-        if let Ok(tab) = result {
-            if let Ok(output) = tab.output(TableFormat::TextTable) {
-                println!("{output}");
-            }
+    /// Every concurrent tabulation gets the same result as a tabulation run on its own.
+    #[test]
+    fn test_tabulator_max_in_flight() {
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let expected = tabulate(&ctx, rq.clone()).expect("should tabulate");
 
-            let tables = tab.into_inner();
-            assert_eq!(2, tables.len());
+        let tabulator = Tabulator::new(ctx, TabulateOptions::default(), Some(2))
+            .expect("should create the tabulator");
+        assert_eq!(tabulator.max_in_flight(), Some(2));
 
-            for (index, table) in tables.iter().enumerate() {
-                // There are some category combinations  rare enough not to exist on every sample
-                if index == 0 {
-                    assert_eq!(
-                        79,
-                        table.rows.len(),
-                        "6 marst X 15 incwage - a few combinations"
-                    );
-                }
-                if index == 1 {
-                    assert_eq!(
-                        77,
-                        table.rows.len(),
-                        "6 marst X 15 incwage - a few combinations"
-                    );
-                }
-            }
+        let results: Vec<Tabulation> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..6)
+                .map(|_| {
+                    let rq = rq.clone();
+                    let tabulator = &tabulator;
+                    scope.spawn(move || tabulator.tabulate(rq).expect("should tabulate"))
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        for tab in results {
+            assert_eq!(tab.tables()[0].rows, expected.tables()[0].rows);
         }
     }
 
+    #[test]
+    fn test_tabulator_zero_max_in_flight_error() {
+        let ctx = Context::from_ipums_collection_name("usa", None, None)
+            .expect("should be able to create USA context");
+        let result = Tabulator::new(ctx, TabulateOptions::default(), Some(0));
+        assert!(result.is_err());
+    }
+
     #[test]
     fn test_subpopulation() {
         let json_request =
@@ -552,4 +4108,61 @@ mod test {
             }
         }
     }
+
+    // Collects log messages from every test in this process so that a test can look for
+    // the ones it expects.
+    struct CapturingLogger {
+        messages: Mutex<Vec<String>>,
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let mut messages = self.messages.lock().unwrap_or_else(|e| e.into_inner());
+            messages.push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURING_LOGGER: CapturingLogger = CapturingLogger {
+        messages: Mutex::new(Vec::new()),
+    };
+
+    #[test]
+    fn test_tabulation_logs_with_request_id() {
+        // Another test may have installed the logger already.
+        let _ = log::set_logger(&CAPTURING_LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let data_root = String::from("tests/data_root");
+        let (ctx, rq) = SimpleRequest::from_names(
+            "usa",
+            &["us2015b"],
+            &["MARST"],
+            Some("P".to_string()),
+            None,
+            Some(data_root),
+        )
+        .expect("should be able to set up the request");
+        let options = TabulateOptions {
+            request_id: Some("req-8f3a".to_string()),
+            ..TabulateOptions::default()
+        };
+        tabulate_with_options(&ctx, rq, &options).expect("should tabulate");
+
+        let messages = CAPTURING_LOGGER
+            .messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let ours: Vec<&String> = messages
+            .iter()
+            .filter(|m| m.starts_with("[req-8f3a] "))
+            .collect();
+        assert!(ours.iter().any(|m| m.contains("running query for us2015b")));
+        assert!(ours.iter().any(|m| m.contains("row(s) for us2015b")));
+    }
 }
@@ -4,6 +4,7 @@
 //! https://rust-cli.github.io/book/tutorial/testing.html.
 
 use assert_cmd::Command;
+use cimdea::request::{DataRequest, SimpleRequest};
 use predicates::prelude::*;
 use serde_json;
 
@@ -181,3 +182,27 @@ fn test_tab_zero_variables() {
     let pred = predicate::str::contains("Must supply at least one request variable");
     assert.failure().stderr(pred);
 }
+
+/// 'abacus tab' accepts the arguments from DataRequest::to_cli_args() and recreates the
+/// request they came from.
+#[test]
+fn test_tab_request_cli_args() {
+    let (_, rq) = SimpleRequest::from_names(
+        "usa",
+        &["us2015b"],
+        &["MARST", "AGE"],
+        Some("P".to_string()),
+        None,
+        Some("tests/data_root".to_string()),
+    )
+    .expect("should be able to set up the request");
+    let args = rq
+        .to_cli_args()
+        .expect("the command line should recreate the request");
+
+    let mut command = Command::cargo_bin("abacus").unwrap();
+    let assert = command.args(args).args(["-d", "tests/data_root"]).assert();
+
+    let pred = predicate::str::starts_with("ct,weighted_ct,MARST,AGE\n");
+    assert.success().stdout(pred);
+}
@@ -1,6 +1,9 @@
 //! Tabulation integration tests
-use cimdea::request::AbacusRequest;
-use cimdea::tabulate::{tabulate, Table};
+use cimdea::query_gen::Condition;
+use cimdea::request::{AbacusRequest, DataRequest, SimpleRequest};
+use cimdea::tabulate::{
+    tabulate, tabulate_with_options, Table, TabulateOptions, NO_RECORDS_MESSAGE,
+};
 
 /// This test tabulates a single P variable MARST, which does not have category
 /// bins. There are no subpopulations applied.
@@ -29,6 +32,119 @@ fn test_no_category_bins_no_subpops() {
     key.check(&table);
 }
 
+/// This test tabulates MARST from the us2016b CSV fixture, which has the first 100 us2016b
+/// persons with MARST left empty for every tenth of them. DuckDB reads the empty MARST
+/// values as NULL, so restricting to persons whose MARST is not missing drops those 10
+/// persons, and restricting to persons whose MARST is missing keeps only them.
+#[test]
+fn test_is_not_null_subpop() {
+    let (ctx, mut rq) = SimpleRequest::from_names(
+        "usa",
+        &["us2016b"],
+        &["MARST"],
+        Some("P".to_string()),
+        None,
+        Some("tests/data_root".to_string()),
+    )
+    .expect("should be able to set up the request");
+    let options = TabulateOptions {
+        csv: true,
+        ..TabulateOptions::default()
+    };
+    let marst = ctx
+        .get_md_variable_by_name("MARST")
+        .expect("should have MARST metadata");
+
+    let everyone = tabulate_with_options(&ctx, rq.clone(), &options)
+        .expect("tabulation should run without errors");
+    let total: usize = everyone.tables()[0]
+        .rows
+        .iter()
+        .map(|row| row[0].parse::<usize>().expect("counts should be integers"))
+        .sum();
+    assert_eq!(total, 100);
+
+    rq.conditions = Some(vec![
+        Condition::is_not_null(&marst).expect("should create the condition")
+    ]);
+    let tab = tabulate_with_options(&ctx, rq.clone(), &options)
+        .expect("tabulation should run without errors");
+    let tables = tab.into_inner();
+    assert_eq!(tables.len(), 1, "expected exactly one output table");
+    let key = KeyTable {
+        column_names: ["ct", "weighted_ct", "MARST"],
+        rows: [
+            [23, 2155, 1],
+            [4, 651, 2],
+            [2, 272, 3],
+            [10, 1296, 4],
+            [6, 691, 5],
+            [45, 5851, 6],
+        ],
+    };
+    key.check(&tables[0]);
+
+    rq.conditions = Some(vec![
+        Condition::is_null(&marst).expect("should create the condition")
+    ]);
+    let tab =
+        tabulate_with_options(&ctx, rq, &options).expect("tabulation should run without errors");
+    assert_eq!(tab.tables()[0].rows, vec![vec!["10", "1447", ""]]);
+}
+
+/// This test tabulates MARST, restricted to persons whose string RECTYPEP starts with
+/// "P". That's every person record, so the counts match the unrestricted tabulation.
+/// Restricting to a prefix that no record has gives an empty table.
+#[test]
+fn test_starts_with_subpop() {
+    let (ctx, rq) = SimpleRequest::from_names(
+        "usa",
+        &["us2015b"],
+        &["MARST", "RECTYPEP"],
+        Some("P".to_string()),
+        None,
+        Some("tests/data_root".to_string()),
+    )
+    .expect("should be able to set up the request");
+    let marst = ctx
+        .get_md_variable_by_name("MARST")
+        .expect("should have MARST metadata");
+    let rectype = ctx
+        .get_md_variable_by_name("RECTYPEP")
+        .expect("should have RECTYPEP metadata");
+
+    let mut person_rq = rq.clone();
+    person_rq.variables = vec![marst];
+    person_rq.conditions = Some(vec![
+        Condition::starts_with(&rectype, "P").expect("should create the condition")
+    ]);
+    let mut household_rq = person_rq.clone();
+    household_rq.conditions = Some(vec![
+        Condition::starts_with(&rectype, "H").expect("should create the condition")
+    ]);
+
+    let tab = tabulate(&ctx, person_rq).expect("tabulation should run without errors");
+    let tables = tab.into_inner();
+    assert_eq!(tables.len(), 1, "expected exactly one output table");
+    let table = tables[0].clone();
+
+    let key = KeyTable {
+        column_names: ["ct", "weighted_ct", "MARST"],
+        rows: [
+            [10050, 998208, 1],
+            [499, 54103, 2],
+            [707, 82407, 3],
+            [3670, 404131, 4],
+            [2267, 204365, 5],
+            [13574, 1730968, 6],
+        ],
+    };
+    key.check(&table);
+
+    let tab = tabulate(&ctx, household_rq).expect("tabulation should run without errors");
+    assert!(tab.is_empty());
+}
+
 /// This test tabulates the P variable MARST with no category bins. It restricts
 /// to the subpopulation SEX = 2, so just women.
 #[test]
@@ -354,6 +470,39 @@ fn test_multiple_request_samples() {
     key_us2016b.check(&table_us2016b);
 }
 
+/// Each output table is titled with the name of its request sample, and the tables
+/// come out in the same order as the request samples.
+#[test]
+fn test_multiple_request_samples_table_titles() {
+    let input_json = include_str!("requests/multiple_request_samples.json");
+    let (ctx, rq) =
+        AbacusRequest::try_from_json(input_json).expect("should be able to parse input JSON");
+    let tab = tabulate(&ctx, rq).expect("should run tabulation without errors");
+
+    let titles: Vec<Option<&str>> = tab.tables().iter().map(|t| t.title.as_deref()).collect();
+    assert_eq!(titles, vec![Some("us2015b"), Some("us2016b")]);
+}
+
+/// The us1850a person records are split into two Parquet part files, with three
+/// and two persons. Tabulating SEX should read all five persons from both parts.
+#[test]
+fn test_multi_part_parquet() {
+    let input_json = include_str!("requests/multi_part_parquet.json");
+    let (ctx, rq) =
+        AbacusRequest::try_from_json(input_json).expect("should be able to parse input JSON");
+    let tab = tabulate(&ctx, rq).expect("should run tabulation without errors");
+
+    let tables = tab.into_inner();
+    assert_eq!(tables.len(), 1, "expected exactly one output table");
+    let table = tables[0].clone();
+
+    let key = KeyTable {
+        column_names: ["ct", "weighted_ct", "SEX"],
+        rows: [[2, 200, 1], [3, 300, 2]],
+    };
+    key.check(&table);
+}
+
 /// This test tabulates the two variables GQ and UHRSWORK. GQ does not have
 /// category bins applied, but UHRSWORK does. There is no subpopulation requested.
 #[test]
@@ -552,6 +701,32 @@ fn test_filter_on_statefip() {
     key.check(&table);
 }
 
+/// No one in the data has SEX between 7 and 9, so this subpopulation excludes
+/// every record. The tabulation still produces a table with a heading, but it
+/// has no rows.
+#[test]
+fn test_impossible_subpop_empty_result() {
+    let input_json = include_str!("requests/impossible_subpop.json");
+    let (ctx, rq) =
+        AbacusRequest::try_from_json(input_json).expect("should be able to parse input JSON");
+    let tab = tabulate(&ctx, rq).expect("tabulation should run without errors");
+    assert!(tab.is_empty());
+
+    let tables = tab.into_inner();
+    assert_eq!(tables.len(), 1, "expected exactly one output table");
+    let table = &tables[0];
+    assert!(table.is_empty());
+    assert_eq!(table.heading.len(), 3);
+
+    // Every format says so in place of the rows.
+    let text = table.format_as_text().expect("should format as text");
+    assert!(text.contains(NO_RECORDS_MESSAGE));
+    assert!(table.format_as_csv().contains(NO_RECORDS_MESSAGE));
+    assert!(table.format_as_html().contains(NO_RECORDS_MESSAGE));
+    let json = table.format_as_json().expect("should format as JSON");
+    assert!(json.contains(NO_RECORDS_MESSAGE));
+}
+
 /// A helpful struct for simplifying comparisons of a tabulation result to a key
 /// table. Uses const generics W (width) and H (height) to keep track of the width
 /// and height of the table.